@@ -0,0 +1,27 @@
+fn main() {
+    // With bundled-spatialite, link libspatialite and everything it depends
+    // on statically, so the binary doesn't need mod_spatialite installed.
+    #[cfg(feature = "bundled-spatialite")]
+    {
+        if let Err(err) = pkg_config::Config::new().statik(true).probe("spatialite") {
+            panic!(
+                "bundled-spatialite needs a static libspatialite, found with pkg-config: {}",
+                err
+            );
+        }
+    }
+
+    // With capi, generate the header for the C interface.
+    #[cfg(feature = "capi")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        match cbindgen::generate(&crate_dir) {
+            Ok(bindings) => {
+                bindings.write_to_file(
+                    std::path::Path::new(&crate_dir).join("include/ebird2spatialite.h"),
+                );
+            }
+            Err(err) => panic!("Could not generate the C header: {}", err),
+        }
+    }
+}
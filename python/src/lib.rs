@@ -0,0 +1,433 @@
+use ebird::{coords, db, filter, grid, input, near, spatialite, EBirdRecord};
+use pyo3::exceptions::{PyIOError, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
+use regex::Regex;
+use rusqlite::Connection;
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+
+// Errors in arguments are InvalidInput, as for the command line.
+fn to_py_err(err: io::Error) -> PyErr {
+    if err.kind() == io::ErrorKind::InvalidInput {
+        PyValueError::new_err(err.to_string())
+    } else {
+        PyIOError::new_err(err.to_string())
+    }
+}
+
+fn to_py(py: Python, value: &serde_json::Value) -> PyResult<PyObject> {
+    Ok(match value {
+        serde_json::Value::Null => py.None(),
+        serde_json::Value::Bool(value) => value.into_pyobject(py)?.to_owned().into_any().unbind(),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(number) => number.into_pyobject(py)?.into_any().unbind(),
+            None => number
+                .as_f64()
+                .unwrap_or(f64::NAN)
+                .into_pyobject(py)?
+                .into_any()
+                .unbind(),
+        },
+        serde_json::Value::String(text) => text.into_pyobject(py)?.into_any().unbind(),
+        value => value.to_string().into_pyobject(py)?.into_any().unbind(),
+    })
+}
+
+/// A record read from eBird data, whose columns can be read with
+/// record["common_name"] or all together with to_dict().
+#[pyclass(unsendable, module = "ebird2spatialite")]
+struct Record {
+    inner: EBirdRecord,
+}
+
+#[pymethods]
+impl Record {
+    fn __getitem__(&self, py: Python, name: &str) -> PyResult<PyObject> {
+        match self.inner.to_json().get(name) {
+            Some(value) => to_py(py, value),
+            None => Err(PyKeyError::new_err(name.to_string())),
+        }
+    }
+
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        for (name, value) in self.inner.to_json() {
+            dict.set_item(name, to_py(py, &value)?)?;
+        }
+        Ok(dict)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Record({} {} on {})",
+            self.inner.guid, self.inner.common_name, self.inner.obs_date
+        )
+    }
+}
+
+/// Iterates over the records of an input file, as returned by read().
+/// Records which can't be parsed are skipped and counted in skipped, unless
+/// read() was given errors="raise".
+#[pyclass(unsendable, module = "ebird2spatialite")]
+struct Reader {
+    records: input::Records,
+    raise_errors: bool,
+    #[pyo3(get)]
+    skipped: usize,
+}
+
+#[pymethods]
+impl Reader {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> PyResult<Option<Record>> {
+        for record in &mut self.records {
+            match record {
+                Ok(inner) => return Ok(Some(Record { inner })),
+                Err(err) if self.raise_errors => {
+                    return Err(PyValueError::new_err(format!(
+                        "could not parse record: {}",
+                        err
+                    )))
+                }
+                Err(_) => self.skipped += 1,
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Read an EBD file, or a personal export with format="personal", which may
+/// be compressed or an HTTP(S) URL. With errors="raise" a record which can't
+/// be parsed raises ValueError rather than being skipped.
+#[pyfunction]
+#[pyo3(signature = (path, format = "ebd", errors = "skip"))]
+fn read(path: &str, format: &str, errors: &str) -> PyResult<Reader> {
+    let raise_errors = match errors {
+        "skip" => false,
+        "raise" => true,
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "Unknown errors {}, expected skip or raise",
+                errors
+            )))
+        }
+    };
+    let stats = Arc::new(input::InputStats::default());
+    let (records, _) =
+        input::open_records(path, format, format == "personal", None, stats).map_err(to_py_err)?;
+    Ok(Reader {
+        records,
+        raise_errors,
+        skipped: 0,
+    })
+}
+
+thread_local! {
+    // An exception raised by a Python filter, which can't be returned through
+    // the Filter trait, so is raised once matching finishes.
+    static CALLBACK_ERROR: RefCell<Option<PyErr>> = const { RefCell::new(None) };
+}
+
+fn take_callback_error() -> PyResult<()> {
+    match CALLBACK_ERROR.with(|error| error.borrow_mut().take()) {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+struct Callable(PyObject);
+
+impl filter::Filter for Callable {
+    fn matches(&self, record: &EBirdRecord) -> bool {
+        Python::with_gil(|py| {
+            let record = Record {
+                inner: record.clone(),
+            };
+            match self
+                .0
+                .call1(py, (record,))
+                .and_then(|keep| keep.is_truthy(py))
+            {
+                Ok(keep) => keep,
+                Err(err) => {
+                    CALLBACK_ERROR.with(|error| {
+                        error.borrow_mut().get_or_insert(err);
+                    });
+                    false
+                }
+            }
+        })
+    }
+}
+
+/// A test of whether to keep a record, made with the static methods and
+/// combined with &, | and ~.
+#[pyclass(unsendable, module = "ebird2spatialite")]
+#[derive(Clone)]
+struct Filter {
+    inner: Rc<dyn filter::Filter>,
+}
+
+fn parse_date(text: &str) -> PyResult<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map_err(|err| PyValueError::new_err(format!("Invalid date {}: {}", text, err)))
+}
+
+fn regex(pattern: &str) -> PyResult<Regex> {
+    Regex::new(pattern).map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+#[pymethods]
+impl Filter {
+    /// Records observed on or after a date, as YYYY-MM-DD.
+    #[staticmethod]
+    fn since_date(date: &str) -> PyResult<Filter> {
+        let date = parse_date(date)?;
+        Ok(Filter::new(move |record| {
+            match parse_date(&record.obs_date) {
+                Ok(other) => other >= date,
+                Err(_) => true,
+            }
+        }))
+    }
+
+    /// Records observed on or before a date, as YYYY-MM-DD.
+    #[staticmethod]
+    fn before_date(date: &str) -> PyResult<Filter> {
+        let date = parse_date(date)?;
+        Ok(Filter::new(move |record| {
+            match parse_date(&record.obs_date) {
+                Ok(other) => other <= date,
+                Err(_) => true,
+            }
+        }))
+    }
+
+    /// Records within buffer (e.g. "50km") of a location, given as
+    /// latitude,longitude or a WKT point.
+    #[staticmethod]
+    #[pyo3(signature = (location, buffer = "1km", metric = "haversine"))]
+    fn near(location: &str, buffer: &str, metric: &str) -> PyResult<Filter> {
+        let point = coords::parse_point(location).map_err(to_py_err)?;
+        let buffer = grid::parse_distance(buffer).map_err(to_py_err)?;
+        let metric = match metric {
+            "haversine" => near::Metric::Haversine,
+            "vincenty" => near::Metric::Vincenty,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown metric {}, expected haversine or vincenty",
+                    metric
+                )))
+            }
+        };
+        let near = near::Near::new(point, buffer, metric);
+        Ok(Filter::new(move |record| {
+            near.contains(record.latitude, record.longitude)
+        }))
+    }
+
+    /// Records whose common name matches a regular expression.
+    #[staticmethod]
+    fn common_name(pattern: &str) -> PyResult<Filter> {
+        let regex = regex(pattern)?;
+        Ok(Filter::new(move |record| {
+            regex.is_match(&record.common_name)
+        }))
+    }
+
+    /// Records whose scientific name matches a regular expression.
+    #[staticmethod]
+    fn scientific_name(pattern: &str) -> PyResult<Filter> {
+        let regex = regex(pattern)?;
+        Ok(Filter::new(move |record| {
+            regex.is_match(&record.scientific_name)
+        }))
+    }
+
+    /// Records of at least this many individuals, leaving out those counted
+    /// as X.
+    #[staticmethod]
+    fn min_count(count: i64) -> Filter {
+        Filter::new(
+            move |record| match record.observation_count.parse::<i64>() {
+                Ok(other) => other >= count,
+                Err(_) => false,
+            },
+        )
+    }
+
+    /// Records for which a Python function of the record returns true.
+    #[staticmethod]
+    #[pyo3(name = "where")]
+    fn where_(function: PyObject) -> Filter {
+        Filter {
+            inner: Rc::new(Callable(function)),
+        }
+    }
+
+    fn matches(&self, record: &Record) -> PyResult<bool> {
+        let keep = self.inner.matches(&record.inner);
+        take_callback_error()?;
+        Ok(keep)
+    }
+
+    fn __and__(&self, other: &Filter) -> Filter {
+        let and = filter::And(vec![
+            Box::new(self.inner.clone()),
+            Box::new(other.inner.clone()),
+        ]);
+        Filter {
+            inner: Rc::new(and),
+        }
+    }
+
+    fn __or__(&self, other: &Filter) -> Filter {
+        let or = filter::Or(vec![
+            Box::new(self.inner.clone()),
+            Box::new(other.inner.clone()),
+        ]);
+        Filter { inner: Rc::new(or) }
+    }
+
+    fn __invert__(&self) -> Filter {
+        let not = filter::Not(Box::new(self.inner.clone()));
+        Filter {
+            inner: Rc::new(not),
+        }
+    }
+}
+
+impl Filter {
+    fn new(f: impl Fn(&EBirdRecord) -> bool + 'static) -> Filter {
+        Filter {
+            inner: filter::predicate(f),
+        }
+    }
+}
+
+/// A SpatiaLite database records are imported into, replacing the table if
+/// it exists. The records are committed, and their geometry and indexes
+/// built, by close() or at the end of a with block.
+#[pyclass(unsendable, module = "ebird2spatialite")]
+struct Database {
+    conn: Option<Connection>,
+    path: String,
+    table: String,
+    spatial: bool,
+}
+
+impl Database {
+    fn conn(&self) -> PyResult<&Connection> {
+        self.conn
+            .as_ref()
+            .ok_or_else(|| PyValueError::new_err("The database is closed"))
+    }
+
+    fn insert_one(&self, record: &EBirdRecord) -> PyResult<()> {
+        db::insert_record(self.conn()?, &self.table, None, record)
+            .map_err(|err| PyIOError::new_err(format!("Could not insert record: {}", err)))?;
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl Database {
+    #[new]
+    #[pyo3(signature = (path, table = "ebird", spatialite_path = "mod_spatialite"))]
+    fn new(path: &str, table: &str, spatialite_path: &str) -> PyResult<Database> {
+        if !db::valid_table_name(table) {
+            return Err(PyValueError::new_err(format!(
+                "Invalid table name {}",
+                table
+            )));
+        }
+        db::set_spatialite_path(spatialite_path);
+        let setup_error =
+            |action: &str, err: rusqlite::Error| to_py_err(db::setup_error(action, path, err));
+        let conn = Connection::open(path).map_err(|err| setup_error("open", err))?;
+        let spatial = spatialite::probe(&conn).spatial();
+        let schema = db::Schema {
+            table,
+            spatial,
+            ..Default::default()
+        };
+        db::initialize_database(&conn, &schema).map_err(|err| setup_error("initialize", err))?;
+        conn.execute_batch("BEGIN")
+            .map_err(|err| setup_error("start a transaction on", err))?;
+        Ok(Database {
+            conn: Some(conn),
+            path: path.to_string(),
+            table: table.to_string(),
+            spatial,
+        })
+    }
+
+    fn insert(&self, record: &Record) -> PyResult<()> {
+        self.insert_one(&record.inner)
+    }
+
+    /// Insert the records from an iterable, such as a Reader, which pass a
+    /// filter if one is given, and return how many were inserted.
+    #[pyo3(signature = (records, filter = None))]
+    fn insert_all(&self, records: &Bound<PyAny>, filter: Option<&Filter>) -> PyResult<usize> {
+        let mut inserted = 0;
+        for record in records.try_iter()? {
+            let record = record?;
+            let record = record.downcast::<Record>()?.borrow();
+            if let Some(filter) = filter {
+                let keep = filter.inner.matches(&record.inner);
+                take_callback_error()?;
+                if !keep {
+                    continue;
+                }
+            }
+            self.insert_one(&record.inner)?;
+            inserted += 1;
+        }
+        Ok(inserted)
+    }
+
+    fn close(&mut self) -> PyResult<()> {
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        let error = |err: rusqlite::Error| {
+            PyIOError::new_err(format!("Could not finish {}: {}", self.path, err))
+        };
+        conn.execute_batch("COMMIT").map_err(error)?;
+        if self.spatial {
+            db::build_geometry(&conn, &self.table).map_err(error)?;
+        }
+        db::create_indexes(&conn, &self.table, db::DEFAULT_INDEXES).map_err(error)?;
+        Ok(())
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&mut self, _args: &Bound<PyTuple>) -> PyResult<bool> {
+        self.close()?;
+        Ok(false)
+    }
+}
+
+/// Read, filter and import eBird data into SpatiaLite, using the parser and
+/// filters of the ebird2spatialite command.
+#[pymodule]
+fn ebird2spatialite(module: &Bound<PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(read, module)?)?;
+    module.add_class::<Record>()?;
+    module.add_class::<Reader>()?;
+    module.add_class::<Filter>()?;
+    module.add_class::<Database>()?;
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::io;
+use tracing::warn;
+
+// Selects species by their four letter banding codes, e.g. AMRO for American
+// Robin, resolved to scientific names using an alpha code list such as the
+// one published by the Institute for Bird Populations. Codes can't be
+// reliably generated from common names, since the list resolves collisions
+// between them by hand.
+pub struct AlphaCodes {
+    scientific_names: HashSet<String>,
+}
+
+impl AlphaCodes {
+    // Resolve the codes with a CSV list having SPEC (the code) and SCINAME
+    // columns.
+    pub fn load<'a>(codes: impl Iterator<Item = &'a str>, path: &str) -> io::Result<AlphaCodes> {
+        let codes = codes
+            .map(|code| code.trim().to_uppercase())
+            .collect::<HashSet<_>>();
+        let mut reader = csv::Reader::from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.trim().eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} has no {} column", path, name),
+                    )
+                })
+        };
+        let (code_column, name_column) = (column("SPEC")?, column("SCINAME")?);
+
+        let mut scientific_names = HashSet::new();
+        let mut found = HashSet::new();
+        for row in reader.records() {
+            let row = row?;
+            let code = row.get(code_column).unwrap_or("").trim().to_uppercase();
+            if codes.contains(&code) {
+                scientific_names.insert(row.get(name_column).unwrap_or("").trim().to_string());
+                found.insert(code);
+            }
+        }
+        for code in codes.difference(&found) {
+            warn!("unknown alpha code {}", code);
+        }
+        Ok(AlphaCodes { scientific_names })
+    }
+
+    pub fn is_match(&self, scientific_name: &str) -> bool {
+        self.scientific_names.contains(scientific_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn list(name: &str, text: &str) -> String {
+        let path = env::temp_dir().join(format!("ebird2spatialite-{}.csv", name));
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn resolves_codes_with_the_list() {
+        let path = list(
+            "alpha-codes",
+            "SP,SPEC,COMMONNAME,SCINAME\n\
+             ,AMRO,American Robin,Turdus migratorius\n\
+             ,BCCH,Black-capped Chickadee,Poecile atricapillus\n\
+             ,BLJA,Blue Jay,Cyanocitta cristata\n",
+        );
+        let codes = AlphaCodes::load(["amro", "BCCH ", "XXXX"].iter().copied(), &path).unwrap();
+        assert!(codes.is_match("Turdus migratorius"));
+        assert!(codes.is_match("Poecile atricapillus"));
+        assert!(!codes.is_match("Cyanocitta cristata"));
+    }
+
+    #[test]
+    fn needs_code_and_name_columns() {
+        let path = list(
+            "alpha-codes-columns",
+            "SPEC,COMMONNAME\nAMRO,American Robin\n",
+        );
+        assert!(AlphaCodes::load(["AMRO"].iter().copied(), &path).is_err());
+    }
+}
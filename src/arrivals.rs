@@ -0,0 +1,109 @@
+use crate::db;
+use crate::geojson::Feature;
+use geo::algorithm::contains::Contains;
+use geo::point;
+use rusqlite::{params, NO_PARAMS};
+use std::collections::BTreeMap;
+
+// The first and last dates a species was recorded, and its number of records.
+struct Dates {
+    common_name: String,
+    first_date: String,
+    last_date: String,
+    records: i64,
+}
+
+// Build the {table}_arrivals table, with the earliest and latest dates each
+// species was recorded in each year, for migration arrival and departure
+// analyses. With regions, dates are found separately within each region,
+// named by its name property, and records outside every region are left out.
+pub fn build_arrivals(
+    conn: &rusqlite::Connection,
+    table: &str,
+    regions: Option<&[Feature]>,
+) -> rusqlite::Result<()> {
+    let source = db::source_table(conn, table)?;
+    let mut arrivals: BTreeMap<(String, String, String), Dates> = BTreeMap::new();
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT scientific_name, common_name, obs_date, longitude, latitude FROM {}",
+        source
+    ))?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let obs_date: String = row.get(2)?;
+        let year = match obs_date.get(..4) {
+            Some(year) => year.to_string(),
+            None => continue,
+        };
+        let names = match regions {
+            Some(regions) => {
+                let location = point!(x: row.get::<_, f64>(3)?, y: row.get::<_, f64>(4)?);
+                regions
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, region)| region.geometry.contains(&location))
+                    .map(|(index, region)| match region.property("name") {
+                        Some(name) => name.to_string(),
+                        None => format!("region {}", index + 1),
+                    })
+                    .collect()
+            }
+            None => vec![String::new()],
+        };
+        for name in names {
+            let entry = arrivals
+                .entry((name, row.get(0)?, year.clone()))
+                .or_insert_with(|| Dates {
+                    common_name: String::new(),
+                    first_date: obs_date.clone(),
+                    last_date: obs_date.clone(),
+                    records: 0,
+                });
+            if entry.records == 0 {
+                entry.common_name = row.get(1)?;
+            }
+            // Dates are YYYY-MM-DD, so compare as strings.
+            if obs_date < entry.first_date {
+                entry.first_date = obs_date.clone();
+            }
+            if obs_date > entry.last_date {
+                entry.last_date = obs_date.clone();
+            }
+            entry.records += 1;
+        }
+    }
+
+    let arrivals_table = format!("{}_arrivals", table);
+    conn.execute_batch(&format!(
+        "BEGIN;
+         DROP TABLE IF EXISTS {table};
+         CREATE TABLE {table} (
+                region                          TEXT,
+                scientific_name                 TEXT,
+                common_name                     TEXT,
+                year                            INTEGER,
+                first_date                      TEXT,
+                last_date                       TEXT,
+                records                         INTEGER);",
+        table = arrivals_table
+    ))?;
+    let mut stmt = conn.prepare(&format!(
+        "INSERT INTO {} (region, scientific_name, common_name, year, first_date, last_date,
+                            records)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        arrivals_table
+    ))?;
+    for ((region, scientific_name, year), dates) in &arrivals {
+        stmt.execute(params![
+            region,
+            scientific_name,
+            dates.common_name,
+            year.parse::<i64>().ok(),
+            dates.first_date,
+            dates.last_date,
+            dates.records
+        ])?;
+    }
+    conn.execute_batch("COMMIT")
+}
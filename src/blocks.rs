@@ -0,0 +1,156 @@
+use crate::db;
+use crate::regions::Regions;
+use crate::EBirdRecord;
+use geo::MultiPolygon;
+use rusqlite::{params, NO_PARAMS};
+use std::collections::HashMap;
+use std::io;
+
+// The totals for a block in the {table}_blocks layer.
+#[derive(Default)]
+struct Summary {
+    checklists: i64,
+    hours: Option<f64>,
+    species: i64,
+    possible: i64,
+    probable: i64,
+    confirmed: i64,
+}
+
+fn wkt(geometry: &MultiPolygon<f64>) -> String {
+    let ring = |ring: &geo::LineString<f64>| {
+        let points = ring
+            .0
+            .iter()
+            .map(|coord| format!("{} {}", coord.x, coord.y))
+            .collect::<Vec<_>>();
+        format!("({})", points.join(", "))
+    };
+    let polygons = geometry
+        .0
+        .iter()
+        .map(|polygon| {
+            let rings = std::iter::once(polygon.exterior())
+                .chain(polygon.interiors())
+                .map(ring)
+                .collect::<Vec<_>>();
+            format!("({})", rings.join(", "))
+        })
+        .collect::<Vec<_>>();
+    format!("MULTIPOLYGON({})", polygons.join(", "))
+}
+
+// Breeding bird atlas blocks, read from a shapefile or GeoJSON file in
+// longitude and latitude, used to assign each record the block it is in.
+pub struct AtlasBlocks {
+    blocks: Regions,
+}
+
+impl AtlasBlocks {
+    pub fn load(path: &str, field: &str) -> io::Result<AtlasBlocks> {
+        Ok(AtlasBlocks {
+            blocks: Regions::load(path, field)?,
+        })
+    }
+
+    pub fn apply(&self, record: &mut EBirdRecord) {
+        record.atlas_block = self
+            .blocks
+            .lookup(record.latitude, record.longitude)
+            .map(|block| block.name.clone());
+    }
+
+    // Build the {table}_blocks layer, with every block's checklists, hours
+    // and number of species, broken down by their highest breeding evidence
+    // category, and {table}_block_species with the highest category and
+    // number of records of each species in each block.
+    pub fn build_summaries(
+        &self,
+        conn: &rusqlite::Connection,
+        table: &str,
+    ) -> rusqlite::Result<()> {
+        let source = db::source_table(conn, table)?;
+        let mut summaries: HashMap<String, Summary> = HashMap::new();
+        conn.execute_batch("BEGIN")?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT atlas_block, COUNT(*), SUM(duration_min) / 60.0 FROM (
+                    SELECT atlas_block, duration_min FROM {} WHERE atlas_block IS NOT NULL
+                        GROUP BY sampling_event_id)
+                GROUP BY atlas_block",
+            source
+        ))?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let summary = summaries.entry(row.get(0)?).or_default();
+            summary.checklists = row.get(1)?;
+            summary.hours = row.get(2)?;
+        }
+
+        // A checklist table has no species.
+        if !db::is_checklist_table(conn, table)? {
+            let species_table = format!("{}_block_species", table);
+            conn.execute_batch(&format!(
+                "DROP TABLE IF EXISTS {table};
+                 CREATE TABLE {table} (
+                        atlas_block                     TEXT,
+                        scientific_name                 TEXT,
+                        common_name                     TEXT,
+                        breeding_bird_atlas_category    TEXT,
+                        records                         INTEGER);
+                 INSERT INTO {table}
+                    SELECT atlas_block, scientific_name, MIN(common_name),
+                            MAX(NULLIF(breeding_bird_atlas_category, '')), COUNT(*)
+                        FROM {source} WHERE atlas_block IS NOT NULL
+                        GROUP BY atlas_block, scientific_name;",
+                table = species_table,
+                source = source
+            ))?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT atlas_block, breeding_bird_atlas_category FROM {}",
+                species_table
+            ))?;
+            let mut rows = stmt.query(NO_PARAMS)?;
+            while let Some(row) = rows.next()? {
+                let summary = summaries.entry(row.get(0)?).or_default();
+                summary.species += 1;
+                match row.get::<_, Option<String>>(1)?.as_deref() {
+                    Some("C2") => summary.possible += 1,
+                    Some("C3") => summary.probable += 1,
+                    Some("C4") => summary.confirmed += 1,
+                    _ => (),
+                }
+            }
+        }
+
+        let layer = format!("{}_blocks", table);
+        db::create_layer(
+            conn,
+            &layer,
+            "atlas_block TEXT, checklists INTEGER, hours REAL, species INTEGER,
+                possible INTEGER, probable INTEGER, confirmed INTEGER",
+            "MULTIPOLYGON",
+        )?;
+        let mut stmt = conn.prepare(&format!(
+            "INSERT INTO {} (atlas_block, checklists, hours, species, possible, probable,
+                                confirmed, geometry)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, CastToMultiPolygon(GeomFromText(?8, 4326)))",
+            layer
+        ))?;
+        let empty = Summary::default();
+        for block in self.blocks.iter() {
+            let summary = summaries.get(&block.name).unwrap_or(&empty);
+            stmt.execute(params![
+                block.name,
+                summary.checklists,
+                summary.hours,
+                summary.species,
+                summary.possible,
+                summary.probable,
+                summary.confirmed,
+                wkt(&block.geometry)
+            ])?;
+        }
+        conn.execute_batch("COMMIT")
+    }
+}
@@ -0,0 +1,622 @@
+// A C interface to the reader, filters and database import, built with
+// --features capi. The header is generated by cbindgen from the /// comments
+// here into include/ebird2spatialite.h.
+//
+// Functions returning a pointer return NULL on failure, and those returning
+// an int return 0 on success and -1 on failure, with the reason available
+// from ebird_last_error. A panic is a failure too, rather than unwinding into
+// the caller.
+use crate::filter::Filter;
+use crate::{coords, db, filter, grid, input, near, spatialite, EBirdRecord};
+use regex::Regex;
+use rusqlite::Connection;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+}
+
+fn clear_error() {
+    LAST_ERROR.with(|error| *error.borrow_mut() = None);
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Turn a result into a pointer, NULL if it failed.
+fn to_ptr<T>(result: io::Result<T>) -> *mut T {
+    match result {
+        Ok(value) => {
+            clear_error();
+            Box::into_raw(Box::new(value))
+        }
+        Err(err) => {
+            set_error(err.to_string());
+            ptr::null_mut()
+        }
+    }
+}
+
+fn to_status(result: io::Result<()>) -> c_int {
+    match result {
+        Ok(()) => {
+            clear_error();
+            0
+        }
+        Err(err) => {
+            set_error(err.to_string());
+            -1
+        }
+    }
+}
+
+// Run the body of an exported function, returning `failed` with the panic
+// as the error if it panics, since unwinding into C is undefined behaviour.
+fn guard<T>(failed: T, f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => value,
+        Err(payload) => {
+            let message = match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "unknown error".to_string(),
+                },
+            };
+            set_error(format!("Panicked: {}", message));
+            failed
+        }
+    }
+}
+
+unsafe fn to_str<'a>(text: *const c_char, name: &str) -> io::Result<&'a str> {
+    if text.is_null() {
+        return Err(invalid(format!("{} is NULL", name)));
+    }
+    CStr::from_ptr(text)
+        .to_str()
+        .map_err(|_| invalid(format!("{} is not valid UTF-8", name)))
+}
+
+/// The message for the last call on this thread that failed, or NULL if it
+/// succeeded. The string is valid until the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn ebird_last_error() -> *const c_char {
+    guard(ptr::null(), || {
+        LAST_ERROR.with(|error| match &*error.borrow() {
+            Some(message) => message.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+}
+
+/// A record read from eBird data.
+pub struct EbirdRecord {
+    record: EBirdRecord,
+    // Strings handed out by ebird_record_get and ebird_record_json, kept
+    // until the record is replaced or freed.
+    strings: RefCell<Vec<CString>>,
+}
+
+impl EbirdRecord {
+    fn new(record: EBirdRecord) -> EbirdRecord {
+        EbirdRecord {
+            record,
+            strings: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn keep(&self, text: String) -> *const c_char {
+        let text = CString::new(text.replace('\0', " ")).unwrap_or_default();
+        let pointer = text.as_ptr();
+        self.strings.borrow_mut().push(text);
+        pointer
+    }
+}
+
+/// Reads the records of an input file.
+pub struct EbirdReader {
+    records: input::Records,
+    current: Option<EbirdRecord>,
+}
+
+fn open_reader(path: &str, format: &str) -> io::Result<EbirdReader> {
+    let stats = Arc::new(input::InputStats::default());
+    let (records, _) = input::open_records(path, format, format == "personal", None, stats)?;
+    Ok(EbirdReader {
+        records,
+        current: None,
+    })
+}
+
+/// Open an EBD file, or a personal export with format "personal", which may
+/// be compressed or an HTTP(S) URL. Free it with ebird_reader_free.
+///
+/// # Safety
+///
+/// path and format must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_reader_open(
+    path: *const c_char,
+    format: *const c_char,
+) -> *mut EbirdReader {
+    guard(ptr::null_mut(), || {
+        to_ptr(to_str(path, "path").and_then(|path| open_reader(path, to_str(format, "format")?)))
+    })
+}
+
+/// The next record, or NULL at the end of the input or if a record could not
+/// be parsed, which ebird_last_error tells apart. The record belongs to the
+/// reader and is valid until the next call.
+///
+/// # Safety
+///
+/// reader must have come from ebird_reader_open and not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_reader_next(reader: *mut EbirdReader) -> *const EbirdRecord {
+    guard(ptr::null(), || {
+        let reader = &mut *reader;
+        reader.current = None;
+        match reader.records.next() {
+            Some(Ok(record)) => {
+                clear_error();
+                reader.current.insert(EbirdRecord::new(record))
+            }
+            Some(Err(err)) => {
+                set_error(format!("Could not parse record: {}", err));
+                ptr::null()
+            }
+            None => {
+                clear_error();
+                ptr::null()
+            }
+        }
+    })
+}
+
+/// # Safety
+///
+/// reader must have come from ebird_reader_open, or be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_reader_free(reader: *mut EbirdReader) {
+    guard((), || {
+        if !reader.is_null() {
+            drop(Box::from_raw(reader));
+        }
+    })
+}
+
+/// A column of a record, such as "common_name", as text, or NULL if the record
+/// has no such column or the value is missing. The string is valid as long as
+/// the record.
+///
+/// # Safety
+///
+/// record must be valid and name a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_record_get(
+    record: *const EbirdRecord,
+    name: *const c_char,
+) -> *const c_char {
+    guard(ptr::null(), || {
+        let record = &*record;
+        let name = match to_str(name, "name") {
+            Ok(name) => name,
+            Err(err) => {
+                set_error(err.to_string());
+                return ptr::null();
+            }
+        };
+        clear_error();
+        match record.record.to_json().remove(name) {
+            Some(serde_json::Value::String(text)) => record.keep(text),
+            Some(serde_json::Value::Null) | None => ptr::null(),
+            Some(value) => record.keep(value.to_string()),
+        }
+    })
+}
+
+/// All the columns of a record as a JSON object. The string is valid as long
+/// as the record.
+///
+/// # Safety
+///
+/// record must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_record_json(record: *const EbirdRecord) -> *const c_char {
+    guard(ptr::null(), || {
+        let record = &*record;
+        clear_error();
+        record.keep(serde_json::Value::Object(record.record.to_json()).to_string())
+    })
+}
+
+/// A test of whether to keep a record. Filters are made by the
+/// ebird_filter_ functions, combined with ebird_filter_and, ebird_filter_or
+/// and ebird_filter_not, and freed with ebird_filter_free.
+pub struct EbirdFilter {
+    filter: Rc<dyn filter::Filter>,
+}
+
+fn new_filter(f: impl Fn(&EBirdRecord) -> bool + 'static) -> EbirdFilter {
+    EbirdFilter {
+        filter: filter::predicate(f),
+    }
+}
+
+fn parse_date(text: &str) -> io::Result<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map_err(|err| invalid(format!("Invalid date {}: {}", text, err)))
+}
+
+fn regex(pattern: &str) -> io::Result<Regex> {
+    Regex::new(pattern).map_err(|err| invalid(err.to_string()))
+}
+
+/// Records observed on or after a date, as YYYY-MM-DD.
+///
+/// # Safety
+///
+/// date must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_since_date(date: *const c_char) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        to_ptr(to_str(date, "date").and_then(parse_date).map(|date| {
+            new_filter(move |record| match parse_date(&record.obs_date) {
+                Ok(other) => other >= date,
+                Err(_) => true,
+            })
+        }))
+    })
+}
+
+/// Records observed on or before a date, as YYYY-MM-DD.
+///
+/// # Safety
+///
+/// date must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_before_date(date: *const c_char) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        to_ptr(to_str(date, "date").and_then(parse_date).map(|date| {
+            new_filter(move |record| match parse_date(&record.obs_date) {
+                Ok(other) => other <= date,
+                Err(_) => true,
+            })
+        }))
+    })
+}
+
+/// Records within buffer (e.g. "50km") of a location, given as
+/// latitude,longitude or a WKT point, measured with metric "haversine" or
+/// "vincenty".
+///
+/// # Safety
+///
+/// location, buffer and metric must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_near(
+    location: *const c_char,
+    buffer: *const c_char,
+    metric: *const c_char,
+) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        let near = || -> io::Result<EbirdFilter> {
+            let point = coords::parse_point(to_str(location, "location")?)?;
+            let buffer = grid::parse_distance(to_str(buffer, "buffer")?)?;
+            let metric = match to_str(metric, "metric")? {
+                "haversine" => near::Metric::Haversine,
+                "vincenty" => near::Metric::Vincenty,
+                metric => {
+                    return Err(invalid(format!(
+                        "Unknown metric {}, expected haversine or vincenty",
+                        metric
+                    )))
+                }
+            };
+            let near = near::Near::new(point, buffer, metric);
+            Ok(new_filter(move |record| {
+                near.contains(record.latitude, record.longitude)
+            }))
+        };
+        to_ptr(near())
+    })
+}
+
+/// Records whose common name matches a regular expression.
+///
+/// # Safety
+///
+/// pattern must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_common_name(pattern: *const c_char) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        to_ptr(
+            to_str(pattern, "pattern")
+                .and_then(regex)
+                .map(|regex| new_filter(move |record| regex.is_match(&record.common_name))),
+        )
+    })
+}
+
+/// Records whose scientific name matches a regular expression.
+///
+/// # Safety
+///
+/// pattern must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_scientific_name(pattern: *const c_char) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        to_ptr(
+            to_str(pattern, "pattern")
+                .and_then(regex)
+                .map(|regex| new_filter(move |record| regex.is_match(&record.scientific_name))),
+        )
+    })
+}
+
+/// Records of at least this many individuals, leaving out those counted as X.
+#[no_mangle]
+pub extern "C" fn ebird_filter_min_count(count: i64) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        to_ptr(Ok(new_filter(move |record| {
+            match record.observation_count.parse::<i64>() {
+                Ok(other) => other >= count,
+                Err(_) => false,
+            }
+        })))
+    })
+}
+
+/// Records passing both filters. The filters can still be used, and must
+/// still be freed.
+///
+/// # Safety
+///
+/// left and right must be valid filters.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_and(
+    left: *const EbirdFilter,
+    right: *const EbirdFilter,
+) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        let and = filter::And(vec![
+            Box::new((*left).filter.clone()),
+            Box::new((*right).filter.clone()),
+        ]);
+        to_ptr(Ok(EbirdFilter {
+            filter: Rc::new(and),
+        }))
+    })
+}
+
+/// Records passing either filter.
+///
+/// # Safety
+///
+/// left and right must be valid filters.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_or(
+    left: *const EbirdFilter,
+    right: *const EbirdFilter,
+) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        let or = filter::Or(vec![
+            Box::new((*left).filter.clone()),
+            Box::new((*right).filter.clone()),
+        ]);
+        to_ptr(Ok(EbirdFilter {
+            filter: Rc::new(or),
+        }))
+    })
+}
+
+/// Records not passing a filter.
+///
+/// # Safety
+///
+/// filter must be a valid filter.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_not(filter: *const EbirdFilter) -> *mut EbirdFilter {
+    guard(ptr::null_mut(), || {
+        let not = filter::Not(Box::new((*filter).filter.clone()));
+        to_ptr(Ok(EbirdFilter {
+            filter: Rc::new(not),
+        }))
+    })
+}
+
+/// Whether a record passes a filter.
+///
+/// # Safety
+///
+/// filter and record must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_matches(
+    filter: *const EbirdFilter,
+    record: *const EbirdRecord,
+) -> bool {
+    guard(false, || (*filter).filter.matches(&(*record).record))
+}
+
+/// # Safety
+///
+/// filter must have come from one of the ebird_filter_ functions, or be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_filter_free(filter: *mut EbirdFilter) {
+    guard((), || {
+        if !filter.is_null() {
+            drop(Box::from_raw(filter));
+        }
+    })
+}
+
+/// A SpatiaLite database records are imported into, replacing the table if
+/// it exists.
+pub struct EbirdDatabase {
+    conn: Connection,
+    path: String,
+    table: String,
+    spatial: bool,
+}
+
+fn open_database(path: &str, table: &str, spatialite_path: &str) -> io::Result<EbirdDatabase> {
+    if !db::valid_table_name(table) {
+        return Err(invalid(format!("Invalid table name {}", table)));
+    }
+    db::set_spatialite_path(spatialite_path);
+    let conn = Connection::open(path).map_err(|err| db::setup_error("open", path, err))?;
+    let spatial = spatialite::probe(&conn).spatial();
+    let schema = db::Schema {
+        table,
+        spatial,
+        ..Default::default()
+    };
+    db::initialize_database(&conn, &schema)
+        .map_err(|err| db::setup_error("initialize", path, err))?;
+    conn.execute_batch("BEGIN")
+        .map_err(|err| db::setup_error("start a transaction on", path, err))?;
+    Ok(EbirdDatabase {
+        conn,
+        path: path.to_string(),
+        table: table.to_string(),
+        spatial,
+    })
+}
+
+/// Open a database to import into, creating table (NULL for "ebird") and
+/// loading SpatiaLite from spatialite_path (NULL for "mod_spatialite"). The
+/// records are committed, and their geometry and indexes built, by
+/// ebird_database_close.
+///
+/// # Safety
+///
+/// path, and table and spatialite_path if not NULL, must be NUL-terminated
+/// strings.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_database_open(
+    path: *const c_char,
+    table: *const c_char,
+    spatialite_path: *const c_char,
+) -> *mut EbirdDatabase {
+    guard(ptr::null_mut(), || {
+        let open = || -> io::Result<EbirdDatabase> {
+            let table = if table.is_null() {
+                db::DEFAULT_TABLE
+            } else {
+                to_str(table, "table")?
+            };
+            let spatialite_path = if spatialite_path.is_null() {
+                "mod_spatialite"
+            } else {
+                to_str(spatialite_path, "spatialite_path")?
+            };
+            open_database(to_str(path, "path")?, table, spatialite_path)
+        };
+        to_ptr(open())
+    })
+}
+
+/// Insert a record into the database.
+///
+/// # Safety
+///
+/// database and record must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_database_insert(
+    database: *mut EbirdDatabase,
+    record: *const EbirdRecord,
+) -> c_int {
+    guard(-1, || {
+        let database = &*database;
+        to_status(
+            db::insert_record(&database.conn, &database.table, None, &(*record).record)
+                .map(|_| ())
+                .map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Could not insert record: {}", err),
+                    )
+                }),
+        )
+    })
+}
+
+/// Insert the rest of the records from a reader that pass a filter, or all of
+/// them if filter is NULL, returning how many were inserted, or -1 on
+/// failure.
+///
+/// # Safety
+///
+/// database and reader must be valid, and filter valid or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_database_insert_all(
+    database: *mut EbirdDatabase,
+    reader: *mut EbirdReader,
+    filter: *const EbirdFilter,
+) -> i64 {
+    guard(-1, || {
+        let mut inserted = 0;
+        loop {
+            let record = ebird_reader_next(reader);
+            if record.is_null() {
+                return if ebird_last_error().is_null() {
+                    inserted
+                } else {
+                    -1
+                };
+            }
+            if !filter.is_null() && !ebird_filter_matches(filter, record) {
+                continue;
+            }
+            if ebird_database_insert(database, record) != 0 {
+                return -1;
+            }
+            inserted += 1;
+        }
+    })
+}
+
+fn finish(database: EbirdDatabase) -> io::Result<()> {
+    let error = |err: rusqlite::Error| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Could not finish {}: {}", database.path, err),
+        )
+    };
+    database.conn.execute_batch("COMMIT").map_err(error)?;
+    if database.spatial {
+        db::build_geometry(&database.conn, &database.table).map_err(error)?;
+    }
+    db::create_indexes(&database.conn, &database.table, db::DEFAULT_INDEXES).map_err(error)?;
+    Ok(())
+}
+
+/// Commit the records, build their geometry and indexes, and free the
+/// database, which is freed even if this fails. Fails if database is NULL.
+///
+/// # Safety
+///
+/// database must have come from ebird_database_open and not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn ebird_database_close(database: *mut EbirdDatabase) -> c_int {
+    guard(-1, || {
+        if database.is_null() {
+            return to_status(Err(invalid("database is NULL".to_string())));
+        }
+        to_status(finish(*Box::from_raw(database)))
+    })
+}
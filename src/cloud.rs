@@ -0,0 +1,117 @@
+use crate::input::InputStats;
+use crate::memory;
+use crate::pipeline::ChannelReader;
+use futures_util::stream::{self, StreamExt};
+use object_store::aws::AmazonS3Builder;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::path::Path;
+use object_store::ObjectStore;
+use std::io::{self, Read};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{self, Sender};
+
+// Objects are read in ranges of this size, with up to CONCURRENT_RANGES
+// requested at once so that a single slow request doesn't stall the import.
+const RANGE_SIZE: u64 = 8 << 20;
+const CONCURRENT_RANGES: usize = 8;
+
+// Up to this many ranges are buffered once read.
+const RANGE_COUNT: usize = 16;
+
+fn to_io_error(err: object_store::Error) -> io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, err),
+        err => io::Error::new(io::ErrorKind::Other, err),
+    }
+}
+
+// An object in S3 or Google Cloud Storage, found from an s3:// or gs:// URL.
+pub struct Object {
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    size: u64,
+}
+
+// Look up an object, with credentials and the region taken from the usual
+// environment variables, such as AWS_ACCESS_KEY_ID, AWS_REGION and
+// GOOGLE_APPLICATION_CREDENTIALS.
+pub async fn open(url: &str) -> io::Result<Object> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid object URL {}, expected e.g. s3://bucket/key", url),
+        )
+    };
+    let (scheme, location) = url.split_once("://").ok_or_else(invalid)?;
+    let (bucket, key) = location.split_once('/').ok_or_else(invalid)?;
+    let store: Arc<dyn ObjectStore> = match scheme {
+        "s3" => Arc::new(
+            AmazonS3Builder::from_env()
+                .with_bucket_name(bucket)
+                .build()
+                .map_err(to_io_error)?,
+        ),
+        "gs" => Arc::new(
+            GoogleCloudStorageBuilder::from_env()
+                .with_bucket_name(bucket)
+                .build()
+                .map_err(to_io_error)?,
+        ),
+        _ => return Err(invalid()),
+    };
+    let path = Path::from_url_path(key).map_err(|_| invalid())?;
+    let size = store.head(&path).await.map_err(to_io_error)?.size;
+    Ok(Object { store, path, size })
+}
+
+// Read an object into the channel in order, a range at a time.
+pub async fn fetch(object: Object, stats: Arc<InputStats>, sender: Sender<io::Result<Vec<u8>>>) {
+    let Object { store, path, size } = object;
+    let mut ranges = stream::iter((0..size).step_by(RANGE_SIZE as usize))
+        .map(|start| {
+            let end = (start + RANGE_SIZE).min(size);
+            store.get_range(&path, start..end)
+        })
+        .buffered(memory::queue_length(RANGE_SIZE as usize, CONCURRENT_RANGES));
+    while let Some(range) = ranges.next().await {
+        let range = match range {
+            Ok(range) => range,
+            Err(err) => {
+                let _ = sender.send(Err(to_io_error(err))).await;
+                return;
+            }
+        };
+        stats.add(&range);
+        // The receiver has gone away if the import stopped early.
+        if sender.send(Ok(range.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Reads an object for the blocking import, fetching it on a runtime of its
+// own.
+struct ObjectReader {
+    reader: ChannelReader,
+    _runtime: Runtime,
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+pub fn open_reader(url: &str, stats: Arc<InputStats>) -> io::Result<Box<dyn Read + Send>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let object = runtime.block_on(open(url))?;
+    let (sender, receiver) = mpsc::channel(memory::queue_length(RANGE_SIZE as usize, RANGE_COUNT));
+    runtime.spawn(fetch(object, stats, sender));
+    Ok(Box::new(ObjectReader {
+        reader: ChannelReader::new(receiver),
+        _runtime: runtime,
+    }))
+}
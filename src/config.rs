@@ -0,0 +1,161 @@
+use clap::App;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+
+// Options can be given on the command line, in EBIRD2SPATIALITE_* environment
+// variables, or in a TOML config file, in that order of precedence. Options of
+// subcommands have the subcommand in their variable's name, e.g.
+// EBIRD2SPATIALITE_EXPORT_OUTPUT, and go in a table named after it in the
+// config file.
+const ENV_PREFIX: &str = "EBIRD2SPATIALITE_";
+
+fn env_name(subcommand: Option<&str>, option: &str) -> String {
+    let name = match subcommand {
+        Some(subcommand) => format!("{}{}_{}", ENV_PREFIX, subcommand, option),
+        None => format!("{}{}", ENV_PREFIX, option),
+    };
+    name.to_uppercase().replace('-', "_")
+}
+
+// The config file, which has to be found before the arguments are parsed so
+// that its values can be used while parsing them.
+fn config_path() -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    env::var(env_name(None, "config")).ok()
+}
+
+fn to_text(key: &str, value: &toml::Value) -> io::Result<String> {
+    match value {
+        toml::Value::String(text) => Ok(text.clone()),
+        toml::Value::Integer(number) => Ok(number.to_string()),
+        toml::Value::Float(number) => Ok(number.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        // Options taking several values take them separated by commas.
+        toml::Value::Array(values) => Ok(values
+            .iter()
+            .map(|value| to_text(key, value))
+            .collect::<io::Result<Vec<_>>>()?
+            .join(",")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid value for {} in config file, expected a string, number, boolean or list",
+                key
+            ),
+        )),
+    }
+}
+
+fn set_default(name: String, value: String) {
+    if env::var_os(&name).is_none() {
+        env::set_var(name, value);
+    }
+}
+
+// Read the config file, if there is one, setting the environment variable of
+// each option in it which isn't already set. Called before any threads are
+// started, since it changes the environment.
+pub fn load() -> io::Result<()> {
+    let path = match config_path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let text = fs::read_to_string(&path).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Could not read {}: {}", path, err),
+        )
+    })?;
+    let config = text.parse::<toml::Table>().map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid config file {}: {}", path, err),
+        )
+    })?;
+    for (key, value) in &config {
+        match value {
+            toml::Value::Table(options) => {
+                for (option, value) in options {
+                    set_default(env_name(Some(key), option), to_text(option, value)?);
+                }
+            }
+            _ => set_default(env_name(None, key), to_text(key, value)?),
+        }
+    }
+    Ok(())
+}
+
+// Whether a flag is turned on by its environment variable.
+fn is_set(name: &str) -> io::Result<bool> {
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(_) => return Ok(false),
+    };
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(true),
+        "" | "0" | "false" | "no" => Ok(false),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid value {} for {}, expected true or false",
+                value, name
+            ),
+        )),
+    }
+}
+
+// Flags of the app or a subcommand turned on in the environment or config
+// file, leaving out those already among the `given` arguments.
+fn flags(app: &App, subcommand: Option<&str>, given: &[OsString]) -> io::Result<Vec<OsString>> {
+    let mut flags = Vec::new();
+    for flag in &app.p.flags {
+        let long = match flag.s.long {
+            Some(long) => long,
+            None => continue,
+        };
+        let arg = format!("--{}", long);
+        let short = flag.s.short.map(|short| format!("-{}", short));
+        if given
+            .iter()
+            .any(|given| *given == *arg || short.as_deref().is_some_and(|short| *given == *short))
+        {
+            continue;
+        }
+        if is_set(&env_name(subcommand, long))? {
+            flags.push(OsString::from(arg));
+        }
+    }
+    Ok(flags)
+}
+
+// The command line arguments, with flags turned on in the environment or
+// config file added. clap reads only options taking a value from the
+// environment, since giving an argument a variable makes it take a value.
+pub fn args(app: &App) -> io::Result<Vec<OsString>> {
+    let mut args = env::args_os().collect::<Vec<_>>();
+    let subcommand = args.iter().enumerate().skip(1).find_map(|(index, arg)| {
+        app.p
+            .subcommands
+            .iter()
+            .find(|subcommand| *arg == *subcommand.get_name())
+            .map(|subcommand| (index, subcommand))
+    });
+    let end = subcommand.map_or(args.len(), |(index, _)| index);
+    if let Some((index, subcommand)) = subcommand {
+        let flags = flags(subcommand, Some(subcommand.get_name()), &args[index + 1..])?;
+        args.splice(index + 1..index + 1, flags);
+    }
+    let flags = flags(app, None, &args[1..end])?;
+    args.splice(1..1, flags);
+    Ok(args)
+}
@@ -0,0 +1,105 @@
+use crate::EBirdRecord;
+use geo::{point, Point};
+use std::convert::TryFrom;
+use std::io;
+
+// What to do with records whose coordinates fail validation.
+#[derive(Clone, Copy, PartialEq)]
+pub enum InvalidCoords {
+    Keep,
+    Flag,
+    Drop,
+}
+
+// Returns the reason the coordinates of a record are invalid, if they are.
+pub fn check(record: &EBirdRecord) -> Option<&'static str> {
+    let lat_valid = (-90.0..=90.0).contains(&record.latitude);
+    let lon_valid = (-180.0..=180.0).contains(&record.longitude);
+
+    if lat_valid && lon_valid {
+        if record.latitude == 0. && record.longitude == 0. {
+            Some("null island")
+        } else {
+            None
+        }
+    } else if !lat_valid && (-90.0..=90.0).contains(&record.longitude) {
+        Some("latitude and longitude swapped")
+    } else {
+        Some("coordinates out of range")
+    }
+}
+
+// Round the coordinates of a record to the given number of decimal places.
+pub fn round(record: &mut EBirdRecord, precision: i32) {
+    let scale = 10f64.powi(precision);
+    record.latitude = (record.latitude * scale).round() / scale;
+    record.longitude = (record.longitude * scale).round() / scale;
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Check a point given on the command line is on the globe, pointing out the
+// likely mistake if its coordinates are the wrong way round.
+fn check_point(text: &str, latitude: f64, longitude: f64, order: &str) -> io::Result<()> {
+    if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) {
+        Ok(())
+    } else if (-90.0..=90.0).contains(&longitude) && (-180.0..=180.0).contains(&latitude) {
+        Err(invalid(format!(
+            "Invalid location {}: latitude {} is out of range, are the coordinates \
+             swapped? Give them as {}",
+            text, latitude, order
+        )))
+    } else {
+        Err(invalid(format!(
+            "Invalid location {}: latitude must be between -90 and 90 and longitude \
+             between -180 and 180",
+            text
+        )))
+    }
+}
+
+// Parse a location as "latitude,longitude", e.g. 49.25,-123.1, or as a WKT
+// point, which has longitude first, e.g. POINT (-123.1 49.25).
+pub fn parse_point(text: &str) -> io::Result<Point<f64>> {
+    let mut parts = text.split(',');
+    if let (false, Some(latitude), Some(longitude), None) =
+        (text.contains('('), parts.next(), parts.next(), parts.next())
+    {
+        let parse = |part: &str, name: &str| {
+            part.trim().parse::<f64>().map_err(|_| {
+                invalid(format!(
+                    "Invalid location {}: {} {} is not a number, expected latitude,longitude \
+                     such as 49.25,-123.1",
+                    text,
+                    name,
+                    part.trim()
+                ))
+            })
+        };
+        let latitude = parse(latitude, "latitude")?;
+        let longitude = parse(longitude, "longitude")?;
+        check_point(text, latitude, longitude, "latitude,longitude")?;
+        return Ok(point!(x: longitude, y: latitude));
+    }
+
+    let wkt = wkt::Wkt::<f64>::from_str(text).map_err(|err| {
+        invalid(format!(
+            "Invalid location {}: {}, expected latitude,longitude such as 49.25,-123.1 or a \
+             WKT point such as POINT (-123.1 49.25)",
+            text, err
+        ))
+    })?;
+    if wkt.items.len() != 1 {
+        return Err(invalid(
+            "Multiple near locations are not supported".to_string(),
+        ));
+    }
+    let point = wkt::conversion::try_into_geometry(&wkt.items[0])
+        .ok()
+        .and_then(|geometry| Point::try_from(geometry).ok())
+        .ok_or_else(|| invalid(format!("Invalid location {}: expected a WKT point", text)))?;
+    check_point(text, point.y(), point.x(), "POINT (longitude latitude)")?;
+    Ok(point)
+}
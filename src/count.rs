@@ -0,0 +1,27 @@
+use crate::memory::{Order, Tally};
+use crate::EBirdRecord;
+
+// Print the number of records, for --count-only, optionally broken down by
+// species or by year.
+pub fn print_counts(
+    records: impl Iterator<Item = EBirdRecord>,
+    group_by: Option<&str>,
+) -> rusqlite::Result<()> {
+    let mut counts = Tally::default();
+    let mut total = 0;
+    for record in records {
+        total += 1;
+        match group_by {
+            Some("species") => counts.add(&record.common_name)?,
+            Some("year") => counts.add(record.obs_date.get(..4).unwrap_or(""))?,
+            _ => continue,
+        }
+    }
+
+    counts.for_each(Order::Key, |key, count| {
+        println!("{}\t{}", key, count);
+        Ok(())
+    })?;
+    println!("{}", total);
+    Ok(())
+}
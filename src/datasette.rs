@@ -0,0 +1,252 @@
+use crate::db;
+use crate::dictionary;
+use crate::to_io_error;
+use rusqlite::{Connection, NO_PARAMS};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Descriptions of the columns shown in Datasette.
+const COLUMN_DESCRIPTIONS: &[(&str, &str)] = &[
+    ("guid", "Global unique identifier of the record"),
+    ("last_edited_date", "When the record was last edited"),
+    ("common_name", "English common name of the species"),
+    ("scientific_name", "Scientific name of the species"),
+    (
+        "observation_count",
+        "Number of individuals, or X if only presence was noted",
+    ),
+    ("breeding_bird_atlas_code", "Breeding evidence code"),
+    (
+        "breeding_bird_atlas_category",
+        "Breeding evidence category, C2 to C4",
+    ),
+    ("obs_date", "Date of the checklist"),
+    ("time_obs_started", "Local start time of the checklist"),
+    ("obs_id", "Observer identifier"),
+    ("sampling_event_id", "Checklist identifier"),
+    (
+        "group_identifier",
+        "Identifier shared by the checklists of a group",
+    ),
+    (
+        "is_shared",
+        "1 if the checklist was shared with other observers",
+    ),
+    (
+        "protocol_type",
+        "Survey protocol, e.g. Stationary or Traveling",
+    ),
+    ("duration_min", "Duration of the checklist in minutes"),
+    ("effort_distance_km", "Distance traveled in kilometres"),
+    ("effort_distance_mi", "Distance traveled in miles"),
+    ("number_observers", "Number of observers"),
+    ("all_species_reported", "1 if the checklist is complete"),
+    ("approved", "1 if the record was approved by reviewers"),
+    ("reviewed", "1 if the record was reviewed"),
+    ("locality", "Name of the location"),
+    (
+        "locality_type",
+        "Location type, e.g. H for hotspots or P for personal",
+    ),
+    ("time_zone", "Time zone of the location"),
+    ("utc_datetime", "Start of the checklist in UTC"),
+    ("elevation", "Elevation of the location in metres"),
+    ("nearest_place", "Nearest named place in the gazetteer"),
+    (
+        "atlas_block",
+        "Breeding bird atlas block containing the location",
+    ),
+    ("split", "Random split the checklist was assigned to"),
+    (
+        "species_count",
+        "Number of species reported on the checklist",
+    ),
+    (
+        "individual_count",
+        "Number of individuals reported on the checklist",
+    ),
+    ("location", "Point geometry in WGS 84 (EPSG:4326)"),
+];
+
+// Columns worth faceting on, if the table has them.
+const FACETS: &[&str] = &[
+    "common_name",
+    "protocol_type",
+    "locality_type",
+    "breeding_bird_atlas_category",
+    "all_species_reported",
+];
+
+// Columns shown by the canned queries, if the table has them.
+const SUMMARY_COLUMNS: &[&str] = &[
+    "common_name",
+    "scientific_name",
+    "observation_count",
+    "obs_date",
+    "sampling_event_id",
+    "locality",
+    "species_count",
+    "latitude",
+    "longitude",
+];
+
+// Canned queries finding records by location with the spatial index, which
+// need Datasette to load SpatiaLite.
+fn canned_queries(table: &str, source: &str, columns: &[&str], species: bool) -> Value {
+    let selected = columns.join(", ");
+    let order = if columns.contains(&"obs_date") {
+        " ORDER BY obs_date DESC"
+    } else {
+        ""
+    };
+    // The spatial index is searched with a square around the point, wide
+    // enough in longitude for the latitude, before checking the distance.
+    let near = format!(
+        "id IN (SELECT rowid FROM SpatialIndex
+                    WHERE f_table_name = '{table}' AND f_geometry_column = 'location'
+                    AND search_frame = BuildCircleMbr(CAST(:longitude AS REAL),
+                        CAST(:latitude AS REAL),
+                        CAST(:metres AS REAL) / 111320.0 / Cos(Radians(:latitude))))
+                AND PtDistWithin(location,
+                    MakePoint(CAST(:longitude AS REAL), CAST(:latitude AS REAL), 4326),
+                    CAST(:metres AS REAL), 1)",
+        table = table.to_lowercase()
+    );
+    let mut queries = Map::new();
+    queries.insert(
+        "records_near".to_string(),
+        json!({
+            "title": "Records near a point",
+            "description": "Records within :metres of :latitude, :longitude",
+            "sql": format!(
+                "SELECT {} FROM {} WHERE {}{}",
+                selected, source, near, order
+            ),
+        }),
+    );
+    if species {
+        queries.insert(
+            "species_near".to_string(),
+            json!({
+                "title": "Species near a point",
+                "description": "Species recorded within :metres of :latitude, :longitude",
+                "sql": format!(
+                    "SELECT common_name, scientific_name, COUNT(*) AS records,
+                            MAX(obs_date) AS last_recorded
+                        FROM {} WHERE {}
+                        GROUP BY common_name, scientific_name
+                        ORDER BY records DESC",
+                    source, near
+                ),
+            }),
+        );
+    }
+    queries.insert(
+        "records_in_box".to_string(),
+        json!({
+            "title": "Records in a bounding box",
+            "description": "Records between two longitudes and two latitudes",
+            "sql": format!(
+                "SELECT {} FROM {}
+                    WHERE id IN (SELECT rowid FROM SpatialIndex
+                        WHERE f_table_name = '{}' AND f_geometry_column = 'location'
+                        AND search_frame = BuildMbr(CAST(:min_longitude AS REAL),
+                            CAST(:min_latitude AS REAL), CAST(:max_longitude AS REAL),
+                            CAST(:max_latitude AS REAL))){}",
+                selected,
+                source,
+                table.to_lowercase(),
+                order
+            ),
+        }),
+    );
+    Value::Object(queries)
+}
+
+// Write a Datasette metadata.json next to the database, describing the table
+// and its columns, with facets and canned spatial queries, so the database
+// can be published as it is.
+pub fn write_metadata(conn: &Connection, path: &str, table: &str, source: &str) -> io::Result<()> {
+    let existing = conn
+        .prepare(&format!("PRAGMA table_info({})", source))
+        .map_err(to_io_error)?
+        .query_map(NO_PARAMS, |row| row.get::<_, String>(1))
+        .map_err(to_io_error)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(to_io_error)?;
+    let has = |column: &str| existing.iter().any(|existing| existing == column);
+    let records: i64 = conn
+        .query_row(
+            &format!("SELECT COUNT(*) FROM {}", table),
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .map_err(to_io_error)?;
+    let checklists = db::is_checklist_table(conn, table).map_err(to_io_error)?;
+
+    let columns = COLUMN_DESCRIPTIONS
+        .iter()
+        .filter(|(column, _)| has(column))
+        .map(|(column, description)| (column.to_string(), json!(description)))
+        .collect::<Map<String, Value>>();
+    let facets = FACETS
+        .iter()
+        .filter(|column| has(column))
+        .collect::<Vec<_>>();
+    let summary = SUMMARY_COLUMNS
+        .iter()
+        .filter(|column| has(column))
+        .cloned()
+        .collect::<Vec<_>>();
+    let description = if checklists {
+        format!("{} eBird checklists", records)
+    } else {
+        format!("{} eBird records", records)
+    };
+
+    let mut tables = Map::new();
+    tables.insert(
+        source.to_string(),
+        json!({
+            "description": description,
+            "columns": columns,
+            "facets": facets,
+            "sort_desc": if has("obs_date") { Some("obs_date") } else { None },
+        }),
+    );
+    // The ids of a dictionary encoded table aren't useful on their own.
+    if source != table {
+        tables.insert(table.to_string(), json!({ "hidden": true }));
+        for column in dictionary::ENCODED_COLUMNS {
+            tables.insert(format!("{}_dictionary", column), json!({ "hidden": true }));
+        }
+    }
+
+    let name = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "ebird".to_string());
+    let metadata = json!({
+        "title": "eBird records",
+        "source": "eBird Basic Dataset, Cornell Lab of Ornithology",
+        "source_url": "https://ebird.org/data/download",
+        "license": "eBird Data Access Terms of Use",
+        "license_url": "https://www.birds.cornell.edu/home/ebird-data-access-terms-of-use/",
+        "databases": {
+            name: {
+                "tables": tables,
+                "queries": canned_queries(table, source, &summary, !checklists && has("common_name")),
+            }
+        }
+    });
+
+    let output = Path::new(path).with_file_name("metadata.json");
+    fs::write(
+        &output,
+        serde_json::to_string_pretty(&metadata).map_err(to_io_error)?,
+    )?;
+    println!("wrote Datasette metadata to {}", output.display());
+    Ok(())
+}
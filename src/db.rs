@@ -0,0 +1,1323 @@
+use crate::dictionary::{self, Dictionary, EncodedValues};
+use crate::exit;
+use crate::memory;
+use crate::spatialite;
+use crate::EBirdRecord;
+use chrono::{DateTime, Utc};
+use rusqlite::types::ToSql;
+use rusqlite::{params, OptionalExtension, NO_PARAMS};
+use std::io;
+use std::sync::OnceLock;
+
+// Columns which can be selected with --columns. The location is always
+// stored.
+pub const COLUMNS: &[&str] = &[
+    "guid",
+    "last_edited_date",
+    "common_name",
+    "scientific_name",
+    "observation_count",
+    "breeding_bird_atlas_code",
+    "breeding_bird_atlas_category",
+    "obs_date",
+    "time_obs_started",
+    "obs_id",
+    "sampling_event_id",
+    "group_identifier",
+    "is_shared",
+    "protocol_type",
+    "duration_min",
+    "effort_distance_km",
+    "effort_distance_mi",
+    "number_observers",
+    "all_species_reported",
+    "approved",
+    "reviewed",
+    "reason",
+    "species_comments",
+    "trip_comments",
+    "locality",
+    "locality_type",
+    "bcr_code",
+    "iba_code",
+    "usfws_code",
+    "time_zone",
+    "local_datetime",
+    "utc_datetime",
+    "elevation",
+    "nearest_place",
+    "nearest_place_distance",
+    "atlas_block",
+    "split",
+    "script_fields",
+    "location",
+];
+
+// Columns indexed by --indexes when no list is given, covering the usual
+// species, date, checklist and observer queries.
+pub const DEFAULT_INDEXES: &[&str] = &[
+    "common_name",
+    "scientific_name",
+    "obs_date",
+    "sampling_event_id",
+    "obs_id",
+];
+
+// Columns of a table imported with --granularity checklist, which has one row
+// per checklist rather than per observation.
+pub const CHECKLIST_COLUMNS: &[&str] = &[
+    "sampling_event_id",
+    "group_identifier",
+    "is_shared",
+    "obs_date",
+    "time_obs_started",
+    "obs_id",
+    "protocol_type",
+    "duration_min",
+    "effort_distance_km",
+    "effort_distance_mi",
+    "number_observers",
+    "all_species_reported",
+    "locality",
+    "locality_type",
+    "bcr_code",
+    "iba_code",
+    "usfws_code",
+    "time_zone",
+    "local_datetime",
+    "utc_datetime",
+    "elevation",
+    "nearest_place",
+    "nearest_place_distance",
+    "atlas_block",
+    "split",
+    "species_count",
+    "individual_count",
+    "location",
+];
+
+// Checklist tables already have a unique index on sampling_event_id.
+pub const DEFAULT_CHECKLIST_INDEXES: &[&str] = &["obs_date", "obs_id"];
+
+// The SpatiaLite extension to load, from --spatialite-path. SQLite adds the
+// platform's library suffix if the path doesn't exist as given.
+static SPATIALITE_PATH: OnceLock<String> = OnceLock::new();
+
+pub fn set_spatialite_path(path: &str) {
+    let _ = SPATIALITE_PATH.set(path.to_string());
+}
+
+fn spatialite_path() -> &'static str {
+    SPATIALITE_PATH
+        .get()
+        .map(String::as_str)
+        .unwrap_or("mod_spatialite")
+}
+
+pub fn load_spatialite(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // Loading it again would register every function a second time.
+    if conn
+        .query_row("SELECT spatialite_version()", NO_PARAMS, |_| Ok(()))
+        .is_ok()
+    {
+        return Ok(());
+    }
+    load_extension(conn)
+}
+
+// SpatiaLite is linked in with the bundled-spatialite feature, and otherwise
+// loaded as an extension.
+#[cfg(feature = "bundled-spatialite")]
+fn load_extension(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    crate::spatialite::init(conn)
+}
+
+#[cfg(not(feature = "bundled-spatialite"))]
+fn load_extension(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let _guard = rusqlite::LoadExtensionGuard::new(conn)?;
+    conn.load_extension(std::path::Path::new(spatialite_path()), None)
+}
+
+// Load SpatiaLite, failing with the exit code for a missing extension.
+pub fn require_spatialite(conn: &rusqlite::Connection) -> io::Result<()> {
+    load_spatialite(conn).map_err(|err| {
+        exit::with_code(
+            exit::EXTENSION_FAILED,
+            format!(
+                "Could not load SpatiaLite from {}: {} (mod_spatialite not found, set \
+                 --spatialite-path to its location)",
+                spatialite_path(),
+                err
+            ),
+        )
+    })
+}
+
+// What to do about an error opening or setting up the database, if there is
+// anything obvious.
+fn hint(err: &rusqlite::Error) -> Option<&'static str> {
+    use rusqlite::ErrorCode;
+    match err {
+        rusqlite::Error::SqliteFailure(_, Some(message))
+            if message.contains("no such function") =>
+        {
+            Some("mod_spatialite not found, set --spatialite-path to its location")
+        }
+        rusqlite::Error::SqliteFailure(err, _) => match err.code {
+            ErrorCode::CannotOpen => Some("check that its directory exists and can be written to"),
+            ErrorCode::PermissionDenied | ErrorCode::ReadOnly => {
+                Some("check that the file can be written to")
+            }
+            ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => Some(
+                "another process is using it, wait for it to finish or choose another --output",
+            ),
+            ErrorCode::DiskFull => Some("free some disk space, or choose another --output"),
+            ErrorCode::NotADatabase => Some("it isn't a SQLite database, choose another --output"),
+            ErrorCode::DatabaseCorrupt => {
+                Some("it is corrupt, remove it or choose another --output")
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Describe an error setting up the database for an import, with a hint at
+// how to fix it, instead of panicking part way through.
+pub fn setup_error(action: &str, database: &str, err: rusqlite::Error) -> io::Error {
+    let message = match hint(&err) {
+        Some(hint) => format!("Could not {} {}: {} ({})", action, database, err, hint),
+        None => format!("Could not {} {}: {}", action, database, err),
+    };
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+// The table records are imported into by default.
+pub const DEFAULT_TABLE: &str = "ebird";
+
+// The version of the table layout, stored in the metadata table for each
+// table. Bump it when adding columns, and add them to MIGRATED_COLUMNS.
+pub const SCHEMA_VERSION: i64 = 5;
+
+// Columns added since the first release, with their types and whether
+// checklist tables have them, which migrate adds to older tables.
+const MIGRATED_COLUMNS: &[(&str, &str, bool)] = &[
+    ("last_edited_date", "TEXT", false),
+    ("latitude", "REAL", true),
+    ("longitude", "REAL", true),
+    ("reviewed", "INTEGER", false),
+    ("reason", "TEXT", false),
+    ("trip_comments", "TEXT", false),
+    ("locality", "TEXT", true),
+    ("locality_type", "TEXT", true),
+    ("bcr_code", "TEXT", true),
+    ("iba_code", "TEXT", true),
+    ("usfws_code", "TEXT", true),
+    ("time_zone", "TEXT", true),
+    ("local_datetime", "TEXT", true),
+    ("utc_datetime", "TEXT", true),
+    ("elevation", "REAL", true),
+    ("nearest_place", "TEXT", true),
+    ("nearest_place_distance", "REAL", true),
+    ("atlas_block", "TEXT", true),
+    ("coord_flag", "TEXT", true),
+    ("effort_distance_mi", "REAL", true),
+    ("group_identifier", "TEXT", true),
+    ("is_shared", "INTEGER", true),
+    ("split", "TEXT", true),
+    ("script_fields", "TEXT", false),
+];
+
+// Table names are interpolated into SQL, so only allow plain identifiers.
+pub fn valid_table_name(table: &str) -> bool {
+    let mut chars = table.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// The columns of the observation table and their types, apart from the id
+// and location.
+const OBSERVATION_COLUMNS: &[(&str, &str)] = &[
+    ("guid", "TEXT"),
+    ("last_edited_date", "TEXT"),
+    ("common_name", "TEXT"),
+    ("scientific_name", "TEXT"),
+    ("observation_count", "TEXT"),
+    ("breeding_bird_atlas_code", "TEXT"),
+    ("breeding_bird_atlas_category", "TEXT"),
+    ("latitude", "REAL"),
+    ("longitude", "REAL"),
+    ("obs_date", "TEXT"),
+    ("time_obs_started", "TEXT"),
+    ("obs_id", "TEXT"),
+    ("sampling_event_id", "TEXT"),
+    ("group_identifier", "TEXT"),
+    ("is_shared", "INTEGER"),
+    ("protocol_type", "TEXT"),
+    ("duration_min", "INTEGER"),
+    ("effort_distance_km", "REAL"),
+    ("effort_distance_mi", "REAL"),
+    ("number_observers", "INTEGER"),
+    ("all_species_reported", "INTEGER"),
+    ("approved", "INTEGER"),
+    ("reviewed", "INTEGER"),
+    ("reason", "TEXT"),
+    ("species_comments", "TEXT"),
+    ("trip_comments", "TEXT"),
+    ("locality", "TEXT"),
+    ("locality_type", "TEXT"),
+    ("bcr_code", "TEXT"),
+    ("iba_code", "TEXT"),
+    ("usfws_code", "TEXT"),
+    ("time_zone", "TEXT"),
+    ("local_datetime", "TEXT"),
+    ("utc_datetime", "TEXT"),
+    ("elevation", "REAL"),
+    ("nearest_place", "TEXT"),
+    ("nearest_place_distance", "REAL"),
+    ("atlas_block", "TEXT"),
+    ("split", "TEXT"),
+    ("coord_flag", "TEXT"),
+    ("script_fields", "TEXT"),
+];
+
+// Whether a table created with the given --columns has a column. The
+// coordinates and their validation flag are always stored.
+fn stores_column(columns: Option<&[&str]>, column: &str) -> bool {
+    match columns {
+        Some(columns) => {
+            columns.contains(&column) || ["latitude", "longitude", "coord_flag"].contains(&column)
+        }
+        None => true,
+    }
+}
+
+// Options affecting the layout of the table records are imported into.
+pub struct Schema<'a> {
+    pub table: &'a str,
+    // Only create these columns of an observation table, with --columns.
+    pub columns: Option<&'a [&'a str]>,
+    // Add the table to an existing SpatiaLite database, which has already
+    // been checked not to contain it.
+    pub attach: bool,
+    // Store repeated strings as ids into dictionary tables.
+    pub dictionary_encode: bool,
+    // Store one row per checklist, with --granularity checklist.
+    pub checklists: bool,
+    // Give the location an elevation as its Z coordinate, with --dem.
+    pub elevation: bool,
+    // Add a location geometry, which needs SpatiaLite. Without it records
+    // only have latitude and longitude columns.
+    pub spatial: bool,
+}
+
+impl Default for Schema<'_> {
+    fn default() -> Self {
+        Schema {
+            table: DEFAULT_TABLE,
+            columns: None,
+            attach: false,
+            dictionary_encode: false,
+            checklists: false,
+            elevation: false,
+            spatial: true,
+        }
+    }
+}
+
+pub fn initialize_database(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+) -> rusqlite::Result<usize> {
+    if schema.spatial {
+        load_spatialite(conn)?;
+    }
+    let table = schema.table;
+
+    if !schema.attach {
+        // InitSpatialMetaData does nothing if the metadata tables already
+        // exist.
+        if schema.spatial {
+            let mut stmt = conn.prepare("SELECT InitSpatialMetaData(1)")?;
+            stmt.exists(NO_PARAMS)?;
+        }
+
+        // Drop the table along with its geometry registration and spatial
+        // index, leaving any other tables alone.
+        if schema.spatial && table_exists(conn, table)? {
+            let mut stmt = conn.prepare("SELECT DropGeoTable(?1)")?;
+            stmt.exists(params![table])?;
+        }
+        conn.execute(&format!("DROP TABLE IF EXISTS {}", table), NO_PARAMS)?;
+        conn.execute(&format!("DROP TABLE IF EXISTS {}_fts", table), NO_PARAMS)?;
+        // The view of the table decoded, from an import with
+        // --dictionary-encode, is created again if this one has it too.
+        conn.execute(&format!("DROP VIEW IF EXISTS {}_decoded", table), NO_PARAMS)?;
+        if table_exists(conn, "views_geometry_columns")? {
+            conn.execute(
+                "DELETE FROM views_geometry_columns WHERE view_name = lower(?1)",
+                params![format!("{}_decoded", table)],
+            )?;
+        }
+    }
+
+    if schema.checklists {
+        create_checklist_table(conn, table)?;
+    } else {
+        create_observation_table(conn, schema)?;
+    }
+
+    if schema.spatial {
+        let dimensions = if schema.elevation { "XYZ" } else { "XY" };
+        let mut stmt =
+            conn.prepare("SELECT AddGeometryColumn(?1, 'location', 4326, 'POINT', ?2)")?;
+        stmt.exists(params![table, dimensions])?;
+    }
+
+    create_metadata_table(conn)?;
+    clear_checkpoint(conn, table)?;
+    set_metadata(
+        conn,
+        &format!("schema_version:{}", table),
+        &SCHEMA_VERSION.to_string(),
+    )?;
+
+    let key = format!("checklists:{}", table);
+    if schema.checklists {
+        set_metadata(conn, &key, "1")?;
+    } else {
+        conn.execute("DELETE FROM metadata WHERE key = ?1", params![key])?;
+    }
+
+    let key = format!("dictionary_encoded:{}", table);
+    if schema.dictionary_encode {
+        Dictionary::open(conn)?;
+        create_decoded_view(conn, table)?;
+        set_metadata(conn, &key, "1")?;
+    } else {
+        conn.execute("DELETE FROM metadata WHERE key = ?1", params![key])?;
+        // The dictionaries are shared by every encoded table in the
+        // database, so are dropped once there are none.
+        let encoded = conn
+            .prepare("SELECT 1 FROM metadata WHERE key LIKE 'dictionary_encoded:%'")?
+            .exists(NO_PARAMS)?;
+        if !encoded {
+            for column in dictionary::ENCODED_COLUMNS {
+                conn.execute(
+                    &format!("DROP TABLE IF EXISTS {}_dictionary", column),
+                    NO_PARAMS,
+                )?;
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+fn create_observation_table(conn: &rusqlite::Connection, schema: &Schema) -> rusqlite::Result<()> {
+    let definitions = OBSERVATION_COLUMNS
+        .iter()
+        .filter(|(column, _)| stores_column(schema.columns, column))
+        .map(|(column, column_type)| {
+            // Dictionary encoded columns hold integer ids.
+            if schema.dictionary_encode && dictionary::ENCODED_COLUMNS.contains(column) {
+                format!("{} INTEGER", column)
+            } else {
+                format!("{} {}", column, column_type)
+            }
+        })
+        .collect::<Vec<_>>();
+    conn.execute(
+        &format!(
+            "CREATE TABLE {} (id INTEGER PRIMARY KEY, {})",
+            schema.table,
+            definitions.join(", ")
+        ),
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+fn create_checklist_table(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE {table} (
+                id                              INTEGER PRIMARY KEY,
+                sampling_event_id               TEXT,
+                group_identifier                TEXT,
+                is_shared                       INTEGER,
+                latitude                        REAL,
+                longitude                       REAL,
+                obs_date                        TEXT,
+                time_obs_started                TEXT,
+                obs_id                          TEXT,
+                protocol_type                   TEXT,
+                duration_min                    INTEGER,
+                effort_distance_km              REAL,
+                effort_distance_mi              REAL,
+                number_observers                INTEGER,
+                all_species_reported            INTEGER,
+                locality                        TEXT,
+                locality_type                   TEXT,
+                bcr_code                        TEXT,
+                iba_code                        TEXT,
+                usfws_code                      TEXT,
+                time_zone                       TEXT,
+                local_datetime                  TEXT,
+                utc_datetime                    TEXT,
+                elevation                       REAL,
+                nearest_place                   TEXT,
+                nearest_place_distance          REAL,
+                atlas_block                     TEXT,
+                split                           TEXT,
+                coord_flag                      TEXT,
+                species_count                   INTEGER,
+                individual_count                INTEGER);
+         CREATE UNIQUE INDEX {table}_sampling_event_id ON {table} (sampling_event_id);",
+        table = table
+    ))
+}
+
+// The schema version of a table, which is 0 for tables made before versions
+// were recorded.
+pub fn schema_version(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<i64> {
+    if !table_exists(conn, "metadata")? {
+        return Ok(0);
+    }
+    Ok(get_metadata(conn, &format!("schema_version:{}", table))?
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(0))
+}
+
+// Bring a table made by an older version up to date in place, adding the
+// columns it is missing, and return their names. The first releases only
+// stored the location, so the coordinate columns are filled in from it.
+pub fn migrate(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    create_metadata_table(conn)?;
+    let checklists = is_checklist_table(conn, table)?;
+    let encoded = is_dictionary_encoded(conn, table)?;
+
+    let existing = table_columns(conn, table)?;
+
+    let mut added = Vec::new();
+    conn.execute_batch("BEGIN")?;
+    for (column, column_type, in_checklists) in MIGRATED_COLUMNS {
+        if existing.iter().any(|existing| existing == column) || (checklists && !in_checklists) {
+            continue;
+        }
+        let column_type = if encoded && dictionary::ENCODED_COLUMNS.contains(column) {
+            "INTEGER"
+        } else {
+            column_type
+        };
+        conn.execute(
+            &format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, column_type
+            ),
+            NO_PARAMS,
+        )?;
+        added.push(column.to_string());
+    }
+    if added
+        .iter()
+        .any(|column| column == "latitude" || column == "longitude")
+    {
+        conn.execute(
+            &format!(
+                "UPDATE {} SET latitude = Y(location), longitude = X(location)
+                    WHERE location IS NOT NULL",
+                table
+            ),
+            NO_PARAMS,
+        )?;
+    }
+    // The decoded view lists every column, so needs the new ones too.
+    if encoded && !added.is_empty() {
+        create_decoded_view(conn, table)?;
+    }
+    set_metadata(
+        conn,
+        &format!("schema_version:{}", table),
+        &SCHEMA_VERSION.to_string(),
+    )?;
+    conn.execute_batch("COMMIT")?;
+    Ok(added)
+}
+
+pub fn is_checklist_table(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<bool> {
+    Ok(table_exists(conn, "metadata")?
+        && get_metadata(conn, &format!("checklists:{}", table))?.is_some())
+}
+
+// Add an observation to the row for its checklist, creating the row for the
+// first observation. Every observation counts as a species, including spuhs
+// and hybrids, and counts of X add nothing to the individuals.
+pub fn insert_checklist_record(
+    conn: &rusqlite::Connection,
+    table: &str,
+    rec: &EBirdRecord,
+) -> rusqlite::Result<usize> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "INSERT INTO {} (sampling_event_id, group_identifier, is_shared, longitude, latitude,
+                            obs_date, time_obs_started, obs_id, protocol_type, duration_min,
+                            effort_distance_km, effort_distance_mi, number_observers,
+                            all_species_reported, locality, locality_type, bcr_code, iba_code,
+                            usfws_code, time_zone, local_datetime, utc_datetime, elevation,
+                            nearest_place, nearest_place_distance, atlas_block, split,
+                            coord_flag, species_count, individual_count)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                        ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, 1, ?29)
+                ON CONFLICT (sampling_event_id) DO UPDATE SET
+                    species_count = species_count + 1,
+                    individual_count = individual_count + excluded.individual_count",
+        table
+    ))?;
+    stmt.execute(params![
+        rec.sampling_event_id,
+        rec.group_identifier,
+        rec.is_shared(),
+        rec.longitude,
+        rec.latitude,
+        rec.obs_date,
+        rec.time_obs_started,
+        rec.obs_id,
+        rec.protocol_type,
+        rec.duration_min,
+        rec.effort_distance_km,
+        rec.effort_distance_mi,
+        rec.number_observers,
+        rec.all_species_reported,
+        rec.locality,
+        rec.locality_type,
+        rec.bcr_code,
+        rec.iba_code,
+        rec.usfws_code,
+        rec.time_zone,
+        rec.local_datetime,
+        rec.utc_datetime,
+        rec.elevation,
+        rec.nearest_place,
+        rec.nearest_place_distance,
+        rec.atlas_block,
+        rec.split,
+        rec.coord_flag,
+        rec.observation_count.parse::<i64>().unwrap_or(0)
+    ])
+}
+
+pub fn is_dictionary_encoded(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<bool> {
+    Ok(table_exists(conn, "metadata")?
+        && get_metadata(conn, &format!("dictionary_encoded:{}", table))?.is_some())
+}
+
+// The table or view to read records from, which for a dictionary encoded
+// table is its decoded view.
+pub fn source_table(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<String> {
+    if is_dictionary_encoded(conn, table)? {
+        Ok(format!("{}_decoded", table))
+    } else {
+        Ok(table.to_string())
+    }
+}
+
+// A view of a dictionary encoded table with the strings looked up again,
+// registered so that GIS tools show it as a layer.
+fn create_decoded_view(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<()> {
+    // The table may only have some of the columns, with --columns.
+    let existing = table_columns(conn, table)?;
+    let mut columns = vec![format!("{}.id", table)];
+    let mut joins = Vec::new();
+    for column in COLUMNS
+        .iter()
+        .filter(|column| existing.iter().any(|existing| existing == *column))
+    {
+        if dictionary::ENCODED_COLUMNS.contains(column) {
+            columns.push(format!("{}_dictionary.value AS {}", column, column));
+            joins.push(format!(
+                "LEFT JOIN {}_dictionary ON {}_dictionary.id = {}.{}",
+                column, column, table, column
+            ));
+        } else {
+            columns.push(format!("{}.{}", table, column));
+        }
+    }
+    columns.push(format!("{}.latitude", table));
+    columns.push(format!("{}.longitude", table));
+    create_spatial_view(
+        conn,
+        table,
+        &format!("{}_decoded", table),
+        &format!(
+            "SELECT {} FROM {} {}",
+            columns.join(", "),
+            table,
+            joins.join(" ")
+        ),
+    )
+}
+
+pub fn insert_record(
+    conn: &rusqlite::Connection,
+    table: &str,
+    columns: Option<&[&str]>,
+    rec: &EBirdRecord,
+) -> rusqlite::Result<usize> {
+    insert(conn, table, columns, rec, None)
+}
+
+// Insert a record into a dictionary encoded table.
+pub fn insert_encoded(
+    conn: &rusqlite::Connection,
+    table: &str,
+    columns: Option<&[&str]>,
+    dictionary: &mut Dictionary,
+    rec: &EBirdRecord,
+) -> rusqlite::Result<usize> {
+    let ids = dictionary.encode(conn, rec)?;
+    insert(conn, table, columns, rec, Some(&ids))
+}
+
+// The value to store for one of the dictionary encoded columns.
+fn encoded<'a, T: ToSql>(
+    ids: Option<&'a EncodedValues>,
+    index: usize,
+    value: &'a T,
+) -> &'a dyn ToSql {
+    match ids {
+        Some(ids) => &ids[index],
+        None => value,
+    }
+}
+
+fn insert(
+    conn: &rusqlite::Connection,
+    table: &str,
+    columns: Option<&[&str]>,
+    rec: &EBirdRecord,
+    ids: Option<&EncodedValues>,
+) -> rusqlite::Result<usize> {
+    let is_shared = rec.is_shared();
+    let values: [(&str, &dyn ToSql); 41] = [
+        ("guid", &rec.guid),
+        ("last_edited_date", &rec.last_edited_date),
+        ("common_name", encoded(ids, 0, &rec.common_name)),
+        ("scientific_name", encoded(ids, 1, &rec.scientific_name)),
+        ("observation_count", &rec.observation_count),
+        ("breeding_bird_atlas_code", &rec.breeding_bird_atlas_code),
+        (
+            "breeding_bird_atlas_category",
+            &rec.breeding_bird_atlas_category,
+        ),
+        ("latitude", &rec.latitude),
+        ("longitude", &rec.longitude),
+        ("obs_date", &rec.obs_date),
+        ("time_obs_started", &rec.time_obs_started),
+        ("obs_id", encoded(ids, 4, &rec.obs_id)),
+        ("sampling_event_id", &rec.sampling_event_id),
+        ("group_identifier", &rec.group_identifier),
+        ("is_shared", &is_shared),
+        ("protocol_type", encoded(ids, 2, &rec.protocol_type)),
+        ("duration_min", &rec.duration_min),
+        ("effort_distance_km", &rec.effort_distance_km),
+        ("effort_distance_mi", &rec.effort_distance_mi),
+        ("number_observers", &rec.number_observers),
+        ("all_species_reported", &rec.all_species_reported),
+        ("approved", &rec.approved),
+        ("reviewed", &rec.reviewed),
+        ("reason", &rec.reason),
+        ("species_comments", &rec.species_comments),
+        ("trip_comments", &rec.trip_comments),
+        ("locality", encoded(ids, 3, &rec.locality)),
+        ("locality_type", &rec.locality_type),
+        ("bcr_code", &rec.bcr_code),
+        ("iba_code", &rec.iba_code),
+        ("usfws_code", &rec.usfws_code),
+        ("time_zone", &rec.time_zone),
+        ("local_datetime", &rec.local_datetime),
+        ("utc_datetime", &rec.utc_datetime),
+        ("elevation", &rec.elevation),
+        ("nearest_place", &rec.nearest_place),
+        ("nearest_place_distance", &rec.nearest_place_distance),
+        ("atlas_block", &rec.atlas_block),
+        ("split", &rec.split),
+        ("coord_flag", &rec.coord_flag),
+        ("script_fields", &rec.script_fields),
+    ];
+    let values = values
+        .iter()
+        .filter(|(column, _)| stores_column(columns, column))
+        .collect::<Vec<_>>();
+    let names = values.iter().map(|(column, _)| *column).collect::<Vec<_>>();
+    let placeholders = (1..=values.len())
+        .map(|i| format!("?{}", i))
+        .collect::<Vec<_>>();
+
+    // Use the statement cache, since the SQL is built for each record.
+    let mut stmt = conn.prepare_cached(&format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        table,
+        names.join(", "),
+        placeholders.join(", ")
+    ))?;
+    stmt.execute(values.iter().map(|(_, value)| *value))
+}
+
+// Calling MakePoint for every insert is slow, so records are inserted with
+// plain coordinate columns and their geometry is filled in afterwards, along
+// with the spatial index.
+pub fn build_geometry(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<()> {
+    build_geometry_from(conn, table, 0)
+}
+
+// Fill in the geometry of records added to an existing table, which have ids
+// from first_id on, without scanning the rest of the table.
+pub fn build_geometry_from(
+    conn: &rusqlite::Connection,
+    table: &str,
+    first_id: i64,
+) -> rusqlite::Result<()> {
+    // Locations have a Z coordinate when imported with --dem, which is 0 for
+    // records without an elevation.
+    let dimension: i64 = conn.query_row(
+        "SELECT coord_dimension FROM geometry_columns
+                WHERE f_table_name = lower(?1) AND f_geometry_column = 'location'",
+        params![table],
+        |row| row.get(0),
+    )?;
+    let point = if dimension == 3 {
+        "MakePointZ(longitude, latitude, COALESCE(elevation, 0), 4326)"
+    } else {
+        "MakePoint(longitude, latitude, 4326)"
+    };
+    conn.execute(
+        &format!(
+            "UPDATE {} SET location = {} WHERE id >= ?1 AND location IS NULL",
+            table, point
+        ),
+        params![first_id],
+    )?;
+
+    // SpatiaLite stores table names in lower case.
+    let indexed: bool = conn.query_row(
+        "SELECT spatial_index_enabled FROM geometry_columns
+                WHERE f_table_name = lower(?1) AND f_geometry_column = 'location'",
+        params![table],
+        |row| row.get(0),
+    )?;
+    // Spatial indexes are R*Trees, which SQLite may have been built without.
+    if !indexed && spatialite::has_rtree(conn) {
+        let mut stmt = conn.prepare("SELECT CreateSpatialIndex(?1, 'location')")?;
+        stmt.exists(params![table])?;
+    }
+    Ok(())
+}
+
+// Add an effort_area geometry to traveling checklists, buffering the start
+// location by the distance travelled, since the birds could have been seen
+// anywhere within it. The buffer is made in Web Mercator, scaled for the
+// latitude so that its radius is in metres on the ground.
+pub fn build_effort_buffers(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<()> {
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM geometry_columns
+                WHERE f_table_name = lower(?1) AND f_geometry_column = 'effort_area'",
+            params![table],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !exists {
+        let mut stmt =
+            conn.prepare("SELECT AddGeometryColumn(?1, 'effort_area', 4326, 'POLYGON', 'XY')")?;
+        stmt.exists(params![table])?;
+    }
+
+    let traveling = if is_dictionary_encoded(conn, table)? {
+        "(SELECT id FROM protocol_type_dictionary WHERE value = 'Traveling')"
+    } else {
+        "'Traveling'"
+    };
+    conn.execute(
+        &format!(
+            "UPDATE {} SET effort_area = CastToPolygon(ST_Transform(ST_Buffer(
+                    ST_Transform(location, 3857),
+                    effort_distance_km * 1000.0 / Cos(Radians(latitude))), 4326))
+                WHERE effort_area IS NULL AND protocol_type = {}
+                    AND effort_distance_km > 0",
+            table, traveling
+        ),
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+// Indexes are created after loading, since maintaining them during the
+// import would slow it down. Column names must already have been checked
+// against COLUMNS.
+pub fn create_indexes(
+    conn: &rusqlite::Connection,
+    table: &str,
+    columns: &[&str],
+) -> rusqlite::Result<()> {
+    for column in columns {
+        conn.execute(
+            &format!(
+                "CREATE INDEX IF NOT EXISTS {}_{} ON {} ({})",
+                table, column, table, column
+            ),
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+// Full text search over free text, as an external content table so that the
+// text isn't stored twice. Search it with e.g.
+// SELECT * FROM ebird WHERE id IN (SELECT rowid FROM ebird_fts WHERE ebird_fts MATCH 'nest').
+pub fn build_fts(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<()> {
+    // The triggers keep the index up to date as records are added, replaced
+    // or deleted later, e.g. by watch and fetch.
+    conn.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS {table}_fts USING fts5(
+                {columns}, content='{table}', content_rowid='id');
+         INSERT INTO {table}_fts ({table}_fts) VALUES ('rebuild');
+         CREATE TRIGGER IF NOT EXISTS {table}_fts_insert AFTER INSERT ON {table} BEGIN
+                INSERT INTO {table}_fts (rowid, {columns}) VALUES (new.id, {new});
+         END;
+         CREATE TRIGGER IF NOT EXISTS {table}_fts_delete AFTER DELETE ON {table} BEGIN
+                INSERT INTO {table}_fts ({table}_fts, rowid, {columns})
+                        VALUES ('delete', old.id, {old});
+         END;
+         CREATE TRIGGER IF NOT EXISTS {table}_fts_update AFTER UPDATE OF {columns} ON {table}
+         BEGIN
+                INSERT INTO {table}_fts ({table}_fts, rowid, {columns})
+                        VALUES ('delete', old.id, {old});
+                INSERT INTO {table}_fts (rowid, {columns}) VALUES (new.id, {new});
+         END;",
+        table = table,
+        columns = FTS_COLUMNS.join(", "),
+        new = prefixed(FTS_COLUMNS, "new"),
+        old = prefixed(FTS_COLUMNS, "old"),
+    ))
+}
+
+// The columns of the full text search table.
+const FTS_COLUMNS: &[&str] = &[
+    "common_name",
+    "scientific_name",
+    "species_comments",
+    "trip_comments",
+    "locality",
+];
+
+fn prefixed(columns: &[&str], prefix: &str) -> String {
+    columns
+        .iter()
+        .map(|column| format!("{}.{}", prefix, column))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Add the triggers to a full text search table built before it had them,
+// rebuilding it to catch up with the records added since.
+pub fn refresh_fts(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<()> {
+    if table_exists(conn, &format!("{}_fts", table))?
+        && !conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'trigger' AND name = ?1")?
+            .exists(params![format!("{}_fts_insert", table)])?
+    {
+        build_fts(conn, table)?;
+    }
+    Ok(())
+}
+
+// Create a view with the same location as the given table and register it
+// with SpatiaLite, so that GIS tools show it as a layer. The view must have
+// an id column holding the id of a row of the table.
+fn create_spatial_view(
+    conn: &rusqlite::Connection,
+    table: &str,
+    name: &str,
+    select: &str,
+) -> rusqlite::Result<()> {
+    // SpatiaLite stores view and table names in lower case.
+    conn.execute_batch(&format!(
+        "DROP VIEW IF EXISTS {name};
+         DELETE FROM views_geometry_columns WHERE view_name = lower('{name}');
+         CREATE VIEW {name} AS {select};
+         INSERT INTO views_geometry_columns
+                (view_name, view_geometry, view_rowid, f_table_name, f_geometry_column, read_only)
+                VALUES (lower('{name}'), 'location', 'id', lower('{table}'), 'location', 1);",
+        name = name,
+        table = table,
+        select = select
+    ))
+}
+
+// A view name for a species, e.g. ebird_species_wood_thrush for "Wood Thrush".
+fn species_view_name(table: &str, species: &str) -> String {
+    let name: String = species
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}_species_{}", table, name)
+}
+
+// Create a layer with one point per checklist, and one layer for each of the
+// given species (matched by common or scientific name). Dictionary encoded
+// tables are selected from through their decoded view.
+pub fn create_views(
+    conn: &rusqlite::Connection,
+    table: &str,
+    species: &[&str],
+) -> rusqlite::Result<()> {
+    let source = if is_dictionary_encoded(conn, table)? {
+        format!("{}_decoded", table)
+    } else {
+        table.to_string()
+    };
+
+    // The bare columns take their values from the row with the minimum id.
+    create_spatial_view(
+        conn,
+        table,
+        &format!("{}_checklists", table),
+        &format!(
+            "SELECT MIN(id) AS id, sampling_event_id, obs_date, time_obs_started, obs_id,
+                    protocol_type, duration_min, effort_distance_km, number_observers,
+                    all_species_reported, locality, COUNT(*) AS species_count, location
+                    FROM {} GROUP BY sampling_event_id",
+            source
+        ),
+    )?;
+    for name in species {
+        let quoted = name.replace('\'', "''");
+        create_spatial_view(
+            conn,
+            table,
+            &species_view_name(table, name),
+            &format!(
+                "SELECT * FROM {} WHERE common_name = '{}' OR scientific_name = '{}'",
+                source, quoted, quoted
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+// Create a table for a derived layer, such as coverage gaps, replacing any
+// existing one, with a geometry column of the given type.
+pub fn create_layer(
+    conn: &rusqlite::Connection,
+    name: &str,
+    columns: &str,
+    geometry_type: &str,
+) -> rusqlite::Result<()> {
+    if table_exists(conn, name)? {
+        let mut stmt = conn.prepare("SELECT DropGeoTable(?1)")?;
+        stmt.exists(params![name])?;
+    }
+    conn.execute(&format!("DROP TABLE IF EXISTS {}", name), NO_PARAMS)?;
+    conn.execute(
+        &format!(
+            "CREATE TABLE {} (id INTEGER PRIMARY KEY, {})",
+            name, columns
+        ),
+        NO_PARAMS,
+    )?;
+    let mut stmt = conn.prepare("SELECT AddGeometryColumn(?1, 'geometry', 4326, ?2, 'XY')")?;
+    stmt.exists(params![name, geometry_type])?;
+    Ok(())
+}
+
+// Build the {table}_ranges layer, with a hull around the records of each
+// species as a rough distribution map. Concave hulls need SpatiaLite built
+// with advanced GEOS support. Species with fewer than three distinct
+// locations have no hull, so are left out.
+pub fn build_ranges(
+    conn: &rusqlite::Connection,
+    table: &str,
+    concave: bool,
+) -> rusqlite::Result<()> {
+    let source = source_table(conn, table)?;
+    let layer = format!("{}_ranges", table);
+    let hull = if concave { "ConcaveHull" } else { "ConvexHull" };
+    conn.execute_batch("BEGIN")?;
+    create_layer(
+        conn,
+        &layer,
+        "scientific_name TEXT, common_name TEXT, records INTEGER",
+        "MULTIPOLYGON",
+    )?;
+    conn.execute(
+        &format!(
+            "INSERT INTO {layer} (scientific_name, common_name, records, geometry)
+                SELECT * FROM (
+                    SELECT scientific_name, MIN(common_name), COUNT(*),
+                            CastToMultiPolygon({hull}(Collect(location))) AS geometry
+                        FROM {source} GROUP BY scientific_name)
+                    WHERE geometry IS NOT NULL",
+            layer = layer,
+            hull = hull,
+            source = source
+        ),
+        NO_PARAMS,
+    )?;
+    conn.execute_batch("COMMIT")
+}
+
+// The names of the columns a table has, which may be only some of them with
+// --columns.
+pub fn table_columns(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    conn.prepare(&format!("PRAGMA table_info({})", table))?
+        .query_map(NO_PARAMS, |row| row.get::<_, String>(1))?
+        .collect()
+}
+
+pub fn table_exists(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<bool> {
+    let mut stmt =
+        conn.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")?;
+    stmt.exists(params![table])
+}
+
+// Replace any existing record with the same GUID, for use when refreshing an
+// existing database. Only the columns the table has are stored, as found by
+// table_columns.
+pub fn upsert_record(
+    conn: &rusqlite::Connection,
+    table: &str,
+    columns: &[&str],
+    rec: &EBirdRecord,
+) -> rusqlite::Result<usize> {
+    conn.prepare_cached(&format!("DELETE FROM {} WHERE guid = ?1", table))?
+        .execute(params![rec.guid])?;
+    insert_record(conn, table, Some(columns), rec)
+}
+
+// Upserting by GUID is unusably slow on a large table without an index.
+pub fn create_guid_index(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<usize> {
+    conn.execute(
+        &format!(
+            "CREATE INDEX IF NOT EXISTS {}_guid ON {} (guid)",
+            table, table
+        ),
+        NO_PARAMS,
+    )
+}
+
+// Open a database for adding records to, creating the table only if it does
+// not already exist.
+pub fn open_for_update(path: &str, table: &str) -> rusqlite::Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open(path)?;
+    if table_exists(&conn, table)? {
+        load_spatialite(&conn)?;
+    } else {
+        let schema = Schema {
+            table,
+            ..Default::default()
+        };
+        initialize_database(&conn, &schema)?;
+    }
+    create_guid_index(&conn, table)?;
+    Ok(conn)
+}
+
+pub fn create_metadata_table(conn: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS metadata (
+                key                             TEXT PRIMARY KEY,
+                value                           TEXT)",
+        NO_PARAMS,
+    )
+}
+
+pub fn get_metadata(conn: &rusqlite::Connection, key: &str) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT value FROM metadata WHERE key = ?1",
+        params![key],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+pub fn set_metadata(
+    conn: &rusqlite::Connection,
+    key: &str,
+    value: &str,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES (?1, ?2)",
+        params![key, value],
+    )
+}
+
+// A record of one import into the database, so that we can reconstruct how a
+// database was produced.
+pub struct ImportRun<'a> {
+    pub table: &'a str,
+    pub input: &'a str,
+    pub input_format: &'a str,
+    // Only known if the input file was read to the end.
+    pub input_sha256: Option<String>,
+    // The options affecting which records were imported, as JSON.
+    pub parameters: String,
+    pub started_at: DateTime<Utc>,
+    pub records_read: usize,
+    pub records_inserted: usize,
+    pub errors: usize,
+    // One of completed, interrupted or aborted.
+    pub status: &'a str,
+}
+
+pub fn insert_import_run(conn: &rusqlite::Connection, run: &ImportRun) -> rusqlite::Result<usize> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS import_runs (
+                id                              INTEGER PRIMARY KEY,
+                tool_version                    TEXT,
+                table_name                      TEXT,
+                input                           TEXT,
+                input_format                    TEXT,
+                input_sha256                    TEXT,
+                parameters                      TEXT,
+                records_read                    INTEGER,
+                records_inserted                INTEGER,
+                errors                          INTEGER,
+                status                          TEXT,
+                started_at                      TEXT,
+                finished_at                     TEXT)",
+        NO_PARAMS,
+    )?;
+    conn.execute(
+        "INSERT INTO import_runs (tool_version, table_name, input, input_format, input_sha256,
+                                  parameters, records_read, records_inserted, errors, status,
+                                  started_at, finished_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            env!("CARGO_PKG_VERSION"),
+            run.table,
+            run.input,
+            run.input_format,
+            run.input_sha256,
+            run.parameters,
+            run.records_read as i64,
+            run.records_inserted as i64,
+            run.errors as i64,
+            run.status,
+            run.started_at.to_rfc3339(),
+            Utc::now().to_rfc3339()
+        ],
+    )
+}
+
+// Returns the number of input records consumed by an interrupted import into
+// a table, if it was run with the same filters. Each table has its own
+// checkpoint, so imports into different tables of a database don't disturb
+// each other.
+pub fn get_checkpoint(
+    conn: &rusqlite::Connection,
+    table: &str,
+    filter_hash: &str,
+) -> rusqlite::Result<Option<usize>> {
+    if !table_exists(conn, "metadata")? {
+        return Ok(None);
+    }
+    let hash = get_metadata(conn, &format!("checkpoint_filter_hash:{}", table))?;
+    if hash.as_deref() != Some(filter_hash) {
+        return Ok(None);
+    }
+    Ok(
+        get_metadata(conn, &format!("checkpoint_records:{}", table))?
+            .and_then(|records| records.parse().ok()),
+    )
+}
+
+// Record how many input records have been consumed and commit everything
+// inserted so far, so that an interrupted import can be resumed from here.
+pub fn checkpoint(
+    conn: &rusqlite::Connection,
+    table: &str,
+    records: usize,
+    filter_hash: &str,
+) -> rusqlite::Result<()> {
+    set_metadata(
+        conn,
+        &format!("checkpoint_records:{}", table),
+        &records.to_string(),
+    )?;
+    set_metadata(
+        conn,
+        &format!("checkpoint_filter_hash:{}", table),
+        filter_hash,
+    )?;
+    conn.execute_batch("COMMIT; BEGIN")
+}
+
+pub fn clear_checkpoint(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<usize> {
+    conn.execute(
+        "DELETE FROM metadata WHERE key IN (?1, ?2)",
+        params![
+            format!("checkpoint_records:{}", table),
+            format!("checkpoint_filter_hash:{}", table)
+        ],
+    )
+}
+
+// Trade crash safety for import speed. With synchronous off, a crash or power
+// loss during the import can corrupt the database.
+pub fn set_fast_pragmas(conn: &rusqlite::Connection, synchronous: &str) -> rusqlite::Result<()> {
+    // Within --max-memory, the cache is smaller and index building sorts on
+    // disk.
+    let (cache_kib, temp_store) = match memory::sqlite_cache_kib() {
+        Some(cache_kib) => (cache_kib.min(1 << 20), "FILE"),
+        None => (1 << 20, "MEMORY"),
+    };
+    conn.execute_batch(&format!(
+        "PRAGMA journal_mode = WAL;
+         PRAGMA synchronous = {};
+         PRAGMA cache_size = -{};
+         PRAGMA temp_store = {};",
+        synchronous, cache_kib, temp_store
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        create_metadata_table(&conn).unwrap();
+        conn.execute_batch("BEGIN").unwrap();
+        conn
+    }
+
+    #[test]
+    fn checkpoint_is_resumed_with_the_same_filters() {
+        let conn = database();
+        assert_eq!(get_checkpoint(&conn, "ebird", "abc").unwrap(), None);
+        checkpoint(&conn, "ebird", 355_000, "abc").unwrap();
+        assert_eq!(
+            get_checkpoint(&conn, "ebird", "abc").unwrap(),
+            Some(355_000)
+        );
+        assert_eq!(get_checkpoint(&conn, "ebird", "def").unwrap(), None);
+    }
+
+    #[test]
+    fn checkpoints_are_kept_per_table() {
+        let conn = database();
+        checkpoint(&conn, "a", 1000, "abc").unwrap();
+        checkpoint(&conn, "b", 2000, "abc").unwrap();
+        assert_eq!(get_checkpoint(&conn, "a", "abc").unwrap(), Some(1000));
+        assert_eq!(get_checkpoint(&conn, "b", "abc").unwrap(), Some(2000));
+
+        assert_eq!(clear_checkpoint(&conn, "b").unwrap(), 2);
+        assert_eq!(get_checkpoint(&conn, "a", "abc").unwrap(), Some(1000));
+        assert_eq!(get_checkpoint(&conn, "b", "abc").unwrap(), None);
+    }
+}
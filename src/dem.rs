@@ -0,0 +1,111 @@
+use crate::EBirdRecord;
+use std::fs::File;
+use std::io::{self, BufReader};
+use tiff::decoder::{Decoder, DecodingResult, Limits};
+use tiff::tags::Tag;
+
+// GeoTIFF tags.
+const MODEL_PIXEL_SCALE: u16 = 33550;
+const MODEL_TIEPOINT: u16 = 33922;
+const GDAL_NODATA: u16 = 42113;
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+// A digital elevation model read from a single band GeoTIFF in geographic
+// coordinates (WGS 84 longitude and latitude), such as the SRTM tiles. The
+// whole raster is read into memory.
+pub struct Dem {
+    // The longitude and latitude of the top left corner, and the size of a
+    // cell in degrees.
+    origin_x: f64,
+    origin_y: f64,
+    scale_x: f64,
+    scale_y: f64,
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+    nodata: Option<f32>,
+}
+
+impl Dem {
+    pub fn load(path: &str) -> io::Result<Dem> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path)?))
+            .map_err(invalid_data)?
+            .with_limits(Limits::unlimited());
+        let (width, height) = decoder.dimensions().map_err(invalid_data)?;
+        let scale = decoder
+            .get_tag_f64_vec(Tag::Unknown(MODEL_PIXEL_SCALE))
+            .map_err(|_| invalid_data(format!("{} is not a GeoTIFF", path)))?;
+        let tiepoint = decoder
+            .get_tag_f64_vec(Tag::Unknown(MODEL_TIEPOINT))
+            .map_err(|_| invalid_data(format!("{} is not a GeoTIFF", path)))?;
+        if scale.len() < 2 || tiepoint.len() < 6 {
+            return Err(invalid_data(format!("{} is not a GeoTIFF", path)));
+        }
+        let nodata = decoder
+            .get_tag_ascii_string(Tag::Unknown(GDAL_NODATA))
+            .ok()
+            .and_then(|text| text.trim_matches(char::from(0)).trim().parse::<f32>().ok());
+
+        let values = match decoder.read_image().map_err(invalid_data)? {
+            DecodingResult::U8(values) => values.into_iter().map(f32::from).collect(),
+            DecodingResult::U16(values) => values.into_iter().map(f32::from).collect(),
+            DecodingResult::I8(values) => values.into_iter().map(f32::from).collect(),
+            DecodingResult::I16(values) => values.into_iter().map(f32::from).collect(),
+            DecodingResult::F32(values) => values,
+            DecodingResult::F64(values) => values.into_iter().map(|value| value as f32).collect(),
+            DecodingResult::U32(values) => values.into_iter().map(|value| value as f32).collect(),
+            DecodingResult::I32(values) => values.into_iter().map(|value| value as f32).collect(),
+            _ => {
+                return Err(invalid_data(format!(
+                    "Unsupported sample format in {}",
+                    path
+                )))
+            }
+        };
+        let (width, height) = (width as usize, height as usize);
+        if values.len() != width * height {
+            return Err(invalid_data(format!("{} must have a single band", path)));
+        }
+
+        Ok(Dem {
+            origin_x: tiepoint[3] - tiepoint[0] * scale[0],
+            origin_y: tiepoint[4] + tiepoint[1] * scale[1],
+            scale_x: scale[0],
+            scale_y: scale[1],
+            width,
+            height,
+            values,
+            nodata,
+        })
+    }
+
+    // The elevation of the cell containing a location, if it is covered by
+    // the model.
+    fn elevation(&self, longitude: f64, latitude: f64) -> Option<f64> {
+        let column = ((longitude - self.origin_x) / self.scale_x).floor();
+        let row = ((self.origin_y - latitude) / self.scale_y).floor();
+        if column < 0.0 || row < 0.0 {
+            return None;
+        }
+        let (column, row) = (column as usize, row as usize);
+        if column >= self.width || row >= self.height {
+            return None;
+        }
+        let value = self.values[row * self.width + column];
+        if Some(value) == self.nodata || value.is_nan() {
+            None
+        } else {
+            Some(value as f64)
+        }
+    }
+
+    pub fn apply(&self, record: &mut EBirdRecord) {
+        record.elevation = self.elevation(record.longitude, record.latitude);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::memory::{self, Order, Tally};
+use crate::EBirdRecord;
+use rusqlite::{params, OptionalExtension, NO_PARAMS};
+use std::collections::HashMap;
+
+// Columns whose values are repeated across many records, and which are stored
+// as ids into a <column>_dictionary table with --dictionary-encode.
+pub const ENCODED_COLUMNS: &[&str] = &[
+    "common_name",
+    "scientific_name",
+    "protocol_type",
+    "locality",
+    "obs_id",
+];
+
+// The ids of the encoded columns of a record, in the order of ENCODED_COLUMNS.
+pub type EncodedValues = [i64; 5];
+
+// The values of the encoded columns of a record, in the order of
+// ENCODED_COLUMNS.
+fn values(rec: &EBirdRecord) -> [&str; 5] {
+    [
+        &rec.common_name,
+        &rec.scientific_name,
+        &rec.protocol_type,
+        &rec.locality,
+        &rec.obs_id,
+    ]
+}
+
+// The number of records with each value of the encoded columns, counted by
+// the first pass over the input with --two-pass.
+pub struct Counts {
+    records: usize,
+    counts: Vec<Tally>,
+    distinct: Vec<usize>,
+}
+
+impl Counts {
+    pub fn count(
+        records: impl Iterator<Item = csv::Result<EBirdRecord>>,
+    ) -> rusqlite::Result<Counts> {
+        let mut counts = Counts {
+            records: 0,
+            counts: ENCODED_COLUMNS.iter().map(|_| Tally::default()).collect(),
+            distinct: Vec::new(),
+        };
+        // Records which can't be parsed are reported by the second pass.
+        for rec in records.flatten() {
+            counts.records += 1;
+            for (counts, value) in counts.counts.iter_mut().zip(values(&rec).iter()) {
+                counts.add(value)?;
+            }
+        }
+        counts.distinct = counts
+            .counts
+            .iter_mut()
+            .map(Tally::distinct)
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(counts)
+    }
+
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    // The number of distinct values of a column.
+    pub fn distinct(&self, column: &str) -> usize {
+        ENCODED_COLUMNS
+            .iter()
+            .position(|encoded| *encoded == column)
+            .map_or(0, |index| self.distinct[index])
+    }
+}
+
+// Maps strings to their ids in each dictionary table, so that the tables only
+// need to be written to for values we haven't seen before. With
+// --max-memory only some are kept, and the others are looked up in the
+// tables.
+pub struct Dictionary {
+    ids: Vec<HashMap<String, i64>>,
+    // The memory taken by the ids of each column, and whether they are every
+    // value in its table, so that values not among them are new.
+    bytes: Vec<usize>,
+    complete: Vec<bool>,
+    // The first id given to a value from the counts of a first pass, if
+    // there was one.
+    filled_from: Option<EncodedValues>,
+}
+
+impl Dictionary {
+    // Create the dictionary tables if necessary, and load any existing
+    // entries, e.g. when resuming an import.
+    pub fn open(conn: &rusqlite::Connection) -> rusqlite::Result<Dictionary> {
+        let mut dictionary = Dictionary {
+            ids: ENCODED_COLUMNS.iter().map(|_| HashMap::new()).collect(),
+            bytes: vec![0; ENCODED_COLUMNS.len()],
+            complete: vec![true; ENCODED_COLUMNS.len()],
+            filled_from: None,
+        };
+        for (index, column) in ENCODED_COLUMNS.iter().enumerate() {
+            conn.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {}_dictionary (
+                            id                              INTEGER PRIMARY KEY,
+                            value                           TEXT UNIQUE)",
+                    column
+                ),
+                NO_PARAMS,
+            )?;
+            let mut stmt = conn.prepare(&format!("SELECT value, id FROM {}_dictionary", column))?;
+            let mut rows = stmt.query(NO_PARAMS)?;
+            while let Some(row) = rows.next()? {
+                dictionary.cache(index, row.get(0)?, row.get(1)?);
+            }
+        }
+        Ok(dictionary)
+    }
+
+    // Remember the id of a value. If that would take more memory than
+    // --max-memory allows, the ids of the column taking the most are
+    // forgotten rather than keeping track of which were used least recently.
+    // That is nearly always a column with a value for every checklist or
+    // record, such as obs_id, so the few species and protocols stay cached
+    // while it churns, and its common values are soon looked up again.
+    fn cache(&mut self, index: usize, value: String, id: i64) {
+        let size = value.len() + memory::ENTRY_OVERHEAD;
+        let total = self.bytes.iter().sum::<usize>();
+        if memory::cache_bytes().is_some_and(|limit| total + size > limit) {
+            let largest = (0..self.bytes.len())
+                .max_by_key(|column| self.bytes[*column])
+                .unwrap_or(index);
+            self.ids[largest] = HashMap::new();
+            self.bytes[largest] = 0;
+            self.complete[largest] = false;
+        }
+        self.bytes[index] += size;
+        self.ids[index].insert(value, id);
+    }
+
+    // Add the values counted by a first pass over the input in one go, the
+    // most common first so that they get the smallest ids, which SQLite
+    // stores in fewer bytes. The dictionary tables are then written in order
+    // rather than a page at a time between the records, and the import only
+    // reads them.
+    pub fn fill(&mut self, conn: &rusqlite::Connection, counts: Counts) -> rusqlite::Result<()> {
+        let mut filled_from = [0; 5];
+        for (index, (counts, distinct)) in
+            counts.counts.into_iter().zip(counts.distinct).enumerate()
+        {
+            if memory::cache_bytes().is_none() {
+                self.ids[index].reserve(distinct);
+            }
+            filled_from[index] = conn.query_row(
+                &format!(
+                    "SELECT COALESCE(MAX(id), 0) + 1 FROM {}_dictionary",
+                    ENCODED_COLUMNS[index]
+                ),
+                NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            // Values already in the table keep their ids.
+            let mut stmt = conn.prepare(&format!(
+                "INSERT OR IGNORE INTO {}_dictionary (value) VALUES (?1)",
+                ENCODED_COLUMNS[index]
+            ))?;
+            counts.for_each(Order::MostCommon, |value, _| {
+                if stmt.execute(params![value])? > 0 {
+                    self.cache(index, value, conn.last_insert_rowid());
+                }
+                Ok(())
+            })?;
+        }
+        self.filled_from = Some(filled_from);
+        Ok(())
+    }
+
+    // Delete the values added by fill() which no record of the table refers
+    // to, such as those only found in records which were filtered out, and
+    // return how many there were. Values already in the dictionaries may be
+    // used by other tables, so are kept.
+    pub fn prune(&self, conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<usize> {
+        let filled_from = match self.filled_from {
+            Some(filled_from) => filled_from,
+            None => return Ok(0),
+        };
+        // The table may only have some of the columns, with --columns.
+        let existing = conn
+            .prepare(&format!("PRAGMA table_info({})", table))?
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(1))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut pruned = 0;
+        for (column, first) in ENCODED_COLUMNS.iter().zip(filled_from.iter()) {
+            let unused = if existing.iter().any(|existing| existing == column) {
+                format!(
+                    " AND id NOT IN (SELECT {} FROM {} WHERE {} IS NOT NULL)",
+                    column, table, column
+                )
+            } else {
+                String::new()
+            };
+            pruned += conn.execute(
+                &format!("DELETE FROM {}_dictionary WHERE id >= ?1{}", column, unused),
+                params![first],
+            )?;
+        }
+        Ok(pruned)
+    }
+
+    fn id(
+        &mut self,
+        conn: &rusqlite::Connection,
+        index: usize,
+        value: &str,
+    ) -> rusqlite::Result<i64> {
+        if let Some(id) = self.ids[index].get(value) {
+            return Ok(*id);
+        }
+        let column = ENCODED_COLUMNS[index];
+        let existing = if self.complete[index] {
+            None
+        } else {
+            conn.query_row(
+                &format!("SELECT id FROM {}_dictionary WHERE value = ?1", column),
+                params![value],
+                |row| row.get(0),
+            )
+            .optional()?
+        };
+        let id = match existing {
+            Some(id) => id,
+            None => {
+                conn.execute(
+                    &format!("INSERT INTO {}_dictionary (value) VALUES (?1)", column),
+                    params![value],
+                )?;
+                conn.last_insert_rowid()
+            }
+        };
+        self.cache(index, value.to_string(), id);
+        Ok(id)
+    }
+
+    pub fn encode(
+        &mut self,
+        conn: &rusqlite::Connection,
+        rec: &EBirdRecord,
+    ) -> rusqlite::Result<EncodedValues> {
+        let mut ids = [0; 5];
+        for (index, value) in values(rec).iter().enumerate() {
+            ids[index] = self.id(conn, index, value)?;
+        }
+        Ok(ids)
+    }
+}
@@ -0,0 +1,139 @@
+use crate::db;
+use crate::to_io_error;
+use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
+use std::io;
+
+// The table or view to compare in a database, and whether it has the last
+// edited date needed to find modified records, which databases made by older
+// versions don't.
+fn open_source(conn: &Connection, path: &str, table: &str) -> io::Result<(String, bool)> {
+    if !db::table_exists(conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No table {} in {}", table, path),
+        ));
+    }
+    if db::is_checklist_table(conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("The checklist table in {} has no GUIDs to compare", path),
+        ));
+    }
+    let source = db::source_table(conn, table).map_err(to_io_error)?;
+    let edited = conn
+        .prepare(&format!("SELECT last_edited_date FROM {} LIMIT 0", source))
+        .is_ok();
+    if !edited {
+        println!(
+            "warning: {} has no last edited dates, so modified records can't be found",
+            path
+        );
+    }
+    Ok((source, edited))
+}
+
+// Compare the records of two databases, usually imports of consecutive EBD
+// releases, by GUID. Records only in the new database were added, those only
+// in the old one were removed, and those in both with a different last edited
+// date were modified.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let old_path = matches.value_of("OLD").unwrap();
+    let new_path = matches.value_of("NEW").unwrap();
+    let table = crate::table_name(matches)?;
+    let change_table = matches.value_of("change-table");
+    if let Some(change_table) = change_table {
+        if !db::valid_table_name(change_table) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Invalid table name {}", change_table),
+            ));
+        }
+    }
+
+    let (old_source, old_edited) = {
+        let conn = Connection::open_with_flags(old_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(to_io_error)?;
+        open_source(&conn, old_path, table)?
+    };
+    // The change table is written to the new database.
+    let conn = match change_table {
+        Some(_) => Connection::open(new_path),
+        None => Connection::open_with_flags(new_path, OpenFlags::SQLITE_OPEN_READ_ONLY),
+    }
+    .map_err(to_io_error)?;
+    let (new_source, new_edited) = open_source(&conn, new_path, table)?;
+    conn.execute("ATTACH DATABASE ?1 AS previous", params![old_path])
+        .map_err(to_io_error)?;
+
+    let modified_condition = if old_edited && new_edited {
+        "n.last_edited_date IS NOT o.last_edited_date"
+    } else {
+        "0"
+    };
+    let changes = format!(
+        "SELECT 'added' AS change, n.guid, n.common_name, n.scientific_name,
+                    n.obs_date, NULL AS old_last_edited_date,
+                    {new_edited} AS new_last_edited_date
+                FROM {new} AS n
+                WHERE NOT EXISTS (SELECT 1 FROM previous.{old} AS o WHERE o.guid = n.guid)
+         UNION ALL
+         SELECT 'removed', o.guid, o.common_name, o.scientific_name, o.obs_date,
+                    {old_edited}, NULL
+                FROM previous.{old} AS o
+                WHERE NOT EXISTS (SELECT 1 FROM {new} AS n WHERE n.guid = o.guid)
+         UNION ALL
+         SELECT 'modified', n.guid, n.common_name, n.scientific_name, n.obs_date,
+                    {old_edited}, {new_edited}
+                FROM {new} AS n JOIN previous.{old} AS o ON o.guid = n.guid
+                WHERE {modified}",
+        new = new_source,
+        old = old_source,
+        new_edited = if new_edited {
+            "n.last_edited_date"
+        } else {
+            "NULL"
+        },
+        old_edited = if old_edited {
+            "o.last_edited_date"
+        } else {
+            "NULL"
+        },
+        modified = modified_condition
+    );
+
+    let counts = match change_table {
+        Some(change_table) => {
+            conn.execute_batch(&format!(
+                "DROP TABLE IF EXISTS {table};
+                 CREATE TABLE {table} AS {changes};",
+                table = change_table,
+                changes = changes
+            ))
+            .map_err(to_io_error)?;
+            format!(
+                "SELECT change, COUNT(*) FROM {} GROUP BY change",
+                change_table
+            )
+        }
+        None => format!("SELECT change, COUNT(*) FROM ({}) GROUP BY change", changes),
+    };
+
+    let (mut added, mut removed, mut modified) = (0, 0, 0);
+    let mut stmt = conn.prepare(&counts).map_err(to_io_error)?;
+    let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        let count: i64 = row.get(1).map_err(to_io_error)?;
+        match row.get::<_, String>(0).map_err(to_io_error)?.as_str() {
+            "added" => added = count,
+            "removed" => removed = count,
+            _ => modified = count,
+        }
+    }
+    println!("added     {}", added);
+    println!("removed   {}", removed);
+    println!("modified  {}", modified);
+    if let Some(change_table) = change_table {
+        println!("changes written to {} in {}", change_table, new_path);
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use crate::EBirdRecord;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use zip::ZipArchive;
+
+// Describes the core data file of a Darwin Core Archive, as read from the
+// meta.xml descriptor.
+struct Meta {
+    location: String,
+    delimiter: u8,
+    quote: Option<u8>,
+    has_headers: bool,
+    // Maps the local name of each Darwin Core term (e.g. "decimalLatitude")
+    // to its column index.
+    columns: HashMap<String, usize>,
+}
+
+// A Darwin Core Archive, such as a GBIF occurrence download.
+pub struct Archive {
+    zip: ZipArchive<File>,
+    meta: Meta,
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+// Attribute values like fieldsTerminatedBy are written with backslash
+// escapes, e.g. "\t".
+fn unescape(text: &str) -> Option<u8> {
+    match text {
+        "" => None,
+        "\\t" => Some(b'\t'),
+        "\\n" => Some(b'\n'),
+        _ => text.bytes().next(),
+    }
+}
+
+fn parse_meta(text: &str) -> io::Result<Meta> {
+    let doc = roxmltree::Document::parse(text).map_err(invalid_data)?;
+    let core = doc
+        .descendants()
+        .find(|node| node.has_tag_name("core"))
+        .ok_or_else(|| invalid_data("meta.xml has no core element"))?;
+
+    let location = core
+        .descendants()
+        .find(|node| node.has_tag_name("location"))
+        .and_then(|node| node.text())
+        .ok_or_else(|| invalid_data("meta.xml has no core file location"))?;
+
+    let mut columns = HashMap::new();
+    for field in core.children().filter(|node| node.has_tag_name("field")) {
+        // Fields without an index supply a default value, which we ignore.
+        if let (Some(index), Some(term)) = (field.attribute("index"), field.attribute("term")) {
+            let index = index.parse::<usize>().map_err(invalid_data)?;
+            let name = term.rsplit('/').next().unwrap_or(term);
+            columns.insert(name.to_string(), index);
+        }
+    }
+
+    let delimiter = core.attribute("fieldsTerminatedBy").unwrap_or("\\t");
+
+    Ok(Meta {
+        location: location.trim().to_string(),
+        delimiter: unescape(delimiter).unwrap_or(b'\t'),
+        quote: unescape(core.attribute("fieldsEnclosedBy").unwrap_or("")),
+        has_headers: core.attribute("ignoreHeaderLines").unwrap_or("0") != "0",
+        columns,
+    })
+}
+
+impl Meta {
+    fn get<'a>(&self, record: &'a csv::StringRecord, term: &str) -> Option<&'a str> {
+        self.columns
+            .get(term)
+            .and_then(|index| record.get(*index))
+            .filter(|value| !value.is_empty())
+    }
+
+    fn to_ebird_record(&self, record: &csv::StringRecord) -> csv::Result<EBirdRecord> {
+        let text = |term| self.get(record, term).unwrap_or("").to_string();
+        let coordinate = |term| -> csv::Result<f64> {
+            let value = self
+                .get(record, term)
+                .ok_or_else(|| invalid_data(format!("missing {}", term)))?;
+            Ok(value.parse::<f64>().map_err(invalid_data)?)
+        };
+
+        // eventDate is ISO 8601, possibly with a time or as an interval.
+        let event_date = text("eventDate");
+        let obs_date = event_date.get(..10).unwrap_or(&event_date).to_string();
+        let time_obs_started = match self.get(record, "eventTime") {
+            Some(time) => time.to_string(),
+            None => event_date
+                .split('/')
+                .next()
+                .and_then(|start| start.split('T').nth(1))
+                .unwrap_or("")
+                .to_string(),
+        };
+
+        Ok(EBirdRecord {
+            guid: self
+                .get(record, "occurrenceID")
+                .or_else(|| self.get(record, "gbifID"))
+                .unwrap_or("")
+                .to_string(),
+            common_name: text("vernacularName"),
+            scientific_name: text("scientificName"),
+            observation_count: self
+                .get(record, "individualCount")
+                .unwrap_or("X")
+                .to_string(),
+            breeding_bird_atlas_code: String::new(),
+            breeding_bird_atlas_category: String::new(),
+            age_sex: String::new(),
+            latitude: coordinate("decimalLatitude")?,
+            longitude: coordinate("decimalLongitude")?,
+            obs_date,
+            time_obs_started,
+            obs_id: text("recordedBy"),
+            sampling_event_id: text("eventID"),
+            protocol_type: text("samplingProtocol"),
+            duration_min: None,
+            effort_distance_km: None,
+            number_observers: None,
+            all_species_reported: 0,
+            // Published occurrence data has already been through review.
+            approved: 1,
+            species_comments: text("occurrenceRemarks"),
+            trip_comments: text("eventRemarks"),
+            locality: text("locality"),
+            ..Default::default()
+        })
+    }
+}
+
+impl Archive {
+    pub fn open(path: &str) -> io::Result<Archive> {
+        let mut zip = ZipArchive::new(File::open(path)?).map_err(invalid_data)?;
+
+        let mut text = String::new();
+        zip.by_name("meta.xml")
+            .map_err(invalid_data)?
+            .read_to_string(&mut text)?;
+        let meta = parse_meta(&text)?;
+
+        Ok(Archive { zip, meta })
+    }
+
+    // Iterate over the records of the core data file (usually
+    // occurrence.txt), mapped into EBird records according to meta.xml.
+    pub fn records(
+        &mut self,
+    ) -> io::Result<Box<dyn Iterator<Item = csv::Result<EBirdRecord>> + '_>> {
+        let meta = &self.meta;
+        let core = self.zip.by_name(&meta.location).map_err(invalid_data)?;
+
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .delimiter(meta.delimiter)
+            .has_headers(meta.has_headers)
+            .flexible(true);
+        match meta.quote {
+            Some(quote) => builder.quote(quote),
+            None => builder.quoting(false),
+        };
+
+        Ok(Box::new(builder.from_reader(core).into_records().map(
+            move |record| match record {
+                Ok(record) => meta.to_ebird_record(&record),
+                Err(err) => Err(err),
+            },
+        )))
+    }
+}
@@ -0,0 +1,379 @@
+use crate::EBirdRecord;
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::rc::Rc;
+use tracing::warn;
+
+// The fields of EBirdRecord which are read from the EBD.
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Guid,
+    LastEditedDate,
+    CommonName,
+    ScientificName,
+    ObservationCount,
+    BreedingBirdAtlasCode,
+    BreedingBirdAtlasCategory,
+    AgeSex,
+    Latitude,
+    Longitude,
+    ObsDate,
+    TimeObsStarted,
+    ObsId,
+    SamplingEventId,
+    GroupIdentifier,
+    ProtocolType,
+    DurationMin,
+    EffortDistanceKm,
+    NumberObservers,
+    AllSpeciesReported,
+    Approved,
+    Reviewed,
+    Reason,
+    SpeciesComments,
+    TripComments,
+    Locality,
+    LocalityType,
+    BcrCode,
+    IbaCode,
+    UsfwsCode,
+}
+
+// The header names used for each field, including names used by earlier or
+// later EBD releases.
+const FIELDS: &[(Field, &[&str])] = &[
+    (Field::Guid, &["GLOBAL UNIQUE IDENTIFIER"]),
+    (Field::LastEditedDate, &["LAST EDITED DATE"]),
+    (Field::CommonName, &["COMMON NAME"]),
+    (Field::ScientificName, &["SCIENTIFIC NAME"]),
+    (Field::ObservationCount, &["OBSERVATION COUNT"]),
+    (
+        Field::BreedingBirdAtlasCode,
+        &["BREEDING BIRD ATLAS CODE", "BREEDING CODE"],
+    ),
+    (
+        Field::BreedingBirdAtlasCategory,
+        &["BREEDING BIRD ATLAS CATEGORY", "BREEDING CATEGORY"],
+    ),
+    (Field::AgeSex, &["AGE/SEX"]),
+    (Field::Latitude, &["LATITUDE"]),
+    (Field::Longitude, &["LONGITUDE"]),
+    (Field::ObsDate, &["OBSERVATION DATE"]),
+    (Field::TimeObsStarted, &["TIME OBSERVATIONS STARTED"]),
+    (Field::ObsId, &["OBSERVER ID"]),
+    (Field::SamplingEventId, &["SAMPLING EVENT IDENTIFIER"]),
+    (Field::GroupIdentifier, &["GROUP IDENTIFIER"]),
+    (Field::ProtocolType, &["PROTOCOL TYPE", "PROTOCOL NAME"]),
+    (Field::DurationMin, &["DURATION MINUTES"]),
+    (Field::EffortDistanceKm, &["EFFORT DISTANCE KM"]),
+    (Field::NumberObservers, &["NUMBER OBSERVERS"]),
+    (Field::AllSpeciesReported, &["ALL SPECIES REPORTED"]),
+    (Field::Approved, &["APPROVED"]),
+    (Field::Reviewed, &["REVIEWED"]),
+    (Field::Reason, &["REASON"]),
+    (Field::SpeciesComments, &["SPECIES COMMENTS"]),
+    (
+        Field::TripComments,
+        &["TRIP COMMENTS", "CHECKLIST COMMENTS"],
+    ),
+    (Field::Locality, &["LOCALITY"]),
+    (Field::LocalityType, &["LOCALITY TYPE"]),
+    (Field::BcrCode, &["BCR CODE"]),
+    (Field::IbaCode, &["IBA CODE"]),
+    (Field::UsfwsCode, &["USFWS CODE"]),
+];
+
+// The database column each field is stored in. Latitude and longitude are
+// always read, since they are needed for the location.
+const FIELD_COLUMNS: &[(Field, &str)] = &[
+    (Field::Guid, "guid"),
+    (Field::LastEditedDate, "last_edited_date"),
+    (Field::CommonName, "common_name"),
+    (Field::ScientificName, "scientific_name"),
+    (Field::ObservationCount, "observation_count"),
+    (Field::BreedingBirdAtlasCode, "breeding_bird_atlas_code"),
+    (
+        Field::BreedingBirdAtlasCategory,
+        "breeding_bird_atlas_category",
+    ),
+    (Field::ObsDate, "obs_date"),
+    (Field::TimeObsStarted, "time_obs_started"),
+    (Field::ObsId, "obs_id"),
+    (Field::SamplingEventId, "sampling_event_id"),
+    (Field::GroupIdentifier, "group_identifier"),
+    (Field::ProtocolType, "protocol_type"),
+    (Field::DurationMin, "duration_min"),
+    (Field::EffortDistanceKm, "effort_distance_km"),
+    (Field::NumberObservers, "number_observers"),
+    (Field::AllSpeciesReported, "all_species_reported"),
+    (Field::Approved, "approved"),
+    (Field::Reviewed, "reviewed"),
+    (Field::Reason, "reason"),
+    (Field::SpeciesComments, "species_comments"),
+    (Field::TripComments, "trip_comments"),
+    (Field::Locality, "locality"),
+    (Field::LocalityType, "locality_type"),
+    (Field::BcrCode, "bcr_code"),
+    (Field::IbaCode, "iba_code"),
+    (Field::UsfwsCode, "usfws_code"),
+];
+
+// The header of the given database column among headers, if the field is
+// read from the EBD.
+pub fn header_for<'a>(column: &str, headers: &'a [String]) -> Option<&'a str> {
+    let (field, _) = FIELD_COLUMNS.iter().find(|(_, name)| *name == column)?;
+    let (_, names) = FIELDS.iter().find(|(other, _)| other == field)?;
+    headers
+        .iter()
+        .map(String::as_str)
+        .find(|header| names.contains(&header.trim()))
+}
+
+// Fields of each observation, which the sampling event data file with one
+// row per checklist doesn't have.
+const OBSERVATION_FIELDS: &[Field] = &[
+    Field::Guid,
+    Field::CommonName,
+    Field::ScientificName,
+    Field::ObservationCount,
+    Field::BreedingBirdAtlasCode,
+    Field::BreedingBirdAtlasCategory,
+    Field::AgeSex,
+    Field::Approved,
+    Field::Reviewed,
+    Field::Reason,
+    Field::SpeciesComments,
+];
+
+// Columns which appear in some EBD release but which we don't import, so that
+// we only warn about columns we have never seen before.
+const IGNORED_COLUMNS: &[&str] = &[
+    "TAXONOMIC ORDER",
+    "CATEGORY",
+    "TAXON CONCEPT ID",
+    "SUBSPECIES COMMON NAME",
+    "SUBSPECIES SCIENTIFIC NAME",
+    "EXOTIC CODE",
+    "BEHAVIOR CODE",
+    "COUNTRY",
+    "COUNTRY CODE",
+    "STATE",
+    "STATE CODE",
+    "COUNTY",
+    "COUNTY CODE",
+    "ATLAS BLOCK",
+    "LOCALITY ID",
+    "OBSERVER ORCID ID",
+    "OBSERVATION TYPE",
+    "PROTOCOL CODE",
+    "PROJECT CODE",
+    "PROJECT NAMES",
+    "PROJECT IDENTIFIERS",
+    "EFFORT AREA HA",
+    "HAS MEDIA",
+];
+
+// Reads EBirdRecords from the EBD, locating each field by its header rather
+// than by position, so that changes to the column layout between releases
+// don't cause every row to be rejected.
+pub struct EbdReader<R: Read> {
+    reader: csv::Reader<R>,
+    // Column index of each field, indexed by Field.
+    columns: Vec<Option<usize>>,
+    record: csv::ByteRecord,
+    // Whether free text in the current record had to be repaired, and the
+    // total number of records repaired so far.
+    repairing: Cell<bool>,
+    repaired: Rc<Cell<usize>>,
+}
+
+fn invalid_data(field: &str, err: impl std::fmt::Display) -> csv::Error {
+    csv::Error::from(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid {}: {}", field, err),
+    ))
+}
+
+impl<R: Read> EbdReader<R> {
+    pub fn new(reader: csv::Reader<R>) -> csv::Result<EbdReader<R>> {
+        EbdReader::open(reader, true)
+    }
+
+    // Read checklists from the sampling event data file, as records with no
+    // species.
+    pub fn sampling_events(reader: csv::Reader<R>) -> csv::Result<EbdReader<R>> {
+        EbdReader::open(reader, false)
+    }
+
+    fn open(mut reader: csv::Reader<R>, observations: bool) -> csv::Result<EbdReader<R>> {
+        let headers = reader.headers()?.clone();
+
+        let mut columns = vec![None; FIELDS.len()];
+        for (field, names) in FIELDS {
+            columns[*field as usize] = headers
+                .iter()
+                .position(|header| names.contains(&header.trim()));
+            let expected = observations || !OBSERVATION_FIELDS.contains(field);
+            if columns[*field as usize].is_none() && expected {
+                warn!("missing column {}", names[0]);
+            }
+        }
+
+        for header in headers.iter().map(|header| header.trim()) {
+            let known = header.is_empty()
+                || IGNORED_COLUMNS.contains(&header)
+                || FIELDS.iter().any(|(_, names)| names.contains(&header));
+            if !known {
+                warn!("unknown column {}", header);
+            }
+        }
+
+        Ok(EbdReader {
+            reader,
+            columns,
+            record: csv::ByteRecord::new(),
+            repairing: Cell::new(false),
+            repaired: Rc::new(Cell::new(0)),
+        })
+    }
+
+    // Only read the fields stored in the given database columns, treating the
+    // rest as missing. This avoids allocating strings for fields which would
+    // be thrown away.
+    pub fn select(&mut self, columns: &[&str]) {
+        self.columns[Field::AgeSex as usize] = None;
+        for (field, column) in FIELD_COLUMNS {
+            if !columns.contains(column) {
+                self.columns[*field as usize] = None;
+            }
+        }
+    }
+
+    // The number of records in which invalid UTF-8 in free text was replaced,
+    // which is updated as records are read.
+    pub fn repaired(&self) -> Rc<Cell<usize>> {
+        self.repaired.clone()
+    }
+
+    // Missing columns read as empty.
+    fn bytes(&self, field: Field) -> &[u8] {
+        self.columns[field as usize]
+            .and_then(|index| self.record.get(index))
+            .unwrap_or(b"")
+    }
+
+    fn get(&self, field: Field, name: &str) -> csv::Result<&str> {
+        std::str::from_utf8(self.bytes(field)).map_err(|err| invalid_data(name, err))
+    }
+
+    fn string(&self, field: Field, name: &str) -> csv::Result<String> {
+        self.get(field, name).map(|value| value.to_string())
+    }
+
+    // Comments are entered by observers and occasionally contain invalid
+    // UTF-8, which shouldn't cause the whole record to be rejected.
+    fn free_text(&self, field: Field) -> String {
+        let bytes = self.bytes(field);
+        match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => {
+                self.repairing.set(true);
+                String::from_utf8_lossy(bytes).into_owned()
+            }
+        }
+    }
+
+    fn parse<T>(&self, field: Field, name: &str) -> csv::Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.get(field, name)?
+            .parse::<T>()
+            .map_err(|err| invalid_data(name, err))
+    }
+
+    fn parse_optional<T>(&self, field: Field, name: &str) -> csv::Result<Option<T>>
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        if self.bytes(field).is_empty() {
+            Ok(None)
+        } else {
+            self.parse(field, name).map(Some)
+        }
+    }
+
+    // Parse a flag which defaults to the given value if its column is
+    // missing.
+    fn parse_flag(&self, field: Field, name: &str, default: i64) -> csv::Result<i64> {
+        if self.columns[field as usize].is_none() {
+            Ok(default)
+        } else {
+            self.parse(field, name)
+        }
+    }
+
+    fn to_ebird_record(&self) -> csv::Result<EBirdRecord> {
+        Ok(EBirdRecord {
+            guid: self.string(Field::Guid, "guid")?,
+            last_edited_date: self.string(Field::LastEditedDate, "last edited date")?,
+            common_name: self.string(Field::CommonName, "common name")?,
+            scientific_name: self.string(Field::ScientificName, "scientific name")?,
+            observation_count: self.string(Field::ObservationCount, "observation count")?,
+            breeding_bird_atlas_code: self.string(Field::BreedingBirdAtlasCode, "breeding code")?,
+            breeding_bird_atlas_category: self
+                .string(Field::BreedingBirdAtlasCategory, "breeding category")?,
+            age_sex: self.string(Field::AgeSex, "age/sex")?,
+            latitude: self.parse(Field::Latitude, "latitude")?,
+            longitude: self.parse(Field::Longitude, "longitude")?,
+            obs_date: self.string(Field::ObsDate, "observation date")?,
+            time_obs_started: self.string(Field::TimeObsStarted, "time observations started")?,
+            obs_id: self.string(Field::ObsId, "observer id")?,
+            sampling_event_id: self.string(Field::SamplingEventId, "sampling event id")?,
+            group_identifier: self.string(Field::GroupIdentifier, "group identifier")?,
+            protocol_type: self.string(Field::ProtocolType, "protocol type")?,
+            duration_min: self.parse_optional(Field::DurationMin, "duration")?,
+            effort_distance_km: self.parse_optional(Field::EffortDistanceKm, "effort distance")?,
+            number_observers: self.parse_optional(Field::NumberObservers, "number of observers")?,
+            all_species_reported: self.parse_flag(
+                Field::AllSpeciesReported,
+                "all species reported",
+                0,
+            )?,
+            // The EBD only contains approved records unless unvetted data
+            // was requested, in which case the column is present.
+            approved: self.parse_flag(Field::Approved, "approved", 1)?,
+            reviewed: self.parse_flag(Field::Reviewed, "reviewed", 0)?,
+            reason: self.string(Field::Reason, "reason")?,
+            species_comments: self.free_text(Field::SpeciesComments),
+            trip_comments: self.free_text(Field::TripComments),
+            locality: self.free_text(Field::Locality),
+            locality_type: self.string(Field::LocalityType, "locality type")?,
+            bcr_code: self.string(Field::BcrCode, "BCR code")?,
+            iba_code: self.string(Field::IbaCode, "IBA code")?,
+            usfws_code: self.string(Field::UsfwsCode, "USFWS code")?,
+            ..Default::default()
+        })
+    }
+}
+
+impl<R: Read> Iterator for EbdReader<R> {
+    type Item = csv::Result<EBirdRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_byte_record(&mut self.record) {
+            Ok(true) => {
+                self.repairing.set(false);
+                let record = self.to_ebird_record();
+                if self.repairing.get() {
+                    self.repaired.set(self.repaired.get() + 1);
+                }
+                Some(record)
+            }
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
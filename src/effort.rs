@@ -0,0 +1,290 @@
+use crate::grid;
+use crate::EBirdRecord;
+use std::io;
+
+// A named set of effort filters matching the protocol of a common kind of
+// analysis, selected with --preset. Options given explicitly override those
+// of the preset.
+pub struct Preset {
+    pub name: &'static str,
+    protocols: &'static [&'static str],
+    max_duration: Option<i64>,
+    // In metres. Stationary counts have no distance, so always pass.
+    max_distance: Option<f64>,
+    max_observers: Option<i64>,
+    complete_only: bool,
+    min_coord_decimals: Option<usize>,
+}
+
+pub const PRESETS: &[Preset] = &[
+    // Complete stationary counts of up to 20 minutes, which stayed in one
+    // place, located to within about 100m.
+    Preset {
+        name: "point-count",
+        protocols: &["Stationary"],
+        max_duration: Some(20),
+        max_distance: Some(0.0),
+        max_observers: None,
+        complete_only: true,
+        min_coord_decimals: Some(3),
+    },
+    // Complete traveling counts of up to an hour over at most 1km, short
+    // enough to be treated as a single location.
+    Preset {
+        name: "short-traveling",
+        protocols: &["Traveling"],
+        max_duration: Some(60),
+        max_distance: Some(1000.0),
+        max_observers: None,
+        complete_only: true,
+        min_coord_decimals: None,
+    },
+    // The filters recommended for species distribution modelling in the
+    // eBird best practices guide: complete stationary and traveling counts
+    // of up to 5 hours and 5km, by up to 10 observers.
+    Preset {
+        name: "best-practices",
+        protocols: &["Stationary", "Traveling"],
+        max_duration: Some(300),
+        max_distance: Some(5000.0),
+        max_observers: Some(10),
+        complete_only: true,
+        min_coord_decimals: None,
+    },
+];
+
+// Selects records by the effort of their checklist, from --protocols,
+// --max-duration, --max-distance, --max-observers, --complete-only and
+// --min-coord-decimals, or --preset.
+pub struct EffortFilter {
+    protocols: Option<Vec<String>>,
+    max_duration: Option<i64>,
+    max_distance: Option<f64>,
+    max_observers: Option<i64>,
+    complete_only: bool,
+    min_coord_decimals: Option<usize>,
+}
+
+// Metres in a kilometre, and in a mile for --units imperial.
+const METRES_PER_KM: f64 = 1000.0;
+const METRES_PER_MILE: f64 = 1609.344;
+
+// The number of decimal places a coordinate was given to. Trailing zeros
+// can't be told apart from a less precise location, so don't count.
+fn decimal_places(value: f64) -> usize {
+    let text = value.to_string();
+    match text.find('.') {
+        Some(point) => text.len() - point - 1,
+        None => 0,
+    }
+}
+
+fn parse_limit(matches: &clap::ArgMatches, name: &str) -> io::Result<Option<i64>> {
+    match matches.value_of(name) {
+        Some(text) => match text.parse::<i64>() {
+            Ok(limit) if limit > 0 => Ok(Some(limit)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Invalid --{} {}", name, text),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+impl EffortFilter {
+    // The filter given on the command line, if any.
+    pub fn from_matches(matches: &clap::ArgMatches) -> io::Result<Option<EffortFilter>> {
+        let preset = matches
+            .value_of("preset")
+            .and_then(|name| PRESETS.iter().find(|preset| preset.name == name));
+
+        let protocols = match matches.values_of("protocols") {
+            Some(values) => Some(values.map(|value| value.trim().to_string()).collect()),
+            None => preset.map(|preset| {
+                preset
+                    .protocols
+                    .iter()
+                    .map(|protocol| protocol.to_string())
+                    .collect()
+            }),
+        };
+        // Effort distances are in kilometres, or miles with --units
+        // imperial, unless given with a unit.
+        let unit = if matches.value_of("units") == Some("imperial") {
+            METRES_PER_MILE
+        } else {
+            METRES_PER_KM
+        };
+        let max_distance = match matches.value_of("max-distance") {
+            Some(text) => Some(grid::parse_distance_in(text, unit)?),
+            None => preset.and_then(|preset| preset.max_distance),
+        };
+        let filter = EffortFilter {
+            protocols,
+            max_duration: parse_limit(matches, "max-duration")?
+                .or_else(|| preset.and_then(|preset| preset.max_duration)),
+            max_distance,
+            max_observers: parse_limit(matches, "max-observers")?
+                .or_else(|| preset.and_then(|preset| preset.max_observers)),
+            complete_only: matches.is_present("complete-only")
+                || matches!(preset, Some(preset) if preset.complete_only),
+            min_coord_decimals: parse_limit(matches, "min-coord-decimals")?
+                .map(|decimals| decimals as usize)
+                .or_else(|| preset.and_then(|preset| preset.min_coord_decimals)),
+        };
+
+        if filter.protocols.is_none()
+            && filter.max_duration.is_none()
+            && filter.max_distance.is_none()
+            && filter.max_observers.is_none()
+            && !filter.complete_only
+            && filter.min_coord_decimals.is_none()
+        {
+            Ok(None)
+        } else {
+            Ok(Some(filter))
+        }
+    }
+
+    // Records without a duration or number of observers can't be shown to be
+    // within the limit, so are dropped, while those without a distance
+    // didn't travel.
+    pub fn is_match(&self, record: &EBirdRecord) -> bool {
+        if let Some(protocols) = &self.protocols {
+            if !protocols
+                .iter()
+                .any(|protocol| protocol.eq_ignore_ascii_case(&record.protocol_type))
+            {
+                return false;
+            }
+        }
+        if let Some(max_duration) = self.max_duration {
+            match record.duration_min {
+                Some(duration) if duration <= max_duration => (),
+                _ => return false,
+            }
+        }
+        if let Some(max_distance) = self.max_distance {
+            if let Some(distance) = record.effort_distance_km {
+                if distance * 1000.0 > max_distance {
+                    return false;
+                }
+            }
+        }
+        if let Some(max_observers) = self.max_observers {
+            match record.number_observers {
+                Some(observers) if observers <= max_observers => (),
+                _ => return false,
+            }
+        }
+        if let Some(min_decimals) = self.min_coord_decimals {
+            if decimal_places(record.latitude) < min_decimals
+                || decimal_places(record.longitude) < min_decimals
+            {
+                return false;
+            }
+        }
+        !self.complete_only || record.all_species_reported == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    fn filter(args: &[&str]) -> Option<EffortFilter> {
+        let app = App::new("test").args(&[
+            Arg::with_name("protocols")
+                .long("protocols")
+                .takes_value(true)
+                .use_delimiter(true),
+            Arg::with_name("max-duration")
+                .long("max-duration")
+                .takes_value(true),
+            Arg::with_name("max-distance")
+                .long("max-distance")
+                .takes_value(true),
+            Arg::with_name("max-observers")
+                .long("max-observers")
+                .takes_value(true),
+            Arg::with_name("min-coord-decimals")
+                .long("min-coord-decimals")
+                .takes_value(true),
+            Arg::with_name("complete-only").long("complete-only"),
+            Arg::with_name("preset").long("preset").takes_value(true),
+            Arg::with_name("units").long("units").takes_value(true),
+        ]);
+        let matches = app.get_matches_from(std::iter::once("test").chain(args.iter().copied()));
+        EffortFilter::from_matches(&matches).unwrap()
+    }
+
+    fn checklist(protocol: &str, duration: i64, distance: Option<f64>) -> EBirdRecord {
+        EBirdRecord {
+            protocol_type: protocol.to_string(),
+            duration_min: Some(duration),
+            effort_distance_km: distance,
+            all_species_reported: 1,
+            latitude: 45.424_806,
+            longitude: -75.699_661,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_decimal_places() {
+        assert_eq!(decimal_places(45.0), 0);
+        assert_eq!(decimal_places(45.4), 1);
+        assert_eq!(decimal_places(-75.699_661), 6);
+        assert_eq!(decimal_places(45.4200), 2);
+    }
+
+    #[test]
+    fn no_filter_without_options() {
+        assert!(filter(&[]).is_none());
+    }
+
+    #[test]
+    fn point_count() {
+        let filter = filter(&["--preset", "point-count"]).unwrap();
+        assert!(filter.is_match(&checklist("Stationary", 15, None)));
+        assert!(!filter.is_match(&checklist("Stationary", 30, None)));
+        assert!(!filter.is_match(&checklist("Traveling", 15, Some(0.5))));
+
+        let mut incomplete = checklist("Stationary", 15, None);
+        incomplete.all_species_reported = 0;
+        assert!(!filter.is_match(&incomplete));
+
+        let mut imprecise = checklist("Stationary", 15, None);
+        imprecise.latitude = 45.42;
+        assert!(!filter.is_match(&imprecise));
+    }
+
+    #[test]
+    fn options_override_the_preset() {
+        let filter = filter(&["--preset", "point-count", "--max-duration", "10"]).unwrap();
+        assert!(!filter.is_match(&checklist("Stationary", 15, None)));
+    }
+
+    #[test]
+    fn max_distance_units() {
+        let traveled = checklist("Traveling", 60, Some(3.0));
+        assert!(filter(&["--max-distance", "5"])
+            .unwrap()
+            .is_match(&traveled));
+        assert!(!filter(&["--max-distance", "2"])
+            .unwrap()
+            .is_match(&traveled));
+        // 3km is under 2 miles, but over 1.
+        assert!(filter(&["--max-distance", "2", "--units", "imperial"])
+            .unwrap()
+            .is_match(&traveled));
+        assert!(!filter(&["--max-distance", "1", "--units", "imperial"])
+            .unwrap()
+            .is_match(&traveled));
+        assert!(!filter(&["--max-distance", "2000m", "--units", "imperial"])
+            .unwrap()
+            .is_match(&traveled));
+    }
+}
@@ -0,0 +1,68 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Exit codes, so that scripts and orchestrators running an import can tell
+// why it failed.
+pub const FAILURE: i32 = 1;
+pub const BAD_ARGUMENTS: i32 = 2;
+pub const INPUT_UNREADABLE: i32 = 3;
+pub const EXTENSION_FAILED: i32 = 4;
+pub const PARTIAL_IMPORT: i32 = 5;
+
+pub const HELP: &str = "\
+0  Success
+1  Failure, e.g. an import aborted after too many errors (--max-errors) or a
+   database which could not be written
+2  Bad arguments, such as an unknown option or an invalid date or number
+3  The input file could not be opened or downloaded
+4  The SpatiaLite extension (mod_spatialite) could not be loaded, by a
+   command which needs it or an import with --require-spatialite, or the
+   geometry of an import could not be built
+5  Partial import: the import finished, but records could not be parsed or
+   inserted, were dropped for invalid coordinates, or it was interrupted";
+
+// An error along with the code to exit with for it.
+#[derive(Debug)]
+struct CodedError {
+    code: i32,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl Error for CodedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+pub fn with_code<E>(code: i32, err: E) -> io::Error
+where
+    E: Into<Box<dyn Error + Send + Sync>>,
+{
+    io::Error::new(
+        io::ErrorKind::Other,
+        CodedError {
+            code,
+            source: err.into(),
+        },
+    )
+}
+
+// The code to exit with for an error. Errors in arguments are InvalidInput,
+// and anything without a code of its own is a failure.
+pub fn code(err: &io::Error) -> i32 {
+    match err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<CodedError>())
+    {
+        Some(err) => err.code,
+        None if err.kind() == io::ErrorKind::InvalidInput => BAD_ARGUMENTS,
+        None => FAILURE,
+    }
+}
@@ -0,0 +1,308 @@
+use crate::datasette;
+use crate::db;
+#[cfg(feature = "export-tiff")]
+use crate::geotiff;
+use crate::grid;
+use crate::qgis;
+use crate::to_io_error;
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use serde_json::json;
+use std::fs;
+use std::io;
+
+// The records embedded in a web map are all loaded by the browser at once.
+const MAX_MAP_RECORDS: usize = 50_000;
+
+// A page showing records as clustered points on an OpenStreetMap base map,
+// loading Leaflet from a CDN, with RECORDS replaced by the records as JSON.
+const HTML_MAP: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>TITLE</title>
+<link rel="stylesheet" href="https://unpkg.com/leaflet@1.9.4/dist/leaflet.css">
+<link rel="stylesheet" href="https://unpkg.com/leaflet.markercluster@1.5.3/dist/MarkerCluster.css">
+<link rel="stylesheet" href="https://unpkg.com/leaflet.markercluster@1.5.3/dist/MarkerCluster.Default.css">
+<script src="https://unpkg.com/leaflet@1.9.4/dist/leaflet.js"></script>
+<script src="https://unpkg.com/leaflet.markercluster@1.5.3/dist/leaflet.markercluster.js"></script>
+<style>html, body, #map { height: 100%; margin: 0; }</style>
+</head>
+<body>
+<div id="map"></div>
+<script>
+var records = RECORDS;
+var map = L.map("map");
+L.tileLayer("https://tile.openstreetmap.org/{z}/{x}/{y}.png", {
+  maxZoom: 19,
+  attribution: '&copy; <a href="https://www.openstreetmap.org/copyright">OpenStreetMap</a> contributors | eBird'
+}).addTo(map);
+var clusters = L.markerClusterGroup();
+records.forEach(function (record) {
+  var popup = document.createElement("div");
+  [["b", record[2]], ["div", record[3]], ["div", record[4]], ["div", record[5]]].forEach(function (line) {
+    if (line[1]) {
+      var element = document.createElement(line[0]);
+      element.textContent = line[1];
+      popup.appendChild(element);
+    }
+  });
+  clusters.addLayer(L.marker([record[0], record[1]]).bindPopup(popup));
+});
+map.addLayer(clusters);
+map.fitBounds(clusters.getBounds(), { maxZoom: 14 });
+</script>
+</body>
+</html>
+"#;
+
+// Write the records as a GeoTIFF raster, for --format geotiff.
+#[cfg(feature = "export-tiff")]
+fn export_geotiff(
+    conn: &Connection,
+    source: &str,
+    output: &str,
+    cell_size: f64,
+    bandwidth: Option<f64>,
+) -> io::Result<()> {
+    geotiff::export(conn, source, output, cell_size, bandwidth)
+}
+
+#[cfg(not(feature = "export-tiff"))]
+fn export_geotiff(
+    _conn: &Connection,
+    _source: &str,
+    _output: &str,
+    _cell_size: f64,
+    _bandwidth: Option<f64>,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "--format geotiff needs ebird2spatialite built with --features export-tiff",
+    ))
+}
+
+// Write a zero-filled detection table for occupancy and encounter rate
+// models, with a row for each species on each complete checklist in the
+// checklist table, saying whether it was detected and its count, which is 0
+// when it wasn't. Records of subspecies are combined with their species.
+fn export_zerofill(
+    conn: &Connection,
+    source: &str,
+    checklists: &str,
+    species: &[&str],
+    output: &str,
+) -> io::Result<()> {
+    let mut writer = csv::Writer::from_path(output)?;
+    writer.write_record([
+        "sampling_event_id",
+        "latitude",
+        "longitude",
+        "obs_date",
+        "time_obs_started",
+        "protocol_type",
+        "duration_min",
+        "effort_distance_km",
+        "number_observers",
+        "species",
+        "detected",
+        "observation_count",
+    ])?;
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT c.sampling_event_id, c.latitude, c.longitude, c.obs_date,
+                    c.time_obs_started, c.protocol_type, c.duration_min,
+                    c.effort_distance_km, c.number_observers,
+                    o.sampling_event_id IS NOT NULL, COALESCE(o.observation_count, '0')
+                FROM {checklists} AS c
+                LEFT JOIN (SELECT sampling_event_id,
+                                CASE WHEN SUM(observation_count = 'X') > 0 THEN 'X'
+                                    ELSE CAST(SUM(CAST(observation_count AS INTEGER)) AS TEXT)
+                                END AS observation_count
+                            FROM {source}
+                            WHERE common_name = ?1 OR scientific_name = ?1
+                            GROUP BY sampling_event_id) AS o
+                    ON o.sampling_event_id = c.sampling_event_id
+                WHERE c.all_species_reported = 1
+                ORDER BY c.sampling_event_id",
+            checklists = checklists,
+            source = source
+        ))
+        .map_err(to_io_error)?;
+    let mut rows_written = 0;
+    for name in species {
+        let mut detections = 0;
+        let mut rows = stmt.query(&[name]).map_err(to_io_error)?;
+        while let Some(row) = rows.next().map_err(to_io_error)? {
+            let detected: bool = row.get(9).map_err(to_io_error)?;
+            if detected {
+                detections += 1;
+            }
+            writer.write_record([
+                row.get::<_, String>(0).map_err(to_io_error)?,
+                row.get::<_, f64>(1).map_err(to_io_error)?.to_string(),
+                row.get::<_, f64>(2).map_err(to_io_error)?.to_string(),
+                row.get::<_, String>(3).map_err(to_io_error)?,
+                row.get::<_, String>(4).map_err(to_io_error)?,
+                row.get::<_, String>(5).map_err(to_io_error)?,
+                optional(row.get::<_, Option<i64>>(6).map_err(to_io_error)?),
+                optional(row.get::<_, Option<f64>>(7).map_err(to_io_error)?),
+                optional(row.get::<_, Option<i64>>(8).map_err(to_io_error)?),
+                name.to_string(),
+                (detected as i64).to_string(),
+                row.get::<_, String>(10).map_err(to_io_error)?,
+            ])?;
+            rows_written += 1;
+        }
+        if detections == 0 {
+            println!(
+                "warning: {} wasn't detected on any complete checklist",
+                name
+            );
+        }
+    }
+    writer.flush()?;
+
+    println!("wrote {} rows to {}", rows_written, output);
+    Ok(())
+}
+
+// Write a web map of the records as a single HTML file, which can be opened
+// in a browser without any GIS software, with popups showing the species,
+// date and count of each record, or the number of species on a checklist.
+fn export_html_map(conn: &Connection, table: &str, source: &str, output: &str) -> io::Result<()> {
+    let checklists = db::is_checklist_table(conn, table).map_err(to_io_error)?;
+    let label = if checklists {
+        "species_count || ' species'"
+    } else {
+        "common_name"
+    };
+    let count = if checklists {
+        "individual_count || ' individuals'"
+    } else {
+        "'Count: ' || observation_count"
+    };
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT latitude, longitude, {}, obs_date, {}, locality FROM {}",
+            label, count, source
+        ))
+        .map_err(to_io_error)?;
+    let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+    let mut records = Vec::new();
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        if records.len() == MAX_MAP_RECORDS {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "More than {} records are too many for a web map, export a smaller table",
+                    MAX_MAP_RECORDS
+                ),
+            ));
+        }
+        records.push(json!([
+            row.get::<_, f64>(0).map_err(to_io_error)?,
+            row.get::<_, f64>(1).map_err(to_io_error)?,
+            row.get::<_, Option<String>>(2).map_err(to_io_error)?,
+            row.get::<_, Option<String>>(3).map_err(to_io_error)?,
+            row.get::<_, Option<String>>(4).map_err(to_io_error)?,
+            row.get::<_, Option<String>>(5).map_err(to_io_error)?,
+        ]));
+    }
+    if records.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, "No records to export"));
+    }
+
+    // A name containing </script> would end the script early.
+    let json = serde_json::to_string(&records)
+        .map_err(to_io_error)?
+        .replace("</", "<\\/");
+    let title = format!(
+        "{} eBird {}",
+        records.len(),
+        if checklists { "checklists" } else { "records" }
+    );
+    fs::write(
+        output,
+        HTML_MAP.replace("TITLE", &title).replace("RECORDS", &json),
+    )?;
+    println!("wrote map of {} records to {}", records.len(), output);
+    Ok(())
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+// Export the records in a database to other formats.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = matches.value_of("DATABASE").unwrap();
+    let table = crate::table_name(matches)?;
+    let output = matches.value_of("output").unwrap_or_default();
+    let cell_size = grid::parse_distance(matches.value_of("cell-size").unwrap())?;
+    let bandwidth = if matches.is_present("kernel-density") {
+        match matches.value_of("bandwidth") {
+            Some(text) => Some(grid::parse_distance(text)?),
+            None => Some(2.0 * cell_size),
+        }
+    } else {
+        None
+    };
+
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io_error)?;
+    if !db::table_exists(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No table {} in {}", table, path),
+        ));
+    }
+    let source = db::source_table(&conn, table).map_err(to_io_error)?;
+
+    if matches.is_present("datasette") {
+        datasette::write_metadata(&conn, path, table, &source)?;
+    }
+    if let Some(project) = matches.value_of("qgis") {
+        qgis::write_project(&conn, path, table, &source, project)?;
+    }
+
+    match matches.value_of("format") {
+        Some("geotiff") => export_geotiff(&conn, &source, output, cell_size, bandwidth),
+        Some("html-map") => export_html_map(&conn, table, &source, output),
+        Some("zerofill") => {
+            let species = match matches.values_of("species") {
+                Some(species) => species.collect::<Vec<_>>(),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "--format zerofill needs --species",
+                    ));
+                }
+            };
+            // The records come from an observation table, the checklists
+            // from one imported with --granularity checklist, usually from
+            // the sampling event data file so that checklists without any
+            // of the species are included.
+            let checklists = matches.value_of("checklist-table").unwrap();
+            if !db::valid_table_name(checklists)
+                || !db::is_checklist_table(&conn, checklists).map_err(to_io_error)?
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("No checklist table {} in {}", checklists, path),
+                ));
+            }
+            if db::is_checklist_table(&conn, table).map_err(to_io_error)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} is a checklist table, not an observation table", table),
+                ));
+            }
+            export_zerofill(&conn, &source, checklists, &species, output)
+        }
+        // Only --datasette or --qgis was given.
+        None => Ok(()),
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,175 @@
+use crate::db;
+use crate::to_io_error;
+use crate::EBirdRecord;
+use chrono::{Datelike, Duration, Utc};
+use serde::Deserialize;
+use std::io;
+use tracing::{info, warn};
+
+const API_URL: &str = "https://api.ebird.org/v2/data/obs";
+
+// An observation as returned by the eBird API 2.0 "historic observations on a
+// date" endpoint with detail=full.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiObservation {
+    com_name: String,
+    sci_name: String,
+    obs_dt: String,
+    how_many: Option<i64>,
+    lat: f64,
+    lng: f64,
+    obs_valid: bool,
+    sub_id: String,
+    obs_id: Option<String>,
+    #[serde(default)]
+    user_display_name: String,
+    #[serde(default)]
+    loc_name: String,
+    #[serde(default)]
+    location_private: bool,
+}
+
+impl From<ApiObservation> for EBirdRecord {
+    fn from(obs: ApiObservation) -> Self {
+        // obsDt is either "YYYY-MM-DD" or "YYYY-MM-DD HH:MM".
+        let mut date_time = obs.obs_dt.splitn(2, ' ');
+        let obs_date = date_time.next().unwrap_or("").to_string();
+        let time_obs_started = match date_time.next() {
+            Some(time) => format!("{}:00", time),
+            None => String::new(),
+        };
+
+        EBirdRecord {
+            // Use the same form of GUID as the EBD so that fetched records
+            // are replaced when a later EBD release is imported.
+            guid: match obs.obs_id {
+                Some(obs_id) => format!("URN:CornellLabOfOrnithology:EBIRD:{}", obs_id),
+                None => format!("{}:{}", obs.sub_id, obs.sci_name),
+            },
+            common_name: obs.com_name,
+            scientific_name: obs.sci_name,
+            observation_count: match obs.how_many {
+                Some(count) => count.to_string(),
+                None => "X".to_string(),
+            },
+            breeding_bird_atlas_code: String::new(),
+            breeding_bird_atlas_category: String::new(),
+            age_sex: String::new(),
+            latitude: obs.lat,
+            longitude: obs.lng,
+            obs_date,
+            time_obs_started,
+            obs_id: obs.user_display_name,
+            sampling_event_id: obs.sub_id,
+            protocol_type: String::new(),
+            duration_min: None,
+            effort_distance_km: None,
+            number_observers: None,
+            all_species_reported: 0,
+            approved: obs.obs_valid as i64,
+            species_comments: String::new(),
+            locality: obs.loc_name,
+            // Private locations are personal locations, the rest hotspots.
+            locality_type: if obs.location_private { "P" } else { "H" }.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let region = matches.value_of("region").unwrap();
+    let table = crate::table_name(matches)?;
+    let api_key = matches.value_of("api-key").unwrap();
+    let back = match matches.value_of("back").unwrap().parse::<u32>() {
+        Ok(back) if (1..=30).contains(&back) => back,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Back must be a number of days between 1 and 30",
+            ));
+        }
+    };
+
+    // The recent observations endpoint has only the latest sighting of each
+    // species over the whole period, so fetch each day separately, which
+    // gives the latest sighting of each species on every day.
+    let url = format!("{}/{}/historic", API_URL, region);
+    let started_at = Utc::now();
+    let today = started_at.naive_utc().date();
+    let client = reqwest::blocking::Client::new();
+    let mut observations: Vec<ApiObservation> = Vec::new();
+    for days in 0..back {
+        let date = today - Duration::days(days.into());
+        let day: Vec<ApiObservation> = client
+            .get(format!(
+                "{}/{}/{}/{}",
+                url,
+                date.year(),
+                date.month(),
+                date.day()
+            ))
+            .header("X-eBirdApiToken", api_key)
+            .query(&[("detail", "full")])
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(to_io_error)?;
+        observations.extend(day);
+    }
+
+    let mut conn = db::open_for_update("ebird.sqlite", table).map_err(to_io_error)?;
+    if db::is_dictionary_encoded(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can't add records to a dictionary encoded database",
+        ));
+    }
+    if db::is_checklist_table(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can't add records to a checklist table",
+        ));
+    }
+
+    let columns = db::table_columns(&conn, table).map_err(to_io_error)?;
+    let columns = columns.iter().map(String::as_str).collect::<Vec<_>>();
+    let tx = conn.transaction().map_err(to_io_error)?;
+    let read = observations.len();
+    let mut count = 0;
+    let mut first_id: Option<i64> = None;
+    for observation in observations {
+        match db::upsert_record(&tx, table, &columns, &EBirdRecord::from(observation)) {
+            Ok(_) => {
+                count += 1;
+                // New ids are above those of the records already in the
+                // table, so the records added are those from the lowest id
+                // added on.
+                let id = tx.last_insert_rowid();
+                first_id = Some(first_id.map_or(id, |first_id| first_id.min(id)));
+            }
+            Err(err) => warn!("could not insert record: {}", err),
+        }
+    }
+    let run = db::ImportRun {
+        table,
+        input: &url,
+        input_format: "api",
+        input_sha256: None,
+        parameters: serde_json::json!({ "region": region, "back": back }).to_string(),
+        started_at,
+        records_read: read,
+        records_inserted: count,
+        errors: read - count,
+        status: "completed",
+    };
+    db::insert_import_run(&tx, &run).map_err(to_io_error)?;
+    tx.commit().map_err(to_io_error)?;
+    if let Some(first_id) = first_id {
+        db::build_geometry_from(&conn, table, first_id).map_err(to_io_error)?;
+    }
+    db::refresh_fts(&conn, table).map_err(to_io_error)?;
+
+    info!("fetched {} observations for {}", count, region);
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use crate::EBirdRecord;
+use std::io;
+use std::rc::Rc;
+
+// A test of whether to import a record. The filter options each make one,
+// and by default a record has to pass all of them; --filter-logic combines
+// them with And, Or and Not instead.
+pub trait Filter {
+    fn matches(&self, record: &EBirdRecord) -> bool;
+}
+
+impl<T: Filter + ?Sized> Filter for Rc<T> {
+    fn matches(&self, record: &EBirdRecord) -> bool {
+        (**self).matches(record)
+    }
+}
+
+struct Predicate<F>(F);
+
+impl<F: Fn(&EBirdRecord) -> bool> Filter for Predicate<F> {
+    fn matches(&self, record: &EBirdRecord) -> bool {
+        (self.0)(record)
+    }
+}
+
+// A filter testing records with a closure, shared so that it can be named
+// more than once in --filter-logic.
+pub fn predicate<'a>(f: impl Fn(&EBirdRecord) -> bool + 'a) -> Rc<dyn Filter + 'a> {
+    Rc::new(Predicate(f))
+}
+
+pub struct And<'a>(pub Vec<Box<dyn Filter + 'a>>);
+
+impl Filter for And<'_> {
+    fn matches(&self, record: &EBirdRecord) -> bool {
+        self.0.iter().all(|filter| filter.matches(record))
+    }
+}
+
+pub struct Or<'a>(pub Vec<Box<dyn Filter + 'a>>);
+
+impl Filter for Or<'_> {
+    fn matches(&self, record: &EBirdRecord) -> bool {
+        self.0.iter().any(|filter| filter.matches(record))
+    }
+}
+
+pub struct Not<'a>(pub Box<dyn Filter + 'a>);
+
+impl Filter for Not<'_> {
+    fn matches(&self, record: &EBirdRecord) -> bool {
+        !self.0.matches(record)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid --filter-logic: {}", message),
+    )
+}
+
+fn tokenize(expression: &str) -> Vec<String> {
+    expression
+        .replace('(', " ( ")
+        .replace(')', " ) ")
+        .split_whitespace()
+        .map(|token| token.trim_start_matches("--").to_lowercase())
+        .collect()
+}
+
+// A recursive descent parser for expressions such as
+// "(common-name-regex or alpha-codes) and region and not checklist-ids",
+// where not binds tightest, then and, then or.
+struct Parser<'a, 'f> {
+    tokens: Vec<String>,
+    position: usize,
+    filters: &'f [(&'static str, Rc<dyn Filter + 'a>)],
+    used: Vec<&'static str>,
+}
+
+impl<'a> Parser<'a, '_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> io::Result<Box<dyn Filter + 'a>> {
+        let mut terms = vec![self.and()?];
+        while self.peek() == Some("or") {
+            self.position += 1;
+            terms.push(self.and()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Box::new(Or(terms))
+        })
+    }
+
+    fn and(&mut self) -> io::Result<Box<dyn Filter + 'a>> {
+        let mut terms = vec![self.not()?];
+        while self.peek() == Some("and") {
+            self.position += 1;
+            terms.push(self.not()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Box::new(And(terms))
+        })
+    }
+
+    fn not(&mut self) -> io::Result<Box<dyn Filter + 'a>> {
+        match self.next().as_deref() {
+            Some("not") => Ok(Box::new(Not(self.not()?))),
+            Some("(") => {
+                let filter = self.or()?;
+                match self.next().as_deref() {
+                    Some(")") => Ok(filter),
+                    _ => Err(invalid("missing closing parenthesis".to_string())),
+                }
+            }
+            Some(name) => match self.filters.iter().find(|(option, _)| *option == name) {
+                Some((option, filter)) => {
+                    self.used.push(option);
+                    Ok(Box::new(filter.clone()))
+                }
+                None => Err(invalid(format!(
+                    "{} is not one of the filters given, which are: {}",
+                    name,
+                    self.filters
+                        .iter()
+                        .map(|(option, _)| *option)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))),
+            },
+            None => Err(invalid("expected a filter name".to_string())),
+        }
+    }
+}
+
+// Parse --filter-logic, combining the named filters. Returns the combined
+// filter along with the names of those used in it, the rest of which still
+// have to be passed as well.
+pub fn parse<'a>(
+    expression: &str,
+    filters: &[(&'static str, Rc<dyn Filter + 'a>)],
+) -> io::Result<(Box<dyn Filter + 'a>, Vec<&'static str>)> {
+    let mut parser = Parser {
+        tokens: tokenize(expression),
+        position: 0,
+        filters,
+        used: Vec::new(),
+    };
+    let filter = parser.or()?;
+    if let Some(token) = parser.peek() {
+        return Err(invalid(format!("unexpected {}", token)));
+    }
+    Ok((filter, parser.used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(common_name: &str, obs_id: &str) -> EBirdRecord {
+        EBirdRecord {
+            common_name: common_name.to_string(),
+            obs_id: obs_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn filters() -> Vec<(&'static str, Rc<dyn Filter>)> {
+        vec![
+            (
+                "common-name-regex",
+                predicate(|record| record.common_name == "Brown Thrasher"),
+            ),
+            (
+                "alpha-codes",
+                predicate(|record| record.common_name == "Hermit Thrush"),
+            ),
+            (
+                "exclude-observer-ids",
+                predicate(|record| record.obs_id != "obsr1"),
+            ),
+        ]
+    }
+
+    #[test]
+    fn combines_species_and_observers() {
+        let filters = filters();
+        let (filter, used) = parse(
+            "(common-name-regex or alpha-codes) and exclude-observer-ids",
+            &filters,
+        )
+        .unwrap();
+        assert_eq!(
+            used,
+            vec!["common-name-regex", "alpha-codes", "exclude-observer-ids"]
+        );
+        assert!(filter.matches(&record("Brown Thrasher", "obsr2")));
+        assert!(filter.matches(&record("Hermit Thrush", "obsr2")));
+        assert!(!filter.matches(&record("Hermit Thrush", "obsr1")));
+        assert!(!filter.matches(&record("American Robin", "obsr2")));
+    }
+
+    #[test]
+    fn binds_not_then_and_then_or() {
+        let filters = filters();
+        let (filter, _) = parse(
+            "--common-name-regex OR alpha-codes and not exclude-observer-ids",
+            &filters,
+        )
+        .unwrap();
+        assert!(filter.matches(&record("Brown Thrasher", "obsr2")));
+        assert!(filter.matches(&record("Hermit Thrush", "obsr1")));
+        assert!(!filter.matches(&record("Hermit Thrush", "obsr2")));
+    }
+
+    #[test]
+    fn leaves_unnamed_filters_unused() {
+        let filters = filters();
+        let (_, used) = parse("alpha-codes", &filters).unwrap();
+        assert_eq!(used, vec!["alpha-codes"]);
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        let filters = filters();
+        for expression in &[
+            "region",
+            "(alpha-codes or common-name-regex",
+            "alpha-codes common-name-regex",
+            "not",
+            "",
+        ] {
+            let err = parse(expression, &filters).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", expression);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::db;
+use crate::grid::Grid;
+use crate::to_io_error;
+use crate::EBirdRecord;
+use rusqlite::{params, NO_PARAMS};
+use std::collections::HashMap;
+use std::io;
+use tracing::warn;
+
+// A month, and the grid cell if there is a grid.
+type Key = (u32, Option<(usize, usize)>);
+
+fn key(grid: &Option<Grid>, obs_date: &str, x: f64, y: f64) -> Option<Key> {
+    let month = obs_date.get(5..7)?.parse::<u32>().ok()?;
+    match grid {
+        Some(grid) => grid.cell(x, y).map(|cell| (month, Some(cell))),
+        None => Some((month, None)),
+    }
+}
+
+// Build the {table}_frequency layer, with the detection frequency of each
+// species in each month (and grid cell, given a cell size): the fraction of
+// complete checklists which reported it. Complete checklists are read from
+// the sampling event data file, keeping those the import's date and location
+// filters would keep. The grid covers the extent of the imported records.
+pub fn build_frequency(
+    conn: &rusqlite::Connection,
+    table: &str,
+    sampling_events: impl Iterator<Item = csv::Result<EBirdRecord>>,
+    keep: impl Fn(&EBirdRecord) -> bool,
+    grid_size: Option<f64>,
+) -> io::Result<()> {
+    let source = db::source_table(conn, table).map_err(to_io_error)?;
+
+    let grid = match grid_size {
+        Some(size) => {
+            let (min_x, min_y, max_x, max_y): (Option<f64>, Option<f64>, Option<f64>, Option<f64>) =
+                conn.query_row(
+                    &format!(
+                        "SELECT MIN(longitude), MIN(latitude), MAX(longitude), MAX(latitude)
+                            FROM {}",
+                        source
+                    ),
+                    NO_PARAMS,
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .map_err(to_io_error)?;
+            match (min_x, min_y, max_x, max_y) {
+                (Some(min_x), Some(min_y), Some(max_x), Some(max_y)) => {
+                    Some(Grid::new(min_x, min_y, max_x, max_y, size))
+                }
+                _ => return Ok(()),
+            }
+        }
+        None => None,
+    };
+
+    let mut complete: HashMap<Key, i64> = HashMap::new();
+    let mut errors = 0;
+    for sampling_event in sampling_events {
+        let checklist = match sampling_event {
+            Ok(checklist) => checklist,
+            Err(_) => {
+                errors += 1;
+                continue;
+            }
+        };
+        if checklist.all_species_reported != 1 || !keep(&checklist) {
+            continue;
+        }
+        if let Some(key) = key(
+            &grid,
+            &checklist.obs_date,
+            checklist.longitude,
+            checklist.latitude,
+        ) {
+            *complete.entry(key).or_insert(0) += 1;
+        }
+    }
+    if errors > 0 {
+        warn!("could not parse {} sampling events", errors);
+    }
+
+    // Each species is counted once per checklist, even if it was reported
+    // more than once, e.g. by subspecies.
+    let mut detections: HashMap<(String, Key), (String, i64)> = HashMap::new();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT scientific_name, MIN(common_name), MIN(obs_date), MIN(longitude),
+                    MIN(latitude)
+                FROM {} WHERE all_species_reported = 1
+                GROUP BY scientific_name, sampling_event_id",
+            source
+        ))
+        .map_err(to_io_error)?;
+    let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        let obs_date: String = row.get(2).map_err(to_io_error)?;
+        let x: f64 = row.get(3).map_err(to_io_error)?;
+        let y: f64 = row.get(4).map_err(to_io_error)?;
+        if let Some(key) = key(&grid, &obs_date, x, y) {
+            let common_name: String = row.get(1).map_err(to_io_error)?;
+            detections
+                .entry((row.get(0).map_err(to_io_error)?, key))
+                .or_insert((common_name, 0))
+                .1 += 1;
+        }
+    }
+
+    let layer = format!("{}_frequency", table);
+    conn.execute_batch("BEGIN").map_err(to_io_error)?;
+    db::create_layer(
+        conn,
+        &layer,
+        "scientific_name TEXT, common_name TEXT, month INTEGER, grid_column INTEGER,
+            grid_row INTEGER, checklists INTEGER, complete_checklists INTEGER, frequency REAL",
+        "POLYGON",
+    )
+    .map_err(to_io_error)?;
+    let mut stmt = conn
+        .prepare(&format!(
+            "INSERT INTO {} (scientific_name, common_name, month, grid_column, grid_row,
+                                checklists, complete_checklists, frequency, geometry)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, BuildMbr(?9, ?10, ?11, ?12, 4326))",
+            layer
+        ))
+        .map_err(to_io_error)?;
+    for ((scientific_name, key), (common_name, checklists)) in &detections {
+        // Checklists missing from the sampling event data, e.g. from a
+        // different release, can't be counted.
+        let complete_checklists = match complete.get(key) {
+            Some(complete_checklists) => *complete_checklists,
+            None => continue,
+        };
+        let (month, cell) = key;
+        let bounds = match (&grid, cell) {
+            (Some(grid), Some((column, row))) => Some(grid.bounds(*column, *row)),
+            _ => None,
+        };
+        stmt.execute(params![
+            scientific_name,
+            common_name,
+            month,
+            cell.map(|(column, _)| column as i64),
+            cell.map(|(_, row)| row as i64),
+            checklists,
+            complete_checklists,
+            *checklists as f64 / complete_checklists as f64,
+            bounds.map(|bounds| bounds.0),
+            bounds.map(|bounds| bounds.1),
+            bounds.map(|bounds| bounds.2),
+            bounds.map(|bounds| bounds.3)
+        ])
+        .map_err(to_io_error)?;
+    }
+    conn.execute_batch("COMMIT").map_err(to_io_error)
+}
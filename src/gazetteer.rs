@@ -0,0 +1,94 @@
+use crate::EBirdRecord;
+use rstar::{PointDistance, RTree, RTreeObject, AABB};
+use std::io;
+use tracing::warn;
+
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+// A position on the unit sphere, so that the nearest place by straight line
+// distance is also the nearest along the surface.
+fn unit_vector(latitude: f64, longitude: f64) -> [f64; 3] {
+    let (latitude, longitude) = (latitude.to_radians(), longitude.to_radians());
+    [
+        latitude.cos() * longitude.cos(),
+        latitude.cos() * longitude.sin(),
+        latitude.sin(),
+    ]
+}
+
+struct Place {
+    name: String,
+    position: [f64; 3],
+}
+
+impl RTreeObject for Place {
+    type Envelope = AABB<[f64; 3]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point(self.position)
+    }
+}
+
+impl PointDistance for Place {
+    fn distance_2(&self, point: &[f64; 3]) -> f64 {
+        self.position
+            .iter()
+            .zip(point)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum()
+    }
+}
+
+// Named places from a GeoNames dump, such as cities1000.txt or a country's
+// file, used to tag each record with the nearest place and its distance.
+pub struct Gazetteer {
+    places: RTree<Place>,
+}
+
+impl Gazetteer {
+    // GeoNames files are tab separated without a header, with the name in
+    // the second column and the latitude and longitude in the fifth and
+    // sixth.
+    pub fn load(path: &str) -> io::Result<Gazetteer> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .quoting(false)
+            .flexible(true)
+            .from_path(path)?;
+        let mut places = Vec::new();
+        for row in reader.records() {
+            let row = row?;
+            let coordinates = (
+                row.get(4).and_then(|text| text.parse::<f64>().ok()),
+                row.get(5).and_then(|text| text.parse::<f64>().ok()),
+            );
+            match (row.get(1), coordinates) {
+                (Some(name), (Some(latitude), Some(longitude))) => places.push(Place {
+                    name: name.to_string(),
+                    position: unit_vector(latitude, longitude),
+                }),
+                _ => warn!("skipping invalid gazetteer row: {:?}", row),
+            }
+        }
+        if places.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No places in {}", path),
+            ));
+        }
+        Ok(Gazetteer {
+            places: RTree::bulk_load(places),
+        })
+    }
+
+    pub fn apply(&self, record: &mut EBirdRecord) {
+        let position = unit_vector(record.latitude, record.longitude);
+        if let Some(place) = self.places.nearest_neighbor(&position) {
+            let chord = place.distance_2(&position).sqrt();
+            record.nearest_place = Some(place.name.clone());
+            record.nearest_place_distance =
+                Some(2.0 * EARTH_RADIUS * (chord / 2.0).min(1.0).asin());
+        }
+    }
+}
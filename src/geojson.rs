@@ -0,0 +1,107 @@
+use geo::{LineString, MultiPolygon, Polygon};
+use serde_json::{Map, Value};
+use std::fs;
+use std::io;
+
+// A polygon or multipolygon feature read from a GeoJSON file, along with its
+// properties.
+pub struct Feature {
+    pub properties: Map<String, Value>,
+    pub geometry: MultiPolygon<f64>,
+}
+
+impl Feature {
+    pub fn property(&self, name: &str) -> Option<&str> {
+        self.properties.get(name).and_then(|value| value.as_str())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn ring(value: &Value) -> io::Result<LineString<f64>> {
+    let positions = value
+        .as_array()
+        .ok_or_else(|| invalid_data("invalid GeoJSON linear ring"))?;
+    let mut coords = Vec::with_capacity(positions.len());
+    for position in positions {
+        match position.as_array().map(|position| position.as_slice()) {
+            Some([x, y, ..]) => match (x.as_f64(), y.as_f64()) {
+                (Some(x), Some(y)) => coords.push((x, y)),
+                _ => return Err(invalid_data("invalid GeoJSON position")),
+            },
+            _ => return Err(invalid_data("invalid GeoJSON position")),
+        }
+    }
+    Ok(LineString::from(coords))
+}
+
+fn polygon(value: &Value) -> io::Result<Polygon<f64>> {
+    let rings = value
+        .as_array()
+        .ok_or_else(|| invalid_data("invalid GeoJSON polygon"))?;
+    let exterior = match rings.first() {
+        Some(exterior) => ring(exterior)?,
+        None => return Err(invalid_data("GeoJSON polygon has no rings")),
+    };
+    let interiors = rings[1..].iter().map(ring).collect::<io::Result<_>>()?;
+    Ok(Polygon::new(exterior, interiors))
+}
+
+// Other geometry types are not areas, so are ignored.
+fn geometry(value: &Value) -> io::Result<Option<MultiPolygon<f64>>> {
+    let coordinates = &value["coordinates"];
+    match value["type"].as_str() {
+        Some("Polygon") => Ok(Some(MultiPolygon(vec![polygon(coordinates)?]))),
+        Some("MultiPolygon") => {
+            let polygons = coordinates
+                .as_array()
+                .ok_or_else(|| invalid_data("invalid GeoJSON multipolygon"))?
+                .iter()
+                .map(polygon)
+                .collect::<io::Result<_>>()?;
+            Ok(Some(MultiPolygon(polygons)))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Read the polygons from a GeoJSON FeatureCollection, Feature or bare
+// geometry.
+pub fn read_polygons(path: &str) -> io::Result<Vec<Feature>> {
+    let text = fs::read_to_string(path)?;
+    let value: Value = serde_json::from_str(&text)?;
+
+    let features = match value["type"].as_str() {
+        Some("FeatureCollection") => value["features"]
+            .as_array()
+            .ok_or_else(|| invalid_data("GeoJSON feature collection has no features"))?
+            .iter()
+            .collect(),
+        Some("Feature") => vec![&value],
+        _ => {
+            return Ok(geometry(&value)?
+                .map(|geometry| Feature {
+                    properties: Map::new(),
+                    geometry,
+                })
+                .into_iter()
+                .collect())
+        }
+    };
+
+    let mut polygons = Vec::new();
+    for feature in features {
+        if let Some(geometry) = geometry(&feature["geometry"])? {
+            polygons.push(Feature {
+                properties: feature["properties"]
+                    .as_object()
+                    .cloned()
+                    .unwrap_or_default(),
+                geometry,
+            });
+        }
+    }
+    Ok(polygons)
+}
@@ -0,0 +1,147 @@
+use crate::to_io_error;
+use rusqlite::{Connection, NO_PARAMS};
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag;
+
+// Rasters are written in Web Mercator (EPSG:3857), so that cells are square.
+const EARTH_RADIUS: f64 = 6_378_137.0;
+const WEB_MERCATOR: u16 = 3857;
+// Web Mercator is undefined at the poles.
+const MAX_LATITUDE: f64 = 85.051_128_78;
+// Refuse to allocate rasters larger than this many cells.
+const MAX_CELLS: usize = 100_000_000;
+
+// GeoTIFF tags.
+const MODEL_PIXEL_SCALE: u16 = 33550;
+const MODEL_TIEPOINT: u16 = 33922;
+const GEO_KEY_DIRECTORY: u16 = 34735;
+// Version 1.1.0 with three keys: a projected model, pixels as areas and the
+// projection.
+#[rustfmt::skip]
+const GEO_KEYS: &[u16] = &[
+    1, 1, 0, 3,
+    1024, 0, 1, 1,
+    1025, 0, 1, 1,
+    3072, 0, 1, WEB_MERCATOR,
+];
+
+fn web_mercator(longitude: f64, latitude: f64) -> (f64, f64) {
+    let latitude = latitude.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    (
+        EARTH_RADIUS * longitude.to_radians(),
+        EARTH_RADIUS * (PI / 4.0 + latitude / 2.0).tan().ln(),
+    )
+}
+
+// Smooth the rows of a raster with a Gaussian kernel, then the columns, which
+// is the same as smoothing with a two dimensional Gaussian.
+fn smooth(cells: &mut Vec<f32>, columns: usize, rows: usize, sigma: f64) {
+    let radius = (3.0 * sigma).ceil() as isize;
+    let mut kernel = (-radius..=radius)
+        .map(|offset| (-(offset * offset) as f64 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<f64>>();
+    let total: f64 = kernel.iter().sum();
+    for weight in kernel.iter_mut() {
+        *weight /= total;
+    }
+
+    let pass = |cells: &[f32], step: usize, stride: usize, length: usize, lines: usize| {
+        let mut smoothed = vec![0.0f32; cells.len()];
+        for line in 0..lines {
+            for index in 0..length {
+                let mut value = 0.0;
+                for (weight, offset) in kernel.iter().zip(-radius..=radius) {
+                    let other = index as isize + offset;
+                    if other >= 0 && (other as usize) < length {
+                        value += weight * cells[line * stride + other as usize * step] as f64;
+                    }
+                }
+                smoothed[line * stride + index * step] = value as f32;
+            }
+        }
+        smoothed
+    };
+    *cells = pass(cells, 1, columns, columns, rows);
+    *cells = pass(cells, columns, 1, rows, columns);
+}
+
+// Write the records as a GeoTIFF raster of the number of records in each
+// cell, smoothed with a Gaussian kernel for a density surface.
+pub fn export(
+    conn: &Connection,
+    source: &str,
+    output: &str,
+    cell_size: f64,
+    bandwidth: Option<f64>,
+) -> io::Result<()> {
+    let mut points = Vec::new();
+    let mut stmt = conn
+        .prepare(&format!("SELECT longitude, latitude FROM {}", source))
+        .map_err(to_io_error)?;
+    let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        let longitude: f64 = row.get(0).map_err(to_io_error)?;
+        let latitude: f64 = row.get(1).map_err(to_io_error)?;
+        points.push(web_mercator(longitude, latitude));
+    }
+    if points.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, "No records to export"));
+    }
+
+    // Leave room around the records for the kernel to spread into.
+    let margin = bandwidth.map_or(0.0, |bandwidth| 3.0 * bandwidth);
+    let min_x = points.iter().map(|point| point.0).fold(f64::MAX, f64::min) - margin;
+    let max_x = points.iter().map(|point| point.0).fold(f64::MIN, f64::max) + margin;
+    let min_y = points.iter().map(|point| point.1).fold(f64::MAX, f64::min) - margin;
+    let max_y = points.iter().map(|point| point.1).fold(f64::MIN, f64::max) + margin;
+    let columns = ((max_x - min_x) / cell_size).floor() as usize + 1;
+    let rows = ((max_y - min_y) / cell_size).floor() as usize + 1;
+    if columns.saturating_mul(rows) > MAX_CELLS {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "A {} by {} raster is too large, use a larger --cell-size",
+                columns, rows
+            ),
+        ));
+    }
+
+    // Rows run from north to south.
+    let mut cells = vec![0.0f32; columns * rows];
+    for (x, y) in points {
+        let column = ((x - min_x) / cell_size) as usize;
+        let row = ((max_y - y) / cell_size) as usize;
+        cells[row.min(rows - 1) * columns + column.min(columns - 1)] += 1.0;
+    }
+    if let Some(bandwidth) = bandwidth {
+        smooth(&mut cells, columns, rows, bandwidth / cell_size);
+    }
+
+    let mut tiff = TiffEncoder::new(BufWriter::new(File::create(output)?)).map_err(to_io_error)?;
+    let mut image = tiff
+        .new_image::<colortype::Gray32Float>(columns as u32, rows as u32)
+        .map_err(to_io_error)?;
+    let encoder = image.encoder();
+    encoder
+        .write_tag(
+            Tag::Unknown(MODEL_PIXEL_SCALE),
+            &[cell_size, cell_size, 0.0][..],
+        )
+        .map_err(to_io_error)?;
+    encoder
+        .write_tag(
+            Tag::Unknown(MODEL_TIEPOINT),
+            &[0.0, 0.0, 0.0, min_x, max_y, 0.0][..],
+        )
+        .map_err(to_io_error)?;
+    encoder
+        .write_tag(Tag::Unknown(GEO_KEY_DIRECTORY), GEO_KEYS)
+        .map_err(to_io_error)?;
+    image.write_data(&cells).map_err(to_io_error)?;
+
+    println!("wrote {} by {} raster to {}", columns, rows, output);
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::io;
+
+// Metres per degree of latitude, and of longitude at the equator.
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
+// Parse a distance such as "5km", "500m", "1mi", "300ft" or "2000" (metres)
+// into metres.
+pub fn parse_distance(text: &str) -> io::Result<f64> {
+    parse_distance_in(text, 1.0)
+}
+
+// Parse a distance into metres, with a bare number in units of the given
+// number of metres.
+pub fn parse_distance_in(text: &str, unit: f64) -> io::Result<f64> {
+    let text = text.trim();
+    let (number, scale) = if let Some(number) = text.strip_suffix("km") {
+        (number, 1000.0)
+    } else if let Some(number) = text.strip_suffix("mi") {
+        (number, 1609.344)
+    } else if let Some(number) = text.strip_suffix("ft") {
+        (number, 0.3048)
+    } else if let Some(number) = text.strip_suffix('m') {
+        (number, 1.0)
+    } else {
+        (text, unit)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(distance) if distance > 0.0 && distance.is_finite() => Ok(distance * scale),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Invalid distance {}, expected e.g. 5km, 500m or 1mi", text),
+        )),
+    }
+}
+
+// A grid of roughly square cells covering an area. Cells are a fixed number
+// of degrees across, using the width in degrees of a cell at the middle
+// latitude of the area, so are only close to square over a small area.
+pub struct Grid {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub cell_width: f64,
+    pub cell_height: f64,
+    pub columns: usize,
+    pub rows: usize,
+}
+
+impl Grid {
+    // Cover the area from (min_x, min_y) to (max_x, max_y) in degrees with
+    // cells of the given size in metres.
+    pub fn new(min_x: f64, min_y: f64, max_x: f64, max_y: f64, size: f64) -> Grid {
+        let middle = ((min_y + max_y) / 2.0).to_radians();
+        let cell_height = size / METRES_PER_DEGREE;
+        let cell_width = size / (METRES_PER_DEGREE * middle.cos().max(0.01));
+        Grid {
+            min_x,
+            min_y,
+            cell_width,
+            cell_height,
+            columns: ((max_x - min_x) / cell_width).floor() as usize + 1,
+            rows: ((max_y - min_y) / cell_height).floor() as usize + 1,
+        }
+    }
+
+    // The column and row of the cell containing a point, if any.
+    pub fn cell(&self, x: f64, y: f64) -> Option<(usize, usize)> {
+        let column = ((x - self.min_x) / self.cell_width).floor();
+        let row = ((y - self.min_y) / self.cell_height).floor();
+        if column < 0.0 || row < 0.0 {
+            return None;
+        }
+        let (column, row) = (column as usize, row as usize);
+        if column < self.columns && row < self.rows {
+            Some((column, row))
+        } else {
+            None
+        }
+    }
+
+    // The bounds of a cell, as (min_x, min_y, max_x, max_y).
+    pub fn bounds(&self, column: usize, row: usize) -> (f64, f64, f64, f64) {
+        let min_x = self.min_x + column as f64 * self.cell_width;
+        let min_y = self.min_y + row as f64 * self.cell_height;
+        (
+            min_x,
+            min_y,
+            min_x + self.cell_width,
+            min_y + self.cell_height,
+        )
+    }
+}
@@ -0,0 +1,565 @@
+use crate::ebd::EbdReader;
+use crate::exit;
+use crate::memory;
+use crate::personal::PersonalRecord;
+use crate::to_io_error;
+use crate::EBirdRecord;
+use bzip2::read::MultiBzDecoder;
+use flate2::read::GzDecoder;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+pub const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+pub const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+pub const BZIP2_MAGIC: &[u8] = b"BZh";
+
+// Decompressed data is handed over from the decompression thread in chunks
+// of this size, with up to CHUNK_COUNT chunks buffered.
+pub const CHUNK_SIZE: usize = 1 << 20;
+pub const CHUNK_COUNT: usize = 64;
+
+// Number of times in a row we will try to resume an interrupted download
+// before giving up, waiting twice as long before each attempt as the last.
+pub const MAX_RESUME_ATTEMPTS: usize = 5;
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+
+// Streams the body of an HTTP(S) response, reissuing the request with a Range
+// header if the connection drops part way through.
+struct HttpReader {
+    client: Client,
+    url: String,
+    response: Response,
+    offset: u64,
+    length: Option<u64>,
+    attempts: usize,
+    delay: Duration,
+}
+
+// The first byte of a Content-Range such as "bytes 1000-1999/2000".
+fn content_range_start(value: &str) -> Option<u64> {
+    value
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+impl HttpReader {
+    fn new(url: &str) -> io::Result<HttpReader> {
+        // The default client timeout covers reading the whole body, which
+        // is far too short for a full EBD release.
+        let client = Client::builder()
+            .timeout(None)
+            .build()
+            .map_err(to_io_error)?;
+        let response = client
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(to_io_error)?;
+        let length = response.content_length();
+
+        Ok(HttpReader {
+            client,
+            url: url.to_string(),
+            response,
+            offset: 0,
+            length,
+            attempts: 0,
+            delay: RESUME_DELAY,
+        })
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        let response = self
+            .client
+            .get(&self.url)
+            .header(RANGE, format!("bytes={}-", self.offset))
+            .send()
+            .map_err(to_io_error)?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Could not resume download, server responded with {}",
+                    response.status()
+                ),
+            ));
+        }
+        // Anything but the rest of the body from where we stopped would be
+        // spliced into the input.
+        let start = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(content_range_start);
+        if start != Some(self.offset) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Could not resume download, server sent a range other than from byte {}",
+                    self.offset
+                ),
+            ));
+        }
+        self.response = response;
+        Ok(())
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let err = match self.response.read(buf) {
+                Ok(0) if buf.is_empty() => return Ok(0),
+                Ok(0) => match self.length {
+                    Some(length) if self.offset < length => {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed early")
+                    }
+                    _ => return Ok(0),
+                },
+                Ok(n) => {
+                    self.offset += n as u64;
+                    self.attempts = 0;
+                    return Ok(n);
+                }
+                Err(err) => err,
+            };
+
+            warn!(
+                "download interrupted at byte {} ({}), resuming",
+                self.offset, err
+            );
+            let mut err = err;
+            loop {
+                if self.attempts == MAX_RESUME_ATTEMPTS {
+                    return Err(err);
+                }
+                thread::sleep(self.delay * (1 << self.attempts));
+                self.attempts += 1;
+                match self.resume() {
+                    Ok(()) => break,
+                    Err(resume_err) => {
+                        warn!("attempt {} to resume failed: {}", self.attempts, resume_err);
+                        err = resume_err;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Reads from another reader on a dedicated thread, so that decompression can
+// run on a different core to parsing and filtering.
+pub struct ThreadedReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+// Read until the buffer is full or the end of input is reached.
+pub fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+impl ThreadedReader {
+    // Read the chunks sent on a channel by another thread, which stops at
+    // the first error.
+    pub fn from_receiver(receiver: Receiver<io::Result<Vec<u8>>>) -> ThreadedReader {
+        ThreadedReader {
+            receiver,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn new<R: Read + Send + 'static>(mut inner: R) -> ThreadedReader {
+        let (sender, receiver) = mpsc::sync_channel(memory::queue_length(CHUNK_SIZE, CHUNK_COUNT));
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            match read_chunk(&mut inner, &mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    // The receiver has gone away if the import stopped early.
+                    if sender.send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    let _ = sender.send(Err(err));
+                    break;
+                }
+            }
+        });
+
+        ThreadedReader::from_receiver(receiver)
+    }
+}
+
+impl Read for ThreadedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(err)) => return Err(err),
+                // The decompression thread has finished.
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = (self.chunk.len() - self.pos).min(buf.len());
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// The number of bytes read from an input file (before decompression) and
+// their SHA-256 hash, for reporting throughput and recording provenance.
+#[derive(Default)]
+pub struct InputStats {
+    bytes: AtomicU64,
+    hasher: Mutex<Sha256>,
+}
+
+impl InputStats {
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    // The hash of the bytes read so far, which is only the hash of the file
+    // if it was read to the end.
+    pub fn sha256(&self) -> String {
+        format!("{:x}", self.hasher.lock().unwrap().clone().finalize())
+    }
+
+    pub fn add(&self, data: &[u8]) {
+        self.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.hasher.lock().unwrap().update(data);
+    }
+}
+
+// Updates InputStats with the bytes read through it.
+struct TrackingReader<R> {
+    inner: R,
+    stats: Arc<InputStats>,
+}
+
+impl<R: Read> Read for TrackingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.stats.add(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// Whether the input is in S3 or Google Cloud Storage.
+pub fn is_object_url(path: &str) -> bool {
+    path.starts_with("s3://") || path.starts_with("gs://")
+}
+
+pub fn needs_cloud(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} is in an object store, which needs ebird2spatialite built with --features cloud",
+            path
+        ),
+    )
+}
+
+#[cfg(feature = "cloud")]
+fn open_object(path: &str, stats: Arc<InputStats>) -> io::Result<Box<dyn Read + Send>> {
+    crate::cloud::open_reader(path, stats)
+}
+
+#[cfg(not(feature = "cloud"))]
+fn open_object(path: &str, _stats: Arc<InputStats>) -> io::Result<Box<dyn Read + Send>> {
+    Err(needs_cloud(path))
+}
+
+// Wrap a reader in a decoder for the compression used, if any, detected from
+// the leading magic bytes rather than the file extension. Returns whether the
+// input was compressed along with the reader of the decompressed data.
+pub fn decompress<R: Read + Send + 'static>(reader: R) -> io::Result<(bool, Box<dyn Read + Send>)> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok((true, Box::new(GzDecoder::new(reader))))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        let decoder = zstd::stream::read::Decoder::with_buffer(reader)?;
+        Ok((true, Box::new(decoder)))
+    } else if magic.starts_with(BZIP2_MAGIC) {
+        // pbzip2 and friends write multiple streams, so keep reading past the
+        // end of the first one.
+        Ok((true, Box::new(MultiBzDecoder::new(reader))))
+    } else {
+        Ok((false, Box::new(reader)))
+    }
+}
+
+// Open the ebird archive at path, which may be a local file, an HTTP(S) URL
+// or an S3 or GCS URL, decompressing it if needed. Compressed input is
+// decompressed on a separate thread. The bytes read from the file are
+// recorded in stats.
+pub fn open_input(path: &str, stats: Arc<InputStats>) -> io::Result<Box<dyn Read>> {
+    let tracked: Box<dyn Read + Send> = if is_object_url(path) {
+        open_object(path, stats)?
+    } else if path.starts_with("http://") || path.starts_with("https://") {
+        Box::new(TrackingReader {
+            inner: HttpReader::new(path)?,
+            stats,
+        })
+    } else {
+        Box::new(TrackingReader {
+            inner: File::open(path)?,
+            stats,
+        })
+    };
+
+    match decompress(tracked)? {
+        (true, decoder) => Ok(Box::new(ThreadedReader::new(decoder))),
+        (false, reader) => Ok(reader),
+    }
+}
+
+// The records parsed from an input.
+pub type Records = Box<dyn Iterator<Item = csv::Result<EBirdRecord>>>;
+
+// Parse the records of an EBD file, or a personal export with format
+// "personal", reading only the columns given if any. For the EBD this also
+// returns the count of records whose invalid UTF-8 was repaired.
+pub fn read_records(
+    data: Box<dyn Read>,
+    format: &str,
+    quoting: bool,
+    columns: Option<&[&str]>,
+) -> io::Result<(Records, Option<Rc<Cell<usize>>>)> {
+    let reader = csv::ReaderBuilder::new()
+        .delimiter(if format == "personal" { b',' } else { b'\t' })
+        .quoting(quoting)
+        .flexible(format == "ebd")
+        .from_reader(data);
+    match format {
+        "ebd" => {
+            let mut reader = EbdReader::new(reader)?;
+            if let Some(columns) = columns {
+                reader.select(columns);
+            }
+            let repaired = reader.repaired();
+            Ok((Box::new(reader), Some(repaired)))
+        }
+        "personal" => Ok((
+            Box::new(
+                reader
+                    .into_deserialize::<PersonalRecord>()
+                    .map(|deserialized| deserialized.map(EBirdRecord::from)),
+            ),
+            None,
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown format {}, expected ebd or personal", format),
+        )),
+    }
+}
+
+// Open the input at path as open_input does and parse its records, for the
+// command line and the bindings alike.
+pub fn open_records(
+    path: &str,
+    format: &str,
+    quoting: bool,
+    columns: Option<&[&str]>,
+    stats: Arc<InputStats>,
+) -> io::Result<(Records, Option<Rc<Cell<usize>>>)> {
+    let data = open_input(path, stats).map_err(|err| {
+        exit::with_code(
+            exit::INPUT_UNREADABLE,
+            format!("Could not read {}: {}", path, err),
+        )
+    })?;
+    read_records(data, format, quoting, columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    // Serve each response in turn to one connection, returning the URL and
+    // a receiver of the Range header of each request.
+    fn serve(responses: Vec<Vec<u8>>) -> (String, Receiver<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ebd.txt", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range:") {
+                        range = Some(value.trim().to_string());
+                    }
+                }
+                let _ = sender.send(range);
+                let mut stream = stream;
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn response(status: &str, headers: &str, length: usize, body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            status, length, headers, body
+        )
+        .into_bytes()
+    }
+
+    fn read(url: &str) -> io::Result<String> {
+        let mut reader = HttpReader::new(url)?;
+        reader.delay = Duration::from_millis(0);
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map(|_| text)
+    }
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(content_range_start("bytes 1000-1999/2000"), Some(1000));
+        assert_eq!(content_range_start("bytes 0-9/*"), Some(0));
+        assert_eq!(content_range_start("bytes */2000"), None);
+        assert_eq!(content_range_start("items 5-9/10"), None);
+    }
+
+    #[test]
+    fn resumes_where_the_connection_dropped() {
+        let (url, ranges) = serve(vec![
+            response("200 OK", "", 10, "hello"),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 5-9/10\r\n",
+                5,
+                "world",
+            ),
+        ]);
+        assert_eq!(read(&url).unwrap(), "helloworld");
+        assert_eq!(ranges.recv().unwrap(), None);
+        assert_eq!(ranges.recv().unwrap().as_deref(), Some("bytes=5-"));
+    }
+
+    #[test]
+    fn retries_a_failed_resume() {
+        let (url, _) = serve(vec![
+            response("200 OK", "", 10, "hello"),
+            response("503 Service Unavailable", "", 0, ""),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 5-9/10\r\n",
+                5,
+                "world",
+            ),
+        ]);
+        assert_eq!(read(&url).unwrap(), "helloworld");
+    }
+
+    #[test]
+    fn rejects_the_whole_body_again() {
+        let mut responses = vec![response("200 OK", "", 10, "hello")];
+        for _ in 0..MAX_RESUME_ATTEMPTS {
+            responses.push(response("200 OK", "", 10, "helloworld"));
+        }
+        let (url, _) = serve(responses);
+        assert!(read(&url).is_err());
+    }
+
+    #[test]
+    fn rejects_a_range_from_elsewhere() {
+        let mut responses = vec![response("200 OK", "", 10, "hello")];
+        for _ in 0..MAX_RESUME_ATTEMPTS {
+            responses.push(response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-9/10\r\n",
+                10,
+                "helloworld",
+            ));
+        }
+        let (url, _) = serve(responses);
+        assert!(read(&url).is_err());
+    }
+
+    const PERSONAL: &str = "Submission ID,Common Name,Scientific Name,Count,Latitude,Longitude,\
+Date,Time,Protocol,Duration (Min),All Obs Reported,Distance Traveled (km),Number of Observers,\
+Breeding Code,Observation Details
+S1,Brown Thrasher,Toxostoma rufum,2,45.4,-75.7,05-01-2020,07:30 AM,eBird - Traveling Count,\
+30,1,1.5,1,,\"singing, then flew\"
+S1,Hermit Thrush,Catharus guttatus,one,45.4,-75.7,05-01-2020,07:30 AM,eBird - Traveling Count,\
+30,1,1.5,1,,
+S2,Swainson's Thrush,Catharus ustulatus,1,not a latitude,-75.7,05-02-2020,,Incidental,,0,,1,,
+";
+
+    #[test]
+    fn reads_personal_exports() {
+        let data = Box::new(io::Cursor::new(PERSONAL.as_bytes().to_vec()));
+        let (records, repaired) = read_records(data, "personal", true, None).unwrap();
+        assert!(repaired.is_none());
+        let records = records.collect::<Vec<_>>();
+        assert_eq!(records.len(), 3);
+        let record = records[0].as_ref().unwrap();
+        assert_eq!(record.common_name, "Brown Thrasher");
+        assert_eq!(record.species_comments, "singing, then flew");
+        assert!(records[2].is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let data = Box::new(io::Cursor::new(PERSONAL.as_bytes().to_vec()));
+        let err = read_records(data, "dwca", false, None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn fails_to_open_missing_input() {
+        let path = std::env::temp_dir().join("ebird2spatialite-missing.txt");
+        let stats = Arc::new(InputStats::default());
+        let err = open_records(path.to_str().unwrap(), "ebd", false, None, stats)
+            .err()
+            .unwrap();
+        assert_eq!(exit::code(&err), exit::INPUT_UNREADABLE);
+    }
+}
@@ -0,0 +1,256 @@
+// The import pipeline and subcommands, as a library used by the
+// ebird2spatialite binary and by bindings to other languages. Without the
+// cli feature only the record model, readers and filters are built, which
+// don't need SQLite and so also build for wasm32.
+use serde_json::json;
+#[cfg(feature = "cli")]
+use std::io;
+
+#[cfg(feature = "cli")]
+pub mod alpha;
+#[cfg(feature = "cli")]
+pub mod arrivals;
+#[cfg(feature = "cli")]
+pub mod blocks;
+#[cfg(feature = "capi")]
+#[cfg(feature = "cli")]
+pub mod capi;
+#[cfg(feature = "cloud")]
+pub mod cloud;
+pub mod coords;
+#[cfg(feature = "cli")]
+pub mod count;
+#[cfg(feature = "cli")]
+pub mod datasette;
+#[cfg(feature = "cli")]
+pub mod db;
+#[cfg(feature = "dem")]
+pub mod dem;
+#[cfg(feature = "cli")]
+pub mod dictionary;
+#[cfg(feature = "cli")]
+pub mod diff;
+#[cfg(feature = "cli")]
+pub mod dwca;
+pub mod ebd;
+#[cfg(feature = "cli")]
+pub mod effort;
+#[cfg(feature = "cli")]
+pub mod exit;
+#[cfg(feature = "cli")]
+pub mod export;
+#[cfg(feature = "cli")]
+pub mod fetch;
+pub mod filter;
+#[cfg(feature = "cli")]
+pub mod frequency;
+#[cfg(feature = "cli")]
+pub mod gazetteer;
+#[cfg(feature = "cli")]
+pub mod geojson;
+#[cfg(feature = "export-tiff")]
+pub mod geotiff;
+pub mod grid;
+#[cfg(feature = "cli")]
+pub mod input;
+#[cfg(feature = "cli")]
+pub mod memory;
+#[cfg(feature = "cli")]
+pub mod merge;
+#[cfg(feature = "cli")]
+pub mod migrate;
+#[cfg(feature = "cli")]
+pub mod mvt;
+pub mod near;
+#[cfg(feature = "cli")]
+pub mod obscure;
+#[cfg(feature = "cli")]
+pub mod partition;
+pub mod personal;
+#[cfg(feature = "async")]
+pub mod pipeline;
+#[cfg(feature = "plugin")]
+pub mod plugin;
+#[cfg(feature = "cli")]
+pub mod qgis;
+#[cfg(feature = "cli")]
+pub mod regions;
+#[cfg(feature = "cli")]
+pub mod rejects;
+#[cfg(feature = "cli")]
+pub mod report;
+#[cfg(feature = "cli")]
+pub mod sample;
+#[cfg(feature = "script")]
+pub mod script;
+#[cfg(feature = "cli")]
+pub mod serve;
+#[cfg(feature = "cli")]
+pub mod shapefile;
+#[cfg(feature = "shell")]
+pub mod shell;
+#[cfg(feature = "cli")]
+pub mod spatialite;
+#[cfg(feature = "cli")]
+pub mod species_list;
+#[cfg(feature = "via-duckdb")]
+pub mod staging;
+#[cfg(feature = "cli")]
+pub mod stats;
+#[cfg(feature = "cli")]
+pub mod sun;
+#[cfg(feature = "cli")]
+pub mod targets;
+#[cfg(feature = "cli")]
+pub mod telemetry;
+#[cfg(feature = "cli")]
+pub mod thin;
+#[cfg(feature = "cli")]
+pub mod timezone;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "cli")]
+pub mod validate;
+#[cfg(feature = "cli")]
+pub mod watch;
+
+#[cfg(feature = "cli")]
+pub fn table_name<'a>(matches: &'a clap::ArgMatches) -> io::Result<&'a str> {
+    let table = matches.value_of("table").unwrap_or(db::DEFAULT_TABLE);
+    if db::valid_table_name(table) {
+        Ok(table)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid table name {}", table),
+        ))
+    }
+}
+
+// Wrap an error from SQLite, an HTTP client or another library as an
+// io::Error, which is what the subcommands return.
+#[cfg(feature = "cli")]
+pub fn to_io_error<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EBirdRecord {
+    pub guid: String,
+    pub last_edited_date: String,
+    pub common_name: String,
+    pub scientific_name: String,
+    pub observation_count: String, // String because 'X' is used for count not specified
+    pub breeding_bird_atlas_code: String,
+    pub breeding_bird_atlas_category: String,
+    pub age_sex: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub obs_date: String,
+    pub time_obs_started: String,
+    pub obs_id: String,
+    pub sampling_event_id: String,
+    // Shared checklists have a group identifier in common, with a sampling
+    // event for each observer.
+    pub group_identifier: String,
+    pub protocol_type: String,
+    pub duration_min: Option<i64>,
+    pub effort_distance_km: Option<f64>,
+    // Converted from effort_distance_km with --units imperial
+    pub effort_distance_mi: Option<f64>,
+    pub number_observers: Option<i64>,
+    pub all_species_reported: i64,
+    pub approved: i64,
+    pub reviewed: i64,
+    // Why the record was not approved, in unvetted data
+    pub reason: String,
+    pub species_comments: String,
+    pub trip_comments: String,
+    pub locality: String,
+    pub locality_type: String,
+    // Bird Conservation Region, Important Bird Area and US Fish and Wildlife
+    // Service region codes
+    pub bcr_code: String,
+    pub iba_code: String,
+    pub usfws_code: String,
+    // Set from the location with --timezones
+    pub time_zone: Option<String>,
+    pub local_datetime: Option<String>,
+    pub utc_datetime: Option<String>,
+    // Sampled from the elevation model with --dem
+    pub elevation: Option<f64>,
+    // The nearest place in the gazetteer and its distance in metres, with
+    // --gazetteer
+    pub nearest_place: Option<String>,
+    pub nearest_place_distance: Option<f64>,
+    // The breeding bird atlas block containing the location, with
+    // --atlas-blocks
+    pub atlas_block: Option<String>,
+    // The split the checklist was assigned to, with --split
+    pub split: Option<String>,
+    // Why the coordinates failed validation, with --flag-invalid-coords
+    pub coord_flag: Option<String>,
+    // Fields derived by --script, as a JSON object
+    pub script_fields: Option<String>,
+}
+
+impl EBirdRecord {
+    // The columns of the record as a JSON object, with missing values as
+    // null, for scripts, plugins and bindings.
+    pub fn to_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        let value = json!({
+            "guid": self.guid,
+            "last_edited_date": self.last_edited_date,
+            "common_name": self.common_name,
+            "scientific_name": self.scientific_name,
+            "observation_count": self.observation_count,
+            "breeding_bird_atlas_code": self.breeding_bird_atlas_code,
+            "breeding_bird_atlas_category": self.breeding_bird_atlas_category,
+            "age_sex": self.age_sex,
+            "latitude": self.latitude,
+            "longitude": self.longitude,
+            "obs_date": self.obs_date,
+            "time_obs_started": self.time_obs_started,
+            "obs_id": self.obs_id,
+            "sampling_event_id": self.sampling_event_id,
+            "group_identifier": self.group_identifier,
+            "protocol_type": self.protocol_type,
+            "duration_min": self.duration_min,
+            "effort_distance_km": self.effort_distance_km,
+            "effort_distance_mi": self.effort_distance_mi,
+            "number_observers": self.number_observers,
+            "all_species_reported": self.all_species_reported,
+            "approved": self.approved,
+            "reviewed": self.reviewed,
+            "reason": self.reason,
+            "species_comments": self.species_comments,
+            "trip_comments": self.trip_comments,
+            "locality": self.locality,
+            "locality_type": self.locality_type,
+            "bcr_code": self.bcr_code,
+            "iba_code": self.iba_code,
+            "usfws_code": self.usfws_code,
+            "time_zone": self.time_zone,
+            "local_datetime": self.local_datetime,
+            "utc_datetime": self.utc_datetime,
+            "elevation": self.elevation,
+            "nearest_place": self.nearest_place,
+            "nearest_place_distance": self.nearest_place_distance,
+            "atlas_block": self.atlas_block,
+            "split": self.split,
+            "coord_flag": self.coord_flag,
+        });
+        match value {
+            serde_json::Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    // Whether the checklist was shared with other observers.
+    pub fn is_shared(&self) -> bool {
+        !self.group_identifier.is_empty()
+    }
+}
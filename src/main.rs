@@ -1,366 +1,3459 @@
-use chrono::NaiveDate;
-use clap::{App, Arg};
-use flate2::read::GzDecoder;
-use geo::algorithm::haversine_distance::HaversineDistance;
-use geo::{point, Point};
-use regex::Regex;
-use rusqlite::{params, Connection, LoadExtensionGuard, NO_PARAMS};
-use serde::Deserialize;
-use std::convert::TryFrom;
-use std::fs::File;
-use std::io::{self};
-use std::path::Path;
-
-#[derive(Debug, Deserialize)]
-struct EBirdRecord {
-    #[serde(rename = "GLOBAL UNIQUE IDENTIFIER")]
-    guid: String,
-    #[serde(rename = "COMMON NAME")]
-    common_name: String,
-    #[serde(rename = "SCIENTIFIC NAME")]
-    scientific_name: String,
-    #[serde(rename = "OBSERVATION COUNT")]
-    observation_count: String, // String because 'X' is used for count not specified
-    #[serde(rename = "BREEDING BIRD ATLAS CODE")]
-    breeding_bird_atlas_code: String,
-    #[serde(rename = "BREEDING BIRD ATLAS CATEGORY")]
-    breeding_bird_atlas_category: String,
-    #[serde(rename = "AGE/SEX")]
-    age_sex: String,
-    #[serde(rename = "LATITUDE")]
-    latitude: f64,
-    #[serde(rename = "LONGITUDE")]
-    longitude: f64,
-    #[serde(rename = "OBSERVATION DATE")]
-    obs_date: String,
-    #[serde(rename = "TIME OBSERVATIONS STARTED")]
-    time_obs_started: String,
-    #[serde(rename = "OBSERVER ID")]
-    obs_id: String,
-    #[serde(rename = "SAMPLING EVENT IDENTIFIER")]
-    sampling_event_id: String,
-    #[serde(rename = "PROTOCOL TYPE")]
-    protocol_type: String,
-    #[serde(rename = "DURATION MINUTES")]
-    duration_min: Option<i64>,
-    #[serde(rename = "EFFORT DISTANCE KM")]
-    effort_distance_km: Option<f64>,
-    #[serde(rename = "NUMBER OBSERVERS")]
-    number_observers: Option<i64>,
-    #[serde(rename = "ALL SPECIES REPORTED")]
-    all_species_reported: i64,
-    #[serde(rename = "APPROVED")]
-    approved: i64,
-    #[serde(rename = "SPECIES COMMENTS")]
-    species_comments: String,
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Utc, Weekday};
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
+use regex::{RegexSet, RegexSetBuilder};
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+
+#[cfg(feature = "dem")]
+use ebird2spatialite::dem;
+#[cfg(feature = "async")]
+use ebird2spatialite::pipeline;
+#[cfg(feature = "plugin")]
+use ebird2spatialite::plugin;
+#[cfg(feature = "script")]
+use ebird2spatialite::script;
+#[cfg(feature = "shell")]
+use ebird2spatialite::shell;
+#[cfg(feature = "via-duckdb")]
+use ebird2spatialite::staging;
+#[cfg(feature = "tui")]
+use ebird2spatialite::tui;
+use ebird2spatialite::{
+    alpha, arrivals, blocks, coords, count, db, dictionary, diff, dwca, ebd, effort, exit, export,
+    fetch, filter, frequency, gazetteer, geojson, grid, input, memory, merge, migrate, near,
+    obscure, partition, regions, rejects, report, sample, serve, spatialite, species_list, stats,
+    sun, table_name, targets, telemetry, thin, timezone, validate, watch, EBirdRecord,
+};
+
+mod config;
+mod man;
+
+// Filters choosing which species or observations of a checklist to import,
+// rather than which checklists.
+const SPECIES_FILTERS: &[&str] = &[
+    "common-name-regex",
+    "scientific-name-regex",
+    "alpha-codes",
+    "min-count",
+    "unreviewed-only",
+];
+
+// Arguments which affect which records are imported. A checkpoint can only be
+// resumed if these are unchanged.
+const FILTER_ARGS: &[&str] = &[
+    "INPUT",
+    "input-format",
+    "quoting",
+    "drop-invalid-coords",
+    "before-date",
+    "since-date",
+    "days-of-week",
+    "near-location",
+    "buffer",
+    "distance-metric",
+    "units",
+    "region",
+    "region-boundaries",
+    "region-field",
+    "checklist-region",
+    "checklist-regions",
+    "common-name-regex",
+    "scientific-name-regex",
+    "ignore-case",
+    "locality-types",
+    "bcr",
+    "iba",
+    "usfws",
+    "checklist-ids",
+    "split-by",
+    "split-field",
+    "include-unapproved",
+    "unreviewed-only",
+    "alpha-codes",
+    "alpha-code-list",
+    "observer-ids",
+    "exclude-observer-ids",
+    "limit",
+    "skip",
+    "sample-fraction",
+    "split",
+    "seed",
+    "shards",
+    "shard-index",
+    "columns",
+    "obscure-species",
+    "obscure-radius",
+    "obscure-method",
+    "coord-precision",
+    "nocturnal-only",
+    "protocols",
+    "max-duration",
+    "max-distance",
+    "max-observers",
+    "complete-only",
+    "min-coord-decimals",
+    "preset",
+    "thin-distance",
+    "min-count",
+    "keep-x",
+    "filter-logic",
+    "script",
+    "plugin",
+    "dem",
+    "gazetteer",
+    "atlas-blocks",
+    "block-field",
+    "dictionary-encode",
+    "granularity",
+    "table",
+];
+
+// For --units imperial.
+const METRES_PER_FOOT: f64 = 0.3048;
+const KM_PER_MILE: f64 = 1.609_344;
+
+// The boundary dataset distributed with release builds, which sits next to the
+// executable.
+fn bundled_region_boundaries() -> io::Result<PathBuf> {
+    let path = env::current_exe()?.with_file_name("region_boundaries.geojson");
+    if path.exists() {
+        Ok(path)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No region boundaries at {}, give them with --region-boundaries",
+                path.display()
+            ),
+        ))
+    }
+}
+
+// Read the sampling event identifiers given with --checklist-ids, e.g. those
+// of checklists picked out by an earlier analysis.
+fn read_checklist_ids(path: &str) -> io::Result<HashSet<String>> {
+    let ids = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect::<HashSet<String>>();
+    if ids.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No checklist ids in {}", path),
+        ));
+    }
+    Ok(ids)
+}
+
+fn filter_hash(matches: &clap::ArgMatches) -> String {
+    let mut hasher = Sha256::new();
+    for arg in FILTER_ARGS {
+        let values = matches
+            .values_of(arg)
+            .map(|values| values.collect::<Vec<_>>());
+        hasher.update(format!("{}={}{:?}\n", arg, matches.is_present(arg), values));
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// The filter arguments as a JSON object, for recording in import_runs.
+fn filter_parameters(matches: &clap::ArgMatches) -> String {
+    let mut parameters = serde_json::Map::new();
+    for arg in FILTER_ARGS.iter().filter(|arg| matches.is_present(arg)) {
+        let value = match matches.values_of(arg) {
+            Some(values) => values.collect::<Vec<_>>().join(",").into(),
+            None => true.into(),
+        };
+        parameters.insert(arg.to_string(), value);
+    }
+    serde_json::Value::Object(parameters).to_string()
+}
+
+// Compile the patterns given for a regex argument into a set, which matches if
+// any pattern matches.
+fn regex_set(matches: &clap::ArgMatches, arg: &str) -> io::Result<Option<RegexSet>> {
+    match matches.values_of(arg) {
+        Some(patterns) => RegexSetBuilder::new(patterns)
+            .case_insensitive(matches.is_present("ignore-case"))
+            .build()
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+        None => Ok(None),
+    }
+}
+
+// The values given for a list argument, which may be repeated as well as
+// separated by commas.
+fn list_values<'a>(matches: &'a clap::ArgMatches, arg: &str) -> Option<Vec<&'a str>> {
+    matches.values_of(arg).map(|values| {
+        values
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect()
+    })
+}
+
+// Log to stdout, as text or as a JSON object per line for log collectors, at
+// the level set by RUST_LOG, info by default.
+fn init_logging(format: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stdout);
+    if format == "json" {
+        builder.json().with_span_list(true).init();
+    } else {
+        builder
+            .without_time()
+            .with_target(false)
+            .with_ansi(io::stdout().is_terminal())
+            .init();
+    }
+}
+
+// The records of the input read with the async pipeline, for --async.
+#[cfg(feature = "async")]
+fn async_records(
+    path: &str,
+    input_format: &str,
+    quoting: bool,
+    columns: &Option<Vec<&str>>,
+    stats: Arc<input::InputStats>,
+) -> io::Result<input::Records> {
+    let options = pipeline::Options {
+        format: input_format.to_string(),
+        quoting,
+        columns: columns
+            .as_ref()
+            .map(|columns| columns.iter().map(|column| column.to_string()).collect()),
+    };
+    let records = pipeline::Records::open(path, options, stats).map_err(|err| {
+        exit::with_code(
+            exit::INPUT_UNREADABLE,
+            format!("Could not read {}: {}", path, err),
+        )
+    })?;
+    Ok(Box::new(records))
+}
+
+#[cfg(not(feature = "async"))]
+fn async_records(
+    _path: &str,
+    _input_format: &str,
+    _quoting: bool,
+    _columns: &Option<Vec<&str>>,
+    _stats: Arc<input::InputStats>,
+) -> io::Result<input::Records> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "--async needs ebird2spatialite built with --features async",
+    ))
 }
 
-fn initialize_database(conn: &rusqlite::Connection) -> rusqlite::Result<usize> {
-    let _guard = LoadExtensionGuard::new(conn)?;
-    conn.load_extension(Path::new("mod_spatialite.so"), None)?;
-
-    conn.execute("DROP TABLE IF EXISTS ebird", params![])?;
-
-    let mut stmt = conn.prepare("SELECT InitSpatialMetaData(1)")?;
-    stmt.exists(NO_PARAMS)?;
-
-    conn.execute(
-        "CREATE TABLE ebird (
-                id                              INTEGER PRIMARY KEY,
-                guid                            TEXT,
-                common_name                     TEXT,
-                scientific_name                 TEXT,
-                observation_count               TEXT,
-                breeding_bird_atlas_code        TEXT,
-                breeding_bird_atlas_category    TEXT,
-                obs_date                        TEXT,
-                time_obs_started                TEXT,
-                obs_id                          TEXT,
-                sampling_event_id               TEXT,
-                protocol_type                   TEXT,
-                duration_min                    INTEGER,
-                effort_distance_km              REAL,
-                number_observers                INTEGER,
-                all_species_reported            INTEGER,
-                approved                        INTEGER,
-                species_comments                TEXT)",
-        params![],
-    )?;
-
-    let mut stmt =
-        conn.prepare("SELECT AddGeometryColumn('ebird', 'location', 4326, 'POINT', 'XY')")?;
-    stmt.exists(NO_PARAMS)?;
-
-    Ok(0)
+// The input read through DuckDB, for --via-duckdb.
+#[cfg(feature = "via-duckdb")]
+fn staged_input(path: &str, matches: &clap::ArgMatches) -> io::Result<Box<dyn io::Read>> {
+    staging::open(path, matches)
 }
 
-fn insert_record(conn: &rusqlite::Connection, rec: &EBirdRecord) -> rusqlite::Result<usize> {
-    conn.execute(
-        "INSERT INTO ebird (guid, common_name, scientific_name, observation_count,
-                            breeding_bird_atlas_code, breeding_bird_atlas_category,
-                            location, obs_date, time_obs_started, obs_id,
-                            sampling_event_id, protocol_type, duration_min, effort_distance_km,
-                            number_observers, all_species_reported, approved,
-                            species_comments)
-                VALUES (?1, ?2, ?3, ?4, ?5, ?6, MakePoint(?7, ?8, 4326), ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
-        params![
-            rec.guid,
-            rec.common_name,
-            rec.scientific_name,
-            rec.observation_count,
-            rec.breeding_bird_atlas_code,
-            rec.breeding_bird_atlas_category,
-            rec.longitude,
-            rec.latitude,
-            rec.obs_date,
-            rec.time_obs_started,
-            rec.obs_id,
-            rec.sampling_event_id,
-            rec.protocol_type,
-            rec.duration_min,
-            rec.effort_distance_km,
-            rec.number_observers,
-            rec.all_species_reported,
-            rec.approved,
-            rec.species_comments
-        ],
+#[cfg(not(feature = "via-duckdb"))]
+fn staged_input(_path: &str, _matches: &clap::ArgMatches) -> io::Result<Box<dyn io::Read>> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "--via-duckdb needs ebird2spatialite built with --features via-duckdb",
+    ))
+}
+
+// Stand-ins for the modules of features which weren't built, which fail if
+// their option or subcommand is used.
+#[cfg(not(all(
+    feature = "dem",
+    feature = "plugin",
+    feature = "script",
+    feature = "shell",
+    feature = "tui"
+)))]
+fn not_built(option: &str, feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} needs ebird2spatialite built with --features {}",
+            option, feature
+        ),
     )
 }
 
-fn main() -> io::Result<()> {
-    let matches = App::new("ebird2spatialite")
+#[cfg(not(feature = "dem"))]
+mod dem {
+    use super::{not_built, EBirdRecord};
+    use std::io;
+
+    pub struct Dem;
+
+    impl Dem {
+        pub fn load(_path: &str) -> io::Result<Dem> {
+            Err(not_built("--dem", "dem"))
+        }
+
+        pub fn apply(&self, _record: &mut EBirdRecord) {}
+    }
+}
+
+#[cfg(not(feature = "plugin"))]
+mod plugin {
+    use super::{not_built, EBirdRecord};
+    use std::io;
+
+    pub struct Plugin;
+
+    impl Plugin {
+        pub fn load(_path: &str) -> io::Result<Plugin> {
+            Err(not_built("--plugin", "plugin"))
+        }
+
+        pub fn apply(&mut self, _record: &mut EBirdRecord) -> Result<bool, String> {
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(not(feature = "script"))]
+mod script {
+    use super::{not_built, EBirdRecord};
+    use std::io;
+
+    pub struct Script;
+
+    impl Script {
+        pub fn load(_path: &str) -> io::Result<Script> {
+            Err(not_built("--script", "script"))
+        }
+
+        pub fn apply(&self, _record: &mut EBirdRecord) -> Result<bool, String> {
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(not(feature = "shell"))]
+mod shell {
+    use super::not_built;
+    use std::io;
+
+    pub fn run(_matches: &clap::ArgMatches) -> io::Result<()> {
+        Err(not_built("The shell subcommand", "shell"))
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+mod tui {
+    use super::not_built;
+    use std::io;
+
+    pub fn run(_matches: &clap::ArgMatches) -> io::Result<()> {
+        Err(not_built("The tui subcommand", "tui"))
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        error!("{}", err);
+        process::exit(exit::code(&err));
+    }
+}
+
+// The command line interface, which also generates the shell completions and
+// man page.
+fn app<'a, 'b>() -> App<'a, 'b> {
+    let presets = effort::PRESETS
+        .iter()
+        .map(|preset| preset.name)
+        .collect::<Vec<_>>();
+    App::new("ebird2spatialite")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("INPUT")
-                .required(true)
+                .required_unless_one(&["help-exit-codes", "generate-man"])
                 .index(1)
-                .help("path to ebird archive"),
+                .help(
+                    "path, HTTP(S) URL, or S3 or GCS URL of ebird archive (gzip, bzip2, zstd \
+                     or uncompressed)",
+                ),
+        )
+        .arg(
+            Arg::with_name("input-format")
+                .long("input-format")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_INPUT_FORMAT")
+                .possible_values(&["ebd", "personal", "dwca"])
+                .default_value("ebd")
+                .help(
+                    "Format of the input: the EBD, a personal MyEBirdData.csv export or a \
+                     Darwin Core Archive",
+                ),
+        )
+        .arg(
+            Arg::with_name("quoting")
+                .long("quoting")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_QUOTING")
+                .possible_values(&["none", "double"])
+                .help(
+                    "Whether fields may be enclosed in double quotes. Defaults to none for the \
+                     EBD, which is not quoted, and double for personal exports",
+                ),
         )
         .arg(
             Arg::with_name("before-date")
                 .long("before-date")
                 .takes_value(true)
+                .env("EBIRD2SPATIALITE_BEFORE_DATE")
                 .help("Select records before the specified observation date"),
         )
         .arg(
             Arg::with_name("since-date")
                 .long("since-date")
                 .takes_value(true)
+                .env("EBIRD2SPATIALITE_SINCE_DATE")
                 .help("Select records since the specified observation date"),
         )
+        .arg(
+            Arg::with_name("days-of-week")
+                .long("days-of-week")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_DAYS_OF_WEEK")
+                .use_delimiter(true)
+                .help("Select records observed on these days of the week, e.g. sat,sun"),
+        )
         .arg(
             Arg::with_name("near-location")
                 .long("near-location")
                 .takes_value(true)
-                .help("Location around which to select records (as WKT point)"),
+                .env("EBIRD2SPATIALITE_NEAR_LOCATION")
+                .alias("near")
+                .allow_hyphen_values(true)
+                .help(
+                    "Location around which to select records, as latitude,longitude \
+                     (e.g. 49.25,-123.1) or a WKT point (e.g. \"POINT (-123.1 49.25)\")",
+                ),
         )
         .arg(
             Arg::with_name("buffer")
                 .long("buffer")
                 .takes_value(true)
-                .help("Buffer around near-location (in metres)"),
+                .env("EBIRD2SPATIALITE_BUFFER")
+                .help(
+                    "Buffer around near-location, e.g. 2.5km, 800m or 1mi. A bare number is in \
+                     metres, or feet with --units imperial",
+                ),
+        )
+        .arg(
+            Arg::with_name("distance-metric")
+                .long("distance-metric")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_DISTANCE_METRIC")
+                .possible_values(&["haversine", "vincenty"])
+                .default_value("haversine")
+                .help(
+                    "Measure distances from near-location on a sphere (haversine), or on the \
+                     WGS84 ellipsoid (vincenty), which is slower",
+                ),
+        )
+        .arg(
+            Arg::with_name("units")
+                .long("units")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_UNITS")
+                .possible_values(&["metric", "imperial"])
+                .default_value("metric")
+                .help("Units of --buffer, imperial also storing effort distances in miles"),
+        )
+        .arg(
+            Arg::with_name("region")
+                .long("region")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_REGION")
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Select records within these eBird regions, e.g. US-CA-037 or CA-ON"),
+        )
+        .arg(
+            Arg::with_name("region-boundaries")
+                .long("region-boundaries")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_REGION_BOUNDARIES")
+                .requires("region")
+                .help(
+                    "Boundaries of eBird regions, as a shapefile or GeoJSON, used by --region. \
+                     Defaults to region_boundaries.geojson next to the executable",
+                ),
+        )
+        .arg(
+            Arg::with_name("region-field")
+                .long("region-field")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_REGION_FIELD")
+                .default_value("region_code")
+                .help("Attribute of --region-boundaries polygons holding their region codes"),
+        )
+        .arg(
+            Arg::with_name("checklist-region")
+                .long("checklist-region")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_CHECKLIST_REGION")
+                .multiple(true)
+                .use_delimiter(true)
+                .requires("checklist-regions")
+                .help("Select records within these custom regions, e.g. ABA"),
+        )
+        .arg(
+            Arg::with_name("checklist-regions")
+                .long("checklist-regions")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_CHECKLIST_REGIONS")
+                .requires("checklist-region")
+                .help(
+                    "CSV table of custom regions, such as listing areas, with code and wkt \
+                     columns giving each region's code and polygon",
+                ),
         )
         .arg(
             Arg::with_name("common-name-regex")
                 .long("common-name-regex")
                 .takes_value(true)
-                .help("Select records matching the specified regex"),
+                .env("EBIRD2SPATIALITE_COMMON_NAME_REGEX")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Select records matching the specified regex (may be given more than once)"),
         )
         .arg(
             Arg::with_name("scientific-name-regex")
                 .long("scientific-name-regex")
                 .takes_value(true)
-                .help("Select records matching the specified regex"),
+                .env("EBIRD2SPATIALITE_SCIENTIFIC_NAME_REGEX")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Select records matching the specified regex (may be given more than once)"),
+        )
+        .arg(
+            Arg::with_name("alpha-codes")
+                .long("alpha-codes")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_ALPHA_CODES")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("alpha-code-list")
+                .help(
+                    "Select species by four letter banding code, e.g. AMRO,HETH,SWTH (may be \
+                     given more than once)",
+                ),
+        )
+        .arg(
+            Arg::with_name("alpha-code-list")
+                .long("alpha-code-list")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_ALPHA_CODE_LIST")
+                .requires("alpha-codes")
+                .help("CSV list of alpha codes with SPEC and SCINAME columns, used to resolve --alpha-codes"),
+        )
+        .arg(
+            Arg::with_name("ignore-case")
+                .long("ignore-case")
+                .help("Match the name regexes case insensitively"),
+        )
+        .arg(
+            Arg::with_name("min-count")
+                .long("min-count")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_MIN_COUNT")
+                .help("Select records with at least this many individuals"),
+        )
+        .arg(
+            Arg::with_name("keep-x")
+                .long("keep-x")
+                .requires("min-count")
+                .help("With --min-count, also select records with a count of X (not counted)"),
+        )
+        .arg(
+            Arg::with_name("locality-types")
+                .long("locality-types")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_LOCALITY_TYPES")
+                .use_delimiter(true)
+                .help(
+                    "Select records by locality type, e.g. H for hotspots or H,P to also \
+                     include personal locations",
+                ),
+        )
+        .arg(
+            Arg::with_name("bcr")
+                .long("bcr")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_BCR")
+                .use_delimiter(true)
+                .help("Select records in these Bird Conservation Regions, e.g. 13 or 12,13"),
+        )
+        .arg(
+            Arg::with_name("iba")
+                .long("iba")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_IBA")
+                .use_delimiter(true)
+                .help("Select records in these Important Bird Areas, e.g. US-NY_3214"),
+        )
+        .arg(
+            Arg::with_name("usfws")
+                .long("usfws")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_USFWS")
+                .use_delimiter(true)
+                .help("Select records in these US Fish and Wildlife Service regions"),
+        )
+        .arg(
+            Arg::with_name("checklist-ids")
+                .long("checklist-ids")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_CHECKLIST_IDS")
+                .help(
+                    "File of sampling event identifiers, one per line, to select the records \
+                     of those checklists",
+                ),
+        )
+        .arg(
+            Arg::with_name("observer-ids")
+                .long("observer-ids")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_OBSERVER_IDS")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Select records from these observers, e.g. obsr123456,obsr654321 (may be \
+                     given more than once)",
+                ),
+        )
+        .arg(
+            Arg::with_name("exclude-observer-ids")
+                .long("exclude-observer-ids")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_EXCLUDE_OBSERVER_IDS")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Drop records from these observers (may be given more than once)"),
+        )
+        .arg(
+            Arg::with_name("include-unapproved")
+                .long("include-unapproved")
+                .help("Also import records which were not approved, from unvetted data"),
+        )
+        .arg(
+            Arg::with_name("unreviewed-only")
+                .long("unreviewed-only")
+                .help("Select records which have not been reviewed"),
+        )
+        .arg(
+            Arg::with_name("nocturnal-only")
+                .long("nocturnal-only")
+                .help("Select records from checklists started between civil dusk and dawn"),
+        )
+        .arg(
+            Arg::with_name("filter-logic")
+                .long("filter-logic")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_FILTER_LOGIC")
+                .help(
+                    "Combine filter options with and, or, not and parentheses instead of \
+                     requiring all of them, e.g. \"(common-name-regex or alpha-codes) and \
+                     not checklist-ids\". The protocol and duration options are named effort, \
+                     and the values of a repeated option are alternatives within one filter",
+                ),
+        )
+        .arg(
+            Arg::with_name("script")
+                .long("script")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SCRIPT")
+                .help(
+                    "Run this Rhai script on each record, which returns true or false to keep \
+                     or drop it, or a map of fields to store in script_fields",
+                ),
+        )
+        .arg(
+            Arg::with_name("plugin")
+                .long("plugin")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_PLUGIN")
+                .help(
+                    "Run this WebAssembly component, implementing the filter world of \
+                     wit/plugin.wit, on each record to keep or drop it and add columns to \
+                     script_fields",
+                ),
+        )
+        .arg(
+            Arg::with_name("protocols")
+                .long("protocols")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_PROTOCOLS")
+                .use_delimiter(true)
+                .help("Select records from checklists with these protocols, e.g. Stationary"),
+        )
+        .arg(
+            Arg::with_name("max-duration")
+                .long("max-duration")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_MAX_DURATION")
+                .help("Select records from checklists of at most this many minutes"),
+        )
+        .arg(
+            Arg::with_name("max-distance")
+                .long("max-distance")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_MAX_DISTANCE")
+                .help(
+                    "Select records from checklists traveling at most this distance, e.g. 5km, \
+                     or in kilometres, or miles with --units imperial",
+                ),
+        )
+        .arg(
+            Arg::with_name("max-observers")
+                .long("max-observers")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_MAX_OBSERVERS")
+                .help("Select records from checklists with at most this many observers"),
+        )
+        .arg(
+            Arg::with_name("min-coord-decimals")
+                .long("min-coord-decimals")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_MIN_COORD_DECIMALS")
+                .help(
+                    "Select records whose coordinates have at least this many decimal places, \
+                     leaving out imprecise locations",
+                ),
+        )
+        .arg(
+            Arg::with_name("complete-only")
+                .long("complete-only")
+                .help("Select records from complete checklists, with all species reported"),
+        )
+        .arg(
+            Arg::with_name("preset")
+                .long("preset")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_PRESET")
+                .possible_values(&presets)
+                .help(
+                    "Apply the effort filters of a survey protocol, overridden by any given \
+                     explicitly",
+                ),
+        )
+        .arg(
+            Arg::with_name("thin-distance")
+                .long("thin-distance")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_THIN_DISTANCE")
+                .help(
+                    "Keep at most one record of each species within this distance, e.g. 1km, \
+                     dropping the later records of a cluster",
+                ),
         )
         .arg(
             Arg::with_name("limit")
                 .long("limit")
                 .takes_value(true)
+                .env("EBIRD2SPATIALITE_LIMIT")
                 .help("Limit the number of records extracted (for debugging)"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("skip")
+                .long("skip")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SKIP")
+                .help("Skip this many records at the start of the input, before --limit"),
+        )
+        .arg(
+            Arg::with_name("sample-fraction")
+                .long("sample-fraction")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SAMPLE_FRACTION")
+                .help("Select a random fraction of the input records, e.g. 0.01"),
+        )
+        .arg(
+            Arg::with_name("split")
+                .long("split")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SPLIT")
+                .use_delimiter(true)
+                .help(
+                    "Assign each checklist to a split at random, e.g. train=0.8,test=0.2, \
+                     stored in the split column",
+                ),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SEED")
+                .help("Random seed for --sample-fraction or --split"),
+        )
+        .arg(
+            Arg::with_name("shards")
+                .long("shards")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SHARDS")
+                .requires("shard-index")
+                .help(
+                    "Split the checklists into this many shards, to be imported by separate \
+                     processes and combined with the merge subcommand",
+                ),
+        )
+        .arg(
+            Arg::with_name("shard-index")
+                .long("shard-index")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SHARD_INDEX")
+                .requires("shards")
+                .help(
+                    "Import the checklists of this shard, from 0 to one less than --shards, \
+                     to ebird-<index>.sqlite unless --output is given",
+                ),
+        )
+        .arg(
+            Arg::with_name("columns")
+                .long("columns")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_COLUMNS")
+                .use_delimiter(true)
+                .help(
+                    "Comma separated list of columns to read from the EBD and store, e.g. \
+                     guid,obs_date",
+                ),
+        )
+        .arg(
+            Arg::with_name("strip-comments")
+                .long("strip-comments")
+                .conflicts_with("fts")
+                .help("Leave out species and checklist comments, e.g. for redistribution"),
+        )
+        .arg(
+            Arg::with_name("strip-observers")
+                .long("strip-observers")
+                .conflicts_with("views")
+                .help("Leave out observer ids"),
+        )
+        .arg(
+            Arg::with_name("table")
+                .long("table")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_TABLE")
+                .default_value("ebird")
+                .help("Table to import records into, replacing only that table"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_OUTPUT")
+                .conflicts_with("attach")
+                .help("Database to create (defaults to ebird.sqlite)"),
+        )
+        .arg(
+            Arg::with_name("attach")
+                .long("attach")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_ATTACH")
+                .help(
+                    "Add the table to this existing SpatiaLite database instead of \
+                     creating one, without dropping or reinitializing anything",
+                ),
+        )
+        .arg(
+            Arg::with_name("dictionary-encode")
+                .long("dictionary-encode")
+                .conflicts_with("fts")
+                .help(
+                    "Store names, protocols, localities and observers as ids into lookup \
+                     tables, to shrink the database. Query the ebird_decoded view to see them",
+                ),
+        )
+        .arg(
+            Arg::with_name("two-pass")
+                .long("two-pass")
+                .requires("dictionary-encode")
+                .help(
+                    "Read the input twice, first counting the values to encode so that the \
+                     lookup tables are written before the records, most common values first",
+                ),
+        )
+        .arg(
+            Arg::with_name("granularity")
+                .long("granularity")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_GRANULARITY")
+                .possible_values(&["observation", "checklist"])
+                .help(
+                    "Import one row per observation (the default) or one row per checklist, \
+                     with the number of species and individuals reported",
+                ),
+        )
+        .arg(
+            Arg::with_name("partition-by")
+                .long("partition-by")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_PARTITION_BY")
+                .possible_values(&["species", "year", "split"])
+                .help(
+                    "Import records into a table per species, year or split (with --split), \
+                     e.g. ebird_american_robin or ebird_2020, instead of a single table",
+                ),
+        )
+        .arg(
+            Arg::with_name("split-by")
+                .long("split-by")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SPLIT_BY")
+                .conflicts_with("partition-by")
+                .help(
+                    "Polygons, as a shapefile or GeoJSON in longitude and latitude, to import \
+                     the records in each into its own table, dropping records outside them",
+                ),
+        )
+        .arg(
+            Arg::with_name("split-field")
+                .long("split-field")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SPLIT_FIELD")
+                .default_value("name")
+                .help("Attribute of --split-by polygons used to name their tables"),
+        )
+        .arg(
+            Arg::with_name("partition-files")
+                .long("partition-files")
+                .help(
+                    "With --partition-by or --split-by, write each partition to its own \
+                     database, e.g. ebird_american_robin.sqlite",
+                ),
+        )
+        .arg(
+            Arg::with_name("fts")
+                .long("fts")
+                .help("Build a full text search table over names, comments and localities"),
+        )
+        .arg(
+            Arg::with_name("views")
+                .long("views")
+                .help("Create a spatial view with one point per checklist"),
+        )
+        .arg(
+            Arg::with_name("species-views")
+                .long("species-views")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SPECIES_VIEWS")
+                .use_delimiter(true)
+                .help(
+                    "Comma separated list of species (common or scientific names) to create \
+                     a spatial view for, along with the checklist view",
+                ),
+        )
+        .arg(Arg::with_name("arrivals").long("arrivals").help(
+            "Build an ebird_arrivals table with the first and last dates each species \
+                     was recorded in each year",
+        ))
+        .arg(
+            Arg::with_name("arrival-regions")
+                .long("arrival-regions")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_ARRIVAL_REGIONS")
+                .requires("arrivals")
+                .help("GeoJSON file of regions to find --arrivals dates within separately"),
+        )
+        .arg(
+            Arg::with_name("frequency")
+                .long("frequency")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_FREQUENCY")
+                .help(
+                    "Build an ebird_frequency table with the detection frequency of each \
+                     species by month, using this sampling event data file",
+                ),
+        )
+        .arg(
+            Arg::with_name("frequency-grid")
+                .long("frequency-grid")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_FREQUENCY_GRID")
+                .requires("frequency")
+                .help("Also find --frequency for each cell of a grid of this size, e.g. 10km"),
+        )
+        .arg(
+            Arg::with_name("ranges")
+                .long("ranges")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_RANGES")
+                .min_values(0)
+                .possible_values(&["convex", "concave"])
+                .help(
+                    "Build an ebird_ranges layer with a convex (the default) or concave hull \
+                     around the records of each species",
+                ),
+        )
+        .arg(
+            Arg::with_name("effort-buffers")
+                .long("effort-buffers")
+                .help(
+                    "Add an effort_area geometry to traveling checklists, buffering the \
+                     location by the distance travelled",
+                ),
+        )
+        .arg(
+            Arg::with_name("indexes")
+                .long("indexes")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_INDEXES")
+                .min_values(0)
+                .use_delimiter(true)
+                .help(
+                    "Index these columns after importing. Without a list, indexes common_name, \
+                     scientific_name, obs_date, sampling_event_id and obs_id",
+                ),
+        )
+        .arg(
+            Arg::with_name("obscure-species")
+                .long("obscure-species")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_OBSCURE_SPECIES")
+                .help(
+                    "File listing sensitive species (one name per line) whose locations to obscure",
+                ),
+        )
+        .arg(
+            Arg::with_name("obscure-radius")
+                .long("obscure-radius")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_OBSCURE_RADIUS")
+                .default_value("5000")
+                .help("Distance to obscure the locations of sensitive species by (in metres)"),
+        )
+        .arg(
+            Arg::with_name("obscure-method")
+                .long("obscure-method")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_OBSCURE_METHOD")
+                .possible_values(&["snap", "jitter"])
+                .default_value("snap")
+                .help("Snap sensitive locations to a grid or jitter them randomly"),
+        )
+        .arg(
+            Arg::with_name("obscure-key")
+                .long("obscure-key")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_OBSCURE_KEY")
+                .hide_env_values(true)
+                .help(
+                    "Secret the offsets of --obscure-method jitter are derived from, \
+                     which is not stored",
+                ),
+        )
+        .arg(
+            Arg::with_name("coord-precision")
+                .long("coord-precision")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_COORD_PRECISION")
+                .help("Round stored coordinates to this many decimal places"),
+        )
+        .arg(
+            Arg::with_name("dem")
+                .long("dem")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_DEM")
+                .help(
+                    "GeoTIFF elevation model in longitude and latitude, e.g. an SRTM tile, to \
+                     add the elevation of each record as a column and Z coordinate",
+                ),
+        )
+        .arg(
+            Arg::with_name("gazetteer")
+                .long("gazetteer")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_GAZETTEER")
+                .help(
+                    "GeoNames file, e.g. cities1000.txt, to tag each record with the nearest \
+                     named place and its distance",
+                ),
+        )
+        .arg(
+            Arg::with_name("atlas-blocks")
+                .long("atlas-blocks")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_ATLAS_BLOCKS")
+                .help(
+                    "Breeding bird atlas blocks, as a shapefile or GeoJSON in longitude and \
+                     latitude, to assign each record its block and summarize each block",
+                ),
+        )
+        .arg(
+            Arg::with_name("block-field")
+                .long("block-field")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_BLOCK_FIELD")
+                .default_value("BLOCK_ID")
+                .help("Attribute of --atlas-blocks with the block id"),
+        )
+        .arg(
+            Arg::with_name("timezones")
+                .long("timezones")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_TIMEZONES")
+                .help(
+                    "GeoJSON time zone boundaries (from timezone-boundary-builder) used to add \
+                     local and UTC start timestamps",
+                ),
+        )
+        .arg(
+            Arg::with_name("drop-invalid-coords")
+                .long("drop-invalid-coords")
+                .conflicts_with("flag-invalid-coords")
+                .help("Drop records with out of range, swapped or (0, 0) coordinates"),
+        )
+        .arg(
+            Arg::with_name("flag-invalid-coords")
+                .long("flag-invalid-coords")
+                .help("Keep records with invalid coordinates, but record why in coord_flag"),
+        )
+        .arg(
+            Arg::with_name("rejects")
+                .long("rejects")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_REJECTS")
+                .help("Write a report of dropped or flagged records to this file"),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_REPORT")
+                .conflicts_with("count-only")
+                .help("Write a JSON summary of the import to this file"),
+        )
+        .arg(Arg::with_name("fast").long("fast").help(
+            "Use WAL journaling, a 1GB cache and in-memory temporary storage, and sync \
+                     less often. Faster, but less safe if the import crashes",
+        ))
+        .arg(
+            Arg::with_name("synchronous")
+                .long("synchronous")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SYNCHRONOUS")
+                .requires("fast")
+                .possible_values(&["normal", "off"])
+                .help("SQLite synchronous setting to use with --fast (defaults to normal)"),
+        )
+        .arg(
+            Arg::with_name("max-memory")
+                .long("max-memory")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_MAX_MEMORY")
+                .help(
+                    "Roughly the most memory to use, e.g. 1500MB, by shrinking the input \
+                     buffers, dictionary cache and SQLite cache and spilling counts to disk",
+                ),
+        )
+        .arg(Arg::with_name("async").long("async").help(
+            "Read, decompress and parse the input as separate tasks of an async \
+                     runtime, so that writing to the database doesn't wait on them. Needs \
+                     ebird2spatialite built with --features async",
+        ))
+        .arg(
+            Arg::with_name("via-duckdb")
+                .long("via-duckdb")
+                .conflicts_with("async")
+                .help(
+                    "Read an EBD file through DuckDB, which applies the date, name, count and \
+                     approval filters as SQL before the records are parsed. Needs \
+                     ebird2spatialite built with --features via-duckdb",
+                ),
+        )
+        .arg(
+            Arg::with_name("batch-size")
+                .long("batch-size")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_BATCH_SIZE")
+                .default_value("100000")
+                .help("Number of input records to read between commits and checkpoints"),
+        )
+        .arg(
+            Arg::with_name("max-errors")
+                .long("max-errors")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_MAX_ERRORS")
+                .help(
+                    "Abort the import if more than this many records can't be parsed or inserted",
+                ),
+        )
+        .arg(
+            Arg::with_name("max-error-rate")
+                .long("max-error-rate")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_MAX_ERROR_RATE")
+                .help(
+                    "Abort the import if more than this fraction of records can't be parsed or \
+                     inserted",
+                ),
+        )
+        .arg(
+            Arg::with_name("count-only")
+                .long("count-only")
+                .help("Print the number of matching records instead of creating a database"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_FORMAT")
+                .possible_values(&["database", "species-list"])
+                .conflicts_with("count-only")
+                .help(
+                    "Create a database (the default), or print the species matching the \
+                     filters with their first and last dates and number of records",
+                ),
+        )
+        .arg(
+            Arg::with_name("count-by")
+                .long("count-by")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_COUNT_BY")
+                .requires("count-only")
+                .possible_values(&["species", "year"])
+                .help("Break down the --count-only count by species or year"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Resume an interrupted import from its last checkpoint"),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_PROGRESS")
+                .help("Print throughput every this many seconds during the import"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_LOG_FORMAT")
+                .global(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Log warnings, errors and progress as text or JSON lines"),
+        )
+        .arg(
+            Arg::with_name("spatialite-path")
+                .long("spatialite-path")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_SPATIALITE_PATH")
+                .global(true)
+                .default_value("mod_spatialite")
+                .help("Path of the SpatiaLite extension to load"),
+        )
+        .arg(
+            Arg::with_name("require-spatialite")
+                .long("require-spatialite")
+                .help(
+                    "Stop if SpatiaLite can't be loaded, rather than importing records \
+                     without a location geometry",
+                ),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .env("EBIRD2SPATIALITE_CONFIG")
+                .global(true)
+                .help(
+                    "Read option values from this TOML file, where they are overridden by \
+                     EBIRD2SPATIALITE_* environment variables and the command line",
+                ),
+        )
+        .arg(
+            Arg::with_name("help-exit-codes")
+                .long("help-exit-codes")
+                .help("Print the exit codes and what they mean"),
+        )
+        .arg(
+            Arg::with_name("generate-man")
+                .long("generate-man")
+                .help("Print a man page in roff format"),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print a shell completion script")
+                .arg(
+                    Arg::with_name("SHELL")
+                        .required(true)
+                        .index(1)
+                        .possible_values(&Shell::variants())
+                        .help("Shell to complete in"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fetch")
+                .about("Fetch recent observations from the eBird API into the database")
+                .arg(
+                    Arg::with_name("region")
+                        .long("region")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_FETCH_REGION")
+                        .required(true)
+                        .help("eBird region code, e.g. CA-BC"),
+                )
+                .arg(
+                    Arg::with_name("back")
+                        .long("back")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_FETCH_BACK")
+                        .default_value("14")
+                        .help("Number of days back to fetch observations (1 to 30)"),
+                )
+                .arg(
+                    Arg::with_name("api-key")
+                        .long("api-key")
+                        .takes_value(true)
+                        .required(true)
+                        .env("EBIRD_API_KEY")
+                        .help("eBird API key"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_FETCH_TABLE")
+                        .default_value("ebird")
+                        .help("Table to add records to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Import new EBD files as they appear in a directory")
+                .arg(
+                    Arg::with_name("DIR")
+                        .required(true)
+                        .index(1)
+                        .help("directory to watch for new ebird archives"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_WATCH_INTERVAL")
+                        .default_value("60")
+                        .help("Number of seconds between checks for new files"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_WATCH_TABLE")
+                        .default_value("ebird")
+                        .help("Table to add records to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("targets")
+                .about("List species recorded in a region which an observer has not recorded")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .index(1)
+                        .help("database created by ebird2spatialite"),
+                )
+                .arg(
+                    Arg::with_name("observer")
+                        .long("observer")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_TARGETS_OBSERVER")
+                        .required(true)
+                        .help("eBird observer id, e.g. obsr123456"),
+                )
+                .arg(
+                    Arg::with_name("region")
+                        .long("region")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_TARGETS_REGION")
+                        .required(true)
+                        .help("GeoJSON file with the polygons of the region"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_TARGETS_TABLE")
+                        .default_value("ebird")
+                        .help("Table to read records from"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Summarize observer effort in a database")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .index(1)
+                        .help("database created by ebird2spatialite"),
+                )
+                .arg(
+                    Arg::with_name("observers")
+                        .long("observers")
+                        .help("Print checklists, hours, distance and species for each observer"),
+                )
+                .arg(
+                    Arg::with_name("monthly")
+                        .long("monthly")
+                        .help("Print checklists, observers, hours and distance for each month"),
+                )
+                .arg(
+                    Arg::with_name("csv")
+                        .long("csv")
+                        .help("Print CSV instead of a table"),
+                )
+                .arg(
+                    Arg::with_name("gaps")
+                        .long("gaps")
+                        .conflicts_with_all(&["observers", "monthly", "csv"])
+                        .help(
+                            "Write grid cells with few complete checklists to a polygon \
+                             layer named after the table, e.g. ebird_gaps",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("grid")
+                        .long("grid")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_STATS_GRID")
+                        .default_value("5km")
+                        .help("Size of the --gaps grid cells, e.g. 5km or 500m"),
+                )
+                .arg(
+                    Arg::with_name("min-checklists")
+                        .long("min-checklists")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_STATS_MIN_CHECKLISTS")
+                        .default_value("1")
+                        .help("Cells with fewer complete checklists than this are gaps"),
+                )
+                .arg(
+                    Arg::with_name("rarities")
+                        .long("rarities")
+                        .conflicts_with_all(&["observers", "monthly", "csv", "gaps"])
+                        .help(
+                            "Write records of species rarely reported that week of the year \
+                             to a review layer named after the table, e.g. ebird_rarities",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("vega-lite")
+                        .long("vega-lite")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_STATS_VEGA_LITE")
+                        .value_name("DIRECTORY")
+                        .conflicts_with_all(&["observers", "monthly", "csv", "gaps", "rarities"])
+                        .help(
+                            "Write Vega-Lite chart specs of species accumulation, weekly \
+                             frequency and effort over time to this directory",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_STATS_THRESHOLD")
+                        .default_value("0.01")
+                        .help("Detection frequency below which --rarities flags a record"),
+                )
+                .arg(
+                    Arg::with_name("since-date")
+                        .long("since-date")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_STATS_SINCE_DATE")
+                        .help(
+                            "Only count checklists (--gaps) or flag records (--rarities) since \
+                             this date",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("before-date")
+                        .long("before-date")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_STATS_BEFORE_DATE")
+                        .help(
+                            "Only count checklists (--gaps) or flag records (--rarities) before \
+                             this date",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_STATS_TABLE")
+                        .default_value("ebird")
+                        .help("Table to read records from"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export the records in a database to other formats")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .index(1)
+                        .help("database created by ebird2spatialite"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_EXPORT_FORMAT")
+                        .required_unless_one(&["datasette", "qgis"])
+                        .requires("output")
+                        .possible_values(&["geotiff", "html-map", "zerofill"])
+                        .help(
+                            "Format to export to: a GeoTIFF raster of record counts, an HTML \
+                             web map of the records, or a zero-filled CSV of detections on \
+                             complete checklists",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_EXPORT_OUTPUT")
+                        .requires("format")
+                        .help("File to write"),
+                )
+                .arg(Arg::with_name("datasette").long("datasette").help(
+                    "Write a Datasette metadata.json next to the database, with \
+                             descriptions, facets and canned spatial queries",
+                ))
+                .arg(
+                    Arg::with_name("qgis")
+                        .long("qgis")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_EXPORT_QGIS")
+                        .value_name("PROJECT")
+                        .help(
+                            "Write a QGIS project (.qgz or .qgs) with styled layers of the \
+                             records by species group, hotspots and any grid summaries",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("cell-size")
+                        .long("cell-size")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_EXPORT_CELL_SIZE")
+                        .default_value("1km")
+                        .help("Size of raster cells, e.g. 500 (metres) or 1km"),
+                )
+                .arg(
+                    Arg::with_name("kernel-density")
+                        .long("kernel-density")
+                        .help("Smooth the raster with a Gaussian kernel for a density surface"),
+                )
+                .arg(
+                    Arg::with_name("bandwidth")
+                        .long("bandwidth")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_EXPORT_BANDWIDTH")
+                        .requires("kernel-density")
+                        .help("Standard deviation of the kernel, by default two cells"),
+                )
+                .arg(
+                    Arg::with_name("species")
+                        .long("species")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_EXPORT_SPECIES")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Common or scientific name of a species to zero-fill"),
+                )
+                .arg(
+                    Arg::with_name("checklist-table")
+                        .long("checklist-table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_EXPORT_CHECKLIST_TABLE")
+                        .default_value("checklists")
+                        .help("Table imported with --granularity checklist to zero-fill"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_EXPORT_TABLE")
+                        .default_value("ebird")
+                        .help("Table to read records from"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve read-only queries of a database over HTTP")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .index(1)
+                        .help("database created by ebird2spatialite"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_SERVE_HOST")
+                        .default_value("127.0.0.1")
+                        .help("Address to listen on, e.g. 0.0.0.0 for every interface"),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_SERVE_PORT")
+                        .default_value("8080")
+                        .help("Port to listen on"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_SERVE_TABLE")
+                        .default_value("ebird")
+                        .help("Table to read records from"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("shell")
+                .about("Run SQL on a database interactively, with SpatiaLite loaded")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .index(1)
+                        .help("database created by ebird2spatialite"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tui")
+                .about("Browse the records in a database in the terminal")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .index(1)
+                        .help("database created by ebird2spatialite"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_TUI_TABLE")
+                        .default_value("ebird")
+                        .help("Table to read records from"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Combine the records of several databases into a new database")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .multiple(true)
+                        .index(1)
+                        .help("databases created by ebird2spatialite"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_MERGE_OUTPUT")
+                        .required(true)
+                        .help("Database to write the merged records to"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_MERGE_TABLE")
+                        .default_value("ebird")
+                        .help("Table to merge, in both the inputs and the output"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Report records added, removed and modified between two databases")
+                .arg(
+                    Arg::with_name("OLD")
+                        .required(true)
+                        .index(1)
+                        .help("database of the earlier release"),
+                )
+                .arg(
+                    Arg::with_name("NEW")
+                        .required(true)
+                        .index(2)
+                        .help("database of the later release"),
+                )
+                .arg(
+                    Arg::with_name("change-table")
+                        .long("change-table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_DIFF_CHANGE_TABLE")
+                        .help("Write each change to this table in the new database"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_DIFF_TABLE")
+                        .default_value("ebird")
+                        .help("Table to compare, in both databases"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check a database for problems, printing a JSON report")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .index(1)
+                        .help("database created by ebird2spatialite"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_VALIDATE_TABLE")
+                        .default_value("ebird")
+                        .help("Table to check"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Upgrade a database made by an older version in place")
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .required(true)
+                        .index(1)
+                        .help("database created by ebird2spatialite"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .env("EBIRD2SPATIALITE_MIGRATE_TABLE")
+                        .default_value("ebird")
+                        .help("Table to upgrade"),
+                ),
+        )
+}
+
+fn run() -> io::Result<()> {
+    // Logging isn't set up until the arguments are parsed.
+    let args = config::load()
+        .and_then(|_| config::args(&app()))
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(exit::code(&err));
+        });
+    let matches = app().get_matches_from_safe(args).unwrap_or_else(|err| {
+        // Help and version requests aren't errors.
+        if !err.use_stderr() {
+            err.exit();
+        }
+        eprintln!("{}", err.message);
+        process::exit(exit::BAD_ARGUMENTS);
+    });
+
+    init_logging(matches.value_of("log-format").unwrap());
+    db::set_spatialite_path(matches.value_of("spatialite-path").unwrap());
+    if let Some(text) = matches.value_of("max-memory") {
+        match memory::parse_size(text) {
+            Some(bytes) if bytes > 0 => memory::set_max_memory(bytes),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid memory size {}, expected e.g. 1500MB or 2GB", text),
+                ));
+            }
+        }
+    }
+    if matches.is_present("help-exit-codes") {
+        println!("{}", exit::HELP);
+        return Ok(());
+    }
+    if matches.is_present("generate-man") {
+        return man::write_man_page(app, &mut io::stdout());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        let shell = matches
+            .value_of("SHELL")
+            .unwrap()
+            .parse::<Shell>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        app().gen_completions_to("ebird2spatialite", shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("fetch") {
+        return fetch::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("watch") {
+        return watch::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("targets") {
+        return targets::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("stats") {
+        return stats::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("export") {
+        return export::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("shell") {
+        return shell::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("tui") {
+        return tui::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("serve") {
+        return serve::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("merge") {
+        return merge::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("diff") {
+        return diff::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        return validate::run(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("migrate") {
+        return migrate::run(matches);
+    }
+
+    let path = matches.value_of("INPUT").unwrap();
+    let input_format = matches.value_of("input-format").unwrap();
+    let mut archive = if input_format == "dwca" {
+        Some(dwca::Archive::open(path).map_err(|err| {
+            exit::with_code(
+                exit::INPUT_UNREADABLE,
+                format!("Could not read {}: {}", path, err),
+            )
+        })?)
+    } else {
+        None
+    };
+    // DuckDB reads the input instead, so its bytes aren't counted.
+    let via_duckdb = matches.is_present("via-duckdb");
+    if via_duckdb && input_format != "ebd" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--via-duckdb only reads EBD files",
+        ));
+    }
+    let quoting = match matches.value_of("quoting") {
+        Some(quoting) => quoting == "double",
+        None => input_format == "personal",
+    };
+    let imperial = matches.value_of("units") == Some("imperial");
+    // Determine the columns to read, if a reduced set was requested. Columns
+    // needed by filters are always read.
+    let columns = match matches.values_of("columns") {
+        Some(values) => {
+            let mut columns = values.collect::<Vec<&str>>();
+            if let Some(column) = columns.iter().find(|column| !db::COLUMNS.contains(column)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown column {}", column),
+                ));
+            }
+            if matches.is_present("before-date")
+                || matches.is_present("since-date")
+                || matches.is_present("days-of-week")
+            {
+                columns.push("obs_date");
+            }
+            if matches.is_present("min-count") {
+                columns.push("observation_count");
+            }
+            if [
+                "protocols",
+                "max-duration",
+                "max-distance",
+                "max-observers",
+                "complete-only",
+                "min-coord-decimals",
+                "preset",
+            ]
+            .iter()
+            .any(|arg| matches.is_present(arg))
+            {
+                columns.extend(&[
+                    "protocol_type",
+                    "duration_min",
+                    "effort_distance_km",
+                    "number_observers",
+                    "all_species_reported",
+                ]);
+            }
+            if matches.is_present("nocturnal-only") || matches.is_present("timezones") {
+                columns.extend(&["obs_date", "time_obs_started"]);
+            }
+            if matches.is_present("common-name-regex") || matches.is_present("obscure-species") {
+                columns.push("common_name");
+            }
+            if matches.is_present("scientific-name-regex") || matches.is_present("obscure-species")
+            {
+                columns.push("scientific_name");
+            }
+            if !matches.is_present("include-unapproved") {
+                columns.push("approved");
+            }
+            if matches.is_present("unreviewed-only") {
+                columns.push("reviewed");
+            }
+            if matches.is_present("locality-types") {
+                columns.push("locality_type");
+            }
+            if matches.is_present("bcr") {
+                columns.push("bcr_code");
+            }
+            if matches.is_present("iba") {
+                columns.push("iba_code");
+            }
+            if matches.is_present("usfws") {
+                columns.push("usfws_code");
+            }
+            if matches.is_present("checklist-ids") || matches.is_present("shards") {
+                columns.push("sampling_event_id");
+            }
+            if columns.contains(&"is_shared") {
+                columns.push("group_identifier");
+            }
+            if matches.is_present("alpha-codes") {
+                columns.push("scientific_name");
+            }
+            if matches.is_present("observer-ids") || matches.is_present("exclude-observer-ids") {
+                columns.push("obs_id");
+            }
+            if matches.is_present("thin-distance") {
+                columns.push("scientific_name");
+            }
+            if matches.is_present("rejects") {
+                columns.extend(&["guid", "sampling_event_id", "obs_date"]);
+            }
+            if matches.value_of("granularity") == Some("checklist") {
+                columns.extend(&["sampling_event_id", "observation_count"]);
+            }
+            if matches.is_present("frequency") {
+                columns.extend(&[
+                    "common_name",
+                    "scientific_name",
+                    "obs_date",
+                    "sampling_event_id",
+                    "all_species_reported",
+                ]);
+            }
+            if matches.is_present("effort-buffers") {
+                columns.extend(&["protocol_type", "effort_distance_km"]);
+            }
+            if imperial {
+                columns.extend(&["effort_distance_km", "effort_distance_mi"]);
+            }
+            if matches.is_present("ranges") {
+                columns.extend(&["common_name", "scientific_name"]);
+            }
+            if matches.is_present("arrivals") {
+                columns.extend(&["common_name", "scientific_name", "obs_date"]);
+            }
+            if matches.is_present("atlas-blocks") {
+                columns.extend(&[
+                    "common_name",
+                    "scientific_name",
+                    "breeding_bird_atlas_category",
+                    "sampling_event_id",
+                    "duration_min",
+                ]);
+            }
+            match matches.value_of("partition-by") {
+                Some("species") => columns.push("common_name"),
+                Some("year") => columns.push("obs_date"),
+                _ => (),
+            }
+            // Columns filled in from other sources, and those used by the
+            // tables and views built after the import, since the table only
+            // has the selected columns.
+            if matches.is_present("timezones") {
+                columns.extend(&["time_zone", "local_datetime", "utc_datetime"]);
+            }
+            if matches.is_present("dem") {
+                columns.push("elevation");
+            }
+            if matches.is_present("gazetteer") {
+                columns.extend(&["nearest_place", "nearest_place_distance"]);
+            }
+            if matches.is_present("atlas-blocks") {
+                columns.push("atlas_block");
+            }
+            if matches.is_present("script") || matches.is_present("plugin") {
+                columns.push("script_fields");
+            }
+            if matches.is_present("split") {
+                columns.extend(&["sampling_event_id", "split"]);
+            }
+            if matches.is_present("fts") {
+                columns.extend(&[
+                    "common_name",
+                    "scientific_name",
+                    "species_comments",
+                    "trip_comments",
+                    "locality",
+                ]);
+            }
+            if matches.is_present("views") || matches.is_present("species-views") {
+                columns.extend(&[
+                    "common_name",
+                    "scientific_name",
+                    "sampling_event_id",
+                    "obs_date",
+                    "time_obs_started",
+                    "obs_id",
+                    "protocol_type",
+                    "duration_min",
+                    "effort_distance_km",
+                    "number_observers",
+                    "all_species_reported",
+                    "locality",
+                ]);
+            }
+            if matches.is_present("indexes") {
+                match matches.values_of("indexes") {
+                    Some(indexes) => columns.extend(indexes),
+                    None => columns.extend(db::DEFAULT_INDEXES),
+                }
+            }
+            if matches.value_of("format") == Some("species-list") {
+                columns.extend(&["common_name", "scientific_name", "obs_date"]);
+            }
+            match matches.value_of("count-by") {
+                Some("species") => columns.push("common_name"),
+                Some("year") => columns.push("obs_date"),
+                _ => (),
+            }
+            Some(columns)
+        }
+        None => None,
+    };
+
+    // Free text and observer ids which may not be redistributed are never
+    // read or stored.
+    let strip_comments = matches.is_present("strip-comments");
+    let strip_observers = matches.is_present("strip-observers");
+    let mut stripped = Vec::new();
+    if strip_comments {
+        stripped.extend(&["species_comments", "trip_comments"]);
+    }
+    if strip_observers {
+        stripped.push("obs_id");
+    }
+    let columns = if stripped.is_empty() {
+        columns
+    } else {
+        Some(
+            columns
+                .unwrap_or_else(|| db::COLUMNS.to_vec())
+                .into_iter()
+                .filter(|column| !stripped.contains(column))
+                .collect(),
+        )
+    };
+
+    let table = table_name(&matches)?;
+    let _import = info_span!("import", input = path, table).entered();
+
+    let checklists = matches.value_of("granularity") == Some("checklist");
+    if checklists {
+        if let Some(arg) = [
+            "dictionary-encode",
+            "fts",
+            "views",
+            "species-views",
+            "arrivals",
+            "frequency",
+            "ranges",
+        ]
+        .iter()
+        .find(|arg| matches.is_present(arg))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--{} can't be used with --granularity checklist", arg),
+            ));
+        }
+    }
+    // Thinning keeps no state in checkpoints, and works on observations.
+    if matches.is_present("thin-distance") {
+        if matches.is_present("resume") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--thin-distance can't be used with --resume",
+            ));
+        }
+        if checklists {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--thin-distance can't be used with --granularity checklist",
+            ));
+        }
+    }
+    // Partitions only get their geometry and indexes, so can't be combined
+    // with options which build other tables from the imported records.
+    let partition_arg = if matches.is_present("split-by") {
+        Some("split-by")
+    } else if matches.is_present("partition-by") {
+        Some("partition-by")
+    } else {
+        None
+    };
+    if let Some(partition_arg) = partition_arg {
+        if let Some(arg) = [
+            "dictionary-encode",
+            "fts",
+            "views",
+            "species-views",
+            "arrivals",
+            "frequency",
+            "ranges",
+            "effort-buffers",
+            "atlas-blocks",
+            "attach",
+            "resume",
+        ]
+        .iter()
+        .find(|arg| matches.is_present(arg))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--{} can't be used with --{}", arg, partition_arg),
+            ));
+        }
+        if matches.value_of("partition-by") == Some("split") && !matches.is_present("split") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--partition-by split needs --split",
+            ));
+        }
+        if checklists && matches.value_of("partition-by") == Some("species") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--partition-by species can't be used with --granularity checklist",
+            ));
+        }
+    } else if matches.is_present("partition-files") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--partition-files needs --partition-by or --split-by",
+        ));
+    }
+    let (table_columns, default_indexes) = if checklists {
+        (db::CHECKLIST_COLUMNS, db::DEFAULT_CHECKLIST_INDEXES)
+    } else {
+        (db::COLUMNS, db::DEFAULT_INDEXES)
+    };
+
+    let indexes = if matches.is_present("indexes") {
+        match matches.values_of("indexes") {
+            Some(values) => values.collect::<Vec<&str>>(),
+            None => default_indexes.to_vec(),
+        }
+    } else {
+        Vec::new()
+    };
+    if let Some(column) = indexes
+        .iter()
+        .find(|column| !table_columns.contains(column) || **column == "location")
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't index column {}", column),
+        ));
+    }
+
+    let count_only = matches.is_present("count-only");
+    let species_list = matches.value_of("format") == Some("species-list");
+    let no_database = count_only || species_list;
+
+    // Determine before date if specified
+    let before_date = if let Some(text) = matches.value_of("before-date") {
+        match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+            Ok(date) => Some(date),
+            Err(err) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+            }
+        }
+    } else {
+        None
+    };
+
+    // Determine since date if specified
+    let since_date = if let Some(text) = matches.value_of("since-date") {
+        match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+            Ok(date) => {
+                if let Some(before_date) = before_date {
+                    if before_date > date {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Before date is after since date",
+                        ));
+                    }
+                }
+                Some(date)
+            }
+            Err(err) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+            }
+        }
+    } else {
+        None
+    };
+
+    let days_of_week = match matches.values_of("days-of-week") {
+        Some(values) => {
+            let mut days = Vec::new();
+            for value in values {
+                match value.trim().parse::<Weekday>() {
+                    Ok(day) => days.push(day),
+                    Err(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Invalid day of the week {}, expected e.g. sat", value),
+                        ));
+                    }
+                }
+            }
+            Some(days)
+        }
+        None => None,
+    };
+
+    // Determine near point, if specified.
+    let near = match matches.value_of("near-location") {
+        Some(text) => Some(coords::parse_point(text)?),
+        None => None,
+    };
+
+    // Determine buffer for use with near location. It defaults to 1000m, and
+    // a bare number is in metres, or feet with --units imperial.
+    let buffer = match matches.value_of("buffer") {
+        Some(text) => grid::parse_distance_in(text, if imperial { METRES_PER_FOOT } else { 1.0 })?,
+        None => 1000.,
+    };
+    let near = near.map(|point| {
+        let metric = match matches.value_of("distance-metric") {
+            Some("vincenty") => near::Metric::Vincenty,
+            _ => near::Metric::Haversine,
+        };
+        near::Near::new(point, buffer, metric)
+    });
+
+    // Determine common name regex if specified
+    let common_name_regex = regex_set(&matches, "common-name-regex")?;
+
+    // Determine scientific name regex if specified
+    let scientific_name_regex = regex_set(&matches, "scientific-name-regex")?;
+
+    let alpha_codes = match (
+        list_values(&matches, "alpha-codes"),
+        matches.value_of("alpha-code-list"),
+    ) {
+        (Some(codes), Some(path)) => Some(alpha::AlphaCodes::load(codes.into_iter(), path)?),
+        _ => None,
+    };
+
+    let limit = match matches.value_of("limit") {
+        Some(text) => match text.parse::<usize>() {
+            Ok(limit) => limit,
+            Err(err) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+            }
+        },
+        None => usize::max_value(),
+    };
+
+    let locality_types = matches
+        .values_of("locality-types")
+        .map(|values| values.collect::<Vec<&str>>());
+
+    let region_codes = |name| {
+        matches
+            .values_of(name)
+            .map(|values| values.map(str::trim).collect::<Vec<&str>>())
+    };
+    let bcr_codes = region_codes("bcr");
+    let iba_codes = region_codes("iba");
+    let usfws_codes = region_codes("usfws");
+
+    let observer_ids = list_values(&matches, "observer-ids");
+    let exclude_observer_ids = list_values(&matches, "exclude-observer-ids");
+
+    let checklist_ids = match matches.value_of("checklist-ids") {
+        Some(path) => Some(read_checklist_ids(path)?),
+        None => None,
+    };
+
+    let include_unapproved = matches.is_present("include-unapproved");
+    let unreviewed_only = matches.is_present("unreviewed-only");
+
+    let nocturnal_only = matches.is_present("nocturnal-only");
+
+    let effort = effort::EffortFilter::from_matches(&matches)?;
+
+    let mut thinner = match matches.value_of("thin-distance") {
+        Some(text) => Some(thin::Thinner::new(grid::parse_distance(text)?)),
+        None => None,
+    };
+
+    let min_count = match matches.value_of("min-count") {
+        Some(text) => match text.parse::<i64>() {
+            Ok(min_count) => Some(min_count),
+            Err(err) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+            }
+        },
+        None => None,
+    };
+    let keep_x = matches.is_present("keep-x");
+
+    let time_zones = match matches.value_of("timezones") {
+        Some(path) => Some(Rc::new(timezone::TimeZones::load(path)?)),
+        None => None,
+    };
+
+    let dem = match matches.value_of("dem") {
+        Some(path) => Some(dem::Dem::load(path)?),
+        None => None,
+    };
+
+    let gazetteer = match matches.value_of("gazetteer") {
+        Some(path) => Some(gazetteer::Gazetteer::load(path)?),
+        None => None,
+    };
+
+    let atlas_blocks = match matches.value_of("atlas-blocks") {
+        Some(path) => Some(blocks::AtlasBlocks::load(
+            path,
+            matches.value_of("block-field").unwrap_or("BLOCK_ID"),
+        )?),
+        None => None,
+    };
+
+    let regions = match matches.values_of("region") {
+        Some(codes) => {
+            let codes = codes.map(|code| code.trim()).collect::<Vec<_>>();
+            let path = match matches.value_of("region-boundaries") {
+                Some(path) => PathBuf::from(path),
+                None => bundled_region_boundaries()?,
+            };
+            Some(regions::Regions::load_codes(
+                &path.to_string_lossy(),
+                matches.value_of("region-field").unwrap_or("region_code"),
+                &codes,
+            )?)
+        }
+        None => None,
+    };
+
+    let checklist_regions = match matches.values_of("checklist-region") {
+        Some(codes) => Some(regions::Regions::load_table(
+            matches.value_of("checklist-regions").unwrap(),
+            &codes.map(|code| code.trim()).collect::<Vec<_>>(),
+        )?),
+        None => None,
+    };
+
+    let split_regions = match matches.value_of("split-by") {
+        Some(path) => Some(regions::Regions::load(
+            path,
+            matches.value_of("split-field").unwrap_or("name"),
+        )?),
+        None => None,
+    };
 
-    let path = matches.value_of("INPUT").unwrap();
-    let ebird_data = File::open(path)?;
-    let mut reader = csv::ReaderBuilder::new()
-        .delimiter(b'\t')
-        .from_reader(GzDecoder::new(ebird_data));
+    let arrival_regions = match matches.value_of("arrival-regions") {
+        Some(path) => Some(geojson::read_polygons(path)?),
+        None => None,
+    };
 
-    // Determine before date if specified
-    let before_date = if let Some(text) = matches.value_of("before-date") {
-        match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
-            Ok(date) => {
-                println!("date {}", date);
-                Some(date)
-            }
-            Err(err) => {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
-            }
-        }
-    } else {
-        None
+    let frequency_grid = match matches.value_of("frequency-grid") {
+        Some(text) => Some(grid::parse_distance(text)?),
+        None => None,
     };
 
-    // Determine since date if specified
-    let since_date = if let Some(text) = matches.value_of("since-date") {
-        match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
-            Ok(date) => {
-                if let Some(before_date) = before_date {
-                    if before_date > date {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "Before date is after since date",
-                        ));
-                    }
-                }
-                Some(date)
-            }
+    let skip = match matches.value_of("skip") {
+        Some(text) => match text.parse::<usize>() {
+            Ok(skip) => skip,
             Err(err) => {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
             }
-        }
-    } else {
-        None
+        },
+        None => 0,
     };
 
-    // Determine near point, if specified.
-    let near: Option<geo::Point<f64>> = match matches.value_of("near-location") {
-        Some(text) => match wkt::Wkt::<f64>::from_str(text) {
-            Ok(wkt) => {
-                if wkt.items.len() == 1 {
-                    match wkt::conversion::try_into_geometry(&wkt.items[0]) {
-                        Ok(as_geometry) => Point::try_from(as_geometry).ok(),
-                        Err(_) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                "Invalid near location geometry",
-                            ));
-                        }
-                    }
-                } else {
+    if matches.is_present("seed")
+        && !matches.is_present("sample-fraction")
+        && !matches.is_present("split")
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--seed needs --sample-fraction or --split",
+        ));
+    }
+    let seed = match matches.value_of("seed").unwrap_or("0").parse::<u64>() {
+        Ok(seed) => seed,
+        Err(err) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+        }
+    };
+    let sampler = match matches.value_of("sample-fraction") {
+        Some(text) => {
+            let fraction = match text.parse::<f64>() {
+                Ok(fraction) if fraction > 0. && fraction <= 1. => fraction,
+                _ => {
                     return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Multiple near locations are not supported",
+                        io::ErrorKind::InvalidInput,
+                        "Sample fraction must be a number greater than 0 and at most 1",
                     ));
                 }
+            };
+            Some(sample::Sampler::new(fraction, seed))
+        }
+        None => None,
+    };
+    let splits = match matches.values_of("split") {
+        Some(values) => Some(sample::Splits::parse(values, seed)?),
+        None => None,
+    };
+    let shard = match (matches.value_of("shards"), matches.value_of("shard-index")) {
+        (Some(count), Some(index)) => match (count.parse::<u64>(), index.parse::<u64>()) {
+            (Ok(count), Ok(index)) if count > 0 && index < count => {
+                Some(sample::Shard::new(count, index))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Shard index must be a number from 0 to one less than the number of shards",
+                ));
             }
+        },
+        _ => None,
+    };
+
+    let batch_size = match matches.value_of("batch-size").unwrap().parse::<usize>() {
+        Ok(batch_size) if batch_size > 0 => batch_size,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Batch size must be a positive number",
+            ));
+        }
+    };
+
+    let max_errors = match matches.value_of("max-errors") {
+        Some(text) => match text.parse::<usize>() {
+            Ok(max_errors) => max_errors,
             Err(err) => {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+            }
+        },
+        None => usize::MAX,
+    };
+
+    let max_error_rate = match matches.value_of("max-error-rate") {
+        Some(text) => match text.parse::<f64>() {
+            Ok(rate) if (0. ..=1.).contains(&rate) => Some(rate),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Max error rate must be a number between 0 and 1",
+                ));
             }
         },
         None => None,
     };
 
-    // Determine buffer for use with near location. It defaults to 1000m,
-    // unless an invalid value was specified.
-    let buffer = if let Some(text) = matches.value_of("buffer") {
-        match text.parse::<f64>() {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+    let invalid_coords = if matches.is_present("drop-invalid-coords") {
+        coords::InvalidCoords::Drop
+    } else if matches.is_present("flag-invalid-coords") {
+        coords::InvalidCoords::Flag
+    } else {
+        coords::InvalidCoords::Keep
+    };
+
+    let mut rejects = match matches.value_of("rejects") {
+        Some(path) => Some(rejects::RejectsReport::create(path)?),
+        None => None,
+    };
+
+    // Determine sensitive species to obscure, if specified
+    let obscurer = if let Some(path) = matches.value_of("obscure-species") {
+        let radius = match matches.value_of("obscure-radius").unwrap().parse::<f64>() {
+            Ok(radius) if radius > 0. => radius,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Obscure radius must be a positive number",
+                ));
             }
-        }
+        };
+        let method = match (
+            matches.value_of("obscure-method"),
+            matches.value_of("obscure-key"),
+        ) {
+            (Some("jitter"), Some(key)) if !key.is_empty() => obscure::Method::Jitter {
+                key: key.to_string(),
+            },
+            (Some("jitter"), _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--obscure-method jitter needs a secret --obscure-key to derive the offsets from",
+                ));
+            }
+            _ => obscure::Method::Snap,
+        };
+        Some(obscure::Obscurer::load(path, radius, method)?)
     } else {
-        1000.
+        None
     };
 
-    // Determine common name regex if specified
-    let common_name_regex = if let Some(text) = matches.value_of("common-name-regex") {
-        match Regex::new(text) {
-            Ok(regex) => Some(regex),
-            Err(err) => {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+    let coord_precision = match matches.value_of("coord-precision") {
+        Some(text) => match text.parse::<i32>() {
+            Ok(precision) if (0..=15).contains(&precision) => Some(precision),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Coordinate precision must be a number of decimal places between 0 and 15",
+                ));
             }
-        }
+        },
+        None => None,
+    };
+
+    let progress = match matches.value_of("progress") {
+        Some(text) => match text.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Progress interval must be a positive number of seconds",
+                ));
+            }
+        },
+        None => None,
+    };
+
+    // With --two-pass, first count the values of the dictionary encoded
+    // columns over the whole input, so that the dictionaries can be written
+    // before any records. A resumed import already has them. This comes after
+    // checking the arguments so that a mistake isn't found only after reading
+    // the whole input.
+    let counts = if matches.is_present("two-pass") && !matches.is_present("resume") && !no_database
+    {
+        let records = if let Some(archive) = &mut archive {
+            archive.records()?
+        } else {
+            let stats = Arc::new(input::InputStats::default());
+            input::open_records(path, input_format, quoting, columns.as_deref(), stats)?.0
+        };
+        let counts = dictionary::Counts::count(records)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        info!(
+            "first pass read {} records, with {} species, {} localities and {} observers",
+            counts.records(),
+            counts.distinct("scientific_name"),
+            counts.distinct("locality"),
+            counts.distinct("obs_id")
+        );
+        Some(counts)
     } else {
         None
     };
 
-    // Determine scientific name regex if specified
-    let scientific_name_regex = if let Some(text) = matches.value_of("scientific-name-regex") {
-        match Regex::new(text) {
-            Ok(regex) => Some(regex),
-            Err(err) => {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+    // Number of records with free text that needed repair, if the format
+    // supports it.
+    let mut repaired = None;
+    let input_stats = Arc::new(input::InputStats::default());
+    let mut records: Box<dyn Iterator<Item = csv::Result<EBirdRecord>>> =
+        if let Some(archive) = &mut archive {
+            archive.records()?
+        } else if matches.is_present("async") {
+            async_records(path, input_format, quoting, &columns, input_stats.clone())?
+        } else {
+            let (records, repaired_records) = if via_duckdb {
+                let ebird_data = staged_input(path, &matches).map_err(|err| {
+                    exit::with_code(
+                        exit::INPUT_UNREADABLE,
+                        format!("Could not read {}: {}", path, err),
+                    )
+                })?;
+                input::read_records(ebird_data, input_format, quoting, columns.as_deref())?
+            } else {
+                let stats = input_stats.clone();
+                input::open_records(path, input_format, quoting, columns.as_deref(), stats)?
+            };
+            repaired = repaired_records;
+            records
+        };
+
+    let attach = matches.value_of("attach");
+    // Each shard gets its own database, so that they can be written at once.
+    let shard_output = shard
+        .as_ref()
+        .map(|shard| format!("ebird-{}.sqlite", shard.index()));
+    let output = attach
+        .or_else(|| matches.value_of("output"))
+        .or(shard_output.as_deref())
+        .unwrap_or("ebird.sqlite");
+    if let Some(path) = attach {
+        if !std::path::Path::new(path).is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Database {} does not exist", path),
+            ));
+        }
+    }
+
+    // With --count-only or --format species-list nothing is written, so use a
+    // throwaway database.
+    let conn = if no_database {
+        Connection::open_in_memory()
+    } else {
+        Connection::open(output)
+    }
+    .map_err(|err| db::setup_error("open", output, err))?;
+    if matches.is_present("fast") && !no_database {
+        let synchronous = matches.value_of("synchronous").unwrap_or("normal");
+        if synchronous == "off" {
+            warn!(
+                "with --synchronous off, a crash or power loss during the import can corrupt \
+                 the database"
+            );
+        } else {
+            warn!(
+                "with --fast, a crash or power loss can lose the most recently committed records"
+            );
+        }
+        db::set_fast_pragmas(&conn, synchronous)
+            .map_err(|err| db::setup_error("set pragmas on", output, err))?;
+    }
+
+    // Without SpatiaLite the records are still imported, but nothing needing
+    // it is built.
+    let capabilities = if no_database {
+        spatialite::Capabilities::default()
+    } else {
+        if matches.is_present("require-spatialite") {
+            db::require_spatialite(&conn)?;
+        }
+        spatialite::probe(&conn)
+    };
+    let spatial = capabilities.spatial();
+
+    // Determine where to start reading from. Compressed input can't be
+    // seeked, so on resume we skip over the records which were consumed
+    // before the last checkpoint.
+    let hash = filter_hash(&matches);
+    let schema = db::Schema {
+        table,
+        // Checklist tables always have every column.
+        columns: if checklists { None } else { columns.as_deref() },
+        attach: attach.is_some(),
+        dictionary_encode: matches.is_present("dictionary-encode"),
+        checklists,
+        elevation: matches.is_present("dem"),
+        spatial,
+    };
+    let checkpoint = if matches.is_present("resume") && !no_database {
+        db::get_checkpoint(&conn, table, &hash)
+            .map_err(|err| db::setup_error("read the checkpoint from", output, err))?
+    } else {
+        None
+    };
+    let start = match checkpoint {
+        Some(start) => {
+            info!("resuming after {} records", start);
+            start
+        }
+        None if no_database => 0,
+        None => {
+            if matches.is_present("resume") {
+                info!("no checkpoint matching these options, starting from the beginning");
+            }
+            if attach.is_some() {
+                if !db::table_exists(&conn, "geometry_columns")
+                    .map_err(|err| db::setup_error("read", output, err))?
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Can only attach to a SpatiaLite database",
+                    ));
+                }
+                if db::table_exists(&conn, table)
+                    .map_err(|err| db::setup_error("read", output, err))?
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Table {} already exists, choose another --table", table),
+                    ));
+                }
             }
+            db::initialize_database(&conn, &schema)
+                .map_err(|err| db::setup_error("initialize", output, err))?;
+            0
+        }
+    };
+    let key = match (matches.value_of("partition-by"), &split_regions) {
+        (_, Some(regions)) => Some(partition::Key::Region(regions)),
+        (Some("year"), None) => Some(partition::Key::Year),
+        (Some("split"), None) => Some(partition::Key::Split),
+        (Some(_), None) => Some(partition::Key::Species),
+        (None, None) => None,
+    };
+    // Shared with the checkpoints taken as records are read.
+    let partitions = match key {
+        Some(_) if no_database => None,
+        Some(key) => Some(RefCell::new(partition::Partitions::new(
+            key,
+            &schema,
+            output,
+            matches.is_present("partition-files"),
+        ))),
+        None => None,
+    };
+    let mut dictionary = if matches.is_present("dictionary-encode") && !no_database {
+        let mut dictionary = dictionary::Dictionary::open(&conn)
+            .map_err(|err| db::setup_error("open the dictionaries in", output, err))?;
+        if let Some(counts) = counts.filter(|_| start == 0) {
+            dictionary
+                .fill(&conn, counts)
+                .map_err(|err| db::setup_error("fill the dictionaries in", output, err))?;
         }
+        Some(dictionary)
     } else {
         None
     };
 
-    let limit = match matches.value_of("limit") {
-        Some(text) => match text.parse::<usize>() {
-            Ok(limit) => limit,
-            Err(err) => {
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+    // Number of input records consumed, whether or not they were inserted,
+    // and the number at the last checkpoint.
+    let consumed = Cell::new(start);
+    let committed = Cell::new(start);
+    let mut inserted = 0;
+    let started_at = Utc::now();
+    conn.execute_batch("BEGIN")
+        .map_err(|err| db::setup_error("start a transaction on", output, err))?;
+
+    // Count records which could not be parsed or inserted, which usually
+    // means the column layout of the input has changed. The error rate is
+    // only checked once enough records have been read for it to be
+    // meaningful.
+    let errors = Cell::new(0);
+    // Records with invalid coordinates, whether dropped or flagged.
+    let rejected = Cell::new(0);
+    let too_many_errors = || {
+        let read = consumed.get() - start;
+        errors.get() > max_errors
+            || match max_error_rate {
+                Some(rate) => read >= 1000 && errors.get() as f64 / read as f64 > rate,
+                None => false,
             }
-        },
-        None => usize::max_value(),
     };
 
-    let mut conn = Connection::open("ebird.sqlite").unwrap();
-    initialize_database(&conn).unwrap();
-    let tx = conn.transaction().unwrap();
+    // On Ctrl-C or SIGTERM stop reading input, but commit what we have and
+    // leave a checkpoint so the import can be resumed.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        if let Err(err) = ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst)) {
+            warn!("could not install signal handler: {}", err);
+        }
+    }
 
-    reader
-        .deserialize()
-        .take(limit)
-        .filter_map(|deserialized| match deserialized {
-            Ok(deserialized) => {
-                let record: EBirdRecord = deserialized;
-                Some(record)
-            }
-            _ => None,
-        })
-        .filter(|record| {
-            if let Some(before_date) = &before_date {
+    // The filters which can be combined with --filter-logic, named after their
+    // options. Records have to pass any not named in it as well.
+    let mut filters: Vec<(&'static str, Rc<dyn filter::Filter>)> = Vec::new();
+    if let Some(before_date) = before_date {
+        filters.push((
+            "before-date",
+            filter::predicate(move |record| {
                 match NaiveDate::parse_from_str(&record.obs_date, "%Y-%m-%d") {
-                    Ok(other) => other <= *before_date,
+                    Ok(other) => other <= before_date,
                     _ => true,
                 }
-            } else {
-                true
-            }
-        })
-        .filter(|record| {
-            if let Some(since_date) = &since_date {
+            }),
+        ));
+    }
+    if let Some(since_date) = since_date {
+        filters.push((
+            "since-date",
+            filter::predicate(move |record| {
                 match NaiveDate::parse_from_str(&record.obs_date, "%Y-%m-%d") {
-                    Ok(other) => other >= *since_date,
+                    Ok(other) => other >= since_date,
                     _ => true,
                 }
+            }),
+        ));
+    }
+    if let Some(days) = &days_of_week {
+        filters.push((
+            "days-of-week",
+            filter::predicate(move |record| {
+                match NaiveDate::parse_from_str(&record.obs_date, "%Y-%m-%d") {
+                    Ok(date) => days.contains(&date.weekday()),
+                    _ => false,
+                }
+            }),
+        ));
+    }
+    if let Some(near) = &near {
+        filters.push((
+            "near-location",
+            filter::predicate(move |record| near.contains(record.latitude, record.longitude)),
+        ));
+    }
+    if let Some(regions) = &regions {
+        filters.push((
+            "region",
+            filter::predicate(move |record| {
+                regions.lookup(record.latitude, record.longitude).is_some()
+            }),
+        ));
+    }
+    if let Some(regions) = &checklist_regions {
+        filters.push((
+            "checklist-region",
+            filter::predicate(move |record| {
+                regions.lookup(record.latitude, record.longitude).is_some()
+            }),
+        ));
+    }
+    if let Some(regex) = &common_name_regex {
+        filters.push((
+            "common-name-regex",
+            filter::predicate(move |record| regex.is_match(&record.common_name)),
+        ));
+    }
+    if let Some(regex) = &scientific_name_regex {
+        filters.push((
+            "scientific-name-regex",
+            filter::predicate(move |record| regex.is_match(&record.scientific_name)),
+        ));
+    }
+    if let Some(alpha_codes) = &alpha_codes {
+        filters.push((
+            "alpha-codes",
+            filter::predicate(move |record| alpha_codes.is_match(&record.scientific_name)),
+        ));
+    }
+    if let Some(min_count) = min_count {
+        // Counts are either a number or X, if individuals weren't counted.
+        filters.push((
+            "min-count",
+            filter::predicate(
+                move |record| match record.observation_count.parse::<i64>() {
+                    Ok(count) => count >= min_count,
+                    Err(_) => keep_x && record.observation_count == "X",
+                },
+            ),
+        ));
+    }
+    if let Some(locality_types) = &locality_types {
+        // Records without a locality type, which the personal export and
+        // Darwin Core archives don't have, are never selected.
+        filters.push((
+            "locality-types",
+            filter::predicate(move |record| {
+                locality_types.contains(&record.locality_type.as_str())
+            }),
+        ));
+    }
+    if let Some(codes) = &bcr_codes {
+        filters.push((
+            "bcr",
+            filter::predicate(move |record| codes.contains(&record.bcr_code.as_str())),
+        ));
+    }
+    if let Some(codes) = &iba_codes {
+        filters.push((
+            "iba",
+            filter::predicate(move |record| codes.contains(&record.iba_code.as_str())),
+        ));
+    }
+    if let Some(codes) = &usfws_codes {
+        filters.push((
+            "usfws",
+            filter::predicate(move |record| codes.contains(&record.usfws_code.as_str())),
+        ));
+    }
+    if let Some(ids) = &checklist_ids {
+        filters.push((
+            "checklist-ids",
+            filter::predicate(move |record| ids.contains(&record.sampling_event_id)),
+        ));
+    }
+    if let Some(ids) = &observer_ids {
+        filters.push((
+            "observer-ids",
+            filter::predicate(move |record| ids.contains(&record.obs_id.as_str())),
+        ));
+    }
+    if let Some(ids) = &exclude_observer_ids {
+        filters.push((
+            "exclude-observer-ids",
+            filter::predicate(move |record| !ids.contains(&record.obs_id.as_str())),
+        ));
+    }
+    if unreviewed_only {
+        filters.push((
+            "unreviewed-only",
+            filter::predicate(|record| record.reviewed == 0),
+        ));
+    }
+    if nocturnal_only {
+        // The clock offset comes from --timezones when given, which follows
+        // daylight saving time, and otherwise from the longitude.
+        let time_zones = time_zones.clone();
+        filters.push((
+            "nocturnal-only",
+            filter::predicate(move |record| {
+                let utc_offset = time_zones
+                    .as_ref()
+                    .and_then(|time_zones| {
+                        let started = format!("{} {}", record.obs_date, record.time_obs_started);
+                        let naive =
+                            NaiveDateTime::parse_from_str(&started, "%Y-%m-%d %H:%M:%S").ok()?;
+                        time_zones.utc_offset(record.latitude, record.longitude, &naive)
+                    })
+                    .unwrap_or_else(|| sun::mean_utc_offset(record.longitude));
+                sun::is_nocturnal(
+                    &record.obs_date,
+                    &record.time_obs_started,
+                    record.latitude,
+                    record.longitude,
+                    utc_offset,
+                )
+            }),
+        ));
+    }
+    if let Some(effort) = &effort {
+        filters.push((
+            "effort",
+            filter::predicate(move |record| effort.is_match(record)),
+        ));
+    }
+    let script = match matches.value_of("script") {
+        Some(path) => Some(script::Script::load(path)?),
+        None => None,
+    };
+    let mut plugin = match matches.value_of("plugin") {
+        Some(path) => Some(plugin::Plugin::load(path)?),
+        None => None,
+    };
+    // The same filters for the checklists counted by --frequency, with those
+    // choosing species passing every checklist, since the frequency of a
+    // species is out of all the checklists it could have been reported on.
+    let mut checklist_filters = filters
+        .iter()
+        .map(|(stage, filter)| {
+            let filter = if SPECIES_FILTERS.contains(stage) {
+                filter::predicate(|_| true)
             } else {
-                true
+                filter.clone()
+            };
+            (*stage, filter)
+        })
+        .collect::<Vec<_>>();
+    let (filter_logic, checklist_logic) = match matches.value_of("filter-logic") {
+        Some(expression) => {
+            let (filter, used) = filter::parse(expression, &filters)?;
+            let (checklist_filter, _) = filter::parse(expression, &checklist_filters)?;
+            filters.retain(|(stage, _)| !used.contains(stage));
+            checklist_filters.retain(|(stage, _)| !used.contains(stage));
+            (Some(filter), Some(checklist_filter))
+        }
+        None => (None, None),
+    };
+
+    let telemetry = telemetry::Telemetry::new(
+        progress,
+        if input_format == "dwca" || via_duckdb {
+            None
+        } else {
+            Some(input_stats.clone())
+        },
+    );
+    if sampler.is_some() {
+        telemetry.add_stage("sample-fraction");
+    }
+    telemetry.add_stage("parse");
+    if shard.is_some() {
+        telemetry.add_stage("shard");
+    }
+    if !include_unapproved {
+        telemetry.add_stage("approved");
+    }
+    if split_regions.is_some() {
+        telemetry.add_stage("split-by");
+    }
+    for (stage, _) in &filters {
+        telemetry.add_stage(stage);
+    }
+    if filter_logic.is_some() {
+        telemetry.add_stage("filter-logic");
+    }
+    if invalid_coords == coords::InvalidCoords::Drop {
+        telemetry.add_stage("drop-invalid-coords");
+    }
+    if thinner.is_some() {
+        telemetry.add_stage("thin-distance");
+    }
+    if script.is_some() {
+        telemetry.add_stage("script");
+    }
+    if plugin.is_some() {
+        telemetry.add_stage("plugin");
+    }
+
+    let mut filtered = std::iter::from_fn(|| telemetry.reading(|| records.next()))
+        .skip(skip)
+        .take(limit)
+        .skip(start)
+        .take_while(|_| !interrupted.load(Ordering::SeqCst) && !too_many_errors())
+        .inspect(|_| {
+            // Every record consumed so far has been inserted or left out, so
+            // checkpoint here rather than after inserting a record, which
+            // might not happen for a long time with selective filters.
+            if consumed.get() - committed.get() >= batch_size {
+                let checkpoint =
+                    db::checkpoint(&conn, table, consumed.get(), &hash).and_then(|_| {
+                        match &partitions {
+                            Some(partitions) => partitions.borrow().checkpoint(),
+                            None => Ok(()),
+                        }
+                    });
+                match checkpoint {
+                    Ok(()) => committed.set(consumed.get()),
+                    Err(err) => error!("error on checkpoint: {}", err),
+                }
             }
+            consumed.set(consumed.get() + 1);
+            telemetry.record_read();
         })
-        .filter(|record| {
-            if let Some(near) = near {
-                let other = point!(x: record.longitude, y: record.latitude);
-                near.haversine_distance(&other) < buffer
-            } else {
-                true
+        .filter(|_| match &sampler {
+            Some(sampler) => sampler.keep(consumed.get()),
+            None => true,
+        })
+        .inspect(|_| telemetry.passed("sample-fraction"))
+        .filter_map(|deserialized| match deserialized {
+            Ok(record) => Some(record),
+            Err(err) => {
+                warn!("could not parse record: {}", err);
+                errors.set(errors.get() + 1);
+                None
             }
         })
+        .inspect(|_| telemetry.passed("parse"))
+        .filter(|record| match &shard {
+            Some(shard) => shard.keep(&record.sampling_event_id),
+            None => true,
+        })
+        .inspect(|_| telemetry.passed("shard"))
+        .filter(|record| match &split_regions {
+            Some(regions) => regions.lookup(record.latitude, record.longitude).is_some(),
+            None => true,
+        })
+        .inspect(|_| telemetry.passed("split-by"))
+        .filter(|record| include_unapproved || record.approved == 1)
+        .inspect(|_| telemetry.passed("approved"))
         .filter(|record| {
-            if let Some(common_name_regex) = &common_name_regex {
-                common_name_regex.is_match(&record.common_name)
+            filters.iter().all(|(stage, filter)| {
+                let keep = filter.matches(record);
+                if keep {
+                    telemetry.passed(stage);
+                }
+                keep
+            })
+        })
+        .filter(|record| match &filter_logic {
+            Some(filter) => filter.matches(record),
+            None => true,
+        })
+        .inspect(|_| telemetry.passed("filter-logic"))
+        // Coordinates are checked only for the selected records, so that the
+        // rejects and the count of dropped records don't include records
+        // which would have been left out anyway.
+        .filter_map(|mut record| {
+            if invalid_coords == coords::InvalidCoords::Keep {
+                return Some(record);
+            }
+            let reason = match coords::check(&record) {
+                Some(reason) => reason,
+                None => return Some(record),
+            };
+            rejected.set(rejected.get() + 1);
+            if let Some(rejects) = &mut rejects {
+                if let Err(err) = rejects.write(&record, reason) {
+                    warn!("could not write to rejects report: {}", err);
+                }
+            }
+            if invalid_coords == coords::InvalidCoords::Flag {
+                record.coord_flag = Some(reason.to_string());
+                Some(record)
             } else {
-                true
+                None
             }
         })
-        .filter(|record| {
-            if let Some(scientific_name_regex) = &scientific_name_regex {
-                scientific_name_regex.is_match(&record.scientific_name)
-            } else {
-                true
+        .inspect(|_| telemetry.passed("drop-invalid-coords"))
+        .filter(|record| match &mut thinner {
+            Some(thinner) => thinner.keep(record),
+            None => true,
+        })
+        .inspect(|_| telemetry.passed("thin-distance"))
+        .map(|mut record| {
+            // Formats which aren't read by column still have the stripped
+            // fields, and checklist tables have every column.
+            if strip_comments {
+                record.species_comments.clear();
+                record.trip_comments.clear();
+            }
+            if strip_observers {
+                record.obs_id.clear();
+            }
+            if let Some(splits) = &splits {
+                record.split = Some(splits.assign(&record.sampling_event_id).to_string());
+            }
+            if imperial {
+                record.effort_distance_mi = record.effort_distance_km.map(|km| km / KM_PER_MILE);
+            }
+            if let Some(obscurer) = &obscurer {
+                obscurer.apply(&mut record);
+            }
+            // Look up the time zone before the location is rounded.
+            if let Some(time_zones) = &time_zones {
+                time_zones.apply(&mut record);
+            }
+            if let Some(dem) = &dem {
+                dem.apply(&mut record);
+            }
+            if let Some(gazetteer) = &gazetteer {
+                gazetteer.apply(&mut record);
             }
+            if let Some(atlas_blocks) = &atlas_blocks {
+                atlas_blocks.apply(&mut record);
+            }
+            if let Some(precision) = coord_precision {
+                coords::round(&mut record, precision);
+            }
+            record
+        })
+        // Scripts and plugins run last, so that they see the fields filled in
+        // above.
+        .filter_map(|mut record| match &script {
+            Some(script) => match script.apply(&mut record) {
+                Ok(true) => Some(record),
+                Ok(false) => None,
+                Err(err) => {
+                    warn!("script failed on record {}: {}", record.guid, err);
+                    errors.set(errors.get() + 1);
+                    None
+                }
+            },
+            None => Some(record),
         })
-        .for_each(|record| {
-            if let Err(err) = insert_record(&tx, &record) {
-                println!("could not insert record: {}", err);
+        .inspect(|_| telemetry.passed("script"))
+        .filter_map(|mut record| match &mut plugin {
+            Some(plugin) => match plugin.apply(&mut record) {
+                Ok(true) => Some(record),
+                Ok(false) => None,
+                Err(err) => {
+                    warn!("plugin failed on record {}: {}", record.guid, err);
+                    errors.set(errors.get() + 1);
+                    None
+                }
+            },
+            None => Some(record),
+        })
+        .inspect(|_| telemetry.passed("plugin"));
+
+    if count_only {
+        count::print_counts(filtered, matches.value_of("count-by"))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        return Ok(());
+    }
+    if species_list {
+        species_list::print_species_list(filtered);
+        return Ok(());
+    }
+
+    while let Some(record) = telemetry.filtering(|| filtered.next()) {
+        telemetry.inserting(|| {
+            let result = match (&partitions, &mut dictionary) {
+                (Some(partitions), _) => partitions.borrow_mut().insert(&conn, &record),
+                (None, Some(dictionary)) => {
+                    db::insert_encoded(&conn, table, schema.columns, dictionary, &record)
+                }
+                (None, None) if checklists => db::insert_checklist_record(&conn, table, &record),
+                (None, None) => db::insert_record(&conn, table, schema.columns, &record),
+            };
+            match result {
+                Ok(_) => {
+                    inserted += 1;
+                    telemetry.record_inserted();
+                }
+                Err(err) => {
+                    warn!("could not insert record: {}", err);
+                    errors.set(errors.get() + 1);
+                }
             }
         });
-    if let Err(err) = tx.commit() {
-        println!("error on commit transaction: {}", err);
     }
 
+    if let Some(rejects) = &mut rejects {
+        rejects.flush()?;
+    }
+
+    telemetry.summary();
+
+    if let Some(repaired) = repaired.filter(|repaired| repaired.get() > 0) {
+        info!("repaired invalid UTF-8 in {} records", repaired.get());
+    }
+
+    let mut run = db::ImportRun {
+        table,
+        input: path,
+        input_format,
+        input_sha256: None,
+        parameters: filter_parameters(&matches),
+        started_at,
+        records_read: consumed.get() - start,
+        records_inserted: inserted,
+        errors: errors.get(),
+        status: "completed",
+    };
+    let interrupted = interrupted.load(Ordering::SeqCst);
+    if input_format != "dwca" && !via_duckdb && !interrupted && !matches.is_present("limit") {
+        run.input_sha256 = Some(input_stats.sha256());
+    }
+    let write_report = |run: &db::ImportRun| match matches.value_of("report") {
+        Some(path) => report::write_report(path, run, output, rejected.get(), &telemetry),
+        None => Ok(()),
+    };
+
+    // Leave the database as of the last checkpoint rather than committing
+    // records from what is probably a bad file.
+    if too_many_errors() {
+        run.status = "aborted";
+        if let Err(err) = conn
+            .execute_batch("ROLLBACK")
+            .and_then(|_| db::insert_import_run(&conn, &run))
+        {
+            error!("error recording import run: {}", err);
+        }
+        write_report(&run)?;
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Aborting import after {} errors in {} records",
+                errors.get(),
+                consumed.get() - start
+            ),
+        ));
+    }
+
+    if interrupted {
+        run.status = "interrupted";
+        db::insert_import_run(&conn, &run)
+            .and_then(|_| db::checkpoint(&conn, table, consumed.get(), &hash))
+            .and_then(|_| conn.execute_batch("COMMIT"))
+            .and_then(|_| match partitions {
+                Some(partitions) => partitions.into_inner().commit(),
+                None => Ok(()),
+            })
+            .map_err(|err| {
+                exit::with_code(
+                    exit::FAILURE,
+                    format!("Error on commit transaction: {}", err),
+                )
+            })?;
+        info!(
+            "interrupted after {} records, saved {} records; use --resume to continue",
+            consumed.get(),
+            inserted
+        );
+    } else {
+        db::insert_import_run(&conn, &run)
+            .and_then(|_| match &dictionary {
+                Some(dictionary) => dictionary.prune(&conn, table).map(|pruned| {
+                    if pruned > 0 {
+                        info!("removed {} dictionary values no record uses", pruned);
+                    }
+                }),
+                None => Ok(()),
+            })
+            .and_then(|_| db::clear_checkpoint(&conn, table))
+            .and_then(|_| conn.execute_batch("COMMIT"))
+            .map_err(|err| {
+                exit::with_code(
+                    exit::FAILURE,
+                    format!("Error on commit transaction: {}", err),
+                )
+            })?;
+        if spatial {
+            db::build_geometry(&conn, table).map_err(|err| {
+                exit::with_code(
+                    exit::EXTENSION_FAILED,
+                    format!("Error building geometry: {}", err),
+                )
+            })?;
+        }
+        db::create_indexes(&conn, table, &indexes).map_err(|err| {
+            exit::with_code(exit::FAILURE, format!("Error creating indexes: {}", err))
+        })?;
+        if let Some(partitions) = partitions {
+            partitions.into_inner().finish(&conn, &indexes);
+        }
+        // Leave out layers needing what this SpatiaLite doesn't have.
+        let available = |option: &str| match capabilities.missing_for(option) {
+            Some(missing) => {
+                warn!("skipping --{} since {} is not available", option, missing);
+                false
+            }
+            None => true,
+        };
+        if matches.is_present("fts") {
+            if let Err(err) = db::build_fts(&conn, table) {
+                error!("error building full text search table: {}", err);
+            }
+        }
+        if (matches.is_present("views") || matches.is_present("species-views"))
+            && available("views")
+        {
+            let species = matches
+                .values_of("species-views")
+                .map(|values| values.collect::<Vec<&str>>())
+                .unwrap_or_default();
+            if let Err(err) = db::create_views(&conn, table, &species) {
+                error!("error creating views: {}", err);
+            }
+        }
+        if let Some(sampling_events) = matches
+            .value_of("frequency")
+            .filter(|_| available("frequency"))
+        {
+            // Only count the complete checklists the import would have
+            // kept records of.
+            let keep = |checklist: &EBirdRecord| {
+                if let Some(shard) = &shard {
+                    if !shard.keep(&checklist.sampling_event_id) {
+                        return false;
+                    }
+                }
+                if let Some(regions) = &split_regions {
+                    if regions
+                        .lookup(checklist.latitude, checklist.longitude)
+                        .is_none()
+                    {
+                        return false;
+                    }
+                }
+                if let Some(filter) = &checklist_logic {
+                    if !filter.matches(checklist) {
+                        return false;
+                    }
+                }
+                checklist_filters
+                    .iter()
+                    .all(|(_, filter)| filter.matches(checklist))
+            };
+            let reader = csv::ReaderBuilder::new()
+                .delimiter(b'\t')
+                .quoting(false)
+                .flexible(true)
+                .from_reader(input::open_input(
+                    sampling_events,
+                    Arc::new(input::InputStats::default()),
+                )?);
+            // Every column is read, since any of them may be filtered on.
+            let checklists = ebd::EbdReader::sampling_events(reader)?;
+            if let Err(err) =
+                frequency::build_frequency(&conn, table, checklists, keep, frequency_grid)
+            {
+                error!("error building frequency table: {}", err);
+            }
+        }
+        if matches.is_present("effort-buffers") && available("effort-buffers") {
+            if let Err(err) = db::build_effort_buffers(&conn, table) {
+                error!("error building effort buffers: {}", err);
+            }
+        }
+        if matches.is_present("ranges") && available("ranges") {
+            let concave = matches.value_of("ranges") == Some("concave");
+            if let Err(err) = db::build_ranges(&conn, table, concave) {
+                error!("error building ranges: {}", err);
+            }
+        }
+        if matches.is_present("arrivals") {
+            if let Err(err) = arrivals::build_arrivals(&conn, table, arrival_regions.as_deref()) {
+                error!("error building arrivals table: {}", err);
+            }
+        }
+        if let Some(atlas_blocks) = atlas_blocks.as_ref().filter(|_| available("atlas-blocks")) {
+            if let Err(err) = atlas_blocks.build_summaries(&conn, table) {
+                error!("error building block summaries: {}", err);
+            }
+        }
+    }
+
+    write_report(&run)?;
+
+    // Records were left out, which a pipeline may want to treat differently
+    // from a complete import.
+    let dropped = if invalid_coords == coords::InvalidCoords::Drop {
+        rejected.get()
+    } else {
+        0
+    };
+    if interrupted || errors.get() > 0 || dropped > 0 {
+        return Err(exit::with_code(
+            exit::PARTIAL_IMPORT,
+            format!(
+                "Partial import: {} records could not be parsed or inserted, {} were dropped \
+                 for invalid coordinates{}",
+                errors.get(),
+                dropped,
+                if interrupted { ", interrupted" } else { "" }
+            ),
+        ));
+    }
     Ok(())
 }
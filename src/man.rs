@@ -0,0 +1,106 @@
+use clap::{App, ErrorKind};
+use ebird2spatialite::to_io_error;
+use std::io::{self, Write};
+
+// Escape a line of help for roff, where backslashes start escapes, a leading
+// dot or quote is a request and hyphens should stay hyphens.
+fn escape(line: &str) -> String {
+    let line = line.replace('\\', "\\\\").replace('-', "\\-");
+    if line.starts_with('.') || line.starts_with('\'') {
+        format!("\\&{}", line)
+    } else {
+        line
+    }
+}
+
+// Headings in clap's help are unindented lines ending in a colon, such as
+// USAGE: and OPTIONS:.
+fn heading(line: &str) -> Option<&str> {
+    if line.starts_with(' ') {
+        None
+    } else {
+        line.strip_suffix(':')
+    }
+}
+
+// Convert help text to roff, with each heading a section (or with subsection,
+// a subsection) and the lines under it kept as they are laid out. Lines
+// before the first heading, other than the name, describe the command.
+fn write_help(out: &mut impl Write, help: &str, section: &str) -> io::Result<()> {
+    let mut lines = help.lines().skip(1).peekable();
+    let mut about = Vec::new();
+    while let Some(line) = lines.next_if(|line| heading(line).is_none()) {
+        if !line.trim().is_empty() {
+            about.push(escape(line.trim()));
+        }
+    }
+    if !about.is_empty() {
+        writeln!(out, "{}", about.join("\n"))?;
+    }
+    let mut unfilled = false;
+    for line in lines {
+        if let Some(heading) = heading(line) {
+            if unfilled {
+                writeln!(out, ".fi")?;
+            }
+            writeln!(out, "{} {}", section, heading)?;
+            writeln!(out, ".nf")?;
+            unfilled = true;
+        } else if !line.trim().is_empty() {
+            writeln!(out, "{}", escape(line.trim_end()))?;
+        }
+    }
+    if unfilled {
+        writeln!(out, ".fi")?;
+    }
+    Ok(())
+}
+
+// The help of a subcommand, which clap returns as an error.
+fn subcommand_help(app: impl Fn() -> App<'static, 'static>, name: &str) -> io::Result<String> {
+    match app().get_matches_from_safe(["ebird2spatialite", name, "--help"]) {
+        Err(err) if err.kind == ErrorKind::HelpDisplayed => Ok(err.message),
+        Err(err) => Err(to_io_error(err.message)),
+        Ok(_) => Err(to_io_error(format!("No help for {}", name))),
+    }
+}
+
+// Write a man page for --generate-man from the help of the command and each of
+// its subcommands.
+pub fn write_man_page(
+    app: impl Fn() -> App<'static, 'static>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut help = Vec::new();
+    app().write_long_help(&mut help).map_err(to_io_error)?;
+    let help = String::from_utf8_lossy(&help);
+
+    writeln!(
+        out,
+        ".TH EBIRD2SPATIALITE 1 \"\" \"ebird2spatialite {}\" \"User Commands\"",
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(
+        out,
+        "ebird2spatialite \\- import eBird data into a SpatiaLite database"
+    )?;
+    write_help(out, &help, ".SH")?;
+
+    // The subcommands are listed in the help, with their descriptions wrapped
+    // onto lines indented further than their names.
+    let subcommands = help
+        .lines()
+        .skip_while(|line| heading(line) != Some("SUBCOMMANDS"))
+        .skip(1)
+        .take_while(|line| heading(line).is_none())
+        .filter(|line| line.starts_with("    ") && !line.starts_with("     "))
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| *name != "help")
+        .collect::<Vec<_>>();
+    for name in subcommands {
+        writeln!(out, ".SH \"ebird2spatialite {}\"", name)?;
+        write_help(out, &subcommand_help(&app, name)?, ".SS")?;
+    }
+    Ok(())
+}
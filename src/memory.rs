@@ -0,0 +1,170 @@
+use rusqlite::{params, Connection, NO_PARAMS};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tracing::info;
+
+// The memory an import may use, from --max-memory. Without it the buffers
+// are sized for speed on a machine with memory to spare.
+static MAX_MEMORY: OnceLock<usize> = OnceLock::new();
+
+// Roughly what each entry of a hash map of strings takes beyond the string
+// itself.
+pub const ENTRY_OVERHEAD: usize = 64;
+
+pub fn set_max_memory(bytes: usize) {
+    let _ = MAX_MEMORY.set(bytes);
+}
+
+// Parse a size such as 2GB, 512MB or 1048576 (bytes).
+pub fn parse_size(text: &str) -> Option<usize> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    Some((number * multiplier as f64) as usize)
+}
+
+// The budget is shared out as an eighth for each queue between the threads
+// reading the input, and a quarter each for the dictionary cache,
+// aggregation and SQLite's page cache. The rest is left for the records in
+// flight and everything else.
+fn share(divisor: usize) -> Option<usize> {
+    MAX_MEMORY.get().map(|bytes| bytes / divisor)
+}
+
+// The number of items of a size to hold in a queue which would otherwise
+// hold up to `default`.
+pub fn queue_length(item_size: usize, default: usize) -> usize {
+    match share(8) {
+        Some(bytes) => (bytes / item_size).max(2).min(default),
+        None => default,
+    }
+}
+
+// The bytes of dictionary values to keep in memory.
+pub fn cache_bytes() -> Option<usize> {
+    share(4)
+}
+
+// The bytes of counts to keep in memory before spilling them to disk.
+pub fn aggregation_bytes() -> Option<usize> {
+    share(4)
+}
+
+// The size of SQLite's page cache, in KiB.
+pub fn sqlite_cache_kib() -> Option<usize> {
+    share(4).map(|bytes| bytes >> 10)
+}
+
+// The order to read the counts of a tally in.
+pub enum Order {
+    Key,
+    MostCommon,
+}
+
+// Counts of strings, such as the values of a column, which are kept in
+// memory until they take up more than the aggregation budget and then added
+// to a table in a temporary database, which is slower but only limited by
+// disk space.
+#[derive(Default)]
+pub struct Tally {
+    counts: HashMap<String, i64>,
+    bytes: usize,
+    spilled: Option<Connection>,
+}
+
+impl Tally {
+    pub fn add(&mut self, key: &str) -> rusqlite::Result<()> {
+        match self.counts.get_mut(key) {
+            Some(count) => *count += 1,
+            None => {
+                self.bytes += key.len() + ENTRY_OVERHEAD;
+                self.counts.insert(key.to_string(), 1);
+            }
+        }
+        if aggregation_bytes().is_some_and(|limit| self.bytes > limit) {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> rusqlite::Result<()> {
+        if self.spilled.is_none() {
+            info!("spilling counts over the memory limit to a temporary database");
+            // An empty path opens a temporary database, deleted when closed.
+            let conn = Connection::open("")?;
+            conn.execute(
+                "CREATE TABLE tally (key TEXT PRIMARY KEY, count INTEGER) WITHOUT ROWID",
+                NO_PARAMS,
+            )?;
+            self.spilled = Some(conn);
+        }
+        let conn = self.spilled.as_ref().unwrap();
+        conn.execute_batch("BEGIN")?;
+        {
+            let mut stmt = conn.prepare_cached(
+                "INSERT INTO tally (key, count) VALUES (?1, ?2)
+                    ON CONFLICT (key) DO UPDATE SET count = count + excluded.count",
+            )?;
+            for (key, count) in self.counts.drain() {
+                stmt.execute(params![key, count])?;
+            }
+        }
+        conn.execute_batch("COMMIT")?;
+        self.bytes = 0;
+        Ok(())
+    }
+
+    // The number of distinct strings counted.
+    pub fn distinct(&mut self) -> rusqlite::Result<usize> {
+        if self.spilled.is_none() {
+            return Ok(self.counts.len());
+        }
+        self.spill()?;
+        let conn = self.spilled.as_ref().unwrap();
+        conn.query_row("SELECT COUNT(*) FROM tally", NO_PARAMS, |row| {
+            row.get::<_, i64>(0)
+        })
+        .map(|count| count as usize)
+    }
+
+    // Call f with each string and its count, in order.
+    pub fn for_each<F>(mut self, order: Order, mut f: F) -> rusqlite::Result<()>
+    where
+        F: FnMut(String, i64) -> rusqlite::Result<()>,
+    {
+        if self.spilled.is_none() {
+            let mut counts = self.counts.into_iter().collect::<Vec<_>>();
+            match order {
+                Order::Key => counts.sort(),
+                Order::MostCommon => {
+                    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+                }
+            }
+            for (key, count) in counts {
+                f(key, count)?;
+            }
+            return Ok(());
+        }
+        self.spill()?;
+        let conn = self.spilled.as_ref().unwrap();
+        let mut stmt = conn.prepare(match order {
+            Order::Key => "SELECT key, count FROM tally ORDER BY key",
+            Order::MostCommon => "SELECT key, count FROM tally ORDER BY count DESC, key",
+        })?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            f(row.get(0)?, row.get(1)?)?;
+        }
+        Ok(())
+    }
+}
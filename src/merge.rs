@@ -0,0 +1,225 @@
+use crate::db;
+use crate::to_io_error;
+use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
+use std::io;
+
+// The table or view to copy from in a database being merged, the columns it
+// shares with the merged table, and whether its locations have elevations.
+struct Input {
+    path: String,
+    source: String,
+    columns: Vec<String>,
+    elevation: bool,
+}
+
+impl Input {
+    fn open(path: &str, table: &str) -> io::Result<Input> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(to_io_error)?;
+        if !db::table_exists(&conn, table).map_err(to_io_error)? {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("No table {} in {}", table, path),
+            ));
+        }
+        if db::is_checklist_table(&conn, table).map_err(to_io_error)? {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Can't merge the checklist table in {}", path),
+            ));
+        }
+        let source = db::source_table(&conn, table).map_err(to_io_error)?;
+
+        // Databases made by older versions may lack some columns, which are
+        // left empty.
+        let mut existing = Vec::new();
+        let mut stmt = conn
+            .prepare(&format!("PRAGMA table_info({})", source))
+            .map_err(to_io_error)?;
+        let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+        while let Some(row) = rows.next().map_err(to_io_error)? {
+            existing.push(row.get::<_, String>(1).map_err(to_io_error)?);
+        }
+        let columns = ["latitude", "longitude"]
+            .iter()
+            .chain(db::COLUMNS.iter().filter(|column| **column != "location"))
+            .filter(|column| existing.iter().any(|existing| existing == *column))
+            .map(|column| column.to_string())
+            .collect();
+
+        let elevation = conn
+            .query_row(
+                "SELECT coord_dimension FROM geometry_columns
+                    WHERE f_table_name = lower(?1) AND f_geometry_column = 'location'",
+                params![table],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|dimension| dimension == 3)
+            .unwrap_or(false);
+
+        Ok(Input {
+            path: path.to_string(),
+            source,
+            columns,
+            elevation,
+        })
+    }
+}
+
+// Copy the records of an input into table, returning how many were merged
+// and how many were skipped as duplicates.
+fn merge(conn: &Connection, table: &str, input: &Input) -> rusqlite::Result<(usize, usize)> {
+    conn.execute("ATTACH DATABASE ?1 AS input", params![input.path])?;
+    let columns = input.columns.join(", ");
+    let total: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM input.{}", input.source),
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let merged = conn.execute(
+        &format!(
+            "INSERT INTO {table} ({columns})
+                SELECT {columns} FROM input.{source} AS record
+                    WHERE record.guid IS NULL OR record.guid = ''
+                        OR (NOT EXISTS (SELECT 1 FROM {table} WHERE guid = record.guid)
+                            AND record.id IN (SELECT MIN(id) FROM input.{source}
+                                GROUP BY guid))",
+            table = table,
+            columns = columns,
+            source = input.source
+        ),
+        NO_PARAMS,
+    )?;
+    conn.execute("DETACH DATABASE input", NO_PARAMS)?;
+    Ok((merged, total as usize - merged))
+}
+
+// Combine the tables of several databases, e.g. from imports of different
+// regions run on different machines, into a new table. Records with a GUID
+// already merged, from an earlier database or earlier in the same one, are
+// skipped.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let output = matches.value_of("output").unwrap();
+    let table = crate::table_name(matches)?;
+    let inputs = matches
+        .values_of("DATABASE")
+        .unwrap()
+        .map(|path| Input::open(path, table))
+        .collect::<io::Result<Vec<_>>>()?;
+    if inputs.iter().any(|input| input.path == output) {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "The output database can't be one of the databases being merged",
+        ));
+    }
+
+    let conn = Connection::open(output).map_err(to_io_error)?;
+    let schema = db::Schema {
+        table,
+        elevation: inputs.iter().any(|input| input.elevation),
+        ..Default::default()
+    };
+    db::require_spatialite(&conn)?;
+    db::initialize_database(&conn, &schema).map_err(to_io_error)?;
+    db::create_guid_index(&conn, table).map_err(to_io_error)?;
+
+    for input in &inputs {
+        let (merged, skipped) = merge(&conn, table, input).map_err(to_io_error)?;
+        println!(
+            "{}: merged {} records, skipped {} duplicates",
+            input.path, merged, skipped
+        );
+    }
+
+    db::build_geometry(&conn, table).map_err(to_io_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EBirdRecord;
+
+    fn schema<'a>(columns: Option<&'a [&'a str]>) -> db::Schema<'a> {
+        db::Schema {
+            columns,
+            spatial: false,
+            ..Default::default()
+        }
+    }
+
+    // A database in the temporary directory holding a record for each GUID.
+    fn database(name: &str, columns: Option<&[&str]>, guids: &[&str]) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "ebird2spatialite-merge-{}-{}.sqlite",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        let conn = Connection::open(&path).unwrap();
+        db::initialize_database(&conn, &schema(columns)).unwrap();
+        for guid in guids {
+            let record = EBirdRecord {
+                guid: guid.to_string(),
+                common_name: "Brown Thrasher".to_string(),
+                latitude: 45.4,
+                longitude: -75.7,
+                ..Default::default()
+            };
+            db::insert_record(&conn, "ebird", columns, &record).unwrap();
+        }
+        path.to_str().unwrap().to_string()
+    }
+
+    fn output() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::initialize_database(&conn, &schema(None)).unwrap();
+        db::create_guid_index(&conn, "ebird").unwrap();
+        conn
+    }
+
+    #[test]
+    fn skips_guids_already_merged() {
+        let first = database("first", None, &["URN:1", "URN:2", "URN:2"]);
+        let second = database("second", None, &["URN:2", "URN:3", "", ""]);
+        let conn = output();
+        let first = Input::open(&first, "ebird").unwrap();
+        let second = Input::open(&second, "ebird").unwrap();
+        assert_eq!(merge(&conn, "ebird", &first).unwrap(), (2, 1));
+        assert_eq!(merge(&conn, "ebird", &second).unwrap(), (3, 1));
+        let guids = conn
+            .prepare("SELECT guid FROM ebird ORDER BY id")
+            .unwrap()
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(guids, vec!["URN:1", "URN:2", "URN:3", "", ""]);
+    }
+
+    #[test]
+    fn leaves_missing_columns_empty() {
+        let path = database("columns", Some(&["guid"]), &["URN:1"]);
+        let conn = output();
+        let input = Input::open(&path, "ebird").unwrap();
+        assert!(!input.columns.contains(&"common_name".to_string()));
+        assert_eq!(merge(&conn, "ebird", &input).unwrap(), (1, 0));
+        let (guid, common_name, latitude): (String, Option<String>, f64) = conn
+            .query_row(
+                "SELECT guid, common_name, latitude FROM ebird",
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(guid, "URN:1");
+        assert_eq!(common_name, None);
+        assert_eq!(latitude, 45.4);
+    }
+
+    #[test]
+    fn rejects_a_missing_table() {
+        let path = database("table", None, &["URN:1"]);
+        let err = Input::open(&path, "other").err().unwrap();
+        assert!(err.to_string().contains("No table other"));
+    }
+}
@@ -0,0 +1,55 @@
+use crate::db;
+use crate::to_io_error;
+use rusqlite::Connection;
+use std::io;
+
+// Upgrade a table made by an older version of ebird2spatialite to the current
+// schema, so that it can be updated and queried like a new import without
+// importing everything again.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = matches.value_of("DATABASE").unwrap();
+    let table = crate::table_name(matches)?;
+
+    let conn = Connection::open(path).map_err(to_io_error)?;
+    if !db::table_exists(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No table {} in {}", table, path),
+        ));
+    }
+    let version = db::schema_version(&conn, table).map_err(to_io_error)?;
+    if version > db::SCHEMA_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} has schema version {}, which is newer than this version of \
+                 ebird2spatialite supports",
+                table, version
+            ),
+        ));
+    }
+    if version == db::SCHEMA_VERSION {
+        println!("{} is already at schema version {}", table, version);
+        return Ok(());
+    }
+
+    db::require_spatialite(&conn)?;
+    let added = db::migrate(&conn, table).map_err(to_io_error)?;
+    if added.is_empty() {
+        println!(
+            "migrated {} from schema version {} to {}",
+            table,
+            version,
+            db::SCHEMA_VERSION
+        );
+    } else {
+        println!(
+            "migrated {} from schema version {} to {}, adding {}",
+            table,
+            version,
+            db::SCHEMA_VERSION,
+            added.join(", ")
+        );
+    }
+    Ok(())
+}
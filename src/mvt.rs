@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+// Mapbox vector tiles (version 2.1) of points, encoded by hand since only a
+// few protobuf fields are needed.
+
+// The number of units across a tile.
+pub const EXTENT: u32 = 4096;
+
+// Protobuf wire types.
+const VARINT: u32 = 0;
+const LENGTH_DELIMITED: u32 = 2;
+
+// Geometry types and commands.
+const POINT: u64 = 1;
+const MOVE_TO: u32 = 1;
+
+pub enum PropertyValue {
+    String(String),
+    Int(i64),
+}
+
+pub struct Feature {
+    pub x: u32,
+    pub y: u32,
+    pub properties: Vec<(&'static str, PropertyValue)>,
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_key(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(buf, u64::from(field << 3 | wire_type));
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(buf, field, LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_uint(buf: &mut Vec<u8>, field: u32, value: u64) {
+    write_key(buf, field, VARINT);
+    write_varint(buf, value);
+}
+
+fn write_packed(buf: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = Vec::new();
+    for value in values {
+        write_varint(&mut packed, u64::from(*value));
+    }
+    write_bytes(buf, field, &packed);
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Encode a tile with a single layer of points. Keys and values are shared by
+// every feature, with each feature's tags indexing into them.
+pub fn encode(layer_name: &str, features: &[Feature]) -> Vec<u8> {
+    let mut keys: Vec<&str> = Vec::new();
+    let mut values: Vec<Vec<u8>> = Vec::new();
+    let mut value_indexes: HashMap<Vec<u8>, u32> = HashMap::new();
+
+    let mut layer = Vec::new();
+    write_uint(&mut layer, 15, 2);
+    write_bytes(&mut layer, 1, layer_name.as_bytes());
+    for (id, feature) in features.iter().enumerate() {
+        let mut tags = Vec::with_capacity(feature.properties.len() * 2);
+        for (key, value) in &feature.properties {
+            let key_index = match keys.iter().position(|existing| existing == key) {
+                Some(index) => index as u32,
+                None => {
+                    keys.push(key);
+                    keys.len() as u32 - 1
+                }
+            };
+            let mut encoded = Vec::new();
+            match value {
+                PropertyValue::String(text) => write_bytes(&mut encoded, 1, text.as_bytes()),
+                PropertyValue::Int(number) => {
+                    write_key(&mut encoded, 6, VARINT);
+                    write_varint(&mut encoded, zigzag(*number));
+                }
+            }
+            let value_index = *value_indexes.entry(encoded.clone()).or_insert_with(|| {
+                values.push(encoded);
+                values.len() as u32 - 1
+            });
+            tags.push(key_index);
+            tags.push(value_index);
+        }
+
+        let mut encoded = Vec::new();
+        write_uint(&mut encoded, 1, id as u64 + 1);
+        write_packed(&mut encoded, 2, &tags);
+        write_uint(&mut encoded, 3, POINT);
+        write_packed(
+            &mut encoded,
+            4,
+            &[
+                MOVE_TO | 1 << 3,
+                zigzag(i64::from(feature.x)) as u32,
+                zigzag(i64::from(feature.y)) as u32,
+            ],
+        );
+        write_bytes(&mut layer, 2, &encoded);
+    }
+    for key in keys {
+        write_bytes(&mut layer, 3, key.as_bytes());
+    }
+    for value in values {
+        write_bytes(&mut layer, 4, &value);
+    }
+    write_uint(&mut layer, 5, u64::from(EXTENT));
+
+    let mut tile = Vec::new();
+    write_bytes(&mut tile, 3, &layer);
+    tile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_varint(bytes: &[u8], position: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[*position];
+            *position += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    // The fields of a message as (field, wire type, value or bytes).
+    fn read_fields(bytes: &[u8]) -> Vec<(u32, u32, u64, &[u8])> {
+        let mut fields = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let key = read_varint(bytes, &mut position);
+            let (field, wire_type) = ((key >> 3) as u32, (key & 7) as u32);
+            let value = read_varint(bytes, &mut position);
+            if wire_type == LENGTH_DELIMITED {
+                let end = position + value as usize;
+                fields.push((field, wire_type, value, &bytes[position..end]));
+                position = end;
+            } else {
+                fields.push((field, wire_type, value, &[][..]));
+            }
+        }
+        fields
+    }
+
+    fn read_packed(bytes: &[u8]) -> Vec<u64> {
+        let mut values = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            values.push(read_varint(bytes, &mut position));
+        }
+        values
+    }
+
+    #[test]
+    fn varint() {
+        for (value, expected) in [
+            (0, &[0x00][..]),
+            (1, &[0x01]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (300, &[0xac, 0x02]),
+            (
+                u64::MAX,
+                &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+            ),
+        ] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(buf, expected);
+            assert_eq!(read_varint(&buf, &mut 0), value);
+        }
+    }
+
+    #[test]
+    fn zigzag_values() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+        assert_eq!(zigzag(2048), 4096);
+        assert_eq!(zigzag(i64::MAX), u64::MAX - 1);
+        assert_eq!(zigzag(i64::MIN), u64::MAX);
+    }
+
+    #[test]
+    fn key() {
+        let mut buf = Vec::new();
+        write_key(&mut buf, 3, LENGTH_DELIMITED);
+        write_key(&mut buf, 15, VARINT);
+        write_key(&mut buf, 16, VARINT);
+        assert_eq!(buf, [0x1a, 0x78, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn tile_round_trip() {
+        let features = [
+            Feature {
+                x: 8,
+                y: 4088,
+                properties: vec![
+                    ("records", PropertyValue::Int(3)),
+                    (
+                        "common_name",
+                        PropertyValue::String("Snowy Owl".to_string()),
+                    ),
+                ],
+            },
+            Feature {
+                x: 2056,
+                y: 24,
+                properties: vec![("records", PropertyValue::Int(3))],
+            },
+        ];
+        let tile = encode("ebird", &features);
+
+        let layers = read_fields(&tile);
+        assert_eq!(layers.len(), 1);
+        assert_eq!((layers[0].0, layers[0].1), (3, LENGTH_DELIMITED));
+        let layer = read_fields(layers[0].3);
+        let field = |number| layer.iter().filter(move |field| field.0 == number);
+        assert_eq!(field(15).map(|field| field.2).collect::<Vec<_>>(), [2]);
+        assert_eq!(
+            field(1).map(|field| field.3).collect::<Vec<_>>(),
+            [b"ebird"]
+        );
+        assert_eq!(
+            field(5).map(|field| field.2).collect::<Vec<_>>(),
+            [u64::from(EXTENT)]
+        );
+        let keys = field(3).map(|field| field.3).collect::<Vec<_>>();
+        assert_eq!(keys, [&b"records"[..], b"common_name"]);
+        // Both features have 3 records, so share the value.
+        let values = field(4)
+            .map(|field| read_fields(field.3))
+            .collect::<Vec<_>>();
+        assert_eq!(values.len(), 2);
+        assert_eq!((values[0][0].0, values[0][0].2), (6, zigzag(3)));
+        assert_eq!((values[1][0].0, values[1][0].3), (1, &b"Snowy Owl"[..]));
+
+        let decoded = field(2)
+            .map(|field| read_fields(field.3))
+            .collect::<Vec<_>>();
+        assert_eq!(decoded.len(), 2);
+        for (index, (feature, fields)) in features.iter().zip(&decoded).enumerate() {
+            assert_eq!((fields[0].0, fields[0].2), (1, index as u64 + 1));
+            assert_eq!(fields[1].0, 2);
+            let tags = read_packed(fields[1].3);
+            let properties = tags
+                .chunks(2)
+                .map(|tag| (keys[tag[0] as usize], &values[tag[1] as usize]))
+                .collect::<Vec<_>>();
+            assert_eq!(properties.len(), feature.properties.len());
+            for ((key, value), (name, expected)) in properties.iter().zip(&feature.properties) {
+                assert_eq!(*key, name.as_bytes());
+                match expected {
+                    PropertyValue::Int(number) => assert_eq!(value[0].2, zigzag(*number)),
+                    PropertyValue::String(text) => assert_eq!(value[0].3, text.as_bytes()),
+                }
+            }
+            assert_eq!((fields[2].0, fields[2].2), (3, POINT));
+            assert_eq!(fields[3].0, 4);
+            assert_eq!(
+                read_packed(fields[3].3),
+                [
+                    u64::from(MOVE_TO | 1 << 3),
+                    zigzag(i64::from(feature.x)),
+                    zigzag(i64::from(feature.y))
+                ]
+            );
+        }
+    }
+}
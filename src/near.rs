@@ -0,0 +1,81 @@
+use geo::algorithm::haversine_distance::HaversineDistance;
+use geo::algorithm::vincenty_distance::VincentyDistance;
+use geo::{point, Point};
+
+// The radius of the sphere haversine distances are measured on, in metres.
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+// Distances on the ellipsoid can be a little longer than on the sphere for
+// the same angle, so the bounding box is made this much larger to be sure it
+// holds everything within the buffer.
+const BOX_MARGIN: f64 = 1.01;
+
+// How distances from --near-location are measured: on a sphere, or on the
+// WGS84 ellipsoid, which is slower but accurate to within millimetres.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Metric {
+    Haversine,
+    Vincenty,
+}
+
+// Selects records within a distance of a point. Most records of a large
+// input are far away, so those outside a bounding box around the buffer are
+// rejected by comparing coordinates before measuring the distance.
+pub struct Near {
+    point: Point<f64>,
+    buffer: f64,
+    metric: Metric,
+    // Half the height and width of the box, in degrees. The box has no
+    // width if it takes in a pole.
+    latitude_delta: f64,
+    longitude_delta: Option<f64>,
+}
+
+impl Near {
+    pub fn new(point: Point<f64>, buffer: f64, metric: Metric) -> Near {
+        let angle = buffer * BOX_MARGIN / EARTH_RADIUS;
+        let latitude_delta = angle.to_degrees();
+        // The widest part of a circle on a sphere is nearer the pole than its
+        // centre, at the longitude where its edge is tangent to a meridian.
+        let ratio = angle.sin() / point.y().to_radians().cos();
+        let longitude_delta = if point.y().abs() + latitude_delta >= 90.
+            || angle >= std::f64::consts::FRAC_PI_2
+            || ratio >= 1.
+        {
+            None
+        } else {
+            Some(ratio.asin().to_degrees())
+        };
+        Near {
+            point,
+            buffer,
+            metric,
+            latitude_delta,
+            longitude_delta,
+        }
+    }
+
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        if (latitude - self.point.y()).abs() > self.latitude_delta {
+            return false;
+        }
+        if let Some(longitude_delta) = self.longitude_delta {
+            // The box may cross the antimeridian.
+            let difference = (longitude - self.point.x()).abs() % 360.;
+            if difference.min(360. - difference) > longitude_delta {
+                return false;
+            }
+        }
+        let other = point!(x: longitude, y: latitude);
+        let distance = match self.metric {
+            Metric::Haversine => self.point.haversine_distance(&other),
+            // Vincenty's formula doesn't converge for nearly antipodal
+            // points, which are far outside any useful buffer anyway.
+            Metric::Vincenty => self
+                .point
+                .vincenty_distance(&other)
+                .unwrap_or_else(|_| self.point.haversine_distance(&other)),
+        };
+        distance < self.buffer
+    }
+}
@@ -0,0 +1,192 @@
+use crate::sample;
+use crate::EBirdRecord;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+
+// Approximate length of a degree of latitude, in metres.
+const METRES_PER_DEGREE: f64 = 111_320.;
+
+#[derive(Clone, PartialEq)]
+pub enum Method {
+    // Move to the centre of a grid cell of the obscuring radius.
+    Snap,
+    // Move by a pseudo-random offset within the obscuring radius, derived
+    // from a secret key so that it can't be recomputed and subtracted.
+    Jitter { key: String },
+}
+
+// Obscures the locations of sensitive species, such as nesting owls and
+// raptors, so that exact sites aren't exposed by maps made from the data.
+pub struct Obscurer {
+    // Lower cased common and scientific names of species to obscure.
+    species: HashSet<String>,
+    radius: f64,
+    method: Method,
+}
+
+impl Obscurer {
+    // Species are read one per line, by common or scientific name. Blank
+    // lines and lines starting with '#' are ignored.
+    pub fn load(path: &str, radius: f64, method: Method) -> io::Result<Obscurer> {
+        let species = fs::read_to_string(path)?
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_lowercase())
+            .collect();
+        Ok(Obscurer {
+            species,
+            radius,
+            method,
+        })
+    }
+
+    fn is_sensitive(&self, record: &EBirdRecord) -> bool {
+        self.species.contains(&record.common_name.to_lowercase())
+            || self
+                .species
+                .contains(&record.scientific_name.to_lowercase())
+    }
+
+    pub fn apply(&self, record: &mut EBirdRecord) {
+        if !self.is_sensitive(record) {
+            return;
+        }
+
+        // The name of the place would give the site away as well as the
+        // coordinates.
+        record.locality.clear();
+        let lat_step = self.radius / METRES_PER_DEGREE;
+        match &self.method {
+            Method::Snap => {
+                let latitude = ((record.latitude / lat_step).floor() + 0.5) * lat_step;
+                // Keep cells roughly square by widening them towards the
+                // poles.
+                let lon_step = lat_step / latitude.to_radians().cos().max(0.01);
+                let longitude = ((record.longitude / lon_step).floor() + 0.5) * lon_step;
+                record.latitude = latitude.clamp(-90., 90.);
+                record.longitude = longitude.clamp(-180., 180.);
+            }
+            Method::Jitter { key } => {
+                // The offset depends only on the key, the species and the
+                // grid cell of the radius the record is in, so every record
+                // of a site moves to the same place rather than scattering
+                // around it, which would give the site away. Without the key
+                // the candidate cells around a point can't be tried to undo
+                // the offset.
+                let cell = format!(
+                    "{}\n{} {} {}",
+                    key,
+                    record.scientific_name.to_lowercase(),
+                    (record.latitude / lat_step).floor(),
+                    (record.longitude / lat_step).floor()
+                );
+                let digest = Sha256::digest(cell.as_bytes());
+                let mut seed = [0; 8];
+                seed.copy_from_slice(&digest[..8]);
+                let seed = u64::from_le_bytes(seed);
+                // Uniformly distributed within a circle of the radius.
+                let distance = lat_step * sample::uniform(seed, 0).sqrt();
+                let bearing = 2. * PI * sample::uniform(seed, 1);
+                let lon_scale = record.latitude.to_radians().cos().max(0.01);
+                let longitude = record.longitude + distance * bearing.sin() / lon_scale;
+                record.latitude = (record.latitude + distance * bearing.cos()).clamp(-90., 90.);
+                record.longitude = (longitude + 180.).rem_euclid(360.) - 180.;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obscurer(method: Method) -> Obscurer {
+        Obscurer {
+            species: ["snowy owl".to_string()].iter().cloned().collect(),
+            radius: 5000.,
+            method,
+        }
+    }
+
+    fn record(common_name: &str, latitude: f64, longitude: f64) -> EBirdRecord {
+        EBirdRecord {
+            common_name: common_name.to_string(),
+            scientific_name: "Bubo scandiacus".to_string(),
+            latitude,
+            longitude,
+            locality: "Nest by the old barn".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn jitter(key: &str) -> Method {
+        Method::Jitter {
+            key: key.to_string(),
+        }
+    }
+
+    // Roughly, in metres.
+    fn distance(a: &EBirdRecord, b: &EBirdRecord) -> f64 {
+        let dx = (a.longitude - b.longitude) * a.latitude.to_radians().cos();
+        let dy = a.latitude - b.latitude;
+        (dx * dx + dy * dy).sqrt() * METRES_PER_DEGREE
+    }
+
+    #[test]
+    fn leaves_other_species() {
+        let mut other = record("Great Horned Owl", 45.42, -75.69);
+        other.scientific_name = "Bubo virginianus".to_string();
+        obscurer(jitter("secret")).apply(&mut other);
+        assert_eq!((other.latitude, other.longitude), (45.42, -75.69));
+        assert_eq!(other.locality, "Nest by the old barn");
+    }
+
+    #[test]
+    fn snaps_to_the_cell_centre() {
+        let obscurer = obscurer(Method::Snap);
+        let mut a = record("Snowy Owl", 45.4201, -75.6901);
+        let mut b = record("snowy owl", 45.4202, -75.6902);
+        obscurer.apply(&mut a);
+        obscurer.apply(&mut b);
+        assert_eq!((a.latitude, a.longitude), (b.latitude, b.longitude));
+        assert!(distance(&a, &record("", 45.4201, -75.6901)) < 5000.);
+        assert!(a.locality.is_empty());
+    }
+
+    #[test]
+    fn jitters_a_site_to_one_place_within_the_radius() {
+        let obscurer = obscurer(jitter("secret"));
+        let original = record("Snowy Owl", 45.4201, -75.6901);
+        let mut a = original.clone();
+        let mut b = original.clone();
+        obscurer.apply(&mut a);
+        obscurer.apply(&mut b);
+        assert_eq!((a.latitude, a.longitude), (b.latitude, b.longitude));
+        assert_ne!((a.latitude, a.longitude), (45.4201, -75.6901));
+        assert!(distance(&a, &original) < 5000.);
+        assert!(a.locality.is_empty());
+    }
+
+    #[test]
+    fn jitter_depends_on_the_key() {
+        let mut a = record("Snowy Owl", 45.4201, -75.6901);
+        let mut b = a.clone();
+        obscurer(jitter("secret")).apply(&mut a);
+        obscurer(jitter("another secret")).apply(&mut b);
+        assert_ne!((a.latitude, a.longitude), (b.latitude, b.longitude));
+    }
+
+    #[test]
+    fn jitter_wraps_longitude() {
+        let obscurer = obscurer(jitter("secret"));
+        for longitude in &[-179.9999, 179.9999] {
+            let mut record = record("Snowy Owl", 64.5, *longitude);
+            obscurer.apply(&mut record);
+            assert!((-180. ..180.).contains(&record.longitude));
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use crate::db;
+use crate::regions::Regions;
+use crate::EBirdRecord;
+use rusqlite::{params, Connection, NO_PARAMS};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{error, info};
+
+// What records are partitioned by, with --partition-by or --split-by.
+pub enum Key<'a> {
+    Species,
+    Year,
+    Split,
+    Region(&'a Regions),
+}
+
+impl Key<'_> {
+    fn value(&self, record: &EBirdRecord) -> String {
+        match self {
+            Key::Species => record.common_name.clone(),
+            // Dates are YYYY-MM-DD.
+            Key::Year => record.obs_date.chars().take(4).collect(),
+            Key::Split => record.split.clone().unwrap_or_default(),
+            // Records outside every region have already been dropped.
+            Key::Region(regions) => regions
+                .lookup(record.latitude, record.longitude)
+                .map(|region| region.name.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// A name usable in table and file names, e.g. red_tailed_hawk for
+// Red-tailed Hawk.
+fn slug(value: &str) -> String {
+    let mut slug = String::new();
+    for c in value.to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    let slug = slug.trim_end_matches('_');
+    if slug.is_empty() {
+        "unknown".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+// The most partition databases to keep open with --partition-files, well
+// under the usual limit of 1024 open files. Past this the least recently
+// used database is committed and closed, and opened again when needed.
+const MAX_OPEN_FILES: usize = 64;
+
+struct Partition {
+    // The database of the partition with --partition-files, otherwise the
+    // partition is a table in the main database.
+    path: Option<String>,
+    // The open connection to the database, in a transaction, unless it was
+    // closed to stay under MAX_OPEN_FILES.
+    conn: Option<Connection>,
+    table: String,
+    records: usize,
+    // When the partition last had a record inserted.
+    used: u64,
+}
+
+impl Partition {
+    fn closed(&self) -> bool {
+        self.path.is_some() && self.conn.is_none()
+    }
+}
+
+// Open a partition database created earlier in the import.
+fn open(path: &str, spatial: bool) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    if spatial {
+        db::load_spatialite(&conn)?;
+    }
+    Ok(conn)
+}
+
+// Routes records into a table per partition, e.g. ebird_american_robin, or
+// with --partition-files a database per partition, e.g.
+// ebird_american_robin.sqlite, created as each partition is first seen.
+pub struct Partitions<'a> {
+    key: Key<'a>,
+    table: &'a str,
+    columns: Option<&'a [&'a str]>,
+    checklists: bool,
+    elevation: bool,
+    spatial: bool,
+    // The main database path without its extension, with --partition-files.
+    files: Option<String>,
+    partitions: BTreeMap<String, Partition>,
+    // The number of records inserted, to order partitions by last use.
+    uses: u64,
+}
+
+impl<'a> Partitions<'a> {
+    pub fn new(
+        key: Key<'a>,
+        schema: &db::Schema<'a>,
+        database: &str,
+        files: bool,
+    ) -> Partitions<'a> {
+        let files = if files {
+            let path = Path::new(database);
+            Some(path.with_extension("").to_string_lossy().to_string())
+        } else {
+            None
+        };
+        Partitions {
+            key,
+            table: schema.table,
+            columns: schema.columns,
+            checklists: schema.checklists,
+            elevation: schema.elevation,
+            spatial: schema.spatial,
+            files,
+            partitions: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    // Close the least recently used partition database if as many as can be
+    // are open.
+    fn make_room(&mut self) -> rusqlite::Result<()> {
+        let mut open = self
+            .partitions
+            .values_mut()
+            .filter(|partition| partition.conn.is_some())
+            .collect::<Vec<_>>();
+        if open.len() < MAX_OPEN_FILES {
+            return Ok(());
+        }
+        open.sort_by_key(|partition| partition.used);
+        match open[0].conn.take() {
+            Some(conn) => conn.execute_batch("COMMIT"),
+            None => Ok(()),
+        }
+    }
+
+    fn create(&self, conn: &Connection, name: &str) -> rusqlite::Result<Partition> {
+        let partition = match &self.files {
+            Some(stem) => {
+                let path = format!("{}_{}.sqlite", stem, name);
+                let conn = Connection::open(&path)?;
+                let schema = db::Schema {
+                    table: self.table,
+                    columns: self.columns,
+                    checklists: self.checklists,
+                    elevation: self.elevation,
+                    spatial: self.spatial,
+                    ..Default::default()
+                };
+                db::initialize_database(&conn, &schema)?;
+                conn.execute_batch("BEGIN")?;
+                Partition {
+                    path: Some(path),
+                    conn: Some(conn),
+                    table: self.table.to_string(),
+                    records: 0,
+                    used: 0,
+                }
+            }
+            None => {
+                // The import is already in a transaction, so drop any table
+                // left from an earlier import here and add the new one as if
+                // attaching to an existing database.
+                let table = format!("{}_{}", self.table, name);
+                if self.spatial && db::table_exists(conn, &table)? {
+                    let mut stmt = conn.prepare("SELECT DropGeoTable(?1)")?;
+                    stmt.exists(params![table])?;
+                }
+                conn.execute(&format!("DROP TABLE IF EXISTS {}", table), NO_PARAMS)?;
+                let schema = db::Schema {
+                    table: &table,
+                    attach: true,
+                    columns: self.columns,
+                    checklists: self.checklists,
+                    elevation: self.elevation,
+                    spatial: self.spatial,
+                    ..Default::default()
+                };
+                db::initialize_database(conn, &schema)?;
+                Partition {
+                    path: None,
+                    conn: None,
+                    table,
+                    records: 0,
+                    used: 0,
+                }
+            }
+        };
+        Ok(partition)
+    }
+
+    pub fn insert(&mut self, conn: &Connection, record: &EBirdRecord) -> rusqlite::Result<usize> {
+        let name = slug(&self.key.value(record));
+        match self.partitions.get(&name).map(Partition::closed) {
+            None => {
+                if self.files.is_some() {
+                    self.make_room()?;
+                }
+                let partition = self.create(conn, &name)?;
+                self.partitions.insert(name.clone(), partition);
+            }
+            Some(true) => {
+                self.make_room()?;
+                let spatial = self.spatial;
+                let partition = self.partitions.get_mut(&name).unwrap();
+                let reopened = open(partition.path.as_ref().unwrap(), spatial)?;
+                reopened.execute_batch("BEGIN")?;
+                partition.conn = Some(reopened);
+            }
+            Some(false) => (),
+        }
+        self.uses += 1;
+        let checklists = self.checklists;
+        let partition = self.partitions.get_mut(&name).unwrap();
+        partition.used = self.uses;
+        let conn = partition.conn.as_ref().unwrap_or(conn);
+        let result = if checklists {
+            db::insert_checklist_record(conn, &partition.table, record)
+        } else {
+            db::insert_record(conn, &partition.table, self.columns, record)
+        };
+        if result.is_ok() {
+            partition.records += 1;
+        }
+        result
+    }
+
+    // Commit the open partition databases along with a checkpoint of the
+    // main database.
+    pub fn checkpoint(&self) -> rusqlite::Result<()> {
+        for conn in self.partitions.values().filter_map(|p| p.conn.as_ref()) {
+            conn.execute_batch("COMMIT; BEGIN")?;
+        }
+        Ok(())
+    }
+
+    // Commit the open partition databases, leaving the records so far when
+    // the import is interrupted.
+    pub fn commit(self) -> rusqlite::Result<()> {
+        for conn in self.partitions.values().filter_map(|p| p.conn.as_ref()) {
+            conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+
+    // Commit the partition databases, then build the geometry and indexes of
+    // every partition, once the import into the main database has been
+    // committed.
+    pub fn finish(mut self, conn: &Connection, indexes: &[&str]) {
+        for partition in self.partitions.values_mut() {
+            let file = match (partition.conn.take(), &partition.path) {
+                (Some(file), _) => file.execute_batch("COMMIT").map(|_| Some(file)),
+                (None, Some(path)) => open(path, self.spatial).map(Some),
+                (None, None) => Ok(None),
+            };
+            let file = match file {
+                Ok(file) => file,
+                Err(err) => {
+                    error!("error on commit transaction: {}", err);
+                    continue;
+                }
+            };
+            let conn = file.as_ref().unwrap_or(conn);
+            if self.spatial {
+                if let Err(err) = db::build_geometry(conn, &partition.table) {
+                    error!("error building geometry: {}", err);
+                    continue;
+                }
+            }
+            if let Err(err) = db::create_indexes(conn, &partition.table, indexes) {
+                error!("error creating indexes: {}", err);
+            }
+        }
+        for (name, partition) in &self.partitions {
+            match &self.files {
+                Some(stem) => info!("{}_{}.sqlite: {} records", stem, name, partition.records),
+                None => info!("{}: {} records", partition.table, partition.records),
+            }
+        }
+    }
+}
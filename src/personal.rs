@@ -0,0 +1,105 @@
+use crate::EBirdRecord;
+use chrono::{NaiveDate, NaiveTime};
+use serde::Deserialize;
+
+// A row of the "MyEBirdData.csv" export available to individual observers
+// from their eBird account.
+#[derive(Debug, Deserialize)]
+pub struct PersonalRecord {
+    #[serde(rename = "Submission ID")]
+    submission_id: String,
+    #[serde(rename = "Common Name")]
+    common_name: String,
+    #[serde(rename = "Scientific Name")]
+    scientific_name: String,
+    #[serde(rename = "Count")]
+    count: String,
+    #[serde(rename = "Latitude")]
+    latitude: f64,
+    #[serde(rename = "Longitude")]
+    longitude: f64,
+    #[serde(rename = "Date")]
+    date: String,
+    #[serde(rename = "Time")]
+    time: String,
+    #[serde(rename = "Protocol")]
+    protocol: String,
+    #[serde(rename = "Duration (Min)")]
+    duration_min: Option<i64>,
+    #[serde(rename = "All Obs Reported")]
+    all_obs_reported: i64,
+    #[serde(rename = "Distance Traveled (km)")]
+    distance_traveled_km: Option<f64>,
+    #[serde(rename = "Number of Observers")]
+    number_observers: Option<i64>,
+    #[serde(rename = "Breeding Code")]
+    breeding_code: String,
+    #[serde(rename = "Observation Details")]
+    observation_details: String,
+    #[serde(rename = "Checklist Comments", default)]
+    checklist_comments: String,
+    #[serde(rename = "Location", default)]
+    location: String,
+}
+
+// The export uses MM-DD-YYYY dates, but be lenient in case this changes to
+// match the EBD.
+fn convert_date(text: &str) -> String {
+    match NaiveDate::parse_from_str(text, "%m-%d-%Y") {
+        Ok(date) => date.format("%Y-%m-%d").to_string(),
+        Err(_) => text.to_string(),
+    }
+}
+
+// The export uses 12 hour times like "07:30 AM", while the EBD uses 24 hour
+// times like "07:30:00".
+fn convert_time(text: &str) -> String {
+    match NaiveTime::parse_from_str(text, "%I:%M %p") {
+        Ok(time) => time.format("%H:%M:%S").to_string(),
+        Err(_) => text.to_string(),
+    }
+}
+
+// Map protocol names like "eBird - Traveling Count" to the names used in the
+// EBD, like "Traveling".
+fn convert_protocol(text: &str) -> String {
+    let protocol = text.trim_start_matches("eBird - ");
+    match protocol {
+        "Casual Observation" => "Incidental".to_string(),
+        _ => protocol.trim_end_matches(" Count").to_string(),
+    }
+}
+
+impl From<PersonalRecord> for EBirdRecord {
+    fn from(rec: PersonalRecord) -> Self {
+        EBirdRecord {
+            // The export has no observation identifier, so synthesize one
+            // which is stable across exports.
+            guid: format!("MyEBirdData:{}:{}", rec.submission_id, rec.scientific_name),
+            common_name: rec.common_name,
+            scientific_name: rec.scientific_name,
+            observation_count: rec.count,
+            breeding_bird_atlas_code: rec.breeding_code,
+            breeding_bird_atlas_category: String::new(),
+            age_sex: String::new(),
+            latitude: rec.latitude,
+            longitude: rec.longitude,
+            obs_date: convert_date(&rec.date),
+            time_obs_started: convert_time(&rec.time),
+            obs_id: String::new(),
+            sampling_event_id: rec.submission_id,
+            protocol_type: convert_protocol(&rec.protocol),
+            duration_min: rec.duration_min,
+            effort_distance_km: rec.distance_traveled_km,
+            number_observers: rec.number_observers,
+            all_species_reported: rec.all_obs_reported,
+            // Personal records have not been through review, but are the
+            // observer's own data, so treat them as approved.
+            approved: 1,
+            species_comments: rec.observation_details,
+            trip_comments: rec.checklist_comments,
+            locality: rec.location,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,305 @@
+#[cfg(feature = "cloud")]
+use crate::cloud;
+use crate::input::{self, InputStats};
+use crate::memory;
+use crate::to_io_error;
+use crate::EBirdRecord;
+use std::io::{self, Read};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tracing::{info, warn};
+
+// Up to this many chunks of input are buffered between each stage, after
+// which the stage before waits for the one after to catch up.
+const CHUNK_COUNT: usize = 64;
+
+// Records are handed to the importer in batches of this size, with up to
+// BATCH_COUNT batches buffered.
+const BATCH_SIZE: usize = 1024;
+const BATCH_COUNT: usize = 16;
+
+// Roughly the memory taken by a parsed record, for --max-memory.
+const RECORD_SIZE: usize = 1024;
+
+fn chunk_count() -> usize {
+    memory::queue_length(input::CHUNK_SIZE, CHUNK_COUNT)
+}
+
+type Chunk = io::Result<Vec<u8>>;
+
+// How the input is read and parsed, as for the other import path.
+pub struct Options {
+    pub format: String,
+    pub quoting: bool,
+    pub columns: Option<Vec<String>>,
+}
+
+enum Source {
+    File(tokio::fs::File),
+    #[cfg(feature = "cloud")]
+    Object(cloud::Object),
+    Http {
+        client: reqwest::Client,
+        url: String,
+        response: reqwest::Response,
+    },
+}
+
+async fn open(path: &str) -> io::Result<Source> {
+    if input::is_object_url(path) {
+        #[cfg(feature = "cloud")]
+        return Ok(Source::Object(cloud::open(path).await?));
+        #[cfg(not(feature = "cloud"))]
+        return Err(input::needs_cloud(path));
+    }
+    if path.starts_with("http://") || path.starts_with("https://") {
+        let client = reqwest::Client::new();
+        let response = client
+            .get(path)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(to_io_error)?;
+        Ok(Source::Http {
+            client,
+            url: path.to_string(),
+            response,
+        })
+    } else {
+        Ok(Source::File(tokio::fs::File::open(path).await?))
+    }
+}
+
+// Read the input into the channel, resuming downloads that are interrupted
+// as the blocking reader does.
+async fn fetch(source: Source, stats: Arc<InputStats>, sender: Sender<Chunk>) {
+    let result = match source {
+        #[cfg(feature = "cloud")]
+        Source::Object(object) => return cloud::fetch(object, stats, sender).await,
+        Source::File(mut file) => loop {
+            let mut chunk = Vec::with_capacity(input::CHUNK_SIZE);
+            match file.read_buf(&mut chunk).await {
+                Ok(0) => break Ok(()),
+                Ok(_) => {
+                    stats.add(&chunk);
+                    if sender.send(Ok(chunk)).await.is_err() {
+                        break Ok(());
+                    }
+                }
+                Err(err) => break Err(err),
+            }
+        },
+        Source::Http {
+            client,
+            url,
+            mut response,
+        } => {
+            let mut offset = 0;
+            let mut attempts = 0;
+            loop {
+                let err = match response.chunk().await {
+                    Ok(Some(chunk)) => {
+                        offset += chunk.len() as u64;
+                        attempts = 0;
+                        stats.add(&chunk);
+                        if sender.send(Ok(chunk.into())).await.is_err() {
+                            break Ok(());
+                        }
+                        continue;
+                    }
+                    Ok(None) => break Ok(()),
+                    Err(err) => to_io_error(err),
+                };
+                if attempts == input::MAX_RESUME_ATTEMPTS {
+                    break Err(err);
+                }
+                attempts += 1;
+                warn!(
+                    "download interrupted at byte {} ({}), resuming",
+                    offset, err
+                );
+                let resumed = client
+                    .get(&url)
+                    .header(reqwest::header::RANGE, format!("bytes={}-", offset))
+                    .send()
+                    .await
+                    .map_err(to_io_error);
+                match resumed {
+                    Ok(resumed) if resumed.status() == reqwest::StatusCode::PARTIAL_CONTENT => {
+                        response = resumed
+                    }
+                    Ok(resumed) => {
+                        break Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!(
+                                "Could not resume download, server responded with {}",
+                                resumed.status()
+                            ),
+                        ))
+                    }
+                    Err(err) => break Err(err),
+                }
+            }
+        }
+    };
+    if let Err(err) = result {
+        let _ = sender.send(Err(err)).await;
+    }
+}
+
+// Reads the chunks sent by the stage before, for the blocking decompression
+// and parsing stages.
+pub struct ChannelReader {
+    receiver: Receiver<Chunk>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    pub fn new(receiver: Receiver<Chunk>) -> ChannelReader {
+        ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.blocking_recv() {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(err)) => return Err(err),
+                // The stage before has finished.
+                None => return Ok(0),
+            }
+        }
+        let n = (self.chunk.len() - self.pos).min(buf.len());
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn decompress(mut decoder: Box<dyn Read + Send>, sender: Sender<Chunk>) {
+    loop {
+        let mut chunk = vec![0; input::CHUNK_SIZE];
+        match input::read_chunk(&mut decoder, &mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                chunk.truncate(n);
+                // The receiver has gone away if the import stopped early.
+                if sender.blocking_send(Ok(chunk)).is_err() {
+                    break;
+                }
+            }
+            Err(err) => {
+                let _ = sender.blocking_send(Err(err));
+                break;
+            }
+        }
+    }
+}
+
+fn parse(data: Box<dyn Read + Send>, options: Options, sender: Sender<Batch>) {
+    let columns = options
+        .columns
+        .as_ref()
+        .map(|columns| columns.iter().map(String::as_str).collect::<Vec<_>>());
+    let (mut records, repaired) =
+        match input::read_records(data, &options.format, options.quoting, columns.as_deref()) {
+            Ok(records) => records,
+            Err(err) => {
+                let _ = sender.blocking_send(Err(err));
+                return;
+            }
+        };
+    loop {
+        let batch = (&mut records).take(BATCH_SIZE).collect::<Vec<_>>();
+        if batch.is_empty() || sender.blocking_send(Ok(batch)).is_err() {
+            break;
+        }
+    }
+    if let Some(repaired) = repaired {
+        if repaired.get() > 0 {
+            info!("repaired invalid UTF-8 in {} records", repaired.get());
+        }
+    }
+}
+
+type Batch = io::Result<Vec<csv::Result<EBirdRecord>>>;
+
+// The records of an input read by an async pipeline, in which reading the
+// file or download, decompressing it and parsing it run as separate tasks
+// joined by bounded channels. Each stage runs ahead of the next until its
+// channel fills up, so the importer always has records waiting to be written.
+pub struct Records {
+    receiver: Receiver<Batch>,
+    batch: std::vec::IntoIter<csv::Result<EBirdRecord>>,
+    runtime: Option<Runtime>,
+}
+
+impl Records {
+    pub fn open(path: &str, options: Options, stats: Arc<InputStats>) -> io::Result<Records> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let source = runtime.block_on(open(path))?;
+        let (fetched, receiver) = mpsc::channel(chunk_count());
+        runtime.spawn(fetch(source, stats, fetched));
+        let (sender, records) =
+            mpsc::channel(memory::queue_length(BATCH_SIZE * RECORD_SIZE, BATCH_COUNT));
+        runtime.spawn_blocking(move || {
+            let (compressed, data) = match input::decompress(ChannelReader::new(receiver)) {
+                Ok(decompressed) => decompressed,
+                Err(err) => {
+                    let _ = sender.blocking_send(Err(err));
+                    return;
+                }
+            };
+            if !compressed {
+                parse(data, options, sender);
+                return;
+            }
+            let (decompressed, receiver) = mpsc::channel(chunk_count());
+            tokio::task::spawn_blocking(move || decompress(data, decompressed));
+            parse(Box::new(ChannelReader::new(receiver)), options, sender);
+        });
+        Ok(Records {
+            receiver: records,
+            batch: Vec::new().into_iter(),
+            runtime: Some(runtime),
+        })
+    }
+}
+
+impl Iterator for Records {
+    type Item = csv::Result<EBirdRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.batch.next() {
+                return Some(record);
+            }
+            match self.receiver.blocking_recv()? {
+                Ok(batch) => self.batch = batch.into_iter(),
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+}
+
+impl Drop for Records {
+    // Don't wait for the stages still running if the import stopped early.
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
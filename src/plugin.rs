@@ -0,0 +1,99 @@
+use crate::to_io_error;
+use crate::EBirdRecord;
+use std::io;
+use wasmtime::component::{Component, Linker};
+use wasmtime::{Config, Engine, Store};
+
+wasmtime::component::bindgen!({
+    path: "wit/plugin.wit",
+    world: "filter",
+});
+
+// How much work a plugin can do on each record before it is stopped, so that
+// one stuck in a loop fails rather than hanging the import.
+const FUEL_PER_RECORD: u64 = 10_000_000;
+
+// A WebAssembly component implementing the filter world of wit/plugin.wit,
+// run on each record with --plugin. The columns it returns are stored in the
+// script_fields column, along with any from --script.
+pub struct Plugin {
+    component: Component,
+    linker: Linker<()>,
+    store: Store<()>,
+    filter: Filter,
+}
+
+fn instantiate(
+    component: &Component,
+    linker: &Linker<()>,
+) -> wasmtime::Result<(Store<()>, Filter)> {
+    let mut store = Store::new(component.engine(), ());
+    let filter = Filter::instantiate(&mut store, component, linker)?;
+    Ok((store, filter))
+}
+
+impl Plugin {
+    pub fn load(path: &str) -> io::Result<Plugin> {
+        let mut config = Config::new();
+        config.wasm_component_model(true);
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|err| to_io_error(err.to_string()))?;
+        let invalid = |err: wasmtime::Error| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid plugin {}: {:#}", path, err),
+            )
+        };
+        let component = Component::from_file(&engine, path).map_err(invalid)?;
+        let linker = Linker::new(&engine);
+        let (store, filter) = instantiate(&component, &linker).map_err(invalid)?;
+        Ok(Plugin {
+            component,
+            linker,
+            store,
+            filter,
+        })
+    }
+
+    // Run the plugin on a record, returning whether to keep it.
+    pub fn apply(&mut self, record: &mut EBirdRecord) -> Result<bool, String> {
+        let columns = record
+            .to_json()
+            .into_iter()
+            .map(|(name, value)| Field {
+                name,
+                value: match value {
+                    serde_json::Value::String(text) => text,
+                    serde_json::Value::Null => String::new(),
+                    value => value.to_string(),
+                },
+            })
+            .collect::<Vec<_>>();
+        self.store
+            .set_fuel(FUEL_PER_RECORD)
+            .map_err(|err| err.to_string())?;
+        let verdict = match self.filter.call_filter(&mut self.store, &columns) {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                // An instance can't be used again after it traps, so start
+                // the next record with a new one.
+                let (store, filter) = instantiate(&self.component, &self.linker)
+                    .map_err(|err| format!("{:#}", err))?;
+                self.store = store;
+                self.filter = filter;
+                return Err(err.root_cause().to_string());
+            }
+        };
+        if verdict.keep && !verdict.columns.is_empty() {
+            let mut fields = match &record.script_fields {
+                Some(json) => serde_json::from_str(json).map_err(|err| err.to_string())?,
+                None => serde_json::Map::new(),
+            };
+            for column in verdict.columns {
+                fields.insert(column.name, column.value.into());
+            }
+            record.script_fields = Some(serde_json::Value::Object(fields).to_string());
+        }
+        Ok(verdict.keep)
+    }
+}
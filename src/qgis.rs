@@ -0,0 +1,361 @@
+use crate::db;
+use crate::to_io_error;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use zip::write::{FileOptions, ZipWriter};
+
+// Colours of the species groups, then of anything else.
+const PALETTE: &[&str] = &[
+    "31,120,180",
+    "51,160,44",
+    "227,26,28",
+    "255,127,0",
+    "106,61,154",
+    "177,89,40",
+    "166,206,227",
+    "178,223,138",
+    "251,154,153",
+    "253,191,111",
+];
+const OTHER_COLOUR: &str = "153,153,153";
+
+// Blues from light to dark, for graduated grid layers.
+const RAMP: &[&str] = &[
+    "239,243,255",
+    "189,215,231",
+    "107,174,214",
+    "49,130,189",
+    "8,81,156",
+];
+
+// Summary layers built by other options, styled by one of their columns if
+// present in the database.
+const SUMMARY_LAYERS: &[(&str, &str, &str)] = &[
+    ("frequency", "Reporting frequency", "frequency"),
+    ("gaps", "Survey gaps", "checklists"),
+    ("blocks", "Atlas blocks", "species"),
+    ("ranges", "Ranges", ""),
+];
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// The species group of a common name, which is usually its last word, e.g.
+// Warbler for Yellow Warbler. Spuhs and hybrids have no group.
+fn species_group(common_name: &str) -> Option<&str> {
+    let group = common_name.rsplit(' ').next()?;
+    if group.chars().next()?.is_uppercase() {
+        Some(group)
+    } else {
+        None
+    }
+}
+
+fn marker(name: &str, colour: &str, shape: &str, size: f64) -> String {
+    format!(
+        r#"<symbol name="{}" type="marker" alpha="1" clip_to_extent="1" force_rhr="0">
+          <layer class="SimpleMarker" enabled="1" locked="0" pass="0">
+            <prop k="name" v="{}"/>
+            <prop k="color" v="{},255"/>
+            <prop k="outline_color" v="35,35,35,255"/>
+            <prop k="outline_width" v="0.2"/>
+            <prop k="size" v="{}"/>
+          </layer>
+        </symbol>"#,
+        name, shape, colour, size
+    )
+}
+
+fn fill(name: &str, colour: &str, style: &str) -> String {
+    format!(
+        r#"<symbol name="{}" type="fill" alpha="0.7" clip_to_extent="1" force_rhr="0">
+          <layer class="SimpleFill" enabled="1" locked="0" pass="0">
+            <prop k="color" v="{},255"/>
+            <prop k="style" v="{}"/>
+            <prop k="outline_color" v="35,35,35,255"/>
+            <prop k="outline_width" v="0.26"/>
+          </layer>
+        </symbol>"#,
+        name, colour, style
+    )
+}
+
+struct Layer {
+    id: String,
+    name: String,
+    datasource: String,
+    geometry: &'static str,
+    renderer: String,
+}
+
+impl Layer {
+    fn new(name: &str, database: &str, table: &str, column: &str, geometry: &'static str) -> Layer {
+        Layer {
+            id: format!("{}_{}", table, name.to_lowercase().replace(' ', "_")),
+            name: name.to_string(),
+            datasource: format!(r#"dbname='{}' table="{}" ({})"#, database, table, column),
+            geometry,
+            renderer: String::new(),
+        }
+    }
+
+    fn tree_entry(&self) -> String {
+        format!(
+            r#"<layer-tree-layer id="{}" name="{}" source="{}" providerKey="spatialite" checked="Qt::Checked" expanded="0"/>"#,
+            escape(&self.id),
+            escape(&self.name),
+            escape(&self.datasource)
+        )
+    }
+
+    fn map_layer(&self) -> String {
+        format!(
+            r#"<maplayer type="vector" geometry="{}" autoRefreshEnabled="0">
+      <id>{}</id>
+      <datasource>{}</datasource>
+      <layername>{}</layername>
+      <srs><spatialrefsys><authid>EPSG:4326</authid></spatialrefsys></srs>
+      <provider encoding="UTF-8">spatialite</provider>
+      {}
+    </maplayer>"#,
+            self.geometry,
+            escape(&self.id),
+            escape(&self.datasource),
+            escape(&self.name),
+            self.renderer
+        )
+    }
+}
+
+// Points coloured by species group, for the most recorded groups.
+fn species_renderer(conn: &Connection, source: &str) -> io::Result<String> {
+    let mut groups: HashMap<String, i64> = HashMap::new();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT common_name, COUNT(*) FROM {} GROUP BY common_name",
+            source
+        ))
+        .map_err(to_io_error)?;
+    let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        let common_name: String = row.get(0).map_err(to_io_error)?;
+        if let Some(group) = species_group(&common_name) {
+            *groups.entry(group.to_string()).or_insert(0) +=
+                row.get::<_, i64>(1).map_err(to_io_error)?;
+        }
+    }
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    groups.truncate(PALETTE.len());
+
+    let mut categories = Vec::new();
+    let mut symbols = Vec::new();
+    for (i, (group, _)) in groups.iter().enumerate() {
+        categories.push(format!(
+            r#"<category render="true" symbol="{}" value="{}" label="{}"/>"#,
+            i,
+            escape(group),
+            escape(group)
+        ));
+        symbols.push(marker(&i.to_string(), PALETTE[i], "circle", 2.0));
+    }
+    // An empty value matches every other group.
+    categories.push(format!(
+        r#"<category render="true" symbol="{}" value="" label="Other"/>"#,
+        groups.len()
+    ));
+    symbols.push(marker(
+        &groups.len().to_string(),
+        OTHER_COLOUR,
+        "circle",
+        1.6,
+    ));
+
+    Ok(format!(
+        r#"<renderer-v2 type="categorizedSymbol" attr="{}" symbollevels="0" enableorderby="0" forceraster="0">
+        <categories>{}</categories>
+        <symbols>{}</symbols>
+      </renderer-v2>"#,
+        escape(r#"regexp_substr("common_name", '([^ ]+)$')"#),
+        categories.join(""),
+        symbols.join("")
+    ))
+}
+
+fn single_renderer(symbol: String) -> String {
+    format!(
+        r#"<renderer-v2 type="singleSymbol" symbollevels="0" enableorderby="0" forceraster="0">
+        <symbols>{}</symbols>
+      </renderer-v2>"#,
+        symbol
+    )
+}
+
+// Polygons shaded by a column in equal intervals between its smallest and
+// largest values.
+fn graduated_renderer(conn: &Connection, table: &str, column: &str) -> io::Result<String> {
+    let (min, max): (Option<f64>, Option<f64>) = conn
+        .query_row(
+            &format!(
+                "SELECT MIN({column}), MAX({column}) FROM {}",
+                table,
+                column = column
+            ),
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(to_io_error)?;
+    let (min, max) = match (min, max) {
+        (Some(min), Some(max)) if max > min => (min, max),
+        _ => return Ok(single_renderer(fill("0", RAMP[2], "solid"))),
+    };
+    let step = (max - min) / RAMP.len() as f64;
+    let mut ranges = Vec::new();
+    let mut symbols = Vec::new();
+    for (i, colour) in RAMP.iter().enumerate() {
+        let lower = min + step * i as f64;
+        let upper = if i + 1 == RAMP.len() {
+            max
+        } else {
+            lower + step
+        };
+        ranges.push(format!(
+            r#"<range render="true" symbol="{}" lower="{}" upper="{}" label="{:.2} - {:.2}"/>"#,
+            i, lower, upper, lower, upper
+        ));
+        symbols.push(fill(&i.to_string(), colour, "solid"));
+    }
+    Ok(format!(
+        r#"<renderer-v2 type="graduatedSymbol" attr="{}" graduatedMethod="GraduatedColor" symbollevels="0" enableorderby="0" forceraster="0">
+        <ranges>{}</ranges>
+        <symbols>{}</symbols>
+      </renderer-v2>"#,
+        escape(column),
+        ranges.join(""),
+        symbols.join("")
+    ))
+}
+
+// Write a QGIS project with layers for the records in a database, coloured by
+// species group, its hotspots, and any grid summaries built by --frequency,
+// stats --gaps, --atlas-blocks or --ranges, as a .qgz or, if the output ends
+// in .qgs, plain XML.
+pub fn write_project(
+    conn: &Connection,
+    path: &str,
+    table: &str,
+    source: &str,
+    output: &str,
+) -> io::Result<()> {
+    let database = fs::canonicalize(path)?.to_string_lossy().to_string();
+    let existing = conn
+        .prepare(&format!("PRAGMA table_info({})", source))
+        .map_err(to_io_error)?
+        .query_map(NO_PARAMS, |row| row.get::<_, String>(1))
+        .map_err(to_io_error)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(to_io_error)?;
+    let has = |column: &str| existing.iter().any(|existing| existing == column);
+
+    let mut layers = Vec::new();
+    for (suffix, name, column) in SUMMARY_LAYERS {
+        let layer_table = format!("{}_{}", table, suffix);
+        let exists = conn
+            .query_row(
+                "SELECT 1 FROM geometry_columns
+                    WHERE f_table_name = lower(?1) AND f_geometry_column = 'geometry'",
+                params![layer_table],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(to_io_error)?
+            .is_some();
+        if !exists {
+            continue;
+        }
+        let mut layer = Layer::new(name, &database, &layer_table, "geometry", "Polygon");
+        layer.renderer = if column.is_empty() {
+            single_renderer(fill("0", PALETTE[0], "no"))
+        } else {
+            graduated_renderer(conn, &layer_table, column)?
+        };
+        layers.push(layer);
+    }
+
+    if has("locality_type") {
+        let mut hotspots = Layer::new("Hotspots", &database, source, "location", "Point");
+        hotspots
+            .datasource
+            .push_str(r#" sql="locality_type" = 'H'"#);
+        hotspots.renderer = single_renderer(marker("0", "255,215,0", "star", 3.0));
+        layers.push(hotspots);
+    }
+
+    let mut records = Layer::new("Records", &database, source, "location", "Point");
+    records.renderer =
+        if has("common_name") && !db::is_checklist_table(conn, table).map_err(to_io_error)? {
+            species_renderer(conn, source)?
+        } else {
+            single_renderer(marker("0", PALETTE[0], "circle", 2.0))
+        };
+    layers.push(records);
+
+    // Layers at the top of the layer tree are drawn over those below, so the
+    // points go first, over the grid summaries.
+    layers.reverse();
+    let project = format!(
+        r#"<!DOCTYPE qgis PUBLIC 'http://mrcc.com/qgis.dtd' 'SYSTEM'>
+<qgis projectname="eBird" version="3.16.0">
+  <title>eBird</title>
+  <projectCrs><spatialrefsys><authid>EPSG:4326</authid></spatialrefsys></projectCrs>
+  <layer-tree-group>
+    {}
+  </layer-tree-group>
+  <projectlayers>
+    {}
+  </projectlayers>
+</qgis>
+"#,
+        layers
+            .iter()
+            .map(Layer::tree_entry)
+            .collect::<Vec<_>>()
+            .join("\n    "),
+        layers
+            .iter()
+            .map(Layer::map_layer)
+            .collect::<Vec<_>>()
+            .join("\n    ")
+    );
+
+    let output_path = Path::new(output);
+    if output.to_lowercase().ends_with(".qgs") {
+        fs::write(output_path, project)?;
+    } else {
+        // A .qgz is a zip archive holding the .qgs.
+        let name = output_path
+            .with_extension("qgs")
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "project.qgs".to_string());
+        let mut zip = ZipWriter::new(File::create(output_path)?);
+        zip.start_file(name, FileOptions::default())
+            .map_err(to_io_error)?;
+        zip.write_all(project.as_bytes())?;
+        zip.finish().map_err(to_io_error)?;
+    }
+    println!(
+        "wrote QGIS project with {} layers to {}",
+        layers.len(),
+        output
+    );
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use crate::geojson::{self, Feature};
+use crate::shapefile;
+use geo::algorithm::bounding_rect::BoundingRect;
+use geo::algorithm::contains::Contains;
+use geo::{point, Geometry, MultiPolygon};
+use rstar::{RTree, RTreeObject, AABB};
+use serde_json::Value;
+use std::io;
+use tracing::warn;
+
+pub struct Region {
+    pub name: String,
+    pub geometry: MultiPolygon<f64>,
+    envelope: AABB<[f64; 2]>,
+}
+
+impl Region {
+    // Polygons without a bounding box are empty, so contain nothing.
+    fn new(name: String, geometry: MultiPolygon<f64>) -> Option<Region> {
+        let rect = geometry.bounding_rect()?;
+        Some(Region {
+            name,
+            envelope: AABB::from_corners(
+                [rect.min().x, rect.min().y],
+                [rect.max().x, rect.max().y],
+            ),
+            geometry,
+        })
+    }
+}
+
+impl RTreeObject for Region {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        self.envelope
+    }
+}
+
+// Named polygons, such as atlas blocks or counties, read from a shapefile or
+// GeoJSON file in longitude and latitude, and indexed so that the region
+// containing each record can be found quickly.
+pub struct Regions {
+    regions: RTree<Region>,
+}
+
+impl Regions {
+    // Load the polygons, naming each by the given attribute.
+    pub fn load(path: &str, field: &str) -> io::Result<Regions> {
+        let regions = read_regions(path, field)?;
+        if regions.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No polygons with a {} field in {}", field, path),
+            ));
+        }
+        Ok(Regions {
+            regions: RTree::bulk_load(regions),
+        })
+    }
+
+    // Load only the polygons of the given eBird region codes, e.g. US-CA-037,
+    // from a boundary dataset named by region code. A code also selects the
+    // regions within it, so US-CA selects every California county if the
+    // dataset only has counties.
+    pub fn load_codes(path: &str, field: &str, codes: &[&str]) -> io::Result<Regions> {
+        let within = |name: &str, code: &str| {
+            name == code || (name.starts_with(code) && name[code.len()..].starts_with('-'))
+        };
+        let regions = read_regions(path, field)?
+            .into_iter()
+            .filter(|region| codes.iter().any(|code| within(&region.name, code)))
+            .collect::<Vec<_>>();
+        for code in codes {
+            if !regions.iter().any(|region| within(&region.name, code)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("No boundary for region {} in {}", code, path),
+                ));
+            }
+        }
+        Ok(Regions {
+            regions: RTree::bulk_load(regions),
+        })
+    }
+
+    // Load the polygons of the given codes, e.g. ABA, from a CSV table of
+    // custom regions such as listing areas, with code and wkt columns holding
+    // each region's code and its polygon or multipolygon.
+    pub fn load_table(path: &str, codes: &[&str]) -> io::Result<Regions> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.trim().eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} has no {} column", path, name),
+                    )
+                })
+        };
+        let (code_column, wkt_column) = (column("code")?, column("wkt")?);
+
+        let mut regions = Vec::new();
+        for row in reader.records() {
+            let row = row?;
+            let code = row.get(code_column).unwrap_or("").trim();
+            if !codes.iter().any(|wanted| wanted.eq_ignore_ascii_case(code)) {
+                continue;
+            }
+            let geometry = parse_polygons(row.get(wkt_column).unwrap_or("")).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Region {} in {} is not a polygon or multipolygon",
+                        code, path
+                    ),
+                )
+            })?;
+            regions.extend(Region::new(code.to_uppercase(), geometry));
+        }
+        for code in codes {
+            if !regions
+                .iter()
+                .any(|region| region.name.eq_ignore_ascii_case(code))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("No region {} in {}", code, path),
+                ));
+            }
+        }
+        Ok(Regions {
+            regions: RTree::bulk_load(regions),
+        })
+    }
+
+    pub fn lookup(&self, latitude: f64, longitude: f64) -> Option<&Region> {
+        let location = point!(x: longitude, y: latitude);
+        self.regions
+            .locate_in_envelope_intersecting(&AABB::from_point([longitude, latitude]))
+            .find(|region| region.geometry.contains(&location))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+}
+
+fn parse_polygons(text: &str) -> Option<MultiPolygon<f64>> {
+    let wkt = wkt::Wkt::<f64>::from_str(text).ok()?;
+    match wkt::conversion::try_into_geometry(wkt.items.first()?).ok()? {
+        Geometry::Polygon(polygon) => Some(MultiPolygon(vec![polygon])),
+        Geometry::MultiPolygon(polygons) => Some(polygons),
+        _ => None,
+    }
+}
+
+fn read_regions(path: &str, field: &str) -> io::Result<Vec<Region>> {
+    let features: Vec<Feature> = if path.to_lowercase().ends_with(".shp") {
+        shapefile::read_polygons(path)?
+    } else {
+        geojson::read_polygons(path)?
+    };
+    let mut regions = Vec::new();
+    for feature in features {
+        // Ids are sometimes numbers in GeoJSON.
+        let name = match feature.properties.get(field) {
+            Some(Value::String(name)) => name.clone(),
+            Some(Value::Number(name)) => name.to_string(),
+            _ => {
+                warn!("skipping polygon without a {} field", field);
+                continue;
+            }
+        };
+        regions.extend(Region::new(name, feature.geometry));
+    }
+    Ok(regions)
+}
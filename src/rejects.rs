@@ -0,0 +1,40 @@
+use crate::EBirdRecord;
+use std::fs::File;
+use std::io;
+
+// A tab separated report of records which were rejected or flagged during
+// import, along with the reason.
+pub struct RejectsReport {
+    writer: csv::Writer<File>,
+}
+
+impl RejectsReport {
+    pub fn create(path: &str) -> io::Result<RejectsReport> {
+        let mut writer = csv::WriterBuilder::new().delimiter(b'\t').from_path(path)?;
+        writer.write_record([
+            "reason",
+            "guid",
+            "sampling_event_id",
+            "latitude",
+            "longitude",
+            "obs_date",
+        ])?;
+        Ok(RejectsReport { writer })
+    }
+
+    pub fn write(&mut self, record: &EBirdRecord, reason: &str) -> io::Result<()> {
+        self.writer.write_record([
+            reason,
+            &record.guid,
+            &record.sampling_event_id,
+            &record.latitude.to_string(),
+            &record.longitude.to_string(),
+            &record.obs_date,
+        ])?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
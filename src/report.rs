@@ -0,0 +1,37 @@
+use crate::db::ImportRun;
+use crate::telemetry::Telemetry;
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+
+// Write a JSON summary of an import for --report, so that pipelines can check
+// what was imported without parsing the log: the input and its hash, the
+// filters given, how many records passed each of them, how many were rejected
+// and where they went.
+pub fn write_report(
+    path: &str,
+    run: &ImportRun,
+    output: &str,
+    rejects: usize,
+    telemetry: &Telemetry,
+) -> io::Result<()> {
+    let report = json!({
+        "tool_version": env!("CARGO_PKG_VERSION"),
+        "status": run.status,
+        "input": run.input,
+        "input_format": run.input_format,
+        "input_sha256": run.input_sha256,
+        "output": output,
+        "table": run.table,
+        "filters": serde_json::from_str::<Value>(&run.parameters)?,
+        "started_at": run.started_at.to_rfc3339(),
+        "finished_at": Utc::now().to_rfc3339(),
+        "records_read": run.records_read,
+        "records_inserted": run.records_inserted,
+        "errors": run.errors,
+        "rejects": rejects,
+        "telemetry": telemetry.to_json(),
+    });
+    fs::write(path, serde_json::to_string_pretty(&report)? + "\n")
+}
@@ -0,0 +1,188 @@
+use std::io;
+
+// Selects a random fraction of input records. Whether a record is selected
+// depends only on the seed and its position in the input, so the same sample
+// is selected on every run, including when an import is resumed.
+pub struct Sampler {
+    fraction: f64,
+    seed: u64,
+}
+
+// The splitmix64 finalizer, which turns consecutive integers into well mixed
+// pseudo-random ones.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl Sampler {
+    pub fn new(fraction: f64, seed: u64) -> Sampler {
+        Sampler { fraction, seed }
+    }
+
+    pub fn keep(&self, index: usize) -> bool {
+        uniform(self.seed, index as u64) < self.fraction
+    }
+}
+
+// A uniform number in [0, 1) for the given seed and value, using the top 53
+// bits of the mixed value.
+pub fn uniform(seed: u64, value: u64) -> f64 {
+    let step = value.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let z = mix(seed.wrapping_add(step));
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// FNV-1a, since the standard hasher isn't guaranteed to be stable between
+// releases.
+pub fn hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Assigns each checklist to one of several named splits, e.g. train and test
+// sets for species distribution models, in proportion to their fractions.
+// Like sampling, the split of a checklist depends only on the seed and its
+// sampling event identifier, so every record of a checklist is in the same
+// split on every run.
+pub struct Splits {
+    // Each split with the upper bound of its share of [0, 1).
+    splits: Vec<(String, f64)>,
+    seed: u64,
+}
+
+impl Splits {
+    // Parse splits such as train=0.8 and test=0.2, whose fractions must add
+    // up to 1.
+    pub fn parse<'a>(values: impl Iterator<Item = &'a str>, seed: u64) -> io::Result<Splits> {
+        let mut splits = Vec::new();
+        let mut total = 0.0;
+        for value in values {
+            let mut parts = value.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let fraction = parts
+                .next()
+                .and_then(|text| text.trim().parse::<f64>().ok());
+            match fraction {
+                Some(fraction) if !name.is_empty() && fraction > 0.0 => {
+                    total += fraction;
+                    splits.push((name.to_string(), total));
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Invalid split {}, expected e.g. train=0.8", value),
+                    ));
+                }
+            }
+        }
+        if (total - 1.0).abs() > 1e-6 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Split fractions add up to {} rather than 1", total),
+            ));
+        }
+        Ok(Splits { splits, seed })
+    }
+
+    pub fn assign(&self, sampling_event_id: &str) -> &str {
+        let value = uniform(self.seed, hash(sampling_event_id));
+        self.splits
+            .iter()
+            .find(|(_, bound)| value < *bound)
+            .or_else(|| self.splits.last())
+            .map(|(name, _)| name.as_str())
+            .unwrap_or_default()
+    }
+}
+
+// Selects the checklists of one of several shards, so that separate processes
+// can each import a slice of the input into their own database, to be
+// combined with the merge subcommand. Every record of a checklist is in the
+// same shard, which doesn't depend on the seed so that every process agrees.
+pub struct Shard {
+    count: u64,
+    index: u64,
+}
+
+impl Shard {
+    pub fn new(count: u64, index: u64) -> Shard {
+        Shard { count, index }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn keep(&self, sampling_event_id: &str) -> bool {
+        // The low bits of FNV-1a are poorly mixed.
+        mix(hash(sampling_event_id)) % self.count == self.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKLISTS: [&str; 8] = [
+        "S1000001", "S1000002", "S1000003", "S1000004", "S1000005", "S1000006", "S1000007",
+        "S1000008",
+    ];
+
+    #[test]
+    fn hash_values() {
+        // Published FNV-1a 64 test vectors.
+        assert_eq!(hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash("foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn mix_values() {
+        assert_eq!(mix(0), 0);
+        // The first output of splitmix64 seeded with 0.
+        assert_eq!(mix(0x9e37_79b9_7f4a_7c15), 0xe220_a839_7b1d_cdaf);
+    }
+
+    #[test]
+    fn uniform_values() {
+        assert_eq!(uniform(0, 0), 0.0);
+        // The top 53 bits of the splitmix64 output above.
+        assert_eq!(uniform(0, 1), 0.8833108082136426);
+    }
+
+    #[test]
+    fn sampler() {
+        let sampler = Sampler::new(0.5, 42);
+        let kept = (0..16)
+            .filter(|index| sampler.keep(*index))
+            .collect::<Vec<_>>();
+        assert_eq!(kept, [2, 3, 4, 5, 7, 9, 11, 12]);
+    }
+
+    #[test]
+    fn splits() {
+        let splits = Splits::parse(["train=0.75", "test=0.25"].iter().copied(), 7).unwrap();
+        let assigned = CHECKLISTS
+            .iter()
+            .map(|id| splits.assign(id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            assigned,
+            ["train", "train", "train", "train", "train", "test", "train", "test"]
+        );
+        assert!(Splits::parse(["train=0.8", "test=0.1"].iter().copied(), 7).is_err());
+        assert!(Splits::parse(["train"].iter().copied(), 7).is_err());
+    }
+
+    #[test]
+    fn shards() {
+        let assigned = CHECKLISTS
+            .iter()
+            .map(|id| (0..3).find(|index| Shard::new(3, *index).keep(id)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(assigned, [1, 1, 2, 2, 2, 0, 2, 1]);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::EBirdRecord;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::io;
+use std::path::Path;
+
+// Records are given to scripts as a map of their columns, with missing values
+// as ().
+fn to_map(record: &EBirdRecord) -> Map {
+    record
+        .to_json()
+        .into_iter()
+        .map(|(name, value)| {
+            let value = rhai::serde::to_dynamic(value).unwrap_or(Dynamic::UNIT);
+            (name.into(), value)
+        })
+        .collect()
+}
+
+// A Rhai script run on each record with --script, which sees it as a map
+// named record. The script returns true or false to keep or drop the
+// record, or a map of fields to store with it in the script_fields column,
+// in which keep: false drops it.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn load(path: &str) -> io::Result<Script> {
+        let engine = Engine::new();
+        let ast = engine.compile_file(Path::new(path).into()).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid script {}: {}", path, err),
+            )
+        })?;
+        Ok(Script { engine, ast })
+    }
+
+    // Run the script on a record, returning whether to keep it.
+    pub fn apply(&self, record: &mut EBirdRecord) -> Result<bool, String> {
+        let mut scope = Scope::new();
+        scope.push("record", to_map(record));
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+            .map_err(|err| err.to_string())?;
+        if result.is_unit() {
+            return Ok(true);
+        }
+        if let Ok(keep) = result.as_bool() {
+            return Ok(keep);
+        }
+        let mut fields = match result.try_cast::<Map>() {
+            Some(fields) => fields,
+            None => return Err("the script must return true, false or a map".to_string()),
+        };
+        let keep = match fields.remove("keep") {
+            Some(keep) => keep
+                .as_bool()
+                .map_err(|_| "keep must be true or false".to_string())?,
+            None => true,
+        };
+        if keep && !fields.is_empty() {
+            let fields = rhai::serde::from_dynamic::<serde_json::Value>(&fields.into())
+                .map_err(|err| err.to_string())?;
+            record.script_fields = Some(fields.to_string());
+        }
+        Ok(keep)
+    }
+}
@@ -0,0 +1,620 @@
+use crate::db;
+use crate::mvt::{self, PropertyValue};
+use crate::to_io_error;
+use reqwest::Url;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Requests are answered by a fixed number of threads, with up to this many
+// more connections waiting for one, so that many clients at once can't start
+// unlimited threads.
+const WORKERS: usize = 8;
+const QUEUED_CONNECTIONS: usize = 64;
+
+// How long a client has to send its request or take the response, so that a
+// slow one can't hold a thread forever.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// The most bytes of request line and headers read, and the most headers.
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+const MAX_HEADERS: usize = 100;
+
+// The number of records returned by /records without a limit, and the most
+// returned with one.
+const DEFAULT_LIMIT: i64 = 1000;
+const MAX_LIMIT: i64 = 10_000;
+
+// Columns returned as the properties of each record, if the table has them.
+const PROPERTIES: &[&str] = &[
+    "common_name",
+    "scientific_name",
+    "observation_count",
+    "obs_date",
+    "time_obs_started",
+    "sampling_event_id",
+    "locality",
+    "protocol_type",
+    "species_count",
+];
+
+// The deepest zoom level tiles are served for.
+const MAX_ZOOM: u32 = 22;
+
+// Records in a tile are grouped into cells of this many tile units across,
+// so that a tile has at most (4096 / 16)^2 features however many records it
+// covers.
+const TILE_CELL: u32 = 16;
+
+// The most records read for a tile. A tile covering more, as those at low
+// zooms do, is drawn from every nth record by id and its counts scaled up, so
+// that it costs about the same to serve whatever its zoom.
+const MAX_TILE_RECORDS: i64 = 50_000;
+
+enum Body {
+    Json(Value),
+    GeoJson(Value),
+    Tile(Vec<u8>),
+}
+
+// The records falling in a cell of a tile.
+#[derive(Default)]
+struct Cell {
+    records: i64,
+    // The species, if every record is of the same one.
+    common_name: Option<String>,
+    mixed: bool,
+    last_date: String,
+}
+
+// An error response, with its status line and message.
+struct HttpError(&'static str, String);
+
+impl HttpError {
+    fn bad_request(message: &str) -> HttpError {
+        HttpError("400 Bad Request", message.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for HttpError {
+    fn from(err: rusqlite::Error) -> HttpError {
+        HttpError("500 Internal Server Error", err.to_string())
+    }
+}
+
+struct Server {
+    path: String,
+    table: String,
+    source: String,
+    checklists: bool,
+    properties: Vec<String>,
+}
+
+fn to_json(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null | ValueRef::Blob(_) => Value::Null,
+        ValueRef::Integer(value) => json!(value),
+        ValueRef::Real(value) => json!(value),
+        ValueRef::Text(value) => json!(String::from_utf8_lossy(value)),
+    }
+}
+
+// The northern edge of row y of the tiles at a zoom level, as a latitude.
+fn tile_latitude(y: u32, tiles: f64) -> f64 {
+    (PI * (1.0 - 2.0 * f64::from(y) / tiles))
+        .sinh()
+        .atan()
+        .to_degrees()
+}
+
+// Distance north in Web Mercator, in units of the earth's radius.
+fn mercator_y(latitude: f64) -> f64 {
+    (PI / 4.0 + latitude.to_radians() / 2.0).tan().ln()
+}
+
+// A bounding box given as min_longitude,min_latitude,max_longitude,max_latitude.
+fn parse_bbox(text: &str) -> Result<[f64; 4], HttpError> {
+    let invalid = || {
+        HttpError::bad_request("bbox must be min_longitude,min_latitude,max_longitude,max_latitude")
+    };
+    let values = text
+        .split(',')
+        .map(|value| value.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    match values.as_slice() {
+        [min_x, min_y, max_x, max_y] if min_x < max_x && min_y < max_y => {
+            Ok([*min_x, *min_y, *max_x, *max_y])
+        }
+        _ => Err(invalid()),
+    }
+}
+
+impl Server {
+    // Each request opens its own connection, so that requests can be served
+    // on separate threads.
+    fn connect(&self) -> Result<Connection, HttpError> {
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        db::load_spatialite(&conn)?;
+        Ok(conn)
+    }
+
+    // Records in the bounding box given as ?1 to ?4, found with the spatial
+    // index of the location, or every record if it is NULL.
+    fn spatial_filter(&self) -> String {
+        format!(
+            "(?1 IS NULL OR id IN (SELECT rowid FROM SpatialIndex
+                WHERE f_table_name = '{}' AND f_geometry_column = 'location'
+                AND search_frame = BuildMbr(?1, ?2, ?3, ?4)))",
+            self.table.to_lowercase()
+        )
+    }
+
+    fn records(&self, query: &Map<String, Value>) -> Result<Value, HttpError> {
+        let bbox = match query.get("bbox").and_then(Value::as_str) {
+            Some(text) => parse_bbox(text)?,
+            None => return Err(HttpError::bad_request("/records needs a bbox")),
+        };
+        let species = query.get("species").and_then(Value::as_str);
+        if species.is_some() && self.checklists {
+            return Err(HttpError::bad_request("A checklist table has no species"));
+        }
+        let limit = match query.get("limit").and_then(Value::as_str) {
+            Some(text) => match text.parse::<i64>() {
+                Ok(limit) if limit > 0 && limit <= MAX_LIMIT => limit,
+                _ => {
+                    return Err(HttpError::bad_request(&format!(
+                        "limit must be between 1 and {}",
+                        MAX_LIMIT
+                    )))
+                }
+            },
+            None => DEFAULT_LIMIT,
+        };
+        let species_filter = if self.checklists {
+            "?5 IS NULL"
+        } else {
+            "(?5 IS NULL OR common_name = ?5 OR scientific_name = ?5)"
+        };
+
+        let conn = self.connect()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT longitude, latitude, {} FROM {} WHERE {} AND {} LIMIT ?6",
+            self.properties.join(", "),
+            self.source,
+            self.spatial_filter(),
+            species_filter
+        ))?;
+        let mut rows = stmt.query(params![bbox[0], bbox[1], bbox[2], bbox[3], species, limit])?;
+        let mut features = Vec::new();
+        while let Some(row) = rows.next()? {
+            let mut properties = Map::new();
+            for (index, name) in self.properties.iter().enumerate() {
+                properties.insert(name.clone(), to_json(row.get_raw(index + 2)));
+            }
+            features.push(json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [row.get::<_, f64>(0)?, row.get::<_, f64>(1)?],
+                },
+                "properties": properties,
+            }));
+        }
+        Ok(json!({ "type": "FeatureCollection", "features": features }))
+    }
+
+    fn species(&self, query: &Map<String, Value>) -> Result<Value, HttpError> {
+        if self.checklists {
+            return Err(HttpError::bad_request("A checklist table has no species"));
+        }
+        let bbox = match query.get("bbox").and_then(Value::as_str) {
+            Some(text) => Some(parse_bbox(text)?),
+            None => None,
+        };
+
+        let conn = self.connect()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT MIN(common_name), scientific_name, COUNT(*) AS records,
+                    MAX(obs_date)
+                FROM {} WHERE {}
+                GROUP BY scientific_name ORDER BY records DESC, scientific_name",
+            self.source,
+            self.spatial_filter()
+        ))?;
+        let mut rows = stmt.query(params![
+            bbox.map(|bbox| bbox[0]),
+            bbox.map(|bbox| bbox[1]),
+            bbox.map(|bbox| bbox[2]),
+            bbox.map(|bbox| bbox[3])
+        ])?;
+        let mut species = Vec::new();
+        while let Some(row) = rows.next()? {
+            species.push(json!({
+                "common_name": to_json(row.get_raw(0)),
+                "scientific_name": to_json(row.get_raw(1)),
+                "records": row.get::<_, i64>(2)?,
+                "last_recorded": to_json(row.get_raw(3)),
+            }));
+        }
+        Ok(Value::Array(species))
+    }
+
+    fn stats(&self) -> Result<Value, HttpError> {
+        let species = if self.checklists {
+            "NULL"
+        } else {
+            "COUNT(DISTINCT scientific_name)"
+        };
+        let conn = self.connect()?;
+        let stats = conn.query_row(
+            &format!(
+                "SELECT COUNT(*), COUNT(DISTINCT sampling_event_id), {},
+                        COUNT(DISTINCT obs_id), MIN(obs_date), MAX(obs_date)
+                    FROM {}",
+                species, self.source
+            ),
+            NO_PARAMS,
+            |row| {
+                Ok(json!({
+                    "table": self.table,
+                    "records": row.get::<_, i64>(0)?,
+                    "checklists": row.get::<_, i64>(1)?,
+                    "species": to_json(row.get_raw(2)),
+                    "observers": row.get::<_, i64>(3)?,
+                    "first_date": to_json(row.get_raw(4)),
+                    "last_date": to_json(row.get_raw(5)),
+                }))
+            },
+        )?;
+        Ok(stats)
+    }
+
+    // A Mapbox vector tile of the records at z/x/y, with a point for each cell
+    // of the tile holding records, giving their number, the species if they
+    // are all of one, and the date of the latest.
+    fn tile(&self, path: &str) -> Result<Body, HttpError> {
+        let not_found = || HttpError("404 Not Found", format!("No tile {}", path));
+        let coordinates = path
+            .strip_suffix(".mvt")
+            .ok_or_else(not_found)?
+            .split('/')
+            .map(|part| part.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| not_found())?;
+        let (z, x, y) = match coordinates.as_slice() {
+            [z, x, y] if *z <= MAX_ZOOM && *x < 1 << z && *y < 1 << z => (*z, *x, *y),
+            _ => return Err(not_found()),
+        };
+        let tiles = f64::from(1u32 << z);
+        let west = f64::from(x) / tiles * 360.0 - 180.0;
+        let east = f64::from(x + 1) / tiles * 360.0 - 180.0;
+        let north = tile_latitude(y, tiles);
+        let south = tile_latitude(y + 1, tiles);
+        let (top, bottom) = (mercator_y(north), mercator_y(south));
+
+        let common_name = if self.checklists {
+            "NULL"
+        } else {
+            "common_name"
+        };
+        let conn = self.connect()?;
+        // Counting the entries of the spatial index is much cheaper than
+        // reading the records they point to.
+        let candidates: i64 = conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM SpatialIndex
+                    WHERE f_table_name = '{}' AND f_geometry_column = 'location'
+                    AND search_frame = BuildMbr(?1, ?2, ?3, ?4)",
+                self.table.to_lowercase()
+            ),
+            params![west, south, east, north],
+            |row| row.get(0),
+        )?;
+        let step = (candidates + MAX_TILE_RECORDS - 1) / MAX_TILE_RECORDS;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT longitude, latitude, {}, obs_date FROM {} WHERE {}
+                AND (?5 <= 1 OR id % ?5 = 0) LIMIT ?6",
+            common_name,
+            self.source,
+            self.spatial_filter()
+        ))?;
+        let mut rows = stmt.query(params![west, south, east, north, step, MAX_TILE_RECORDS])?;
+        let extent = f64::from(mvt::EXTENT);
+        let mut cells: HashMap<(u32, u32), Cell> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let longitude: f64 = row.get(0)?;
+            let latitude: f64 = row.get(1)?;
+            // Records on the eastern or southern edge belong to the next tile.
+            if longitude >= east || latitude <= south {
+                continue;
+            }
+            let column = ((longitude - west) / (east - west) * extent) as u32 / TILE_CELL;
+            let row_index =
+                ((top - mercator_y(latitude)) / (top - bottom) * extent) as u32 / TILE_CELL;
+            let cell = cells.entry((column, row_index)).or_default();
+            let name: Option<String> = row.get(2)?;
+            if cell.records == 0 {
+                cell.common_name = name;
+            } else if cell.common_name != name {
+                cell.mixed = true;
+            }
+            cell.records += step.max(1);
+            let date: Option<String> = row.get(3)?;
+            if let Some(date) = date {
+                if date > cell.last_date {
+                    cell.last_date = date;
+                }
+            }
+        }
+
+        let features = cells
+            .into_iter()
+            .map(|((column, row), cell)| {
+                let mut properties = vec![("records", PropertyValue::Int(cell.records))];
+                if let (Some(common_name), false) = (cell.common_name, cell.mixed) {
+                    properties.push(("common_name", PropertyValue::String(common_name)));
+                }
+                if !cell.last_date.is_empty() {
+                    properties.push(("last_date", PropertyValue::String(cell.last_date)));
+                }
+                mvt::Feature {
+                    x: column * TILE_CELL + TILE_CELL / 2,
+                    y: row * TILE_CELL + TILE_CELL / 2,
+                    properties,
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(Body::Tile(mvt::encode(&self.table, &features)))
+    }
+
+    fn route(&self, method: &str, target: &str) -> Result<Body, HttpError> {
+        if method != "GET" {
+            return Err(HttpError(
+                "405 Method Not Allowed",
+                "Only GET requests are supported".to_string(),
+            ));
+        }
+        let url = Url::parse(&format!("http://localhost{}", target))
+            .map_err(|_| HttpError::bad_request("Invalid request target"))?;
+        let query = url
+            .query_pairs()
+            .map(|(name, value)| (name.into_owned(), json!(value)))
+            .collect::<Map<String, Value>>();
+        match url.path() {
+            "/" => Ok(Body::Json(json!({
+                "endpoints": {
+                    "/records?bbox=min_longitude,min_latitude,max_longitude,max_latitude":
+                        "Records in a bounding box as GeoJSON, optionally of one species \
+                         with species=NAME, up to limit=N",
+                    "/species": "Species with their number of records, optionally in a bbox",
+                    "/stats": "Numbers of records, checklists, species and observers",
+                    "/tiles/{z}/{x}/{y}.mvt":
+                        "Mapbox vector tiles of the records, grouped into cells with their \
+                         number, species and latest date",
+                }
+            }))),
+            "/records" => self.records(&query).map(Body::GeoJson),
+            "/species" => self.species(&query).map(Body::Json),
+            "/stats" => self.stats().map(Body::Json),
+            path if path.starts_with("/tiles/") => self.tile(&path["/tiles/".len()..]),
+            path => Err(HttpError("404 Not Found", format!("No endpoint {}", path))),
+        }
+    }
+
+    // Requests are read up to the end of their headers, and connections
+    // closed after each response.
+    fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let result = read_request_line(&stream)?.and_then(|request_line| {
+            let mut parts = request_line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(method), Some(target)) => self.route(method, target),
+                _ => Err(HttpError::bad_request("Invalid request")),
+            }
+        });
+        let (status, body) = match result {
+            Ok(body) => ("200 OK", body),
+            Err(HttpError(status, message)) => (status, Body::Json(json!({ "error": message }))),
+        };
+        let (content_type, body) = match body {
+            Body::Json(value) => (
+                "application/json",
+                serde_json::to_vec(&value).map_err(to_io_error)?,
+            ),
+            Body::GeoJson(value) => (
+                "application/geo+json",
+                serde_json::to_vec(&value).map_err(to_io_error)?,
+            ),
+            Body::Tile(tile) => ("application/vnd.mapbox-vector-tile", tile),
+        };
+        let mut stream = &stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+            status,
+            content_type,
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        stream.flush()
+    }
+}
+
+// Serve read-only queries of a database over HTTP, so that it can be browsed
+// from a web map or script without GIS software.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = matches.value_of("DATABASE").unwrap();
+    let table = crate::table_name(matches)?;
+    let host = matches.value_of("host").unwrap();
+    let port = match matches.value_of("port").unwrap().parse::<u16>() {
+        Ok(port) => port,
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+    };
+
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io_error)?;
+    if !db::table_exists(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No table {} in {}", table, path),
+        ));
+    }
+    // Check that SpatiaLite can be loaded before accepting any requests.
+    db::require_spatialite(&conn)?;
+    let source = db::source_table(&conn, table).map_err(to_io_error)?;
+    let checklists = db::is_checklist_table(&conn, table).map_err(to_io_error)?;
+    let existing = conn
+        .prepare(&format!("PRAGMA table_info({})", source))
+        .map_err(to_io_error)?
+        .query_map(NO_PARAMS, |row| row.get::<_, String>(1))
+        .map_err(to_io_error)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(to_io_error)?;
+    let properties = PROPERTIES
+        .iter()
+        .filter(|column| existing.iter().any(|existing| existing == *column))
+        .map(|column| column.to_string())
+        .collect();
+    drop(conn);
+
+    let server = Arc::new(Server {
+        path: path.to_string(),
+        table: table.to_string(),
+        source,
+        checklists,
+        properties,
+    });
+    let listener = TcpListener::bind((host, port))?;
+    info!(
+        "serving {} from {} on http://{}:{}/",
+        table, path, host, port
+    );
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(QUEUED_CONNECTIONS);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let server = server.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || serve_connections(&server, &receiver));
+    }
+    for stream in listener.incoming() {
+        match stream {
+            // Waits for a worker once the queue is full, leaving further
+            // connections in the listen backlog.
+            Ok(stream) => {
+                if sender.send(stream).is_err() {
+                    break;
+                }
+            }
+            Err(err) => warn!("could not accept connection: {}", err),
+        }
+    }
+    Ok(())
+}
+
+// Answer connections taken from the queue, one at a time.
+fn serve_connections(server: &Server, receiver: &Mutex<Receiver<TcpStream>>) {
+    loop {
+        // The lock is only held while waiting for the next connection.
+        let stream = match receiver.lock().unwrap().recv() {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        if let Err(err) = server.handle(stream) {
+            warn!("could not respond to request: {}", err);
+        }
+    }
+}
+
+// Read the request line, skipping the headers, which aren't used. Requests
+// with too many or too long headers are refused rather than read into
+// memory.
+fn read_request_line(stream: &TcpStream) -> io::Result<Result<String, HttpError>> {
+    let too_large = || {
+        HttpError(
+            "431 Request Header Fields Too Large",
+            "Request line and headers too large".to_string(),
+        )
+    };
+    let mut reader = BufReader::new(stream.take(MAX_HEAD_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    if !request_line.ends_with('\n') {
+        return Ok(Err(too_large()));
+    }
+    for _ in 0..=MAX_HEADERS {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        if header.trim().is_empty() {
+            return Ok(Ok(request_line));
+        }
+        if !header.ends_with('\n') {
+            return Ok(Err(too_large()));
+        }
+    }
+    Ok(Err(too_large()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The request line read from a connection sent the given bytes.
+    fn read(request: Vec<u8>) -> Result<String, &'static str> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            // The server may stop reading part way through.
+            let _ = stream.write_all(&request);
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let result = read_request_line(&stream).unwrap();
+        drop(stream);
+        client.join().unwrap();
+        result.map_err(|HttpError(status, _)| status)
+    }
+
+    #[test]
+    fn reads_the_request_line() {
+        let request = b"GET /stats HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n";
+        assert_eq!(read(request.to_vec()).unwrap(), "GET /stats HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn refuses_a_long_request_line() {
+        let mut request = b"GET /".to_vec();
+        request.extend(vec![b'a'; MAX_HEAD_BYTES as usize]);
+        request.extend(b" HTTP/1.1\r\n\r\n");
+        assert_eq!(read(request), Err("431 Request Header Fields Too Large"));
+    }
+
+    #[test]
+    fn refuses_too_many_headers() {
+        let mut request = b"GET /stats HTTP/1.1\r\n".to_vec();
+        for index in 0..=MAX_HEADERS {
+            request.extend(format!("X-Header-{}: 1\r\n", index).bytes());
+        }
+        request.extend(b"\r\n");
+        assert_eq!(read(request), Err("431 Request Header Fields Too Large"));
+    }
+
+    #[test]
+    fn refuses_long_headers() {
+        let mut request = b"GET /stats HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.extend(vec![b'a'; MAX_HEAD_BYTES as usize]);
+        request.extend(b"\r\n\r\n");
+        assert_eq!(read(request), Err("431 Request Header Fields Too Large"));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::geojson::Feature;
+use geo::algorithm::contains::Contains;
+use geo::{point, LineString, MultiPolygon, Polygon};
+use serde_json::{Map, Value};
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Shape types with polygon geometry, with and without Z and M values, which
+// are ignored.
+const POLYGON_TYPES: &[i32] = &[5, 15, 25];
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn i32_le(bytes: &[u8], offset: usize) -> io::Result<i32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| invalid_data("truncated shapefile"))
+}
+
+fn f64_le(bytes: &[u8], offset: usize) -> io::Result<f64> {
+    bytes
+        .get(offset..offset + 8)
+        .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| invalid_data("truncated shapefile"))
+}
+
+// Twice the signed area of a ring, which is negative for clockwise rings.
+fn signed_area(ring: &[(f64, f64)]) -> f64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|((x1, y1), (x2, y2))| x1 * y2 - x2 * y1)
+        .sum()
+}
+
+// Shapefile polygons are a list of rings, where clockwise rings are
+// exteriors and counterclockwise rings are holes in the exterior containing
+// them.
+fn polygons(rings: Vec<Vec<(f64, f64)>>) -> MultiPolygon<f64> {
+    let mut exteriors = Vec::new();
+    let mut holes = Vec::new();
+    for ring in rings {
+        if signed_area(&ring) <= 0.0 {
+            exteriors.push((ring, Vec::new()));
+        } else {
+            holes.push(ring);
+        }
+    }
+    for hole in holes {
+        let (x, y) = hole[0];
+        let exterior = exteriors.iter_mut().find(|(exterior, _)| {
+            Polygon::new(LineString::from(exterior.clone()), vec![]).contains(&point!(x: x, y: y))
+        });
+        if let Some((_, interiors)) = exterior {
+            interiors.push(hole);
+        }
+    }
+    MultiPolygon(
+        exteriors
+            .into_iter()
+            .map(|(exterior, interiors)| {
+                Polygon::new(
+                    LineString::from(exterior),
+                    interiors.into_iter().map(LineString::from).collect(),
+                )
+            })
+            .collect(),
+    )
+}
+
+// The geometry of each record in the .shp file, or None for null shapes.
+fn read_shapes(bytes: &[u8]) -> io::Result<Vec<Option<MultiPolygon<f64>>>> {
+    if bytes.len() < 100 || bytes[..4] != [0, 0, 0x27, 0x0a] {
+        return Err(invalid_data("not a shapefile"));
+    }
+    let mut shapes = Vec::new();
+    let mut offset = 100;
+    while offset + 8 <= bytes.len() {
+        // Record lengths are big endian, in 16 bit words.
+        let length = u32::from_be_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let record = bytes
+            .get(offset + 8..offset + 8 + 2 * length)
+            .ok_or_else(|| invalid_data("truncated shapefile"))?;
+        offset += 8 + 2 * length;
+
+        let shape_type = i32_le(record, 0)?;
+        if shape_type == 0 {
+            shapes.push(None);
+            continue;
+        }
+        if !POLYGON_TYPES.contains(&shape_type) {
+            return Err(invalid_data("shapefile does not contain polygons"));
+        }
+        let parts = i32_le(record, 36)? as usize;
+        let points = i32_le(record, 40)? as usize;
+        let mut starts = Vec::with_capacity(parts + 1);
+        for part in 0..parts {
+            starts.push(i32_le(record, 44 + 4 * part)? as usize);
+        }
+        starts.push(points);
+
+        let first_point = 44 + 4 * parts;
+        let mut rings = Vec::with_capacity(parts);
+        for part in starts.windows(2) {
+            let mut ring = Vec::with_capacity(part[1].saturating_sub(part[0]));
+            for index in part[0]..part[1] {
+                let offset = first_point + 16 * index;
+                ring.push((f64_le(record, offset)?, f64_le(record, offset + 8)?));
+            }
+            if !ring.is_empty() {
+                rings.push(ring);
+            }
+        }
+        shapes.push(Some(polygons(rings)));
+    }
+    Ok(shapes)
+}
+
+// The attributes of each record in the .dbf file, as strings.
+fn read_attributes(bytes: &[u8]) -> io::Result<Vec<Map<String, Value>>> {
+    if bytes.len() < 32 {
+        return Err(invalid_data("truncated dBase file"));
+    }
+    let records = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let header_length = u16::from_le_bytes(bytes[8..10].try_into().unwrap()) as usize;
+    let record_length = u16::from_le_bytes(bytes[10..12].try_into().unwrap()) as usize;
+
+    // Field descriptors are 32 bytes each, ending with a 0x0d byte.
+    let mut fields = Vec::new();
+    let mut offset = 32;
+    let mut field_offset = 1;
+    while offset + 32 <= header_length && bytes.get(offset) != Some(&0x0d) {
+        let descriptor = &bytes[offset..offset + 32];
+        let name = String::from_utf8_lossy(&descriptor[..11])
+            .trim_end_matches(char::from(0))
+            .to_string();
+        let length = descriptor[16] as usize;
+        fields.push((name, field_offset, length));
+        field_offset += length;
+        offset += 32;
+    }
+
+    let mut attributes = Vec::with_capacity(records);
+    for index in 0..records {
+        let start = header_length + index * record_length;
+        let record = bytes
+            .get(start..start + record_length)
+            .ok_or_else(|| invalid_data("truncated dBase file"))?;
+        let mut properties = Map::new();
+        for (name, offset, length) in &fields {
+            let value = record.get(*offset..offset + length).unwrap_or(&[]);
+            properties.insert(
+                name.clone(),
+                Value::String(String::from_utf8_lossy(value).trim().to_string()),
+            );
+        }
+        attributes.push(properties);
+    }
+    Ok(attributes)
+}
+
+// Read the polygons from a shapefile, along with their attributes from the
+// .dbf file alongside it. Coordinates are used as they are, so the shapefile
+// must be in longitude and latitude.
+pub fn read_polygons(path: &str) -> io::Result<Vec<Feature>> {
+    let shapes = read_shapes(&fs::read(path)?)?;
+    let attributes = read_attributes(&fs::read(Path::new(path).with_extension("dbf"))?)?;
+    Ok(shapes
+        .into_iter()
+        .zip(attributes)
+        .filter_map(|(geometry, properties)| {
+            geometry.map(|geometry| Feature {
+                properties,
+                geometry,
+            })
+        })
+        .collect())
+}
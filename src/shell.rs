@@ -0,0 +1,297 @@
+use crate::db;
+use crate::stats;
+use crate::to_io_error;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, Connection, NO_PARAMS};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use tracing::{error, warn};
+
+const HELP: &str = "\
+.help               Show this message
+.mode MODE          Print results as a table, csv, json or geojson
+.schema [TABLE]     Show the CREATE statements of every table or one
+.tables             List the tables and views
+.quit               Exit, as does Ctrl-D
+
+SQL statements end with a semicolon and may span several lines. SpatiaLite
+geometries are printed as WKT, or as the geometry of each feature with .mode
+geojson, which otherwise makes points from latitude and longitude columns.";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Table,
+    Csv,
+    Json,
+    GeoJson,
+}
+
+// Split text into complete statements, ending in semicolons outside of
+// quotes, and whatever follows the last of them.
+fn split_statements(text: &str) -> (Vec<&str>, &str) {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    for (index, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '\'') | (None, '"') | (None, '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (Some(open), _) if c == open => quote = None,
+            (None, ';') => {
+                statements.push(&text[start..=index]);
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+    (statements, &text[start..])
+}
+
+// A geometry as WKT or GeoJSON, if the blob is one and SpatiaLite is loaded.
+fn convert_geometry(conn: &Connection, function: &str, blob: &[u8]) -> Option<String> {
+    conn.query_row(&format!("SELECT {}(?1)", function), params![blob], |row| {
+        row.get::<_, Option<String>>(0)
+    })
+    .ok()
+    .flatten()
+}
+
+fn to_text(conn: &Connection, value: &SqlValue) -> String {
+    match value {
+        SqlValue::Null => String::new(),
+        SqlValue::Integer(value) => value.to_string(),
+        SqlValue::Real(value) => value.to_string(),
+        SqlValue::Text(value) => value.clone(),
+        SqlValue::Blob(blob) => convert_geometry(conn, "AsText", blob)
+            .unwrap_or_else(|| format!("<{} byte blob>", blob.len())),
+    }
+}
+
+fn to_json(conn: &Connection, value: &SqlValue) -> Value {
+    match value {
+        SqlValue::Blob(_) => json!(to_text(conn, value)),
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(value) => json!(value),
+        SqlValue::Real(value) => json!(value),
+        SqlValue::Text(value) => json!(value),
+    }
+}
+
+// Features with the first geometry column as their geometry, or a point from
+// the latitude and longitude columns, and the other columns as properties.
+fn to_geojson(conn: &Connection, names: &[String], rows: &[Vec<SqlValue>]) -> Value {
+    let latitude = names.iter().position(|name| name == "latitude");
+    let longitude = names.iter().position(|name| name == "longitude");
+    let features = rows
+        .iter()
+        .map(|row| {
+            let mut geometry = Value::Null;
+            let mut geometry_column = None;
+            for (index, value) in row.iter().enumerate() {
+                if let SqlValue::Blob(blob) = value {
+                    if let Some(text) = convert_geometry(conn, "AsGeoJSON", blob) {
+                        geometry = serde_json::from_str(&text).unwrap_or(Value::Null);
+                        geometry_column = Some(index);
+                        break;
+                    }
+                }
+            }
+            if geometry.is_null() {
+                if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+                    if let (SqlValue::Real(latitude), SqlValue::Real(longitude)) =
+                        (&row[latitude], &row[longitude])
+                    {
+                        geometry = json!({ "type": "Point", "coordinates": [longitude, latitude] });
+                    }
+                }
+            }
+            let properties = names
+                .iter()
+                .zip(row)
+                .enumerate()
+                .filter(|(index, _)| Some(*index) != geometry_column)
+                .map(|(_, (name, value))| (name.clone(), to_json(conn, value)))
+                .collect::<Map<String, Value>>();
+            json!({ "type": "Feature", "geometry": geometry, "properties": properties })
+        })
+        .collect::<Vec<_>>();
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+fn run_statement(conn: &Connection, sql: &str, mode: Mode) -> io::Result<()> {
+    let mut stmt = conn.prepare(sql).map_err(to_io_error)?;
+    let names = stmt
+        .column_names()
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+    let mut values = Vec::new();
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        let mut row_values = Vec::with_capacity(names.len());
+        for index in 0..names.len() {
+            row_values.push(row.get::<_, SqlValue>(index).map_err(to_io_error)?);
+        }
+        values.push(row_values);
+    }
+    // Statements without results, such as CREATE VIEW, print nothing.
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    match mode {
+        Mode::Table | Mode::Csv => {
+            let headers = names.iter().map(|name| name.as_str()).collect::<Vec<_>>();
+            let rows = values
+                .iter()
+                .map(|row| row.iter().map(|value| to_text(conn, value)).collect())
+                .collect::<Vec<_>>();
+            stats::print_rows(&headers, &rows, mode == Mode::Csv)
+        }
+        Mode::Json => {
+            let rows = values
+                .iter()
+                .map(|row| {
+                    Value::Object(
+                        names
+                            .iter()
+                            .zip(row)
+                            .map(|(name, value)| (name.clone(), to_json(conn, value)))
+                            .collect(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&rows).map_err(to_io_error)?
+            );
+            Ok(())
+        }
+        Mode::GeoJson => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&to_geojson(conn, &names, &values))
+                    .map_err(to_io_error)?
+            );
+            Ok(())
+        }
+    }
+}
+
+// Run a dot command, returning false to quit.
+fn run_command(conn: &Connection, line: &str, mode: &mut Mode) -> io::Result<bool> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some(".quit"), _) | (Some(".exit"), _) => return Ok(false),
+        (Some(".help"), _) => println!("{}", HELP),
+        (Some(".mode"), Some(name)) => {
+            *mode = match name {
+                "table" => Mode::Table,
+                "csv" => Mode::Csv,
+                "json" => Mode::Json,
+                "geojson" => Mode::GeoJson,
+                _ => {
+                    println!(
+                        "Unknown mode {}, expected table, csv, json or geojson",
+                        name
+                    );
+                    return Ok(true);
+                }
+            }
+        }
+        (Some(".tables"), _) => run_statement(
+            conn,
+            "SELECT name, type FROM sqlite_master WHERE type IN ('table', 'view')
+                AND name NOT LIKE 'sqlite_%' AND name NOT LIKE 'idx_%' ORDER BY name",
+            Mode::Table,
+        )?,
+        (Some(".schema"), table) => {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT sql FROM sqlite_master WHERE sql IS NOT NULL
+                        AND (?1 IS NULL OR tbl_name = ?1) ORDER BY tbl_name, type DESC",
+                )
+                .map_err(to_io_error)?;
+            let statements = stmt
+                .query_map(params![table], |row| row.get::<_, String>(0))
+                .map_err(to_io_error)?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .map_err(to_io_error)?;
+            for sql in statements {
+                println!("{};", sql);
+            }
+        }
+        _ => println!("Unknown command {}, see .help", line),
+    }
+    Ok(true)
+}
+
+// An interactive SQL prompt on a database with SpatiaLite loaded, keeping a
+// history of the lines entered in ~/.ebird2spatialite_history.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = matches.value_of("DATABASE").unwrap();
+    let conn = Connection::open(path).map_err(to_io_error)?;
+    if let Err(err) = db::load_spatialite(&conn) {
+        warn!("could not load SpatiaLite: {}", err);
+    }
+
+    let history =
+        env::var_os("HOME").map(|home| PathBuf::from(home).join(".ebird2spatialite_history"));
+    let mut editor = DefaultEditor::new().map_err(to_io_error)?;
+    if let Some(history) = &history {
+        // There is no history the first time.
+        let _ = editor.load_history(history);
+    }
+    println!("Enter SQL statements ending in \";\", or .help for commands");
+
+    let mut mode = Mode::Table;
+    let mut buffer = String::new();
+    loop {
+        let prompt = if buffer.is_empty() {
+            "ebird> "
+        } else {
+            "  ...> "
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C abandons the statement being entered.
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(to_io_error(err)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor
+            .add_history_entry(line.as_str())
+            .map_err(to_io_error)?;
+
+        if buffer.is_empty() && line.trim_start().starts_with('.') {
+            if !run_command(&conn, line.trim(), &mut mode)? {
+                break;
+            }
+            continue;
+        }
+        buffer.push_str(&line);
+        buffer.push('\n');
+        let (statements, rest) = split_statements(&buffer);
+        for statement in statements {
+            if let Err(err) = run_statement(&conn, statement, mode) {
+                error!("{}", err);
+            }
+        }
+        buffer = rest.trim_start().to_string();
+    }
+
+    if let Some(history) = &history {
+        editor.save_history(history).map_err(to_io_error)?;
+    }
+    Ok(())
+}
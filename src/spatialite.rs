@@ -0,0 +1,141 @@
+use crate::db;
+use rusqlite::{Connection, NO_PARAMS};
+use tracing::{info, warn};
+
+#[cfg(feature = "bundled-spatialite")]
+pub use bundled::init;
+
+// What the SpatiaLite available at run time can do, probed before an import
+// so that it can leave out what can't be built rather than failing part way
+// through. Each is the version of the library, if present.
+#[derive(Default)]
+pub struct Capabilities {
+    pub spatialite: Option<String>,
+    // Needed for buffers and hulls.
+    pub geos: Option<String>,
+    // Needed to transform between coordinate systems.
+    pub proj: Option<String>,
+    // SQLite's R*Tree module, which spatial indexes are built on.
+    pub rtree: bool,
+}
+
+fn query_version(conn: &Connection, function: &str) -> Option<String> {
+    conn.query_row(&format!("SELECT {}()", function), NO_PARAMS, |row| {
+        row.get::<_, Option<String>>(0)
+    })
+    .ok()
+    .flatten()
+}
+
+pub fn has_rtree(conn: &Connection) -> bool {
+    conn.query_row(
+        "SELECT sqlite_compileoption_used('ENABLE_RTREE')",
+        NO_PARAMS,
+        |row| row.get::<_, bool>(0),
+    )
+    .unwrap_or(false)
+}
+
+// Load SpatiaLite and find out what it supports, logging what it is and what
+// will be left out if it's missing.
+pub fn probe(conn: &Connection) -> Capabilities {
+    if let Err(err) = db::load_spatialite(conn) {
+        warn!(
+            "could not load SpatiaLite ({}), so records will only have latitude and longitude \
+             columns, without a location geometry or spatial index; give --spatialite-path, \
+             or --require-spatialite to stop instead",
+            err
+        );
+        return Capabilities::default();
+    }
+    let capabilities = Capabilities {
+        spatialite: query_version(conn, "spatialite_version"),
+        geos: query_version(conn, "geos_version"),
+        // SpatiaLite 4 has proj4_version instead.
+        proj: query_version(conn, "proj_version").or_else(|| query_version(conn, "proj4_version")),
+        rtree: has_rtree(conn),
+    };
+    let version = |version: &Option<String>| version.as_deref().unwrap_or("missing").to_string();
+    info!(
+        "SpatiaLite {}, GEOS {}, PROJ {}",
+        version(&capabilities.spatialite),
+        version(&capabilities.geos),
+        version(&capabilities.proj)
+    );
+    if !capabilities.rtree {
+        warn!("SQLite was built without R*Tree, so no spatial index is created");
+    }
+    capabilities
+}
+
+impl Capabilities {
+    pub fn spatial(&self) -> bool {
+        self.spatialite.is_some()
+    }
+
+    // What is missing to build the layer of an option, if anything.
+    pub fn missing_for(&self, option: &str) -> Option<&'static str> {
+        match option {
+            _ if !self.spatial() => Some("SpatiaLite"),
+            "effort-buffers" if self.geos.is_none() => Some("GEOS"),
+            "effort-buffers" if self.proj.is_none() => Some("PROJ"),
+            "ranges" if self.geos.is_none() => Some("GEOS"),
+            _ => None,
+        }
+    }
+}
+
+// With the bundled-spatialite feature, libspatialite is linked in and its
+// functions are registered on each connection, as mod_spatialite does when
+// loaded as an extension.
+#[cfg(feature = "bundled-spatialite")]
+mod bundled {
+    use rusqlite::{ffi, Connection};
+    use std::os::raw::{c_char, c_int, c_void};
+
+    extern "C" {
+        fn spatialite_alloc_connection() -> *mut c_void;
+        fn spatialite_init_ex(db: *mut ffi::sqlite3, cache: *const c_void, verbose: c_int);
+        fn spatialite_cleanup_ex(cache: *const c_void);
+    }
+
+    // Holds the connection's SpatiaLite cache, which SQLite frees when it
+    // drops the function as the connection closes.
+    const CACHE_FUNCTION: &[u8] = b"ebird2spatialite_spatialite_cache\0";
+
+    unsafe extern "C" fn cache_function(
+        ctx: *mut ffi::sqlite3_context,
+        _argc: c_int,
+        _argv: *mut *mut ffi::sqlite3_value,
+    ) {
+        ffi::sqlite3_result_null(ctx);
+    }
+
+    unsafe extern "C" fn free_cache(cache: *mut c_void) {
+        spatialite_cleanup_ex(cache);
+    }
+
+    pub fn init(conn: &Connection) -> rusqlite::Result<()> {
+        unsafe {
+            let db = conn.handle();
+            let cache = spatialite_alloc_connection();
+            spatialite_init_ex(db, cache, 0);
+            // If this fails SQLite calls free_cache itself.
+            let rc = ffi::sqlite3_create_function_v2(
+                db,
+                CACHE_FUNCTION.as_ptr() as *const c_char,
+                0,
+                ffi::SQLITE_UTF8,
+                cache,
+                Some(cache_function),
+                None,
+                None,
+                Some(free_cache),
+            );
+            if rc != ffi::SQLITE_OK {
+                return Err(rusqlite::Error::SqliteFailure(ffi::Error::new(rc), None));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use crate::EBirdRecord;
+use std::collections::BTreeMap;
+
+struct Species {
+    scientific_name: String,
+    first_date: String,
+    last_date: String,
+    records: usize,
+}
+
+// Print the species among the records, for --format species-list, with the
+// dates they were first and last recorded and their number of records. This
+// gives a checklist for the site or region selected by the filters.
+pub fn print_species_list(records: impl Iterator<Item = EBirdRecord>) {
+    let mut species: BTreeMap<String, Species> = BTreeMap::new();
+    for record in records {
+        let EBirdRecord {
+            common_name,
+            scientific_name,
+            obs_date,
+            ..
+        } = record;
+        let entry = species.entry(common_name).or_insert_with(|| Species {
+            scientific_name,
+            first_date: obs_date.clone(),
+            last_date: obs_date.clone(),
+            records: 0,
+        });
+        // Dates are YYYY-MM-DD, so compare as strings.
+        if obs_date < entry.first_date {
+            entry.first_date = obs_date.clone();
+        }
+        if obs_date > entry.last_date {
+            entry.last_date = obs_date;
+        }
+        entry.records += 1;
+    }
+
+    println!("common_name\tscientific_name\tfirst_date\tlast_date\trecords");
+    for (common_name, species) in &species {
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            common_name,
+            species.scientific_name,
+            species.first_date,
+            species.last_date,
+            species.records
+        );
+    }
+    println!("{} species", species.len());
+}
@@ -0,0 +1,190 @@
+use crate::ebd;
+use crate::input::{self, ThreadedReader};
+use crate::memory;
+use crate::to_io_error;
+use chrono::NaiveDate;
+use duckdb::Connection;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// The compression DuckDB should expect, found from the leading magic bytes
+// as for the usual import rather than from the file extension.
+fn compression(path: &str) -> io::Result<&'static str> {
+    let mut magic = [0; 4];
+    let n = input::read_chunk(&mut File::open(path)?, &mut magic)?;
+    let magic = &magic[..n];
+    if magic.starts_with(input::GZIP_MAGIC) {
+        Ok("gzip")
+    } else if magic.starts_with(input::ZSTD_MAGIC) {
+        Ok("zstd")
+    } else if magic.starts_with(input::BZIP2_MAGIC) {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "DuckDB can't read bzip2, so decompress the input or leave out --via-duckdb",
+        ))
+    } else {
+        Ok("none")
+    }
+}
+
+// The filters which can be written as SQL, for DuckDB to apply while it
+// reads the input. They only narrow down the records: each one keeps every
+// record the usual filter would, which still runs afterwards.
+fn conditions(matches: &clap::ArgMatches, headers: &[String]) -> Vec<String> {
+    let mut conditions = Vec::new();
+    let column = |name: &str| ebd::header_for(name, headers).map(identifier);
+    let date = |arg: &str| {
+        matches
+            .value_of(arg)
+            .and_then(|text| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok())
+    };
+    if let Some(obs_date) = column("obs_date") {
+        // Records with dates which don't parse are kept, as by the filter.
+        let obs_date = format!("TRY_CAST({} AS DATE)", obs_date);
+        if let Some(since) = date("since-date") {
+            conditions.push(format!(
+                "({0} IS NULL OR {0} >= DATE '{1}')",
+                obs_date, since
+            ));
+        }
+        if let Some(before) = date("before-date") {
+            conditions.push(format!(
+                "({0} IS NULL OR {0} <= DATE '{1}')",
+                obs_date, before
+            ));
+        }
+    }
+    let flags = if matches.is_present("ignore-case") {
+        ", 'i'"
+    } else {
+        ""
+    };
+    for (arg, name) in &[
+        ("common-name-regex", "common_name"),
+        ("scientific-name-regex", "scientific_name"),
+    ] {
+        if let (Some(patterns), Some(column)) = (matches.values_of(arg), column(name)) {
+            let any = patterns
+                .map(|pattern| format!("regexp_matches({}, {}{})", column, quote(pattern), flags))
+                .collect::<Vec<_>>();
+            conditions.push(format!("({})", any.join(" OR ")));
+        }
+    }
+    if let (Some(min_count), Some(count)) = (
+        matches
+            .value_of("min-count")
+            .and_then(|text| text.parse::<i64>().ok()),
+        column("observation_count"),
+    ) {
+        let keep_x = if matches.is_present("keep-x") {
+            format!(" OR {} = 'X'", count)
+        } else {
+            String::new()
+        };
+        conditions.push(format!(
+            "(TRY_CAST({} AS BIGINT) >= {}{})",
+            count, min_count, keep_x
+        ));
+    }
+    if !matches.is_present("include-unapproved") {
+        if let Some(approved) = column("approved") {
+            conditions.push(format!(
+                "TRY_CAST({} AS BIGINT) IS DISTINCT FROM 0",
+                approved
+            ));
+        }
+    }
+    conditions
+}
+
+type Chunk = io::Result<Vec<u8>>;
+
+// Run the query, sending the rows on as tab separated lines under the header.
+fn stream(
+    conn: &Connection,
+    query: &str,
+    headers: &[String],
+    sender: &SyncSender<Chunk>,
+) -> io::Result<()> {
+    let mut chunk = headers.join("\t").into_bytes();
+    chunk.push(b'\n');
+    let mut statement = conn.prepare(query).map_err(to_io_error)?;
+    let mut rows = statement.query([]).map_err(to_io_error)?;
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        for i in 0..headers.len() {
+            if i > 0 {
+                chunk.push(b'\t');
+            }
+            if let Some(value) = row.get::<_, Option<String>>(i).map_err(to_io_error)? {
+                chunk.extend_from_slice(value.as_bytes());
+            }
+        }
+        chunk.push(b'\n');
+        if chunk.len() >= input::CHUNK_SIZE {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(input::CHUNK_SIZE));
+            // The receiver has gone away if the import stopped early.
+            if sender.send(Ok(full)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    let _ = sender.send(Ok(chunk));
+    Ok(())
+}
+
+// Read an EBD file through DuckDB for --via-duckdb, which reads it in
+// parallel and applies the filters it can as SQL before the records are
+// parsed, as tab separated text again. This is often faster than filtering
+// every record in Rust when most of a full dump is filtered out.
+// Rows DuckDB can't read, such as those with invalid UTF-8, are skipped
+// rather than repaired.
+pub fn open(path: &str, matches: &clap::ArgMatches) -> io::Result<Box<dyn Read>> {
+    if input::is_object_url(path) || path.starts_with("http://") || path.starts_with("https://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--via-duckdb only reads local files",
+        ));
+    }
+    let source = format!(
+        "read_csv({}, delim = '\\t', quote = '', escape = '', header = true, \
+         all_varchar = true, null_padding = true, ignore_errors = true, compression = '{}')",
+        quote(path),
+        compression(path)?
+    );
+    let conn = Connection::open_in_memory().map_err(to_io_error)?;
+    let headers = {
+        let mut statement = conn
+            .prepare(&format!("DESCRIBE SELECT * FROM {}", source))
+            .map_err(to_io_error)?;
+        let headers = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(to_io_error)?;
+        headers
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(to_io_error)?
+    };
+    let mut query = format!("SELECT * FROM {}", source);
+    let conditions = conditions(matches, &headers);
+    if !conditions.is_empty() {
+        query.push_str(" WHERE ");
+        query.push_str(&conditions.join(" AND "));
+    }
+    let (sender, receiver) =
+        mpsc::sync_channel(memory::queue_length(input::CHUNK_SIZE, input::CHUNK_COUNT));
+    thread::spawn(move || {
+        if let Err(err) = stream(&conn, &query, &headers, &sender) {
+            let _ = sender.send(Err(err));
+        }
+    });
+    Ok(Box::new(ThreadedReader::from_receiver(receiver)))
+}
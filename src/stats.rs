@@ -0,0 +1,521 @@
+use crate::db;
+use crate::grid::{self, Grid};
+use crate::to_io_error;
+use chrono::NaiveDate;
+use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tracing::warn;
+
+// Print rows as CSV, or as a table with aligned columns.
+pub fn print_rows(headers: &[&str], rows: &[Vec<String>], csv: bool) -> io::Result<()> {
+    if csv {
+        let mut writer = csv::Writer::from_writer(io::stdout());
+        writer.write_record(headers)?;
+        for row in rows {
+            writer.write_record(row)?;
+        }
+        return writer.flush();
+    }
+
+    let mut widths = headers
+        .iter()
+        .map(|header| header.len())
+        .collect::<Vec<_>>();
+    for row in rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+    let line = |values: Vec<&str>| {
+        let padded = values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{:width$}", value, width = width))
+            .collect::<Vec<_>>();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(|value| value.as_str()).collect());
+    }
+    Ok(())
+}
+
+// Run a query, reading every column as text. NULLs read as empty.
+fn query(conn: &Connection, sql: &str) -> rusqlite::Result<Vec<Vec<String>>> {
+    let mut stmt = conn.prepare(sql)?;
+    let columns = stmt.column_count();
+    let mut rows = stmt.query(NO_PARAMS)?;
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut values = Vec::with_capacity(columns);
+        for index in 0..columns {
+            let value = match row.get_raw(index) {
+                rusqlite::types::ValueRef::Null => String::new(),
+                rusqlite::types::ValueRef::Integer(value) => value.to_string(),
+                rusqlite::types::ValueRef::Real(value) => format!("{:.1}", value),
+                rusqlite::types::ValueRef::Text(value) => {
+                    String::from_utf8_lossy(value).into_owned()
+                }
+                rusqlite::types::ValueRef::Blob(_) => String::new(),
+            };
+            values.push(value);
+        }
+        result.push(values);
+    }
+    Ok(result)
+}
+
+fn parse_date(matches: &clap::ArgMatches, arg: &str) -> io::Result<Option<String>> {
+    match matches.value_of(arg) {
+        Some(text) => match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+            Ok(date) => Ok(Some(date.format("%Y-%m-%d").to_string())),
+            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+        },
+        None => Ok(None),
+    }
+}
+
+// Write the grid cells with fewer than the given number of complete
+// checklists to the {table}_gaps layer, to direct surveyors to under-covered
+// areas. The grid covers the bounding box of all the checklists.
+fn gaps(matches: &clap::ArgMatches, path: &str, table: &str) -> io::Result<()> {
+    let size = grid::parse_distance(matches.value_of("grid").unwrap())?;
+    let min_checklists = match matches.value_of("min-checklists").unwrap().parse::<usize>() {
+        Ok(min_checklists) => min_checklists,
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+    };
+    let since_date = parse_date(matches, "since-date")?;
+    let before_date = parse_date(matches, "before-date")?;
+
+    let conn = Connection::open(path).map_err(to_io_error)?;
+    db::require_spatialite(&conn)?;
+    let source = db::source_table(&conn, table).map_err(to_io_error)?;
+
+    let mut checklists = Vec::new();
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT longitude, latitude, all_species_reported = 1
+                    AND (?1 IS NULL OR obs_date >= ?1) AND (?2 IS NULL OR obs_date <= ?2)
+                FROM {} GROUP BY sampling_event_id",
+            source
+        ))
+        .map_err(to_io_error)?;
+    let mut rows = stmt
+        .query(params![since_date, before_date])
+        .map_err(to_io_error)?;
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        let x: f64 = row.get(0).map_err(to_io_error)?;
+        let y: f64 = row.get(1).map_err(to_io_error)?;
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+        if row.get(2).map_err(to_io_error)? {
+            checklists.push((x, y));
+        }
+    }
+    if min_x > max_x {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No checklists in {}", table),
+        ));
+    }
+
+    let grid = Grid::new(min_x, min_y, max_x, max_y, size);
+    let mut counts: HashMap<(usize, usize), usize> = HashMap::new();
+    for (x, y) in checklists {
+        if let Some(cell) = grid.cell(x, y) {
+            *counts.entry(cell).or_insert(0) += 1;
+        }
+    }
+
+    let layer = format!("{}_gaps", table);
+    conn.execute_batch("BEGIN").map_err(to_io_error)?;
+    db::create_layer(
+        &conn,
+        &layer,
+        "grid_column INTEGER, grid_row INTEGER, checklists INTEGER",
+        "POLYGON",
+    )
+    .map_err(to_io_error)?;
+    let mut stmt = conn
+        .prepare(&format!(
+            "INSERT INTO {} (grid_column, grid_row, checklists, geometry)
+                VALUES (?1, ?2, ?3, BuildMbr(?4, ?5, ?6, ?7, 4326))",
+            layer
+        ))
+        .map_err(to_io_error)?;
+    let mut gaps = 0;
+    for row in 0..grid.rows {
+        for column in 0..grid.columns {
+            let count = counts.get(&(column, row)).cloned().unwrap_or(0);
+            if count >= min_checklists {
+                continue;
+            }
+            let (x1, y1, x2, y2) = grid.bounds(column, row);
+            stmt.execute(params![
+                column as i64,
+                row as i64,
+                count as i64,
+                x1,
+                y1,
+                x2,
+                y2
+            ])
+            .map_err(to_io_error)?;
+            gaps += 1;
+        }
+    }
+    conn.execute_batch("COMMIT").map_err(to_io_error)?;
+
+    println!(
+        "{} of {} cells have fewer than {} complete checklists, see {}",
+        gaps,
+        grid.rows * grid.columns,
+        min_checklists,
+        layer
+    );
+    Ok(())
+}
+
+// Write records of species rarely detected in the region that week of the
+// year to the {table}_rarities layer, as a queue for review. The detection
+// frequency is the fraction of complete checklists in the table from that
+// week, in any year, reporting the species. Only records in the date window
+// are flagged, while the baseline uses every record.
+fn rarities(matches: &clap::ArgMatches, path: &str, table: &str) -> io::Result<()> {
+    let threshold = match matches.value_of("threshold").unwrap().parse::<f64>() {
+        Ok(threshold) => threshold,
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+    };
+    let since_date = parse_date(matches, "since-date")?;
+    let before_date = parse_date(matches, "before-date")?;
+
+    let conn = Connection::open(path).map_err(to_io_error)?;
+    db::require_spatialite(&conn)?;
+    if db::is_checklist_table(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "A checklist table has no species",
+        ));
+    }
+    let source = db::source_table(&conn, table).map_err(to_io_error)?;
+
+    let layer = format!("{}_rarities", table);
+    conn.execute_batch("BEGIN").map_err(to_io_error)?;
+    db::create_layer(
+        &conn,
+        &layer,
+        "record_id INTEGER, guid TEXT, common_name TEXT, scientific_name TEXT,
+            observation_count TEXT, obs_date TEXT, obs_id TEXT, sampling_event_id TEXT,
+            week INTEGER, frequency REAL",
+        "POINT",
+    )
+    .map_err(to_io_error)?;
+    let flagged = conn
+        .execute(
+            &format!(
+                "INSERT INTO {layer} (record_id, guid, common_name, scientific_name,
+                        observation_count, obs_date, obs_id, sampling_event_id, week, frequency,
+                        geometry)
+                    WITH weeks AS (
+                        SELECT CAST(strftime('%W', obs_date) AS INTEGER) AS week,
+                                COUNT(DISTINCT sampling_event_id) AS complete
+                            FROM {source} WHERE all_species_reported = 1 GROUP BY week),
+                    detections AS (
+                        SELECT scientific_name, CAST(strftime('%W', obs_date) AS INTEGER) AS week,
+                                COUNT(DISTINCT sampling_event_id) AS detected
+                            FROM {source} WHERE all_species_reported = 1
+                            GROUP BY scientific_name, week)
+                    SELECT r.id, r.guid, r.common_name, r.scientific_name, r.observation_count,
+                            r.obs_date, r.obs_id, r.sampling_event_id, weeks.week,
+                            COALESCE(detections.detected, 0) * 1.0 / weeks.complete AS frequency,
+                            MakePoint(r.longitude, r.latitude, 4326)
+                        FROM {source} AS r
+                        JOIN weeks ON weeks.week = CAST(strftime('%W', r.obs_date) AS INTEGER)
+                        LEFT JOIN detections ON detections.scientific_name = r.scientific_name
+                            AND detections.week = weeks.week
+                        WHERE (?2 IS NULL OR r.obs_date >= ?2) AND (?3 IS NULL OR r.obs_date <= ?3)
+                            AND COALESCE(detections.detected, 0) * 1.0 / weeks.complete < ?1",
+                layer = layer,
+                source = source
+            ),
+            params![threshold, since_date, before_date],
+        )
+        .map_err(to_io_error)?;
+    conn.execute_batch("COMMIT").map_err(to_io_error)?;
+
+    println!(
+        "flagged {} records with a detection frequency below {}, see {}",
+        flagged, threshold, layer
+    );
+    Ok(())
+}
+
+const VEGA_LITE_SCHEMA: &str = "https://vega.github.io/schema/vega-lite/v5.json";
+
+// The number of species whose weekly frequency is charted.
+const CHARTED_SPECIES: usize = 10;
+
+fn write_spec(directory: &Path, name: &str, spec: Value) -> io::Result<()> {
+    let path = directory.join(format!("{}.vl.json", name));
+    fs::write(
+        &path,
+        serde_json::to_string_pretty(&spec).map_err(to_io_error)?,
+    )?;
+    println!("wrote {}", path.display());
+    Ok(())
+}
+
+// Write Vega-Lite specs, with their data inline, for charts of the species
+// accumulation, the weekly detection frequency of the most frequently detected
+// species, and the effort each month, which can be rendered with the Vega
+// Editor or any Vega-Lite library.
+fn vega_lite(
+    conn: &Connection,
+    source: &str,
+    checklists: bool,
+    effort: &str,
+    directory: &str,
+) -> io::Result<()> {
+    let directory = Path::new(directory);
+    fs::create_dir_all(directory)?;
+
+    if checklists {
+        warn!("a checklist table has no species, only charting effort");
+    } else {
+        // The number of species recorded by each date, from the date each
+        // was first recorded.
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT first_date, COUNT(*) FROM (
+                    SELECT MIN(obs_date) AS first_date FROM {} GROUP BY scientific_name)
+                    GROUP BY first_date ORDER BY first_date",
+                source
+            ))
+            .map_err(to_io_error)?;
+        let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+        let mut species = 0;
+        let mut values = Vec::new();
+        while let Some(row) = rows.next().map_err(to_io_error)? {
+            species += row.get::<_, i64>(1).map_err(to_io_error)?;
+            values.push(json!({
+                "date": row.get::<_, String>(0).map_err(to_io_error)?,
+                "species": species,
+            }));
+        }
+        write_spec(
+            directory,
+            "species_accumulation",
+            json!({
+                "$schema": VEGA_LITE_SCHEMA,
+                "title": "Species accumulation",
+                "data": { "values": values },
+                "mark": { "type": "line", "interpolate": "step-after" },
+                "encoding": {
+                    "x": { "field": "date", "type": "temporal", "title": "Date" },
+                    "y": { "field": "species", "type": "quantitative", "title": "Species recorded" },
+                },
+            }),
+        )?;
+
+        // Detection frequency on complete checklists, as in --rarities.
+        let mut stmt = conn
+            .prepare(&format!(
+                "WITH complete AS (
+                    SELECT * FROM {source} WHERE all_species_reported = 1),
+                weeks AS (
+                    SELECT CAST(strftime('%W', obs_date) AS INTEGER) AS week,
+                            COUNT(DISTINCT sampling_event_id) AS checklists
+                        FROM complete GROUP BY week),
+                charted AS (
+                    SELECT scientific_name, MIN(common_name) AS common_name FROM complete
+                        GROUP BY scientific_name
+                        ORDER BY COUNT(DISTINCT sampling_event_id) DESC, scientific_name
+                        LIMIT {limit})
+                SELECT charted.common_name, weeks.week,
+                        COUNT(DISTINCT complete.sampling_event_id) * 1.0 / weeks.checklists
+                    FROM charted CROSS JOIN weeks
+                    LEFT JOIN complete ON complete.scientific_name = charted.scientific_name
+                        AND CAST(strftime('%W', complete.obs_date) AS INTEGER) = weeks.week
+                    GROUP BY charted.scientific_name, weeks.week
+                    ORDER BY charted.common_name, weeks.week",
+                source = source,
+                limit = CHARTED_SPECIES
+            ))
+            .map_err(to_io_error)?;
+        let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+        let mut values = Vec::new();
+        while let Some(row) = rows.next().map_err(to_io_error)? {
+            values.push(json!({
+                "species": row.get::<_, String>(0).map_err(to_io_error)?,
+                "week": row.get::<_, i64>(1).map_err(to_io_error)?,
+                "frequency": row.get::<_, f64>(2).map_err(to_io_error)?,
+            }));
+        }
+        write_spec(
+            directory,
+            "weekly_frequency",
+            json!({
+                "$schema": VEGA_LITE_SCHEMA,
+                "title": "Weekly detection frequency on complete checklists",
+                "data": { "values": values },
+                "mark": "line",
+                "encoding": {
+                    "x": { "field": "week", "type": "quantitative", "title": "Week of the year" },
+                    "y": {
+                        "field": "frequency",
+                        "type": "quantitative",
+                        "title": "Frequency",
+                        "axis": { "format": "%" },
+                    },
+                    "color": { "field": "species", "type": "nominal", "title": "Species" },
+                },
+            }),
+        )?;
+    }
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "{} SELECT substr(obs_date, 1, 7) AS month, COUNT(*),
+                    COALESCE(SUM(duration_min), 0) / 60.0
+                FROM checklists GROUP BY month ORDER BY month",
+            effort
+        ))
+        .map_err(to_io_error)?;
+    let mut rows = stmt.query(NO_PARAMS).map_err(to_io_error)?;
+    let mut values = Vec::new();
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        let month = row.get::<_, String>(0).map_err(to_io_error)?;
+        values.push(json!({
+            "month": month,
+            "measure": "Checklists",
+            "value": row.get::<_, i64>(1).map_err(to_io_error)?,
+        }));
+        values.push(json!({
+            "month": month,
+            "measure": "Hours",
+            "value": row.get::<_, f64>(2).map_err(to_io_error)?,
+        }));
+    }
+    write_spec(
+        directory,
+        "effort_over_time",
+        json!({
+            "$schema": VEGA_LITE_SCHEMA,
+            "title": "Effort each month",
+            "data": { "values": values },
+            "mark": "bar",
+            "encoding": {
+                "x": { "field": "month", "type": "temporal", "timeUnit": "yearmonth", "title": "Month" },
+                "y": { "field": "value", "type": "quantitative", "title": null },
+                "row": { "field": "measure", "type": "nominal", "title": null },
+            },
+            "resolve": { "scale": { "y": "independent" } },
+        }),
+    )
+}
+
+// Summaries of the records in a database, for atlas coordinators and others
+// tracking coverage.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = matches.value_of("DATABASE").unwrap();
+    let table = crate::table_name(matches)?;
+    let csv = matches.is_present("csv");
+
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io_error)?;
+    if !db::table_exists(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No table {} in {}", table, path),
+        ));
+    }
+    if matches.is_present("gaps") {
+        drop(conn);
+        return gaps(matches, path, table);
+    }
+    if matches.is_present("rarities") {
+        drop(conn);
+        return rarities(matches, path, table);
+    }
+
+    let source = db::source_table(&conn, table).map_err(to_io_error)?;
+    let checklists = db::is_checklist_table(&conn, table).map_err(to_io_error)?;
+
+    // Effort is reported once per checklist, but repeated on each of its
+    // observations.
+    let effort = format!(
+        "WITH checklists AS (
+            SELECT obs_id, obs_date, duration_min, effort_distance_km FROM {}
+                GROUP BY sampling_event_id)",
+        source
+    );
+
+    if let Some(directory) = matches.value_of("vega-lite") {
+        return vega_lite(&conn, &source, checklists, &effort, directory);
+    }
+
+    // Without either option, print both summaries.
+    let all = !matches.is_present("observers") && !matches.is_present("monthly");
+    if all || matches.is_present("observers") {
+        // A checklist table doesn't have the species each observer recorded.
+        let (species, join) = if checklists {
+            ("NULL", String::new())
+        } else {
+            (
+                "species",
+                format!(
+                    "LEFT JOIN (SELECT obs_id, COUNT(DISTINCT scientific_name) AS species
+                        FROM {} GROUP BY obs_id) USING (obs_id)",
+                    source
+                ),
+            )
+        };
+        let rows = query(
+            &conn,
+            &format!(
+                "{} SELECT obs_id, COUNT(*) AS checklists, SUM(duration_min) / 60.0,
+                        SUM(effort_distance_km), {}
+                    FROM checklists {} GROUP BY obs_id ORDER BY checklists DESC, obs_id",
+                effort, species, join
+            ),
+        )
+        .map_err(to_io_error)?;
+        print_rows(
+            &["observer", "checklists", "hours", "distance_km", "species"],
+            &rows,
+            csv,
+        )?;
+    }
+
+    if all {
+        println!();
+    }
+
+    if all || matches.is_present("monthly") {
+        let rows = query(
+            &conn,
+            &format!(
+                "{} SELECT substr(obs_date, 1, 7) AS month, COUNT(*), COUNT(DISTINCT obs_id),
+                        SUM(duration_min) / 60.0, SUM(effort_distance_km)
+                    FROM checklists GROUP BY month ORDER BY month",
+                effort
+            ),
+        )
+        .map_err(to_io_error)?;
+        print_rows(
+            &["month", "checklists", "observers", "hours", "distance_km"],
+            &rows,
+            csv,
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use chrono::{Datelike, NaiveDate, NaiveTime, Timelike};
+use std::f64::consts::PI;
+
+// The sun is 6 degrees below the horizon at civil dusk and dawn.
+const CIVIL_TWILIGHT_ZENITH: f64 = 96.0;
+
+// Civil dawn and dusk in minutes after midnight of the local clock, using
+// the NOAA approximations, for a clock offset from UTC by the given number of
+// minutes.
+fn dawn_and_dusk(date: NaiveDate, latitude: f64, longitude: f64, utc_offset: f64) -> (f64, f64) {
+    let gamma = 2.0 * PI / 365.0 * (date.ordinal0() as f64);
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * gamma.cos()
+            - 0.032077 * gamma.sin()
+            - 0.014615 * (2.0 * gamma).cos()
+            - 0.040849 * (2.0 * gamma).sin());
+    let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+        - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin()
+        - 0.002697 * (3.0 * gamma).cos()
+        + 0.00148 * (3.0 * gamma).sin();
+
+    let latitude = latitude.to_radians();
+    // Out of range values mean it is dark all day (polar night) or never gets
+    // dark, which clamping turns into dawn and dusk at noon or a day apart.
+    let cos_hour_angle = (CIVIL_TWILIGHT_ZENITH.to_radians().cos()
+        / (latitude.cos() * declination.cos())
+        - latitude.tan() * declination.tan())
+    .clamp(-1.0, 1.0);
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    let solar_noon = 720.0 - 4.0 * longitude - equation_of_time + utc_offset;
+    (solar_noon - 4.0 * hour_angle, solar_noon + 4.0 * hour_angle)
+}
+
+// The offset from UTC in minutes of the nominal time zone of a longitude, for
+// records whose time zone isn't known. This ignores daylight saving time and
+// zones drawn far from their meridian, so can be an hour or more out.
+pub fn mean_utc_offset(longitude: f64) -> f64 {
+    (longitude / 15.0).round() * 60.0
+}
+
+// Whether a checklist started between civil dusk and dawn, given the offset
+// of its clock time from UTC in minutes. Records without a start time are not
+// nocturnal.
+pub fn is_nocturnal(
+    obs_date: &str,
+    time_obs_started: &str,
+    latitude: f64,
+    longitude: f64,
+    utc_offset: f64,
+) -> bool {
+    let date = match NaiveDate::parse_from_str(obs_date, "%Y-%m-%d") {
+        Ok(date) => date,
+        Err(_) => return false,
+    };
+    let time = match NaiveTime::parse_from_str(time_obs_started, "%H:%M:%S") {
+        Ok(time) => time,
+        Err(_) => return false,
+    };
+    let minutes = (time.hour() * 60 + time.minute()) as f64;
+
+    // Dawn and dusk may fall either side of midnight in zones far from their
+    // meridian.
+    let (dawn, dusk) = dawn_and_dusk(date, latitude, longitude, utc_offset);
+    (minutes - dawn).rem_euclid(1440.0) >= dusk - dawn
+}
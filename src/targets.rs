@@ -0,0 +1,121 @@
+use crate::db;
+use crate::geojson;
+use crate::to_io_error;
+use geo::algorithm::bounding_rect::BoundingRect;
+use geo::algorithm::contains::Contains;
+use geo::point;
+use rusqlite::{params, Connection, OpenFlags};
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use tracing::warn;
+
+// Print the species recorded in a region which the observer has never
+// recorded anywhere, most often recorded first, i.e. their targets for the
+// region.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = matches.value_of("DATABASE").unwrap();
+    let observer = matches.value_of("observer").unwrap();
+    let table = crate::table_name(matches)?;
+    let region = geojson::read_polygons(matches.value_of("region").unwrap())?;
+    if region.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "No polygons in the region file",
+        ));
+    }
+
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io_error)?;
+    if !db::table_exists(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No table {} in {}", table, path),
+        ));
+    }
+    if db::is_checklist_table(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "A checklist table has no species",
+        ));
+    }
+    let source = db::source_table(&conn, table).map_err(to_io_error)?;
+
+    let mut seen = HashSet::new();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT DISTINCT scientific_name FROM {} WHERE obs_id = ?1",
+            source
+        ))
+        .map_err(to_io_error)?;
+    let mut rows = stmt.query(params![observer]).map_err(to_io_error)?;
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        seen.insert(row.get::<_, String>(0).map_err(to_io_error)?);
+    }
+    if seen.is_empty() {
+        warn!("no records by observer {}", observer);
+    }
+
+    // Only read records within the bounding box of the region, then check
+    // them against the polygons.
+    let rects = region
+        .iter()
+        .filter_map(|feature| feature.geometry.bounding_rect())
+        .collect::<Vec<_>>();
+    let bounds = (
+        rects
+            .iter()
+            .map(|rect| rect.min().x)
+            .fold(f64::MAX, f64::min),
+        rects
+            .iter()
+            .map(|rect| rect.min().y)
+            .fold(f64::MAX, f64::min),
+        rects
+            .iter()
+            .map(|rect| rect.max().x)
+            .fold(f64::MIN, f64::max),
+        rects
+            .iter()
+            .map(|rect| rect.max().y)
+            .fold(f64::MIN, f64::max),
+    );
+
+    let mut targets: BTreeMap<String, (String, usize)> = BTreeMap::new();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT common_name, scientific_name, longitude, latitude FROM {}
+                WHERE longitude BETWEEN ?1 AND ?3 AND latitude BETWEEN ?2 AND ?4",
+            source
+        ))
+        .map_err(to_io_error)?;
+    let mut rows = stmt
+        .query(params![bounds.0, bounds.1, bounds.2, bounds.3])
+        .map_err(to_io_error)?;
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        let scientific_name: String = row.get(1).map_err(to_io_error)?;
+        if seen.contains(&scientific_name) {
+            continue;
+        }
+        let location = point!(
+            x: row.get::<_, f64>(2).map_err(to_io_error)?,
+            y: row.get::<_, f64>(3).map_err(to_io_error)?
+        );
+        if !region
+            .iter()
+            .any(|feature| feature.geometry.contains(&location))
+        {
+            continue;
+        }
+        let common_name: String = row.get(0).map_err(to_io_error)?;
+        targets.entry(scientific_name).or_insert((common_name, 0)).1 += 1;
+    }
+
+    let mut targets = targets.into_iter().collect::<Vec<_>>();
+    targets.sort_by(|(_, (_, a)), (_, (_, b))| b.cmp(a));
+    println!("common_name\tscientific_name\trecords");
+    for (scientific_name, (common_name, records)) in &targets {
+        println!("{}\t{}\t{}", common_name, scientific_name, records);
+    }
+    println!("{} species", targets.len());
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use crate::input::InputStats;
+use serde_json::{json, Value};
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, info_span, Span};
+
+// Tracks import throughput and how many records pass each filter, logging
+// progress every interval (if given) and a summary at the end, so that
+// tuning options like --batch-size and --fast can be compared between runs.
+// Reading, filtering and inserting each happen in a span, and the time spent
+// in each is reported with the summary.
+pub struct Telemetry {
+    start: Instant,
+    interval: Option<Duration>,
+    last_progress: Cell<Instant>,
+    // Bytes read from the input file, before decompression. Not available
+    // for Darwin Core Archives, which are read from inside a zip file.
+    input: Option<Arc<InputStats>>,
+    read: Cell<usize>,
+    // Records remaining after each active filter, in the order applied.
+    stages: RefCell<Vec<(&'static str, usize)>>,
+    inserted: Cell<usize>,
+    read_span: Span,
+    filter_span: Span,
+    insert_span: Span,
+    read_time: Cell<Duration>,
+    // Time spent producing filtered records, including reading them.
+    pipeline_time: Cell<Duration>,
+    insert_time: Cell<Duration>,
+}
+
+// Run f in a span, adding the time it took to a total.
+fn timed<T>(span: &Span, total: &Cell<Duration>, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = span.in_scope(f);
+    total.set(total.get() + start.elapsed());
+    result
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        100.0 * count as f64 / total as f64
+    }
+}
+
+impl Telemetry {
+    pub fn new(interval: Option<Duration>, input: Option<Arc<InputStats>>) -> Telemetry {
+        let now = Instant::now();
+        Telemetry {
+            start: now,
+            interval,
+            last_progress: Cell::new(now),
+            input,
+            read: Cell::new(0),
+            stages: RefCell::new(Vec::new()),
+            inserted: Cell::new(0),
+            read_span: info_span!("read"),
+            filter_span: info_span!("filter"),
+            insert_span: info_span!("insert"),
+            read_time: Cell::new(Duration::default()),
+            pipeline_time: Cell::new(Duration::default()),
+            insert_time: Cell::new(Duration::default()),
+        }
+    }
+
+    pub fn reading<T>(&self, f: impl FnOnce() -> T) -> T {
+        timed(&self.read_span, &self.read_time, f)
+    }
+
+    pub fn filtering<T>(&self, f: impl FnOnce() -> T) -> T {
+        timed(&self.filter_span, &self.pipeline_time, f)
+    }
+
+    pub fn inserting<T>(&self, f: impl FnOnce() -> T) -> T {
+        timed(&self.insert_span, &self.insert_time, f)
+    }
+
+    // Register a filter stage; stages which aren't registered (because the
+    // corresponding option wasn't given) are not reported.
+    pub fn add_stage(&self, stage: &'static str) {
+        self.stages.borrow_mut().push((stage, 0));
+    }
+
+    pub fn passed(&self, stage: &'static str) {
+        let mut stages = self.stages.borrow_mut();
+        if let Some((_, count)) = stages.iter_mut().find(|(name, _)| *name == stage) {
+            *count += 1;
+        }
+    }
+
+    pub fn record_read(&self) {
+        self.read.set(self.read.get() + 1);
+        if let Some(interval) = self.interval {
+            let now = Instant::now();
+            if now.duration_since(self.last_progress.get()) >= interval {
+                self.last_progress.set(now);
+                info!(
+                    read = self.read.get(),
+                    inserted = self.inserted.get(),
+                    "progress: {}",
+                    self.rates()
+                );
+            }
+        }
+    }
+
+    pub fn record_inserted(&self) {
+        self.inserted.set(self.inserted.get() + 1);
+    }
+
+    fn rates(&self) -> String {
+        let elapsed = self.start.elapsed().as_secs_f64().max(0.001);
+        let mut text = format!(
+            "read {} records ({:.0} records/s",
+            self.read.get(),
+            self.read.get() as f64 / elapsed
+        );
+        if let Some(input) = &self.input {
+            let bytes = input.bytes();
+            text += &format!(
+                ", {:.1} MB, {:.1} MB/s",
+                megabytes(bytes),
+                megabytes(bytes) / elapsed
+            );
+        }
+        text += &format!("), inserted {} records", self.inserted.get());
+        text
+    }
+
+    fn filter_time(&self) -> Duration {
+        self.pipeline_time
+            .get()
+            .checked_sub(self.read_time.get())
+            .unwrap_or_default()
+    }
+
+    // The counts and timings of the summary, for --report.
+    pub fn to_json(&self) -> Value {
+        let mut previous = self.read.get();
+        let stages = self
+            .stages
+            .borrow()
+            .iter()
+            .map(|(stage, count)| {
+                let stage = json!({ "stage": stage, "passed": count, "of": previous });
+                previous = *count;
+                stage
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "read": self.read.get(),
+            "inserted": self.inserted.get(),
+            "elapsed_secs": self.start.elapsed().as_secs_f64(),
+            "read_secs": self.read_time.get().as_secs_f64(),
+            "filter_secs": self.filter_time().as_secs_f64(),
+            "insert_secs": self.insert_time.get().as_secs_f64(),
+            "stages": stages,
+        })
+    }
+
+    pub fn summary(&self) {
+        info!(
+            read = self.read.get(),
+            inserted = self.inserted.get(),
+            elapsed_secs = self.start.elapsed().as_secs_f64(),
+            read_secs = self.read_time.get().as_secs_f64(),
+            filter_secs = self.filter_time().as_secs_f64(),
+            insert_secs = self.insert_time.get().as_secs_f64(),
+            "{} in {:.1}s",
+            self.rates(),
+            self.start.elapsed().as_secs_f64()
+        );
+        let mut previous = self.read.get();
+        for (stage, count) in self.stages.borrow().iter() {
+            info!(
+                stage = *stage,
+                passed = *count,
+                of = previous,
+                "{}: {} of {} records passed ({:.1}%)",
+                stage,
+                count,
+                previous,
+                percent(*count, previous)
+            );
+            previous = *count;
+        }
+    }
+}
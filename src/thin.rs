@@ -0,0 +1,99 @@
+use crate::EBirdRecord;
+use rstar::RTree;
+use std::collections::HashMap;
+use std::f64::consts::FRAC_PI_2;
+
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+// A point on the sphere in metres from the centre of the earth, so that the
+// straight line distance between nearby points is their distance along the
+// surface, wherever they are.
+fn to_cartesian(latitude: f64, longitude: f64) -> [f64; 3] {
+    let (latitude, longitude) = (latitude.to_radians(), longitude.to_radians());
+    [
+        EARTH_RADIUS * latitude.cos() * longitude.cos(),
+        EARTH_RADIUS * latitude.cos() * longitude.sin(),
+        EARTH_RADIUS * latitude.sin(),
+    ]
+}
+
+// Greedy spatial thinning with --thin-distance, keeping a record unless a
+// record of the same species already kept is within the distance. Records are
+// considered in input order, so which of a cluster of records is kept depends
+// on the order of the input.
+pub struct Thinner {
+    // The straight line distance through the earth matching the distance
+    // along the surface, squared as rstar expects.
+    chord_squared: f64,
+    kept: HashMap<String, RTree<[f64; 3]>>,
+}
+
+impl Thinner {
+    pub fn new(distance: f64) -> Thinner {
+        // Half the angle between the points, which can be at most half way
+        // round the earth.
+        let half_angle = (distance / (2.0 * EARTH_RADIUS)).min(FRAC_PI_2);
+        let chord = 2.0 * EARTH_RADIUS * half_angle.sin();
+        Thinner {
+            chord_squared: chord * chord,
+            kept: HashMap::new(),
+        }
+    }
+
+    pub fn keep(&mut self, record: &EBirdRecord) -> bool {
+        let point = to_cartesian(record.latitude, record.longitude);
+        if !self.kept.contains_key(&record.scientific_name) {
+            self.kept
+                .insert(record.scientific_name.clone(), RTree::new());
+        }
+        let kept = self.kept.get_mut(&record.scientific_name).unwrap();
+        if kept
+            .locate_within_distance(point, self.chord_squared)
+            .next()
+            .is_some()
+        {
+            return false;
+        }
+        kept.insert(point);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(scientific_name: &str, latitude: f64, longitude: f64) -> EBirdRecord {
+        EBirdRecord {
+            scientific_name: scientific_name.to_string(),
+            latitude,
+            longitude,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keeps_one_record_per_species_within_the_distance() {
+        let mut thinner = Thinner::new(1000.0);
+        assert!(thinner.keep(&record("Bubo scandiacus", 45.0, -75.0)));
+        // About 550m north.
+        assert!(!thinner.keep(&record("Bubo scandiacus", 45.005, -75.0)));
+        assert!(thinner.keep(&record("Bubo virginianus", 45.005, -75.0)));
+        // About 1.1km north.
+        assert!(thinner.keep(&record("Bubo scandiacus", 45.01, -75.0)));
+    }
+
+    #[test]
+    fn distances_over_a_radian_of_arc() {
+        // A quarter of the way round the earth is about 10,000km, and
+        // the antipode about 20,000km.
+        let mut thinner = Thinner::new(15_000_000.0);
+        assert!(thinner.keep(&record("Bubo scandiacus", 0.0, 0.0)));
+        assert!(!thinner.keep(&record("Bubo scandiacus", 0.0, 90.0)));
+        assert!(thinner.keep(&record("Bubo scandiacus", 0.0, 180.0)));
+
+        let mut thinner = Thinner::new(25_000_000.0);
+        assert!(thinner.keep(&record("Bubo scandiacus", 0.0, 0.0)));
+        assert!(!thinner.keep(&record("Bubo scandiacus", 0.0, 180.0)));
+    }
+}
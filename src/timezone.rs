@@ -0,0 +1,95 @@
+use crate::geojson;
+use crate::EBirdRecord;
+use chrono::{LocalResult, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use geo::algorithm::bounding_rect::BoundingRect;
+use geo::algorithm::contains::Contains;
+use geo::{point, MultiPolygon, Rect};
+use std::io;
+use tracing::warn;
+
+// Time zone boundaries, as published by timezone-boundary-builder, used to
+// find the time zone of each record so that its local date and start time
+// can be converted to a proper timestamp.
+pub struct TimeZones {
+    // The bounding box of each zone is checked first, since most points are
+    // only near a few zones.
+    zones: Vec<(Rect<f64>, MultiPolygon<f64>, Tz)>,
+}
+
+impl TimeZones {
+    pub fn load(path: &str) -> io::Result<TimeZones> {
+        let mut zones = Vec::new();
+        for feature in geojson::read_polygons(path)? {
+            let tz = match feature.property("tzid").map(|tzid| tzid.parse::<Tz>()) {
+                Some(Ok(tz)) => tz,
+                Some(Err(err)) => {
+                    warn!("skipping unknown time zone: {}", err);
+                    continue;
+                }
+                None => continue,
+            };
+            if let Some(rect) = feature.geometry.bounding_rect() {
+                zones.push((rect, feature.geometry, tz));
+            }
+        }
+        if zones.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No time zones with a tzid property in {}", path),
+            ));
+        }
+        Ok(TimeZones { zones })
+    }
+
+    fn lookup(&self, latitude: f64, longitude: f64) -> Option<Tz> {
+        let location = point!(x: longitude, y: latitude);
+        self.zones
+            .iter()
+            .filter(|(rect, _, _)| {
+                rect.min().x <= longitude
+                    && longitude <= rect.max().x
+                    && rect.min().y <= latitude
+                    && latitude <= rect.max().y
+            })
+            .find(|(_, zone, _)| zone.contains(&location))
+            .map(|(_, _, tz)| *tz)
+    }
+
+    // The offset from UTC in minutes of the clock at a location and local
+    // time, including daylight saving time.
+    pub fn utc_offset(&self, latitude: f64, longitude: f64, naive: &NaiveDateTime) -> Option<f64> {
+        let tz = self.lookup(latitude, longitude)?;
+        let local = match tz.from_local_datetime(naive) {
+            LocalResult::Single(local) => local,
+            LocalResult::Ambiguous(earlier, _) => earlier,
+            LocalResult::None => return None,
+        };
+        Some(f64::from(local.offset().fix().local_minus_utc()) / 60.0)
+    }
+
+    // Set the time zone of the record, along with its local and UTC start
+    // timestamps if it has a start time. Times which are skipped or repeated
+    // by daylight saving time changes are left empty or taken as the earlier
+    // of the two, respectively.
+    pub fn apply(&self, record: &mut EBirdRecord) {
+        let tz = match self.lookup(record.latitude, record.longitude) {
+            Some(tz) => tz,
+            None => return,
+        };
+        record.time_zone = Some(tz.name().to_string());
+
+        let text = format!("{} {}", record.obs_date, record.time_obs_started);
+        let naive = match NaiveDateTime::parse_from_str(&text, "%Y-%m-%d %H:%M:%S") {
+            Ok(naive) => naive,
+            Err(_) => return,
+        };
+        let local = match tz.from_local_datetime(&naive) {
+            LocalResult::Single(local) => local,
+            LocalResult::Ambiguous(earlier, _) => earlier,
+            LocalResult::None => return,
+        };
+        record.local_datetime = Some(local.to_rfc3339());
+        record.utc_datetime = Some(local.with_timezone(&Utc).to_rfc3339());
+    }
+}
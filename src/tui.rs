@@ -0,0 +1,445 @@
+use crate::db;
+use crate::to_io_error;
+use chrono::NaiveDate;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use rusqlite::{params, Connection, OpenFlags};
+use std::collections::HashMap;
+use std::io;
+
+// Characters for cells of the density map, from fewest records to most.
+const SHADES: &[char] = &[' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+
+// Record counts in each character cell of the density map.
+type Density = HashMap<(u16, u16), i64>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Species,
+    Since,
+    Before,
+}
+
+impl Field {
+    fn label(self) -> &'static str {
+        match self {
+            Field::Species => "species",
+            Field::Since => "since",
+            Field::Before => "before",
+        }
+    }
+}
+
+struct Browser {
+    conn: Connection,
+    source: String,
+    checklists: bool,
+    columns: &'static [&'static str],
+    species: String,
+    since_date: String,
+    before_date: String,
+    // The filter being edited, and the text typed so far.
+    editing: Option<(Field, String)>,
+    message: String,
+    total: i64,
+    offset: i64,
+    page_size: i64,
+    rows: Vec<Vec<String>>,
+    table_state: TableState,
+    // Record counts in each cell of the map, for the map size they were
+    // counted for.
+    density: Option<((u16, u16), Density)>,
+}
+
+impl Browser {
+    // The filters as query parameters, NULL when not set.
+    fn filters(&self) -> (Option<String>, Option<&str>, Option<&str>) {
+        (
+            Some(&self.species)
+                .filter(|species| !species.is_empty())
+                .map(|species| format!("%{}%", species)),
+            Some(self.since_date.as_str()).filter(|date| !date.is_empty()),
+            Some(self.before_date.as_str()).filter(|date| !date.is_empty()),
+        )
+    }
+
+    fn filter_sql(&self) -> &'static str {
+        if self.checklists {
+            "?1 IS NULL AND (?2 IS NULL OR obs_date >= ?2) AND (?3 IS NULL OR obs_date <= ?3)"
+        } else {
+            "(?1 IS NULL OR common_name LIKE ?1 OR scientific_name LIKE ?1)
+                AND (?2 IS NULL OR obs_date >= ?2) AND (?3 IS NULL OR obs_date <= ?3)"
+        }
+    }
+
+    // Count the matching records, and start again from the first page.
+    fn apply_filters(&mut self) -> rusqlite::Result<()> {
+        let (species, since_date, before_date) = self.filters();
+        self.total = self.conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {} WHERE {}",
+                self.source,
+                self.filter_sql()
+            ),
+            params![species, since_date, before_date],
+            |row| row.get(0),
+        )?;
+        self.offset = 0;
+        self.table_state.select(Some(0));
+        self.density = None;
+        self.load_page()
+    }
+
+    fn load_page(&mut self) -> rusqlite::Result<()> {
+        let (species, since_date, before_date) = self.filters();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM {} WHERE {}
+                ORDER BY obs_date DESC, id LIMIT ?4 OFFSET ?5",
+            self.columns.join(", "),
+            self.source,
+            self.filter_sql()
+        ))?;
+        let mut rows = stmt.query(params![
+            species,
+            since_date,
+            before_date,
+            self.page_size,
+            self.offset
+        ])?;
+        self.rows.clear();
+        while let Some(row) = rows.next()? {
+            let mut values = Vec::with_capacity(self.columns.len());
+            for index in 0..self.columns.len() {
+                values.push(match row.get_raw(index) {
+                    rusqlite::types::ValueRef::Integer(value) => value.to_string(),
+                    rusqlite::types::ValueRef::Real(value) => value.to_string(),
+                    rusqlite::types::ValueRef::Text(value) => {
+                        String::from_utf8_lossy(value).into_owned()
+                    }
+                    _ => String::new(),
+                });
+            }
+            self.rows.push(values);
+        }
+        Ok(())
+    }
+
+    // Count the matching records falling in each character cell of a map of
+    // the given size, covering their extent.
+    fn count_density(&mut self, size: (u16, u16)) -> rusqlite::Result<()> {
+        let (species, since_date, before_date) = self.filters();
+        let (west, east, south, north): (Option<f64>, Option<f64>, Option<f64>, Option<f64>) =
+            self.conn.query_row(
+                &format!(
+                    "SELECT MIN(longitude), MAX(longitude), MIN(latitude), MAX(latitude)
+                        FROM {} WHERE {}",
+                    self.source,
+                    self.filter_sql()
+                ),
+                params![species, since_date, before_date],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?;
+        let mut cells = HashMap::new();
+        if let (Some(west), Some(east), Some(south), Some(north)) = (west, east, south, north) {
+            let (columns, rows) = size;
+            let width = (east - west).max(1e-9) / f64::from(columns);
+            let height = (north - south).max(1e-9) / f64::from(rows);
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT MIN(CAST((longitude - ?4) / ?5 AS INTEGER), ?7 - 1),
+                        MIN(CAST((?6 - latitude) / ?8 AS INTEGER), ?9 - 1), COUNT(*)
+                    FROM {} WHERE {} GROUP BY 1, 2",
+                self.source,
+                self.filter_sql()
+            ))?;
+            let mut counts = stmt.query(params![
+                species,
+                since_date,
+                before_date,
+                west,
+                width,
+                north,
+                columns,
+                height,
+                rows
+            ])?;
+            while let Some(row) = counts.next()? {
+                cells.insert((row.get(0)?, row.get(1)?), row.get(2)?);
+            }
+        }
+        self.density = Some((size, cells));
+        Ok(())
+    }
+
+    fn move_selection(&mut self, delta: i64) -> rusqlite::Result<()> {
+        let selected = self.table_state.selected().unwrap_or(0) as i64;
+        let position = (self.offset + selected + delta).max(0).min(self.total - 1);
+        if position < 0 {
+            return Ok(());
+        }
+        if position < self.offset || position >= self.offset + self.page_size {
+            self.offset = position / self.page_size * self.page_size;
+            self.load_page()?;
+        }
+        self.table_state
+            .select(Some((position - self.offset) as usize));
+        Ok(())
+    }
+
+    fn finish_editing(&mut self) -> rusqlite::Result<()> {
+        let (field, text) = match self.editing.take() {
+            Some(editing) => editing,
+            None => return Ok(()),
+        };
+        let text = text.trim().to_string();
+        if field != Field::Species
+            && !text.is_empty()
+            && NaiveDate::parse_from_str(&text, "%Y-%m-%d").is_err()
+        {
+            self.message = format!("Invalid date {}, expected YYYY-MM-DD", text);
+            return Ok(());
+        }
+        if field == Field::Species && self.checklists && !text.is_empty() {
+            self.message = "A checklist table has no species".to_string();
+            return Ok(());
+        }
+        match field {
+            Field::Species => self.species = text,
+            Field::Since => self.since_date = text,
+            Field::Before => self.before_date = text,
+        }
+        self.message.clear();
+        self.apply_filters()
+    }
+
+    // Handle a key, returning false to quit.
+    fn handle_key(&mut self, code: KeyCode) -> rusqlite::Result<bool> {
+        if let Some((_, text)) = &mut self.editing {
+            match code {
+                KeyCode::Enter => self.finish_editing()?,
+                KeyCode::Esc => self.editing = None,
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Char(c) => text.push(c),
+                _ => (),
+            }
+            return Ok(true);
+        }
+        let start = |field: Field, value: &str| Some((field, value.to_string()));
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1)?,
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1)?,
+            KeyCode::PageDown | KeyCode::Char(' ') => self.move_selection(self.page_size)?,
+            KeyCode::PageUp => self.move_selection(-self.page_size)?,
+            KeyCode::Home => self.move_selection(-self.total)?,
+            KeyCode::End => self.move_selection(self.total)?,
+            KeyCode::Char('/') | KeyCode::Char('s') => {
+                self.editing = start(Field::Species, &self.species)
+            }
+            KeyCode::Char('f') => self.editing = start(Field::Since, &self.since_date),
+            KeyCode::Char('t') => self.editing = start(Field::Before, &self.before_date),
+            KeyCode::Char('c') => {
+                self.species.clear();
+                self.since_date.clear();
+                self.before_date.clear();
+                self.apply_filters()?;
+            }
+            _ => (),
+        }
+        Ok(true)
+    }
+
+    fn filter_line(&self) -> Line<'static> {
+        let value = |field: Field, text: &str| match &self.editing {
+            Some((editing, typed)) if *editing == field => format!("{}_", typed),
+            _ if text.is_empty() => "any".to_string(),
+            _ => text.to_string(),
+        };
+        Line::from(format!(
+            "species: {}   since: {}   before: {}   {} records",
+            value(Field::Species, &self.species),
+            value(Field::Since, &self.since_date),
+            value(Field::Before, &self.before_date),
+            self.total
+        ))
+    }
+
+    fn draw(&mut self, frame: &mut Frame, map_area: Rect) {
+        let [filters, body, help] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [records, _] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(body);
+
+        let title = match &self.editing {
+            Some((field, _)) => format!(
+                " Editing {} (Enter to apply, Esc to cancel) ",
+                field.label()
+            ),
+            None => " Filters ".to_string(),
+        };
+        frame.render_widget(
+            Paragraph::new(self.filter_line()).block(Block::bordered().title(title)),
+            filters,
+        );
+
+        let widths = self
+            .columns
+            .iter()
+            .map(|column| match *column {
+                "obs_date" => Constraint::Length(10),
+                "observation_count" | "species_count" => Constraint::Length(7),
+                _ => Constraint::Fill(1),
+            })
+            .collect::<Vec<_>>();
+        let header = Row::new(self.columns.iter().map(|column| column.replace('_', " ")))
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = self.rows.iter().map(|row| Row::new(row.clone()));
+        let page = if self.total == 0 {
+            " Records ".to_string()
+        } else {
+            format!(
+                " Records {}-{} of {} ",
+                self.offset + 1,
+                self.offset + self.rows.len() as i64,
+                self.total
+            )
+        };
+        frame.render_stateful_widget(
+            Table::new(rows, widths)
+                .header(header)
+                .block(Block::bordered().title(page))
+                .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+            records,
+            &mut self.table_state,
+        );
+
+        let mut map = String::new();
+        if let Some((size, cells)) = &self.density {
+            let max = cells.values().cloned().max().unwrap_or(0) as f64;
+            for row in 0..size.1 {
+                for column in 0..size.0 {
+                    // Shaded on a log scale, so that sparse areas still show.
+                    let shade = match cells.get(&(column, row)) {
+                        Some(count) if max > 1.0 => {
+                            1 + ((*count as f64).ln() / max.ln() * (SHADES.len() - 2) as f64)
+                                as usize
+                        }
+                        Some(_) => SHADES.len() - 1,
+                        None => 0,
+                    };
+                    map.push(SHADES[shade.min(SHADES.len() - 1)]);
+                }
+                map.push('\n');
+            }
+        }
+        frame.render_widget(
+            Paragraph::new(map).block(Block::bordered().title(" Density ")),
+            map_area,
+        );
+
+        let message = if self.message.is_empty() {
+            "q quit  ↑↓ move  PgUp/PgDn page  / species  f since  t before  c clear"
+        } else {
+            self.message.as_str()
+        };
+        frame.render_widget(Paragraph::new(message), help);
+    }
+}
+
+// The area of the density map within the screen, matching the layout drawn.
+fn map_area(area: Rect) -> Rect {
+    let [_, body, _] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(5),
+        Constraint::Length(1),
+    ])
+    .areas(area);
+    let [_, map] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(body);
+    map
+}
+
+fn run_browser(terminal: &mut DefaultTerminal, browser: &mut Browser) -> io::Result<()> {
+    loop {
+        let area = map_area(terminal.get_frame().area());
+        // The table has a border, and a header row.
+        let page_size = i64::from(area.height.saturating_sub(3).max(1));
+        if page_size != browser.page_size {
+            browser.page_size = page_size;
+            browser.offset = 0;
+            browser.table_state.select(Some(0));
+            browser.load_page().map_err(to_io_error)?;
+        }
+        let size = (
+            area.width.saturating_sub(2).max(1),
+            area.height.saturating_sub(2).max(1),
+        );
+        if !matches!(&browser.density, Some((counted, _)) if *counted == size) {
+            browser.count_density(size).map_err(to_io_error)?;
+        }
+        terminal.draw(|frame| browser.draw(frame, area))?;
+
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press
+                && !browser.handle_key(key.code).map_err(to_io_error)?
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Browse the records in a database in the terminal, filtering by species and
+// date, for checking an import on a server without a desktop.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = matches.value_of("DATABASE").unwrap();
+    let table = crate::table_name(matches)?;
+
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io_error)?;
+    if !db::table_exists(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No table {} in {}", table, path),
+        ));
+    }
+    let source = db::source_table(&conn, table).map_err(to_io_error)?;
+    let checklists = db::is_checklist_table(&conn, table).map_err(to_io_error)?;
+    let mut browser = Browser {
+        conn,
+        source,
+        checklists,
+        columns: if checklists {
+            &["obs_date", "locality", "species_count"]
+        } else {
+            &["obs_date", "common_name", "observation_count", "locality"]
+        },
+        species: String::new(),
+        since_date: String::new(),
+        before_date: String::new(),
+        editing: None,
+        message: String::new(),
+        total: 0,
+        offset: 0,
+        page_size: 0,
+        rows: Vec::new(),
+        table_state: TableState::default(),
+        density: None,
+    };
+    browser.apply_filters().map_err(to_io_error)?;
+
+    let mut terminal = ratatui::try_init()?;
+    let result = run_browser(&mut terminal, &mut browser);
+    ratatui::try_restore()?;
+    result
+}
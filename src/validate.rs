@@ -0,0 +1,228 @@
+use crate::db;
+use crate::dictionary;
+use crate::to_io_error;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, NO_PARAMS};
+use serde_json::json;
+use std::io;
+
+// The result of one check. Errors mean the database is broken, warnings that
+// it will be slow or is missing something it would usually have.
+struct Check {
+    name: &'static str,
+    status: &'static str,
+    message: String,
+}
+
+impl Check {
+    fn new(name: &'static str, failed: bool, status: &'static str, message: String) -> Check {
+        Check {
+            name,
+            status: if failed { status } else { "ok" },
+            message,
+        }
+    }
+}
+
+fn count(conn: &Connection, sql: &str) -> rusqlite::Result<i64> {
+    conn.query_row(sql, NO_PARAMS, |row| row.get(0))
+}
+
+fn run_checks(conn: &Connection, table: &str) -> rusqlite::Result<Vec<Check>> {
+    let mut checks = Vec::new();
+
+    let registered: Option<(i64, bool)> = conn
+        .query_row(
+            "SELECT srid, spatial_index_enabled FROM geometry_columns
+                WHERE f_table_name = lower(?1) AND f_geometry_column = 'location'",
+            params![table],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let (srid, indexed) = match registered {
+        Some(registered) => registered,
+        None => {
+            checks.push(Check::new(
+                "srid",
+                true,
+                "error",
+                format!("{}.location is not registered in geometry_columns", table),
+            ));
+            return Ok(checks);
+        }
+    };
+    let mismatched = count(
+        conn,
+        &format!(
+            "SELECT COUNT(*) FROM {} WHERE location IS NOT NULL AND SRID(location) <> 4326",
+            table
+        ),
+    )?;
+    checks.push(Check::new(
+        "srid",
+        srid != 4326 || mismatched > 0,
+        "error",
+        format!(
+            "location is registered with SRID {}, {} geometries have another SRID",
+            srid, mismatched
+        ),
+    ));
+
+    let missing = count(
+        conn,
+        &format!("SELECT COUNT(*) FROM {} WHERE location IS NULL", table),
+    )?;
+    let invalid = count(
+        conn,
+        &format!(
+            "SELECT COUNT(*) FROM {} WHERE location IS NOT NULL AND ST_IsValid(location) <> 1",
+            table
+        ),
+    )?;
+    checks.push(Check::new(
+        "geometry",
+        missing > 0 || invalid > 0,
+        "error",
+        format!(
+            "{} records have no location, {} are invalid",
+            missing, invalid
+        ),
+    ));
+
+    let index_table = db::table_exists(conn, &format!("idx_{}_location", table))?;
+    checks.push(Check::new(
+        "spatial_index",
+        !indexed || !index_table,
+        "warning",
+        if indexed && index_table {
+            "location has a spatial index".to_string()
+        } else {
+            "location has no spatial index".to_string()
+        },
+    ));
+
+    let checklists = db::is_checklist_table(conn, table)?;
+    let default_indexes = if checklists {
+        db::DEFAULT_CHECKLIST_INDEXES
+    } else {
+        db::DEFAULT_INDEXES
+    };
+    let mut missing_indexes = Vec::new();
+    for column in default_indexes {
+        let exists = conn
+            .query_row(
+                "SELECT 1 FROM pragma_index_list(?1) AS list, pragma_index_info(list.name) AS info
+                    WHERE info.name = ?2",
+                params![table, column],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !exists {
+            missing_indexes.push(*column);
+        }
+    }
+    checks.push(Check::new(
+        "indexes",
+        !missing_indexes.is_empty(),
+        "warning",
+        if missing_indexes.is_empty() {
+            "the default columns are indexed".to_string()
+        } else {
+            format!("no index on {}", missing_indexes.join(", "))
+        },
+    ));
+
+    if db::is_dictionary_encoded(conn, table)? {
+        let mut orphaned = Vec::new();
+        for column in dictionary::ENCODED_COLUMNS {
+            let orphans = count(
+                conn,
+                &format!(
+                    "SELECT COUNT(*) FROM {table} WHERE {column} IS NOT NULL
+                        AND {column} NOT IN (SELECT id FROM {column}_dictionary)",
+                    table = table,
+                    column = column
+                ),
+            )?;
+            if orphans > 0 {
+                orphaned.push(format!("{} {}", orphans, column));
+            }
+        }
+        checks.push(Check::new(
+            "dictionary",
+            !orphaned.is_empty(),
+            "error",
+            if orphaned.is_empty() {
+                "every encoded value is in its dictionary".to_string()
+            } else {
+                format!("ids missing from dictionaries: {}", orphaned.join(", "))
+            },
+        ));
+    }
+
+    // Checklist tables have a unique index on the checklist instead.
+    if !checklists {
+        let duplicates = count(
+            conn,
+            &format!(
+                "SELECT COUNT(*) FROM (SELECT guid FROM {} WHERE guid <> ''
+                    GROUP BY guid HAVING COUNT(*) > 1)",
+                table
+            ),
+        )?;
+        checks.push(Check::new(
+            "duplicate_guids",
+            duplicates > 0,
+            "error",
+            format!("{} GUIDs belong to more than one record", duplicates),
+        ));
+    }
+
+    Ok(checks)
+}
+
+// Check a database for problems, printing a JSON report with the result of
+// each check, and fail if any check found an error.
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = matches.value_of("DATABASE").unwrap();
+    let table = crate::table_name(matches)?;
+
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io_error)?;
+    if !db::table_exists(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("No table {} in {}", table, path),
+        ));
+    }
+    db::require_spatialite(&conn)?;
+    let checks = run_checks(&conn, table).map_err(to_io_error)?;
+
+    let valid = checks.iter().all(|check| check.status != "error");
+    let report = json!({
+        "database": path,
+        "table": table,
+        "valid": valid,
+        "checks": checks
+            .iter()
+            .map(|check| json!({
+                "check": check.name,
+                "status": check.status,
+                "message": check.message,
+            }))
+            .collect::<Vec<_>>(),
+    });
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).map_err(to_io_error)?
+    );
+
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} failed validation", path),
+        ))
+    }
+}
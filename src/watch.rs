@@ -0,0 +1,160 @@
+use crate::db;
+use crate::ebd::EbdReader;
+use crate::input::{self, InputStats};
+use crate::to_io_error;
+use chrono::Utc;
+use rusqlite::{params, Connection, NO_PARAMS};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Files which are still being written are commonly given a temporary name
+// and renamed once complete, so ignore them along with hidden files.
+fn is_candidate(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    path.is_file() && !name.starts_with('.') && !name.ends_with(".part") && !name.ends_with(".tmp")
+}
+
+fn is_imported(conn: &Connection, name: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare("SELECT 1 FROM imported_files WHERE name = ?1")?;
+    stmt.exists(params![name])
+}
+
+fn import_file(conn: &mut Connection, table: &str, path: &Path) -> io::Result<usize> {
+    let input = path.to_string_lossy();
+    let stats = Arc::new(InputStats::default());
+    let started_at = Utc::now();
+    let reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .quoting(false)
+        .flexible(true)
+        .from_reader(input::open_input(&input, stats.clone())?);
+
+    let columns = db::table_columns(conn, table).map_err(to_io_error)?;
+    let columns = columns.iter().map(String::as_str).collect::<Vec<_>>();
+    let tx = conn.transaction().map_err(to_io_error)?;
+    let mut read = 0;
+    let mut count = 0;
+    let mut first_id: Option<i64> = None;
+    for deserialized in EbdReader::new(reader)? {
+        read += 1;
+        let record = match deserialized {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        match db::upsert_record(&tx, table, &columns, &record) {
+            Ok(_) => {
+                count += 1;
+                // New ids are above those of the records already in the
+                // table, so the records added are those from the lowest id
+                // added on.
+                let id = tx.last_insert_rowid();
+                first_id = Some(first_id.map_or(id, |first_id| first_id.min(id)));
+            }
+            Err(err) => warn!("could not insert record: {}", err),
+        }
+    }
+
+    let run = db::ImportRun {
+        table,
+        input: &input,
+        input_format: "ebd",
+        input_sha256: Some(stats.sha256()),
+        parameters: "{}".to_string(),
+        started_at,
+        records_read: read,
+        records_inserted: count,
+        errors: read - count,
+        status: "completed",
+    };
+    db::insert_import_run(&tx, &run).map_err(to_io_error)?;
+
+    let name = path.file_name().unwrap().to_string_lossy();
+    tx.execute(
+        "INSERT INTO imported_files (name, imported_at) VALUES (?1, datetime('now'))",
+        params![name],
+    )
+    .map_err(to_io_error)?;
+    tx.commit().map_err(to_io_error)?;
+    if let Some(first_id) = first_id {
+        db::build_geometry_from(conn, table, first_id).map_err(to_io_error)?;
+    }
+    db::refresh_fts(conn, table).map_err(to_io_error)?;
+
+    Ok(count)
+}
+
+pub fn run(matches: &clap::ArgMatches) -> io::Result<()> {
+    let dir = matches.value_of("DIR").unwrap();
+    let table = crate::table_name(matches)?;
+    let interval = match matches.value_of("interval").unwrap().parse::<u64>() {
+        Ok(interval) => Duration::from_secs(interval),
+        Err(err) => {
+            return Err(io::Error::new(io::ErrorKind::Other, err));
+        }
+    };
+
+    let mut conn = db::open_for_update("ebird.sqlite", table).map_err(to_io_error)?;
+    if db::is_dictionary_encoded(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can't add records to a dictionary encoded database",
+        ));
+    }
+    if db::is_checklist_table(&conn, table).map_err(to_io_error)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can't add records to a checklist table",
+        ));
+    }
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS imported_files (
+                name                            TEXT PRIMARY KEY,
+                imported_at                     TEXT)",
+        NO_PARAMS,
+    )
+    .map_err(to_io_error)?;
+
+    // Sizes of files seen on the previous poll. A file is only imported once
+    // its size has stopped changing, so we don't pick up partial copies.
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+
+    info!("watching {} for new files", dir);
+    loop {
+        let mut paths = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| is_candidate(path))
+            .collect::<Vec<PathBuf>>();
+        // Delta files are named by date, so import them in order.
+        paths.sort();
+
+        for path in paths {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if is_imported(&conn, &name).map_err(to_io_error)? {
+                continue;
+            }
+
+            let size = fs::metadata(&path)?.len();
+            if sizes.get(&path) != Some(&size) {
+                sizes.insert(path, size);
+                continue;
+            }
+            sizes.remove(&path);
+
+            match import_file(&mut conn, table, &path) {
+                Ok(count) => info!("imported {} records from {}", count, name),
+                Err(err) => warn!("could not import {}: {}", name, err),
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
@@ -0,0 +1,209 @@
+// Filtering of eBird data in the browser, using the record model and filters
+// of ebird2spatialite built without SQLite. Build with
+// wasm-pack build --target web.
+use ebird::filter::Filter as _;
+use ebird::{coords, ebd, filter, grid, near, personal, EBirdRecord};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::io;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+
+fn to_js_error(err: impl std::fmt::Display) -> JsError {
+    JsError::new(&err.to_string())
+}
+
+fn parse_date(text: &str) -> chrono::ParseResult<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+}
+
+fn date_argument(text: &str) -> Result<chrono::NaiveDate, JsError> {
+    parse_date(text).map_err(|err| JsError::new(&format!("Invalid date {}: {}", text, err)))
+}
+
+fn regex(pattern: &str) -> Result<Regex, JsError> {
+    Regex::new(pattern).map_err(to_js_error)
+}
+
+/// A test of whether to keep a record, made with the static methods and
+/// combined with and(), or() and not().
+#[wasm_bindgen]
+pub struct Filter {
+    inner: Rc<dyn filter::Filter>,
+}
+
+impl Filter {
+    fn new(f: impl Fn(&EBirdRecord) -> bool + 'static) -> Filter {
+        Filter {
+            inner: filter::predicate(f),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Filter {
+    /// Every record.
+    pub fn all() -> Filter {
+        Filter::new(|_| true)
+    }
+
+    /// Records observed on or after a date, as YYYY-MM-DD.
+    #[wasm_bindgen(js_name = sinceDate)]
+    pub fn since_date(date: &str) -> Result<Filter, JsError> {
+        let date = date_argument(date)?;
+        Ok(Filter::new(move |record| {
+            match parse_date(&record.obs_date) {
+                Ok(other) => other >= date,
+                Err(_) => true,
+            }
+        }))
+    }
+
+    /// Records observed on or before a date, as YYYY-MM-DD.
+    #[wasm_bindgen(js_name = beforeDate)]
+    pub fn before_date(date: &str) -> Result<Filter, JsError> {
+        let date = date_argument(date)?;
+        Ok(Filter::new(move |record| {
+            match parse_date(&record.obs_date) {
+                Ok(other) => other <= date,
+                Err(_) => true,
+            }
+        }))
+    }
+
+    /// Records within buffer (e.g. "50km") of a location, given as
+    /// latitude,longitude or a WKT point, measured with metric "haversine"
+    /// or "vincenty".
+    pub fn near(location: &str, buffer: &str, metric: &str) -> Result<Filter, JsError> {
+        let point = coords::parse_point(location).map_err(to_js_error)?;
+        let buffer = grid::parse_distance(buffer).map_err(to_js_error)?;
+        let metric = match metric {
+            "haversine" => near::Metric::Haversine,
+            "vincenty" => near::Metric::Vincenty,
+            _ => {
+                return Err(JsError::new(&format!(
+                    "Unknown metric {}, expected haversine or vincenty",
+                    metric
+                )))
+            }
+        };
+        let near = near::Near::new(point, buffer, metric);
+        Ok(Filter::new(move |record| {
+            near.contains(record.latitude, record.longitude)
+        }))
+    }
+
+    /// Records whose common name matches a regular expression.
+    #[wasm_bindgen(js_name = commonName)]
+    pub fn common_name(pattern: &str) -> Result<Filter, JsError> {
+        let regex = regex(pattern)?;
+        Ok(Filter::new(move |record| {
+            regex.is_match(&record.common_name)
+        }))
+    }
+
+    /// Records whose scientific name matches a regular expression.
+    #[wasm_bindgen(js_name = scientificName)]
+    pub fn scientific_name(pattern: &str) -> Result<Filter, JsError> {
+        let regex = regex(pattern)?;
+        Ok(Filter::new(move |record| {
+            regex.is_match(&record.scientific_name)
+        }))
+    }
+
+    /// Records of at least this many individuals, leaving out those counted
+    /// as X.
+    #[wasm_bindgen(js_name = minCount)]
+    pub fn min_count(count: i32) -> Filter {
+        let count = i64::from(count);
+        Filter::new(
+            move |record| match record.observation_count.parse::<i64>() {
+                Ok(other) => other >= count,
+                Err(_) => false,
+            },
+        )
+    }
+
+    pub fn and(&self, other: &Filter) -> Filter {
+        let and = filter::And(vec![
+            Box::new(self.inner.clone()),
+            Box::new(other.inner.clone()),
+        ]);
+        Filter {
+            inner: Rc::new(and),
+        }
+    }
+
+    pub fn or(&self, other: &Filter) -> Filter {
+        let or = filter::Or(vec![
+            Box::new(self.inner.clone()),
+            Box::new(other.inner.clone()),
+        ]);
+        Filter { inner: Rc::new(or) }
+    }
+
+    pub fn not(&self) -> Filter {
+        let not = filter::Not(Box::new(self.inner.clone()));
+        Filter {
+            inner: Rc::new(not),
+        }
+    }
+}
+
+fn records<'a>(
+    text: &'a str,
+    format: &str,
+) -> io::Result<Box<dyn Iterator<Item = csv::Result<EBirdRecord>> + 'a>> {
+    let reader = csv::ReaderBuilder::new()
+        .delimiter(if format == "personal" { b',' } else { b'\t' })
+        .quoting(format == "personal")
+        .flexible(format == "ebd")
+        .from_reader(text.as_bytes());
+    Ok(match format {
+        "ebd" => Box::new(ebd::EbdReader::new(reader)?),
+        "personal" => Box::new(
+            reader
+                .into_deserialize::<personal::PersonalRecord>()
+                .map(|deserialized| deserialized.map(EBirdRecord::from)),
+        ),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown format {}, expected ebd or personal", format),
+            ))
+        }
+    })
+}
+
+fn feature(record: &EBirdRecord) -> Value {
+    let mut properties = record.to_json();
+    properties.remove("latitude");
+    properties.remove("longitude");
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Point",
+            "coordinates": [record.longitude, record.latitude],
+        },
+        "properties": properties,
+    })
+}
+
+/// The records of an EBD extract, or a personal export with format
+/// "personal", which pass a filter, as a GeoJSON FeatureCollection of points.
+#[wasm_bindgen(js_name = toGeoJSON)]
+pub fn to_geojson(text: &str, format: &str, filter: &Filter) -> Result<String, JsError> {
+    let mut features = Vec::new();
+    for record in records(text, format).map_err(to_js_error)? {
+        let record =
+            record.map_err(|err| JsError::new(&format!("Could not parse record: {}", err)))?;
+        if filter.inner.matches(&record) {
+            features.push(feature(&record));
+        }
+    }
+    let collection = json!({
+        "type": "FeatureCollection",
+        "features": features,
+    });
+    Ok(collection.to_string())
+}